@@ -0,0 +1,308 @@
+// Addresses known for discovered peers, from rendezvous, restored records and mDNS.
+// Each peer keeps at most `max_per_peer` addresses, evicting the least recently seen, and
+// addresses that keep failing to dial are skipped so we stop redialing dead ones. Addresses
+// this client can't dial at all are never stored, and relay circuits only while the server relays.
+use libp2p::{multiaddr::Protocol, Multiaddr, PeerId};
+use std::{collections::HashMap, net::{Ipv4Addr, Ipv6Addr}};
+
+// Addresses kept per peer unless configured otherwise
+pub const MAX_ADDRESSES_PER_PEER: usize = 8;
+// Failed dials in a row after which an address is no longer dialed
+pub const MAX_DIAL_FAILURES: u32 = 3;
+
+struct KnownAddress {
+    addr: Multiaddr,
+    // Logical time of the last discovery or successful dial, for LRU eviction
+    last_used: u64,
+    last_success: Option<u64>,
+    failures: u32,
+}
+
+impl KnownAddress {
+    fn dead(&self) -> bool {
+        self.failures >= MAX_DIAL_FAILURES
+    }
+}
+
+pub struct AddressBook {
+    peers: HashMap<PeerId, Vec<KnownAddress>>,
+    max_per_peer: usize,
+    clock: u64,
+    // Whether /p2p-circuit addresses can be dialed, i.e. the server offers the relay protocol
+    relay: bool,
+}
+
+impl Default for AddressBook {
+    fn default() -> Self {
+        Self::new(MAX_ADDRESSES_PER_PEER)
+    }
+}
+
+impl AddressBook {
+    pub fn new(max_per_peer: usize) -> Self {
+        Self { peers: HashMap::new(), max_per_peer: max_per_peer.max(1), clock: 0, relay: false }
+    }
+
+    pub fn set_relay(&mut self, relay: bool) {
+        self.relay = relay;
+    }
+
+    pub fn relay(&self) -> bool {
+        self.relay
+    }
+
+    fn usable(&self, addr: &Multiaddr) -> bool {
+        is_supported_address(addr) && (self.relay || !is_relayed(addr))
+    }
+
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    // Records a discovered address; returns true if it wasn't known. Rediscovery refreshes an
+    // address but doesn't revive one that keeps failing. A full list evicts a dead address
+    // first, otherwise the least recently used one. Unusable addresses are dropped.
+    pub fn insert(&mut self, peer: PeerId, addr: Multiaddr) -> bool {
+        let addr = without_peer_id(addr);
+        if !self.usable(&addr) {
+            tracing::debug!("Ignoring address {} of {}: not dialable from here", addr, peer);
+            return false;
+        }
+        let now = self.tick();
+        let max = self.max_per_peer;
+        let known = self.peers.entry(peer).or_default();
+        if let Some(entry) = known.iter_mut().find(|a| a.addr == addr) {
+            entry.last_used = now;
+            return false;
+        }
+        if known.len() >= max
+            && let Some(oldest) = known.iter().enumerate().min_by_key(|(_, a)| (!a.dead(), a.last_used)).map(|(i, _)| i)
+        {
+            known.swap_remove(oldest);
+        }
+        known.push(KnownAddress { addr, last_used: now, last_success: None, failures: 0 });
+        true
+    }
+
+    // Forgets one address, and the peer once it has none left
+    pub fn remove(&mut self, peer: &PeerId, addr: &Multiaddr) {
+        let addr = without_peer_id(addr.clone());
+        if let Some(known) = self.peers.get_mut(peer) {
+            known.retain(|a| a.addr != addr);
+            if known.is_empty() {
+                self.peers.remove(peer);
+            }
+        }
+    }
+
+    pub fn dial_succeeded(&mut self, peer: &PeerId, addr: &Multiaddr) {
+        let addr = without_peer_id(addr.clone());
+        let now = self.tick();
+        if let Some(entry) = self.peers.get_mut(peer).and_then(|known| known.iter_mut().find(|a| a.addr == addr)) {
+            entry.last_used = now;
+            entry.last_success = Some(now);
+            entry.failures = 0;
+        }
+    }
+
+    pub fn dial_failed(&mut self, peer: &PeerId, addr: &Multiaddr) {
+        let addr = without_peer_id(addr.clone());
+        if let Some(entry) = self.peers.get_mut(peer).and_then(|known| known.iter_mut().find(|a| a.addr == addr)) {
+            entry.failures += 1;
+        }
+    }
+
+    // Addresses worth dialing: those that connected most recently first, then the most specific
+    // (an IP before a name to resolve, a direct route before a relay), then the most recently seen
+    pub fn dialable(&self, peer: &PeerId) -> Vec<Multiaddr> {
+        let Some(known) = self.peers.get(peer) else { return Vec::new() };
+        let mut live: Vec<&KnownAddress> = known.iter().filter(|a| !a.dead() && self.usable(&a.addr)).collect();
+        live.sort_by_key(|a| (std::cmp::Reverse(a.last_success), specificity(&a.addr), std::cmp::Reverse(a.last_used)));
+        live.into_iter().map(|a| a.addr.clone()).collect()
+    }
+
+    pub fn contains(&self, peer: &PeerId) -> bool {
+        self.peers.contains_key(peer)
+    }
+
+    pub fn peers(&self) -> impl Iterator<Item = &PeerId> {
+        self.peers.keys()
+    }
+}
+
+// Whether this client could dial the address at all: TCP to an IP or a DNS name, optionally as
+// the relay hop of a /p2p-circuit, and nothing else after it but the target's PeerId. Other
+// transports, unspecified or multicast IPs and port 0 would only fail.
+pub fn is_supported_address(addr: &Multiaddr) -> bool {
+    let protocols: Vec<Protocol> = addr.iter().collect();
+    match protocols.iter().position(|p| *p == Protocol::P2pCircuit) {
+        None => matches!(tcp_endpoint(&protocols), Some([] | [Protocol::P2p(_)])),
+        // The relay has to be named, the circuit can't be nested
+        Some(at) => {
+            matches!(tcp_endpoint(&protocols[..at]), Some([Protocol::P2p(_)])) && matches!(protocols[at + 1..], [] | [Protocol::P2p(_)])
+        }
+    }
+}
+
+pub fn is_relayed(addr: &Multiaddr) -> bool {
+    addr.iter().any(|p| p == Protocol::P2pCircuit)
+}
+
+// What follows a usable host and TCP port, if the address starts with them
+fn tcp_endpoint<'a>(protocols: &'a [Protocol<'a>]) -> Option<&'a [Protocol<'a>]> {
+    let [host, Protocol::Tcp(port), rest @ ..] = protocols else { return None };
+    let host_ok = match host {
+        Protocol::Ip4(ip) => !(ip.is_unspecified() || ip.is_multicast() || ip.is_broadcast()),
+        Protocol::Ip6(ip) => !(ip.is_unspecified() || ip.is_multicast()),
+        Protocol::Dns(_) | Protocol::Dns4(_) | Protocol::Dns6(_) => true,
+        _ => false,
+    };
+    (host_ok && *port != 0).then_some(rest)
+}
+
+// Lower is more specific: a direct IP, a name to resolve, then anything through a relay
+fn specificity(addr: &Multiaddr) -> u8 {
+    match addr.iter().next() {
+        _ if is_relayed(addr) => 2,
+        Some(Protocol::Ip4(_) | Protocol::Ip6(_)) => 0,
+        _ => 1,
+    }
+}
+
+// Whether a remote peer could reach this address: loopback, private, link-local and unspecified
+// IPs can't be, nor can circuits through such a relay. DNS names are assumed to resolve publicly.
+pub fn is_public_address(addr: &Multiaddr) -> bool {
+    match addr.iter().next() {
+        Some(Protocol::Ip4(ip)) => is_public_v4(ip),
+        Some(Protocol::Ip6(ip)) => is_public_v6(ip),
+        _ => true,
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    // 100.64.0.0/10 is carrier-grade NAT space
+    let shared = ip.octets()[0] == 100 && (ip.octets()[1] & 0xc0) == 64;
+    !(ip.is_loopback() || ip.is_private() || ip.is_link_local() || ip.is_unspecified() || shared)
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    if let Some(v4) = ip.to_ipv4_mapped() {
+        return is_public_v4(v4);
+    }
+    let unique_local = (ip.segments()[0] & 0xfe00) == 0xfc00;
+    let link_local = (ip.segments()[0] & 0xffc0) == 0xfe80;
+    !(ip.is_loopback() || ip.is_unspecified() || unique_local || link_local)
+}
+
+// Dial errors and endpoints may carry a trailing /p2p/<peer>; discovery records don't
+fn without_peer_id(mut addr: Multiaddr) -> Multiaddr {
+    if matches!(addr.iter().last(), Some(Protocol::P2p(_))) {
+        addr.pop();
+    }
+    addr
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> Multiaddr {
+        format!("/ip4/10.0.0.1/tcp/{}", port).parse().unwrap()
+    }
+
+    #[test]
+    fn full_lists_evict_the_least_recently_seen() {
+        let peer = PeerId::random();
+        let mut book = AddressBook::new(3);
+        for port in 1..=3 {
+            assert!(book.insert(peer, addr(port)));
+        }
+        // Seen again, so port 2 is now the oldest
+        assert!(!book.insert(peer, addr(1)));
+        assert!(book.insert(peer, addr(4)));
+        let kept = book.dialable(&peer);
+        assert_eq!(kept.len(), 3);
+        assert!(!kept.contains(&addr(2)));
+        assert!(kept.contains(&addr(1)) && kept.contains(&addr(4)));
+    }
+
+    #[test]
+    fn failing_addresses_are_skipped_and_evicted_first() {
+        let peer = PeerId::random();
+        let mut book = AddressBook::new(2);
+        book.insert(peer, addr(1));
+        book.insert(peer, addr(2));
+        let dialed = addr(1).with(Protocol::P2p(peer));
+        for _ in 0..MAX_DIAL_FAILURES {
+            book.dial_failed(&peer, &dialed);
+        }
+        // Rediscovery doesn't bring it back
+        book.insert(peer, addr(1));
+        assert_eq!(book.dialable(&peer), [addr(2)]);
+        // The dead address makes room, not the live one
+        book.insert(peer, addr(3));
+        assert_eq!(book.dialable(&peer).len(), 2);
+        assert!(!book.dialable(&peer).contains(&addr(1)));
+    }
+
+    #[test]
+    fn only_globally_reachable_addresses_are_public() {
+        for private in ["/ip4/127.0.0.1/tcp/1", "/ip4/192.168.1.20/tcp/1", "/ip4/10.1.2.3/tcp/1", "/ip4/100.64.0.1/tcp/1", "/ip6/::1/tcp/1", "/ip6/fd00::1/tcp/1", "/ip6/fe80::1/tcp/1"] {
+            assert!(!is_public_address(&private.parse().unwrap()), "{}", private);
+        }
+        for public in ["/ip4/203.0.113.9/tcp/1", "/ip6/2001:db8::1/tcp/1", "/dns4/chat.example.org/tcp/1"] {
+            assert!(is_public_address(&public.parse().unwrap()), "{}", public);
+        }
+    }
+
+    #[test]
+    fn only_tcp_addresses_the_client_can_dial_are_kept() {
+        let relay = PeerId::random();
+        let circuit: Multiaddr = format!("/ip4/203.0.113.9/tcp/62649/p2p/{}/p2p-circuit", relay).parse().unwrap();
+        for supported in ["/ip4/10.0.0.1/tcp/1", "/ip6/2001:db8::1/tcp/1", "/dns4/chat.example.org/tcp/1"] {
+            assert!(is_supported_address(&supported.parse().unwrap()), "{}", supported);
+        }
+        assert!(is_supported_address(&circuit));
+        for unsupported in [
+            "/ip4/10.0.0.1/udp/1/quic-v1",
+            "/ip4/10.0.0.1/tcp/1/ws",
+            "/ip4/0.0.0.0/tcp/1",
+            "/ip6/::/tcp/1",
+            "/ip4/224.0.0.251/tcp/1",
+            "/ip4/10.0.0.1/tcp/0",
+            "/unix/tmp%2Fsocket",
+            "/ip4/10.0.0.1/tcp/1/p2p-circuit",
+        ] {
+            assert!(!is_supported_address(&unsupported.parse().unwrap()), "{}", unsupported);
+        }
+
+        let peer = PeerId::random();
+        let mut book = AddressBook::default();
+        assert!(!book.insert(peer, "/ip4/10.0.0.1/udp/1/quic-v1".parse().unwrap()));
+        // Circuits wait until the server is known to relay
+        assert!(!book.insert(peer, circuit.clone()));
+        book.set_relay(true);
+        assert!(book.insert(peer, circuit.clone()));
+        assert!(book.insert(peer, "/dns4/chat.example.org/tcp/1".parse().unwrap()));
+        assert!(book.insert(peer, addr(1)));
+        assert_eq!(book.dialable(&peer), [addr(1), "/dns4/chat.example.org/tcp/1".parse().unwrap(), circuit]);
+        book.set_relay(false);
+        assert_eq!(book.dialable(&peer).len(), 2);
+    }
+
+    #[test]
+    fn recent_successes_are_dialed_first() {
+        let peer = PeerId::random();
+        let mut book = AddressBook::default();
+        book.insert(peer, addr(1));
+        book.insert(peer, addr(2));
+        book.dial_succeeded(&peer, &addr(1));
+        book.insert(peer, addr(3));
+        assert_eq!(book.dialable(&peer)[0], addr(1));
+        book.remove(&peer, &addr(1));
+        book.remove(&peer, &addr(2));
+        book.remove(&peer, &addr(3));
+        assert!(!book.contains(&peer));
+    }
+}
@@ -0,0 +1,177 @@
+// The queues between the UI and the networking task. Each holds up to `capacity` messages;
+// past that, a lossy message (presence, status, progress) replaces the oldest queued message
+// of the same kind, so a burst of updates costs memory once and the latest state still gets
+// through. Everything else (chat, receipts, auth replies) is queued regardless, since dropping
+// it would lose data the user expects to see. Sending never blocks, so the swarm loop and the
+// UI thread can both send freely.
+use std::{
+    collections::VecDeque,
+    mem::discriminant,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::Notify;
+
+// Messages the channel may drop when full, in favour of a newer message of the same kind
+pub trait Lossy {
+    fn lossy(&self) -> bool;
+}
+
+struct State<T> {
+    queue: VecDeque<T>,
+    senders: usize,
+    receiver_alive: bool,
+    dropped: u64,
+}
+
+struct Shared<T> {
+    state: Mutex<State<T>>,
+    capacity: usize,
+    notify: Notify,
+}
+
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+pub fn channel<T: Lossy>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State { queue: VecDeque::new(), senders: 1, receiver_alive: true, dropped: 0 }),
+        capacity: capacity.max(1),
+        notify: Notify::new(),
+    });
+    (Sender { shared: shared.clone() }, Receiver { shared })
+}
+
+impl<T: Lossy> Sender<T> {
+    // Hands the message back if the receiver is gone
+    pub fn send(&self, msg: T) -> Result<(), T> {
+        let mut state = self.shared.state.lock().unwrap();
+        if !state.receiver_alive {
+            return Err(msg);
+        }
+        if state.queue.len() >= self.shared.capacity && msg.lossy() {
+            let kind = discriminant(&msg);
+            if let Some(oldest) = state.queue.iter().position(|queued| queued.lossy() && discriminant(queued) == kind) {
+                state.queue.remove(oldest);
+                state.dropped += 1;
+                if state.dropped.is_power_of_two() {
+                    tracing::debug!("Channel full; {} superseded update(s) dropped so far", state.dropped);
+                }
+            }
+        }
+        state.queue.push_back(msg);
+        drop(state);
+        self.shared.notify.notify_one();
+        Ok(())
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.state.lock().unwrap().senders += 1;
+        Self { shared: self.shared.clone() }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().senders -= 1;
+        self.shared.notify.notify_one();
+    }
+}
+
+impl<T> Receiver<T> {
+    pub fn try_recv(&mut self) -> Option<T> {
+        self.shared.state.lock().unwrap().queue.pop_front()
+    }
+
+    // Waits for the next message; None once it's empty and every sender is gone.
+    // Cancel-safe: a message is only taken off the queue when this returns it.
+    pub async fn recv(&mut self) -> Option<T> {
+        loop {
+            {
+                let mut state = self.shared.state.lock().unwrap();
+                if let Some(msg) = state.queue.pop_front() {
+                    return Some(msg);
+                }
+                if state.senders == 0 {
+                    return None;
+                }
+            }
+            self.shared.notify.notified().await;
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.receiver_alive = false;
+        state.queue.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    enum Msg {
+        Chat(u32),
+        Status(u32),
+        Progress(u32),
+    }
+
+    impl Lossy for Msg {
+        fn lossy(&self) -> bool {
+            !matches!(self, Msg::Chat(_))
+        }
+    }
+
+    fn drain(rx: &mut Receiver<Msg>) -> Vec<Msg> {
+        std::iter::from_fn(|| rx.try_recv()).collect()
+    }
+
+    #[test]
+    fn full_channels_drop_the_oldest_update_of_the_same_kind() {
+        let (tx, mut rx) = channel(3);
+        tx.send(Msg::Status(1)).unwrap();
+        tx.send(Msg::Progress(1)).unwrap();
+        tx.send(Msg::Status(2)).unwrap();
+        // Full: the oldest status makes room, progress is left alone
+        tx.send(Msg::Status(3)).unwrap();
+        assert_eq!(drain(&mut rx), [Msg::Progress(1), Msg::Status(2), Msg::Status(3)]);
+    }
+
+    #[test]
+    fn chat_is_queued_past_capacity() {
+        let (tx, mut rx) = channel(2);
+        for n in 0..4 {
+            tx.send(Msg::Chat(n)).unwrap();
+        }
+        // No queued update of this kind to replace, so it waits too
+        tx.send(Msg::Progress(1)).unwrap();
+        tx.send(Msg::Progress(2)).unwrap();
+        assert_eq!(drain(&mut rx), [Msg::Chat(0), Msg::Chat(1), Msg::Chat(2), Msg::Chat(3), Msg::Progress(2)]);
+    }
+
+    #[tokio::test]
+    async fn recv_ends_once_senders_are_gone() {
+        let (tx, mut rx) = channel(4);
+        let second = tx.clone();
+        tokio::spawn(async move {
+            tx.send(Msg::Chat(1)).unwrap();
+            second.send(Msg::Chat(2)).unwrap();
+        });
+        assert_eq!(rx.recv().await, Some(Msg::Chat(1)));
+        assert_eq!(rx.recv().await, Some(Msg::Chat(2)));
+        assert_eq!(rx.recv().await, None);
+        let (tx, rx) = channel(1);
+        drop(rx);
+        assert_eq!(tx.send(Msg::Chat(3)), Err(Msg::Chat(3)));
+    }
+}
@@ -1,29 +1,162 @@
-use async_trait::async_trait;
-use futures::{prelude::*, StreamExt};
+mod addresses;
+mod channel;
+mod routes;
+mod transfer;
+
+use futures::StreamExt;
 use libp2p::{
-    identify, noise, ping, rendezvous, request_response,
+    dcutr, gossipsub, identify, mdns, noise, ping, relay, rendezvous, request_response,
     swarm::{NetworkBehaviour, SwarmEvent},
     tcp, yamux, Multiaddr, PeerId,
 };
-use std::{collections::{HashMap, HashSet}, io, str::FromStr, time::SystemTime};
-use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    io,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime},
+};
 use tracing_subscriber::EnvFilter;
 use eframe::egui;
+use server::protocol::{
+    AuthCodec, AuthError, AuthProtocol, AuthRequest, AuthResponse, FileCodec, FileProtocol, FileRequest, FileResponse, HelloCodec,
+    HelloProtocol, RelayCodec, RelayProtocol, RelayRequest, RelayResponse, DEFAULT_MAX_FRAME_BYTES, FILE_CHUNK_BYTES,
+    KEEPALIVE, SERVER_PROTOCOL_PREFIX, username_key,
+};
+use addresses::{is_public_address, AddressBook};
+use channel::{Lossy, Receiver, Sender};
+use routes::Routes;
+use transfer::{format_size, IncomingFile, OutgoingFile, DEFAULT_MAX_FILE_BYTES, MAX_INCOMING_PER_PEER, MAX_INCOMING_TRANSFERS};
 
     // ---- UI Theme & Sizing ------------------------------------------------------
     const UI_HEIGHT: f32 = 36.0; // uniform height for interactive controls
     const BUTTON_WIDTH: f32 = 120.0; // default button width
     const RADIUS: f32 = 8.0; // rounded corners
 
-    fn configure_theme(ctx: &egui::Context) {
-        let blue = egui::Color32::from_rgb(25, 118, 210); // #1976D2
-        let blue_hover = egui::Color32::from_rgb(30, 136, 229); // #1E88E5
-        let blue_dark = egui::Color32::from_rgb(21, 101, 192); // #1565C0
-        let orange = egui::Color32::from_rgb(255, 152, 0); // #FF9800
-        let orange_dark = egui::Color32::from_rgb(230, 130, 0);
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    enum ThemeMode {
+        Dark,
+        Light,
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    enum ThemeVariant {
+        Standard,
+        // Okabe-Ito hues that stay distinct under the common color-vision deficiencies
+        ColorBlind,
+    }
+
+    // Accent colors shared by the theme and the hand-painted widgets (sidebar rows, bubbles, Send)
+    struct Palette {
+        accent: egui::Color32,
+        accent_hover: egui::Color32,
+        accent_dark: egui::Color32,
+        highlight: egui::Color32,
+        highlight_dark: egui::Color32,
+        unread_fill: egui::Color32,
+        unread_stroke: egui::Color32,
+    }
+
+    const STANDARD_PALETTE: Palette = Palette {
+        accent: egui::Color32::from_rgb(25, 118, 210), // #1976D2
+        accent_hover: egui::Color32::from_rgb(30, 136, 229), // #1E88E5
+        accent_dark: egui::Color32::from_rgb(21, 101, 192), // #1565C0
+        highlight: egui::Color32::from_rgb(255, 152, 0), // #FF9800
+        highlight_dark: egui::Color32::from_rgb(230, 130, 0),
+        unread_fill: egui::Color32::from_rgb(56, 142, 60),
+        unread_stroke: egui::Color32::from_rgb(67, 160, 71),
+    };
+
+    const COLOR_BLIND_PALETTE: Palette = Palette {
+        accent: egui::Color32::from_rgb(0, 114, 178), // #0072B2
+        accent_hover: egui::Color32::from_rgb(30, 136, 200),
+        accent_dark: egui::Color32::from_rgb(0, 90, 145),
+        highlight: egui::Color32::from_rgb(230, 159, 0), // #E69F00
+        highlight_dark: egui::Color32::from_rgb(200, 130, 0),
+        // Unread rows use a dark orange against the blue selection; the row also gets an icon and bold text
+        unread_fill: egui::Color32::from_rgb(140, 90, 0),
+        unread_stroke: egui::Color32::from_rgb(230, 159, 0),
+    };
+
+    fn palette(variant: ThemeVariant) -> &'static Palette {
+        match variant {
+            ThemeVariant::Standard => &STANDARD_PALETTE,
+            ThemeVariant::ColorBlind => &COLOR_BLIND_PALETTE,
+        }
+    }
+
+    // Surface colors of the light or dark theme; accents come from the Palette
+    struct Theme {
+        visuals: fn() -> egui::Visuals,
+        panel_fill: egui::Color32,
+        window_fill: egui::Color32,
+        window_stroke: egui::Color32,
+        widget_fill: egui::Color32,
+        widget_text: egui::Color32,
+        // Incoming bubbles, the message input and transfer rows
+        surface_fill: egui::Color32,
+        surface_stroke: egui::Color32,
+        surface_text: egui::Color32,
+        surface_weak_text: egui::Color32,
+    }
+
+    impl Theme {
+        fn dark() -> Self {
+            Self {
+                visuals: egui::Visuals::dark,
+                panel_fill: egui::Color32::from_rgb(24, 27, 31),
+                window_fill: egui::Color32::from_rgb(22, 24, 28),
+                window_stroke: egui::Color32::from_rgb(40, 44, 50),
+                widget_fill: egui::Color32::from_rgb(45, 49, 55),
+                widget_text: egui::Color32::LIGHT_GRAY,
+                surface_fill: egui::Color32::from_rgb(38, 43, 50),
+                surface_stroke: egui::Color32::from_rgb(55, 61, 69),
+                surface_text: egui::Color32::WHITE,
+                surface_weak_text: egui::Color32::LIGHT_GRAY,
+            }
+        }
+
+        fn light() -> Self {
+            Self {
+                visuals: egui::Visuals::light,
+                panel_fill: egui::Color32::from_rgb(245, 246, 248),
+                window_fill: egui::Color32::from_rgb(252, 252, 253),
+                window_stroke: egui::Color32::from_rgb(208, 212, 218),
+                widget_fill: egui::Color32::from_rgb(226, 229, 234),
+                widget_text: egui::Color32::from_rgb(40, 44, 50),
+                surface_fill: egui::Color32::WHITE,
+                surface_stroke: egui::Color32::from_rgb(208, 212, 218),
+                surface_text: egui::Color32::from_rgb(24, 27, 31),
+                surface_weak_text: egui::Color32::from_rgb(100, 106, 115),
+            }
+        }
+
+        fn of(mode: ThemeMode) -> Self {
+            match mode {
+                ThemeMode::Dark => Self::dark(),
+                ThemeMode::Light => Self::light(),
+            }
+        }
+    }
+
+    fn configure_theme(ctx: &egui::Context, mode: ThemeMode, variant: ThemeVariant) {
+        let theme = Theme::of(mode);
+        let colors = palette(variant);
+        let blue = colors.accent;
+        let blue_hover = colors.accent_hover;
+        let blue_dark = colors.accent_dark;
+        let orange = colors.highlight;
+        let orange_dark = colors.highlight_dark;
 
-        let mut style = egui::Style::default();
-        style.visuals = egui::Visuals::dark();
+        let mut style = egui::Style {
+            visuals: (theme.visuals)(),
+            ..Default::default()
+        };
 
         // Spacing & element sizing
         style.spacing.interact_size = egui::vec2(0.0, UI_HEIGHT); // enforce uniform height
@@ -45,8 +178,8 @@ use eframe::egui;
         style.visuals.hyperlink_color = blue;
 
         // Button-esque widget visuals
-        style.visuals.widgets.inactive.bg_fill = egui::Color32::from_rgb(45, 49, 55);
-        style.visuals.widgets.inactive.fg_stroke = egui::Stroke { width: 1.0, color: egui::Color32::LIGHT_GRAY };
+        style.visuals.widgets.inactive.bg_fill = theme.widget_fill;
+        style.visuals.widgets.inactive.fg_stroke = egui::Stroke { width: 1.0, color: theme.widget_text };
         style.visuals.widgets.hovered.bg_fill = blue_hover;
         style.visuals.widgets.hovered.fg_stroke = egui::Stroke { width: 1.0, color: egui::Color32::WHITE };
         style.visuals.widgets.hovered.bg_stroke = egui::Stroke { width: 1.0, color: blue_dark };
@@ -55,9 +188,9 @@ use eframe::egui;
         style.visuals.widgets.active.bg_stroke = egui::Stroke { width: 1.0, color: orange_dark };
 
         // Panels / backgrounds
-        style.visuals.panel_fill = egui::Color32::from_rgb(24, 27, 31);
-        style.visuals.window_fill = egui::Color32::from_rgb(22, 24, 28);
-        style.visuals.window_stroke = egui::Stroke { width: 1.0, color: egui::Color32::from_rgb(40, 44, 50) };
+        style.visuals.panel_fill = theme.panel_fill;
+        style.visuals.window_fill = theme.window_fill;
+        style.visuals.window_stroke = egui::Stroke { width: 1.0, color: theme.window_stroke };
 
         ctx.set_style(style);
     }
@@ -65,178 +198,131 @@ use eframe::egui;
     // --- Protocol Definition (must match the server) -----------------------------
     const RENDEZVOUS_NAMESPACE: &str = "p2p-client";
 
-    #[derive(Debug, Clone)]
-    struct HelloProtocol();
+    // Rendezvous namespace of a space; None is the default space everyone shares
+    fn space_namespace(space: Option<&str>) -> rendezvous::Namespace {
+        let namespace = match space {
+            Some(space) => format!("{}/space/{}", RENDEZVOUS_NAMESPACE, space),
+            None => RENDEZVOUS_NAMESPACE.to_string(),
+        };
+        rendezvous::Namespace::new(namespace).expect("space names are short")
+    }
 
-    #[derive(Default, Clone)]
-    struct HelloCodec();
+    // Space names are 1-32 letters, digits, '-', '_' or '.', so the namespace stays valid
+    fn valid_space_name(name: &str) -> bool {
+        (1..=32).contains(&name.chars().count()) && name.chars().all(|c| c.is_alphanumeric() || matches!(c, '-' | '_' | '.'))
+    }
 
-    impl AsRef<str> for HelloProtocol {
-        fn as_ref(&self) -> &str {
-            "/hello/1.0"
-        }
+    // Gossip (--gossip): presence and room messages go over pub/sub topics instead of the server
+    const DEFAULT_ROOM: &str = "lobby";
+    const PRESENCE_REPUBLISH_SECS: u64 = 30;
+
+    fn presence_topic() -> libp2p::gossipsub::IdentTopic {
+        libp2p::gossipsub::IdentTopic::new(format!("{}/presence", RENDEZVOUS_NAMESPACE))
     }
 
-    #[async_trait]
-    impl request_response::Codec for HelloCodec {
-        type Protocol = HelloProtocol;
-        type Request = String;
-        type Response = String;
+    fn room_topic(room: &str) -> libp2p::gossipsub::IdentTopic {
+        libp2p::gossipsub::IdentTopic::new(format!("{}/room/{}", RENDEZVOUS_NAMESPACE, room))
+    }
 
-        async fn read_request<T>(&mut self, _: &Self::Protocol, io: &mut T) -> io::Result<Self::Request>
-        where
-            T: AsyncRead + Unpin + Send,
-        {
-            let len = unsigned_varint::aio::read_u16(&mut *io)
-                .await
-                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
-            let mut buffer = vec![0; len as usize];
-            io.read_exact(&mut buffer).await?;
-            Ok(String::from_utf8(buffer).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?)
-        }
-
-        async fn read_response<T>(
-            &mut self,
-            _: &Self::Protocol,
-            io: &mut T,
-        ) -> io::Result<Self::Response>
-        where
-            T: AsyncRead + Unpin + Send,
-        {
-            let len = unsigned_varint::aio::read_u16(&mut *io)
-                .await
-                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
-            let mut buffer = vec![0; len as usize];
-            io.read_exact(&mut buffer).await?;
-            Ok(String::from_utf8(buffer).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?)
-        }
-
-        async fn write_request<T>(
-            &mut self,
-            _: &Self::Protocol,
-            io: &mut T,
-            req: Self::Request,
-        ) -> io::Result<()>
-        where
-            T: AsyncWrite + Unpin + Send,
-        {
-            let mut uvi_buf = unsigned_varint::encode::u16_buffer();
-            let encoded_len = unsigned_varint::encode::u16(req.len() as u16, &mut uvi_buf);
-
-            io.write_all(encoded_len).await?;
-            io.write_all(req.as_bytes()).await?;
-            io.flush().await
-        }
-
-        async fn write_response<T>(
-            &mut self,
-            _: &Self::Protocol,
-            io: &mut T,
-            res: Self::Response,
-        ) -> io::Result<()>
-        where
-            T: AsyncWrite + Unpin + Send,
-        {
-            let mut uvi_buf = unsigned_varint::encode::u16_buffer();
-            let encoded_len = unsigned_varint::encode::u16(res.len() as u16, &mut uvi_buf);
+    // Conversation key of a room, kept apart from usernames by the leading '#'
+    fn room_key(room: &str) -> String {
+        format!("#{}", room)
+    }
 
-            io.write_all(encoded_len).await?;
-            io.write_all(res.as_bytes()).await?;
-            io.flush().await
-        }
+    fn room_from_key(key: &str) -> Option<&str> {
+        key.strip_prefix('#')
     }
 
-    // --- Auth Protocol -----------------------------------------------------------
-    #[derive(Debug, Clone)]
-    struct AuthProtocol();
+    // Chat protocol version advertised through identify. Peers with a different major
+    // version are treated as incompatible and never sent chat frames.
+    const CLIENT_PROTOCOL_PREFIX: &str = "/p2p-client/";
+    // 1.7 marks the percent-escaped payload fields (see server::wire); the escaping change itself went out without a bump
+    const CLIENT_PROTOCOL_VERSION: &str = "/p2p-client/1.7.0";
+    // Older clients would show READ:, TYPING and ENC: frames as chat messages, so only newer peers get them
+    const READ_RECEIPTS_SINCE: (u32, u32) = (1, 3);
+    const TYPING_SINCE: (u32, u32) = (1, 4);
+    // 1.5 sealed payloads without binding them to the recipient; those can't be opened any more
+    const E2E_SINCE: (u32, u32) = (1, 6);
 
-    #[derive(Default, Clone)]
-    struct AuthCodec();
+    // (major, minor) from an identify protocol_version such as "/p2p-client/1.2.0"
+    fn parse_protocol_version(version: &str) -> Option<(u32, u32)> {
+        let mut parts = version.strip_prefix(CLIENT_PROTOCOL_PREFIX)?.split('.');
+        let major = parts.next()?.parse().ok()?;
+        let minor = parts.next().unwrap_or("0").parse().ok()?;
+        Some((major, minor))
+    }
 
-    impl AsRef<str> for AuthProtocol {
-        fn as_ref(&self) -> &str {
-            "/auth/1.0"
+    // A peer is compatible when it speaks our major version and is at least `min` (major, minor).
+    // `min` defaults to our own major version and can be raised with NEUTRAL_MIN_PEER_VERSION=<major>.<minor>.
+    fn is_compatible_peer(version: &str, min: (u32, u32)) -> bool {
+        let ours = parse_protocol_version(CLIENT_PROTOCOL_VERSION).expect("valid own protocol version");
+        match parse_protocol_version(version) {
+            Some(theirs) => theirs.0 == ours.0 && theirs >= min,
+            None => false,
         }
     }
 
-    #[async_trait]
-    impl request_response::Codec for AuthCodec {
-        type Protocol = AuthProtocol;
-        type Request = String;
-        type Response = String;
+    fn peer_supports(versions: &HashMap<PeerId, String>, peer: &PeerId, since: (u32, u32)) -> bool {
+        versions.get(peer).is_some_and(|version| is_compatible_peer(version, since))
+    }
 
-        async fn read_request<T>(&mut self, _: &Self::Protocol, io: &mut T) -> io::Result<Self::Request>
-        where
-            T: AsyncRead + Unpin + Send,
-        {
-            let len = unsigned_varint::aio::read_u16(&mut *io)
-                .await
-                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
-            let mut buffer = vec![0; len as usize];
-            io.read_exact(&mut buffer).await?;
-            Ok(String::from_utf8(buffer).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?)
-        }
-
-        async fn read_response<T>(
-            &mut self,
-            _: &Self::Protocol,
-            io: &mut T,
-        ) -> io::Result<Self::Response>
-        where
-            T: AsyncRead + Unpin + Send,
-        {
-            let len = unsigned_varint::aio::read_u16(&mut *io)
-                .await
-                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
-            let mut buffer = vec![0; len as usize];
-            io.read_exact(&mut buffer).await?;
-            Ok(String::from_utf8(buffer).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?)
-        }
-
-        async fn write_request<T>(
-            &mut self,
-            _: &Self::Protocol,
-            io: &mut T,
-            req: Self::Request,
-        ) -> io::Result<()>
-        where
-            T: AsyncWrite + Unpin + Send,
-        {
-            let mut uvi_buf = unsigned_varint::encode::u16_buffer();
-            let encoded_len = unsigned_varint::encode::u16(req.len() as u16, &mut uvi_buf);
-
-            io.write_all(encoded_len).await?;
-            io.write_all(req.as_bytes()).await?;
-            io.flush().await
-        }
-
-        async fn write_response<T>(
-            &mut self,
-            _: &Self::Protocol,
-            io: &mut T,
-            res: Self::Response,
-        ) -> io::Result<()>
-        where
-            T: AsyncWrite + Unpin + Send,
-        {
-            let mut uvi_buf = unsigned_varint::encode::u16_buffer();
-            let encoded_len = unsigned_varint::encode::u16(res.len() as u16, &mut uvi_buf);
+    // Server versions this client understands, and the first minor version answering space-scoped LIST
+    const SERVER_MAJOR_VERSION: u32 = 1;
+    const SPACES_SINCE: (u32, u32) = (1, 1);
 
-            io.write_all(encoded_len).await?;
-            io.write_all(res.as_bytes()).await?;
-            io.flush().await
+    // (major, minor) of the server from its identify info, or why we can't talk to it. Servers from
+    // before versioned identify advertise something else and count as 1.0 as long as they speak
+    // our auth protocol; one that doesn't would refuse every login with a protocol error.
+    fn server_compatibility(version: &str, speaks_auth: bool) -> Result<(u32, u32), String> {
+        if !speaks_auth {
+            return Err(format!("Server version incompatible: {} doesn't speak {}", version, AuthProtocol().as_ref()));
+        }
+        let Some(rest) = version.strip_prefix(SERVER_PROTOCOL_PREFIX) else { return Ok((1, 0)) };
+        let mut parts = rest.split('.');
+        let parsed = parts.next().and_then(|major| major.parse().ok()).zip(parts.next().unwrap_or("0").parse().ok());
+        match parsed {
+            Some(version) if version.0 == SERVER_MAJOR_VERSION => Ok(version),
+            _ => Err(format!("Server version incompatible: it runs {}, this client needs {}{}.x", version, SERVER_PROTOCOL_PREFIX, SERVER_MAJOR_VERSION)),
         }
     }
 
+    // What an outstanding auth request was; Ok and Error replies don't say
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum PendingAuth {
+        Register,
+        Login,
+        ChangePassword,
+        Logout,
+        Delete,
+        List,
+        Discover,
+    }
+
     // Messages from UI to networking task
     #[derive(Debug, Clone)]
     enum UiToNet {
         Connect { peer_id: String },
-        Write { peer_id: String, from_username: String, to_username: String, msg: String },
+        // `resend` is set when retrying a failed message: its id and original send time, so the
+        // result updates the same bubble and nothing is echoed
+        Write { peer_id: String, from_username: String, to_username: String, msg: String, forwarded: bool, resend: Option<(String, SystemTime)> },
         Register { username: String, password: String, birthdate: String },
         Login { username: String, password: String },
-        Logout { username: String },
-        DeleteAccount { username: String, password: String },
+        // Session token from the AuthResult replaces credentials after login
+        Logout { username: String, token: String },
+        DeleteAccount { token: String },
+        ChangePassword { username: String, old_password: String, new_password: String },
+        // Publish to a gossip room (only with --gossip)
+        RoomMessage { room: String, from_username: String, text: String },
+        // Tell a peer we've seen their messages up to and including `msg_id`
+        Read { peer_id: String, msg_id: String },
+        // We're typing to this peer; the UI throttles these
+        Typing { peer_id: String },
+        // Offer a file over /file/1.0
+        SendFile { peer_id: String, to_username: String, path: std::path::PathBuf },
+        // Move to another space (None: the default one), re-registering and refetching the user list there
+        JoinSpace { space: Option<String> },
+        // The whole block list after each change, so the network task drops their traffic
+        Blocked(BlockList),
     }
 
     #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -245,18 +331,244 @@ use eframe::egui;
         Outgoing,
     }
 
+    // Why a connection ended, from SwarmEvent::ConnectionClosed's cause
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    enum DisconnectReason {
+        // Closed without an error, e.g. by a logout or the idle timeout
+        Closed,
+        // The peer stopped answering within the keep-alive period
+        KeepAliveTimeout,
+        // The other side reset or closed the connection
+        RemoteClosed,
+        Transport(String),
+    }
+
+    impl DisconnectReason {
+        fn from_cause(cause: Option<&libp2p::swarm::ConnectionError>) -> Self {
+            match cause {
+                None => DisconnectReason::Closed,
+                Some(libp2p::swarm::ConnectionError::KeepAliveTimeout) => DisconnectReason::KeepAliveTimeout,
+                Some(libp2p::swarm::ConnectionError::IO(e)) => match e.kind() {
+                    io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::BrokenPipe
+                    | io::ErrorKind::UnexpectedEof => DisconnectReason::RemoteClosed,
+                    _ => DisconnectReason::Transport(e.to_string()),
+                },
+            }
+        }
+
+        // Wording for toasts and the status line
+        fn describe(&self) -> String {
+            match self {
+                DisconnectReason::Closed => "connection closed".to_string(),
+                DisconnectReason::KeepAliveTimeout => "connection timed out".to_string(),
+                DisconnectReason::RemoteClosed => "closed by the other side".to_string(),
+                DisconnectReason::Transport(e) => format!("network error: {}", e),
+            }
+        }
+    }
+
+    // What the user asked for while messages were still unsent, awaiting confirmation
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum LeaveRequest {
+        Logout,
+        Quit,
+    }
+
+    // Whether other peers can dial us, as far as the client can tell without probing
+    #[derive(Debug, Clone, Default, PartialEq, Eq)]
+    enum Reachability {
+        // Not connected to the server yet, so nobody has told us our address
+        #[default]
+        Unknown,
+        // The server sees us on our own listen port, or an external address was configured
+        Reachable,
+        // Behind a NAT that rewrites the port; peers only get through the server's relay
+        Relayed,
+        // Behind such a NAT without a relay reservation; only outgoing connections work
+        BehindNat,
+    }
+
+    impl Reachability {
+        // `observed` is our address as the server's identify reports it. TCP port reuse is on,
+        // so a NAT that keeps our listen port is likely to let inbound connections through too.
+        fn assess(observed: Option<&Multiaddr>, listen_ports: &HashSet<u16>, external_configured: bool, relayed: bool) -> Self {
+            if external_configured || observed.and_then(tcp_port).is_some_and(|port| listen_ports.contains(&port)) {
+                Reachability::Reachable
+            } else if relayed {
+                Reachability::Relayed
+            } else if observed.is_some() {
+                Reachability::BehindNat
+            } else {
+                Reachability::Unknown
+            }
+        }
+
+        fn label(&self) -> &'static str {
+            match self {
+                Reachability::Unknown => "Checking reachability…",
+                Reachability::Reachable => "Reachable",
+                Reachability::Relayed => "Behind NAT (relayed)",
+                Reachability::BehindNat => "Behind NAT",
+            }
+        }
+
+        fn explain(&self) -> &'static str {
+            match self {
+                Reachability::Unknown => "Waiting for the server to report the address it sees",
+                Reachability::Reachable => "Other users can connect to you directly",
+                Reachability::Relayed => "Other users reach you through the server's relay until a direct connection is punched",
+                Reachability::BehindNat => "Other users can't connect to you; chats only work when you connect to them, or through the server's offline queue",
+            }
+        }
+    }
+
     // Messages from networking task to UI
     #[derive(Debug, Clone)]
     enum NetToUi {
         Discovered(Vec<String>),
         Connected(String),
-        Disconnected(String),
-        ChatMessage { peer: String, direction: MessageDirection, text: String },
+        // All connections to `peer` closed; `server` is set for the rendezvous server
+        Disconnected { peer: String, reason: DisconnectReason, server: bool },
+        // `timestamp`, `msg_id` and `seq` come from the sender; the id is what receipts refer to
+        ChatMessage {
+            peer: String,
+            direction: MessageDirection,
+            text: String,
+            forwarded: bool,
+            timestamp: SystemTime,
+            msg_id: Option<String>,
+            seq: Option<u64>,
+        },
+        // The recipient acknowledged the outgoing message with this id
+        Delivered { peer: String, msg_id: String },
+        // Neither the recipient nor the server's relay queue accepted it
+        DeliveryFailed { peer: String, msg_id: String },
+        // The outgoing message waits for a connection to the recipient, or has stopped waiting
+        Queued { peer: String, msg_id: String, queued: bool },
+        // The recipient has seen our messages up to and including this id
+        Read { peer: String, up_to_id: String },
+        // The contact is typing to us
+        Typing { peer: String },
+        // A file transfer started; `id` is local to this client and names it in the updates below
+        FileTransfer { id: u64, peer: String, filename: String, size: u64, outgoing: bool },
+        // Bytes sent and acknowledged, or received
+        FileProgress { id: u64, done: u64 },
+        // Verified by the receiver; `saved_to` is set on the receiving side
+        FileDone { id: u64, saved_to: Option<String> },
+        FileFailed { id: u64, reason: String },
+        // Rendezvous server connection state, shown in the top bar
+        Connection(String),
+        // Short-lived notice, shown as a fading toast
         Info(String),
         Error(String),
-        AuthResult { ok: bool, message: String },
-        Users(HashMap<String, String>), // username -> PeerId
-        DeleteResult { ok: bool, message: String },
+        // The server's token on LOGIN success; other acks carry none
+        AuthResult(Result<Option<String>, AuthFailure>),
+        // REGISTER's reply: the new account is logged in with this token, or wasn't created
+        Registered(Result<String, AuthFailure>),
+        // The server rejected our session token (expired or revoked)
+        SessionExpired,
+        PasswordChanged(Result<(), AuthFailure>),
+        // username -> PeerIds of its sessions, empty while offline; and when offline users were last online
+        Users(HashMap<String, Vec<String>>, HashMap<String, SystemTime>),
+        DeleteResult(Result<(), AuthFailure>),
+        // Result of checking a connected peer's identify protocol version
+        PeerCompatibility { peer: String, compatible: bool, version: String },
+        // The server's identify info checked out; `spaces` says whether it scopes LIST to a space
+        ServerVersion { version: String, spaces: bool },
+        // Sent whenever our own reachability changes
+        Reachability(Reachability),
+        // Gossip mode: subscribed to a room's topic after login
+        RoomJoined(String),
+        RoomMessage { room: String, from: String, direction: MessageDirection, text: String, timestamp: SystemTime },
+        // Gossip presence announcement; a hint only, LIST from the server stays authoritative
+        Presence { username: String, peer: String, online: bool },
+        // A peer found by mDNS on the local network, with what it advertises over identify
+        Nearby { peer: String, version: String, agent: String },
+        // All of its mDNS records expired
+        NearbyGone(String),
+        // Latest ping round-trip time to a connected peer
+        Rtt { peer: String, millis: u64 },
+        // PING_DEGRADED_AFTER pings in a row got no answer
+        LinkDegraded(String),
+    }
+
+    // Queue sizes for the two directions; see channel.rs for what happens past them
+    const UI_TO_NET_CAPACITY: usize = 256;
+    const NET_TO_UI_CAPACITY: usize = 1024;
+    // Events the UI handles per frame; the rest wait for the next one so a burst can't freeze it
+    const MAX_EVENTS_PER_FRAME: usize = 256;
+
+    // Only the typing signal may be dropped; commands are always carried out
+    impl Lossy for UiToNet {
+        fn lossy(&self) -> bool {
+            matches!(self, UiToNet::Typing { .. })
+        }
+    }
+
+    impl UiToNet {
+        // The session a command goes to, which the server may have to resolve first
+        fn session(&self) -> Option<&str> {
+            match self {
+                UiToNet::Connect { peer_id }
+                | UiToNet::Write { peer_id, .. }
+                | UiToNet::Read { peer_id, .. }
+                | UiToNet::Typing { peer_id }
+                | UiToNet::SendFile { peer_id, .. } => Some(peer_id),
+                _ => None,
+            }
+        }
+    }
+
+    // Presence and status updates are superseded by the next one of their kind
+    impl Lossy for NetToUi {
+        fn lossy(&self) -> bool {
+            matches!(
+                self,
+                NetToUi::Discovered(_)
+                    | NetToUi::Typing { .. }
+                    | NetToUi::FileProgress { .. }
+                    | NetToUi::Connection(_)
+                    | NetToUi::Info(_)
+                    | NetToUi::Users(..)
+                    | NetToUi::Reachability(_)
+                    | NetToUi::Presence { .. }
+                    | NetToUi::Rtt { .. }
+            )
+        }
+    }
+
+    // Why an auth request failed: the server refused it, or it never got an answer
+    #[derive(Debug, Clone)]
+    enum AuthFailure {
+        Refused(AuthError),
+        Unreachable(String),
+    }
+
+    // User-facing wording of auth failures; the server only sends codes
+    fn auth_failure_text(failure: &AuthFailure) -> String {
+        let error = match failure {
+            AuthFailure::Refused(error) => error,
+            AuthFailure::Unreachable(reason) => return format!("Auth request failed: {}", reason),
+        };
+        match error {
+            AuthError::UsernameTaken => "Username taken".to_string(),
+            AuthError::InvalidPassword => "Invalid password".to_string(),
+            AuthError::UnknownUser => "Unknown user".to_string(),
+            AuthError::WrongPeer => "Username belongs to another peer".to_string(),
+            AuthError::RateLimited => "Too many attempts, try later".to_string(),
+            AuthError::WeakPassword(reason) => format!("Weak password: {}", reason),
+            AuthError::InvalidUsername(reason) => format!("Invalid username: {}", reason),
+            AuthError::InvalidBirthdate => "Invalid birthdate".to_string(),
+            AuthError::TooYoung { min_age } => format!("Must be {} or older", min_age),
+            AuthError::AwaitingOwner => "Account awaiting new owner".to_string(),
+            AuthError::Storage => "Server storage error".to_string(),
+            AuthError::InvalidCredential => "Invalid credential".to_string(),
+            AuthError::NotAuthorized => "Not authorized".to_string(),
+            AuthError::UnexpectedRequest => "Unexpected auth request".to_string(),
+            AuthError::Unknown(code) => format!("Server error ({})", code),
+        }
     }
 
     fn main() -> eframe::Result<()> {
@@ -267,37 +579,350 @@ use eframe::egui;
             )
             .try_init();
 
-    // Optional CLI: rendezvous server ip:port (defaults to 127.0.0.1:62649)
-    let rendezvous_arg = std::env::args().nth(1).unwrap_or_else(|| "127.0.0.1:62649".to_string());
-    let (rv_ip, rv_port) = match rendezvous_arg.split_once(':') {
-        Some((ip, port)) if !ip.is_empty() && !port.is_empty() => (ip.to_string(), port.to_string()),
-        _ => ("127.0.0.1".to_string(), "62649".to_string()),
+    // Optional CLI: rendezvous server ip:port, [ipv6]:port, host:port or a multiaddr (defaults to
+    // 127.0.0.1:62649), then the server's PeerId as printed at its startup (defaults to RENDEZVOUS_PEER_ID)
+    // Flags: --embedded-server runs a server inside this process and ignores ip:port and PeerId
+    //        --gossip enables gossipsub presence and rooms
+    //        --mdns finds peers on the local network without the server
+    //        --listen=<multiaddr> listens there instead of an ephemeral port on all interfaces
+    //        --external=<multiaddr> advertises that address to peers (repeatable)
+    //        --advertise-private also advertises loopback and LAN addresses to a public server
+    //        --peer-idle-timeout=<secs> closes quiet peer connections after that long (default 30)
+    //        --data-dir=<dir> keeps identity, history, drafts and block lists there, default <config dir>/neutral
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let embedded_server = args.iter().any(|a| a == "--embedded-server");
+    let flag_values = |name: &str| -> Vec<String> {
+        let prefix = format!("--{}=", name);
+        args.iter().filter_map(|a| a.strip_prefix(prefix.as_str())).map(str::to_string).collect()
     };
-    let rendezvous_multiaddr: Multiaddr = format!("/ip4/{}/tcp/{}", rv_ip, rv_port)
-        .parse()
-        .unwrap_or_else(|_| "/ip4/127.0.0.1/tcp/62649".parse().unwrap());
+    let listen_arg = flag_values("listen").pop();
+    let external_args = flag_values("external");
+    let peer_idle_arg = flag_values("peer-idle-timeout").pop();
+    if let Some(dir) = flag_values("data-dir").pop() {
+        // Absolute, so the files don't move if the working directory changes
+        let dir = std::path::absolute(&dir).unwrap_or_else(|_| dir.into());
+        let _ = DATA_DIR.set(dir);
+    }
+    match data_root() {
+        Some(dir) => tracing::info!("Data directory: {}", dir.display()),
+        None => tracing::warn!("No data directory; identity, history and drafts won't be saved"),
+    }
+    let mut positional = args.iter().filter(|a| !a.starts_with("--"));
+    let rendezvous_arg = positional.next().cloned().unwrap_or_else(|| "127.0.0.1:62649".to_string());
+    let peer_id_arg = positional.next().cloned();
+    let rendezvous_parsed = server::parse_tcp_address(&rendezvous_arg);
+    let mut rendezvous_multiaddr: Multiaddr = rendezvous_parsed.clone().unwrap_or_else(|_| "/ip4/127.0.0.1/tcp/62649".parse().unwrap());
 
     // Build a Tokio runtime for networking and keep it alive for app lifetime
     let rt = std::sync::Arc::new(tokio::runtime::Runtime::new().expect("Tokio runtime"));
 
+    // Offline demo mode: a fresh server identity for each run, on an ephemeral loopback port
+    let mut embedded_peer_id = None;
+    let embedded = if embedded_server {
+        let (addr, peer_id, handle) = start_embedded_server(&rt);
+        rendezvous_multiaddr = addr;
+        embedded_peer_id = Some(peer_id);
+        Some(handle)
+    } else {
+        None
+    };
+
         // Create channels between UI and networking task
-        let (ui_to_net_tx, ui_to_net_rx) = tokio::sync::mpsc::unbounded_channel::<UiToNet>();
-        let (net_to_ui_tx, net_to_ui_rx) = tokio::sync::mpsc::unbounded_channel::<NetToUi>();
+        let (ui_to_net_tx, ui_to_net_rx) = channel::channel::<UiToNet>(UI_TO_NET_CAPACITY);
+        let (net_to_ui_tx, net_to_ui_rx) = channel::channel::<NetToUi>(NET_TO_UI_CAPACITY);
+
+    // A broken identity file is reported and left alone; this session runs with a throwaway key
+    let local_key = load_or_create_identity().unwrap_or_else(|e| {
+        tracing::error!("{}", e);
+        let _ = net_to_ui_tx.send(NetToUi::Error(format!("{}; using a temporary identity", e)));
+        libp2p::identity::Keypair::generate_ed25519()
+    });
 
     // Spawn networking task
-    rt.spawn(network_task(ui_to_net_rx, net_to_ui_tx, rendezvous_multiaddr.clone()));
-
-        // Keep runtime alive by holding it in scope while UI runs
-        let native_options = eframe::NativeOptions::default();
-        eframe::run_native(
-            "P2P Chat Client",
-            native_options,
-            Box::new(|cc| {
-                // Apply our theme before UI starts
-                configure_theme(&cc.egui_ctx);
-                Box::new(ChatApp::new(ui_to_net_tx, net_to_ui_rx, rt))
-            }),
-        )
+    let default_peer_id = PeerId::from_str(RENDEZVOUS_PEER_ID).unwrap();
+    let rendezvous_point_peer_id = match (embedded_peer_id, peer_id_arg) {
+        (Some(peer_id), _) => peer_id,
+        (None, Some(arg)) => PeerId::from_str(&arg).unwrap_or_else(|e| {
+            let _ = net_to_ui_tx.send(NetToUi::Error(format!(
+                "Invalid server PeerId '{}' ({}); using the default {}",
+                arg, e, RENDEZVOUS_PEER_ID
+            )));
+            default_peer_id
+        }),
+        (None, None) => default_peer_id,
+    };
+    if let Err(e) = &rendezvous_parsed
+        && !embedded_server
+    {
+        let _ = net_to_ui_tx.send(NetToUi::Error(format!("Invalid server address '{}' ({}); using 127.0.0.1:62649", rendezvous_arg, e)));
+    }
+    let parse_multiaddr = |flag: &str, arg: &str| {
+        arg.parse::<Multiaddr>()
+            .map_err(|e| {
+                let _ = net_to_ui_tx.send(NetToUi::Error(format!("Invalid {} address '{}' ({}); ignoring it", flag, arg, e)));
+            })
+            .ok()
+    };
+    let features = NetFeatures {
+        gossip: args.iter().any(|a| a == "--gossip"),
+        mdns: args.iter().any(|a| a == "--mdns"),
+        listen: listen_arg.and_then(|arg| parse_multiaddr("--listen", &arg)),
+        external: external_args.iter().filter_map(|arg| parse_multiaddr("--external", arg)).collect(),
+        advertise_private: args.iter().any(|a| a == "--advertise-private"),
+        peer_idle_timeout: peer_idle_arg.and_then(|arg| match arg.parse::<u64>() {
+            Ok(secs) if secs > 0 => Some(Duration::from_secs(secs)),
+            _ => {
+                let _ = net_to_ui_tx.send(NetToUi::Error(format!("Invalid --peer-idle-timeout '{}'; using {}s", arg, PEER_IDLE_TIMEOUT_SECS)));
+                None
+            }
+        }),
+    };
+    // Received files are saved to the user's Downloads folder
+    let download_dir = dirs::download_dir().unwrap_or_else(std::env::temp_dir);
+    let network = rt.spawn(network_task(
+        ui_to_net_rx,
+        net_to_ui_tx,
+        rendezvous_multiaddr.clone(),
+        rendezvous_point_peer_id,
+        local_key,
+        features,
+        download_dir,
+    ));
+
+        // Keep runtime alive by holding it in scope while UI runs.
+        // The app state lives outside of eframe so a lost GPU context can be
+        // recovered by re-creating the window without touching the networking task.
+        let gpu_context_lost = Arc::new(AtomicBool::new(false));
+        let app = ChatApp::new(ui_to_net_tx, net_to_ui_rx, rt.clone(), gpu_context_lost.clone());
+        let result = run_ui(app, gpu_context_lost);
+        // The app is gone, which ends the network task once its LOGOUT had a chance to go out
+        let _ = rt.block_on(tokio::time::timeout(Duration::from_secs(SHUTDOWN_FLUSH_SECS + 1), network));
+        if let Some(server) = embedded {
+            server.shutdown(&rt);
+        }
+        result
+    }
+
+    // Runs the window, recreating it after a lost GPU context (up to MAX_UI_RESTARTS times)
+    fn run_ui(app: ChatApp, gpu_context_lost: Arc<AtomicBool>) -> eframe::Result<()> {
+        let mut app = app;
+        let mut restarts = 0;
+        loop {
+            let first_launch = restarts == 0;
+            let slot: Arc<Mutex<Option<ChatApp>>> = Arc::new(Mutex::new(None));
+            let app_slot = slot.clone();
+            let native_options = native_options(gpu_context_lost.clone());
+            let outcome = std::panic::catch_unwind(std::panic::AssertUnwindSafe(move || {
+                eframe::run_native(
+                    APP_TITLE,
+                    native_options,
+                    Box::new(move |cc| {
+                        let mut app = app;
+                        // A recreated window keeps the state it had rather than the saved one
+                        if first_launch {
+                            app.restore_ui_state(cc.storage);
+                        }
+                        // Apply our theme before UI starts
+                        configure_theme(&cc.egui_ctx, app.settings.mode, app.settings.theme);
+                        Box::new(RecoverableApp { inner: Some(app), slot: app_slot })
+                    }),
+                )
+            }));
+            let recovered = slot.lock().ok().and_then(|mut s| s.take());
+            let failure = match outcome {
+                Ok(Ok(())) => return Ok(()),
+                Ok(Err(e)) => Ok(e),
+                Err(panic) => Err(panic),
+            };
+            let reason = match &failure {
+                Ok(e) => e.to_string(),
+                Err(panic) => panic
+                    .downcast_ref::<String>()
+                    .cloned()
+                    .or_else(|| panic.downcast_ref::<&str>().map(|s| s.to_string()))
+                    .unwrap_or_else(|| "unknown panic".to_string()),
+            };
+            match recovered {
+                Some(state) if restarts < MAX_UI_RESTARTS => {
+                    restarts += 1;
+                    tracing::error!("UI renderer failed ({}); recreating window (attempt {}/{})", reason, restarts, MAX_UI_RESTARTS);
+                    app = state;
+                    app.on_context_lost();
+                }
+                _ => {
+                    tracing::error!("UI renderer failed ({}); giving up", reason);
+                    return match failure {
+                        Ok(e) => Err(e),
+                        Err(panic) => std::panic::resume_unwind(panic),
+                    };
+                }
+            }
+        }
+    }
+
+    // How many times the window is recreated after a fatal render error before giving up
+    const MAX_UI_RESTARTS: u32 = 3;
+
+    // Set from --data-dir at startup, before anything reads or writes client files
+    static DATA_DIR: std::sync::OnceLock<std::path::PathBuf> = std::sync::OnceLock::new();
+
+    // Root of everything the client saves: --data-dir if given, otherwise <config dir>/neutral
+    fn data_root() -> Option<std::path::PathBuf> {
+        DATA_DIR.get().cloned().or_else(|| dirs::config_dir().map(|dir| dir.join("neutral")))
+    }
+
+    // Where the client's ed25519 identity is kept: NEUTRAL_IDENTITY if set (e.g. for a second
+    // client on the same machine), otherwise <data root>/identity.key
+    fn identity_path() -> Option<std::path::PathBuf> {
+        if let Some(path) = std::env::var_os("NEUTRAL_IDENTITY").filter(|p| !p.is_empty()) {
+            return Some(path.into());
+        }
+        data_root().map(|dir| dir.join("identity.key"))
+    }
+
+    // Loads the identity saved by an earlier run, or generates and saves one on first run,
+    // so the PeerId stays the same across restarts. The file holds the raw 32-byte secret.
+    fn load_or_create_identity() -> Result<libp2p::identity::Keypair, String> {
+        let path = identity_path().ok_or("no data directory for the identity file")?;
+        match std::fs::read(&path) {
+            Ok(bytes) => {
+                let secret: [u8; 32] = bytes.try_into().map_err(|b: Vec<u8>| {
+                    format!("identity file {} is corrupt (expected 32 bytes, found {})", path.display(), b.len())
+                })?;
+                libp2p::identity::Keypair::ed25519_from_bytes(secret)
+                    .map_err(|e| format!("identity file {} is corrupt: {}", path.display(), e))
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let keypair = libp2p::identity::Keypair::generate_ed25519();
+                let secret = keypair
+                    .clone()
+                    .try_into_ed25519()
+                    .expect("generated an ed25519 key")
+                    .secret();
+                save_identity(&path, secret.as_ref())
+                    .map_err(|e| format!("could not save identity to {}: {}", path.display(), e))?;
+                tracing::info!("Created new identity at {}", path.display());
+                Ok(keypair)
+            }
+            Err(e) => Err(format!("could not read identity file {}: {}", path.display(), e)),
+        }
+    }
+
+    fn save_identity(path: &std::path::Path, secret: &[u8]) -> io::Result<()> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        // The secret is the account's network identity; keep it private to the user
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        io::Write::write_all(&mut options.open(path)?, secret)
+    }
+
+    // Server started by --embedded-server; its data lives in a temp dir for the session
+    struct EmbeddedServer {
+        shutdown: tokio::sync::oneshot::Sender<()>,
+        task: tokio::task::JoinHandle<()>,
+        data_dir: std::path::PathBuf,
+    }
+
+    fn start_embedded_server(rt: &tokio::runtime::Runtime) -> (Multiaddr, PeerId, EmbeddedServer) {
+        let data_dir = std::env::temp_dir().join(format!("neutral-embedded-{}", std::process::id()));
+        std::fs::create_dir_all(&data_dir).expect("embedded server data dir");
+        let (ready_tx, ready_rx) = tokio::sync::oneshot::channel();
+        let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel();
+        // Never the well-known fixed key: anyone could impersonate a server using it
+        let keypair = libp2p::identity::Keypair::generate_ed25519();
+        let peer_id = keypair.public().to_peer_id();
+        let config = server::ServerConfig {
+            keypair,
+            listen_addr: "/ip4/127.0.0.1/tcp/0".parse().unwrap(),
+            data_dir: data_dir.clone(),
+            persist_registrations: false,
+            admin_token: None,
+            protocol_probes: None,
+            strict_protocols: false,
+            shutdown: Some(shutdown_rx),
+            password_hashing: server::PasswordHashing::default(),
+            max_frame_bytes: DEFAULT_MAX_FRAME_BYTES,
+            circuit_relay: false,
+            metrics_addr: None,
+            idle_timeout: server::DEFAULT_IDLE_TIMEOUT,
+            max_connections: server::DEFAULT_MAX_CONNECTIONS,
+            max_connections_per_peer: server::DEFAULT_MAX_CONNECTIONS_PER_PEER,
+        };
+        let task = rt.spawn(async move {
+            if let Err(e) = server::run_server(config, Some(ready_tx)).await {
+                tracing::error!("Embedded server stopped: {}", e);
+            }
+        });
+        let addr = rt.block_on(ready_rx).expect("embedded server failed to start");
+        tracing::info!("Embedded server listening on {}", addr);
+        (addr, peer_id, EmbeddedServer { shutdown: shutdown_tx, task, data_dir })
+    }
+
+    impl EmbeddedServer {
+        // Asks the server loop to stop, waits briefly for it, then removes its data
+        fn shutdown(self, rt: &tokio::runtime::Runtime) {
+            let _ = self.shutdown.send(());
+            let _ = rt.block_on(async { tokio::time::timeout(Duration::from_secs(2), self.task).await });
+            let _ = std::fs::remove_dir_all(&self.data_dir);
+        }
+    }
+
+    fn native_options(gpu_context_lost: Arc<AtomicBool>) -> eframe::NativeOptions {
+        let mut options = eframe::NativeOptions {
+            // Size and position are saved on exit alongside UiState and restored on the next launch
+            persist_window: true,
+            ..Default::default()
+        };
+        // A lost surface (driver reset, GPU switch) is recreated instead of dropping frames forever.
+        // The flag tells the app to re-apply its theme on the next frame.
+        options.wgpu_options.on_surface_error = Arc::new(move |err| {
+            match err {
+                eframe::wgpu::SurfaceError::Lost | eframe::wgpu::SurfaceError::OutOfMemory => {
+                    tracing::warn!("GPU surface error: {}; recreating surface", err);
+                    gpu_context_lost.store(true, Ordering::SeqCst);
+                    eframe::egui_wgpu::SurfaceErrorAction::RecreateSurface
+                }
+                eframe::wgpu::SurfaceError::Outdated => eframe::egui_wgpu::SurfaceErrorAction::RecreateSurface,
+                eframe::wgpu::SurfaceError::Timeout => {
+                    tracing::warn!("GPU surface timeout; skipping frame");
+                    eframe::egui_wgpu::SurfaceErrorAction::SkipFrame
+                }
+            }
+        });
+        options
+    }
+
+    // Hands the app state back to `main` when eframe tears the window down,
+    // whether that is a normal close or a renderer failure.
+    struct RecoverableApp {
+        inner: Option<ChatApp>,
+        slot: Arc<Mutex<Option<ChatApp>>>,
+    }
+
+    impl eframe::App for RecoverableApp {
+        fn update(&mut self, ctx: &egui::Context, frame: &mut eframe::Frame) {
+            if let Some(app) = self.inner.as_mut() {
+                app.update(ctx, frame);
+            }
+        }
+
+        fn save(&mut self, storage: &mut dyn eframe::Storage) {
+            if let Some(app) = self.inner.as_mut() {
+                app.save(storage);
+            }
+        }
+    }
+
+    impl Drop for RecoverableApp {
+        fn drop(&mut self) {
+            if let (Some(app), Ok(mut slot)) = (self.inner.take(), self.slot.lock()) {
+                *slot = Some(app);
+            }
+        }
     }
 
     // The eframe/egui application struct
@@ -305,96 +930,1615 @@ use eframe::egui;
     struct ChatMessage {
         from_self: bool,
         text: String,
+        timestamp: SystemTime,
+        // Relayed from another conversation; shown with a "Forwarded" marker
+        forwarded: bool,
+        // Sender shown on the bubble in rooms, where it varies per message; None in 1:1 chats
+        author: Option<String>,
+        // Sender's id of a message exchanged this session, for receipts; reloaded history has none
+        msg_id: Option<String>,
+        // Incoming only: the sender's per-conversation sequence number, which orders messages that overtook each other
+        seq: Option<u64>,
+        delivered: bool,
+        failed: bool,
+        // Outgoing only: waiting for a connection to the recipient
+        queued: bool,
+        // Outgoing only: the recipient sent a read receipt covering it
+        read: bool,
     }
 
     #[derive(Debug, Clone)]
     struct Conversation {
+        // Live messages, rendered normally in the timeline
         messages: Vec<ChatMessage>,
+        // Messages older than the archive threshold; only rendered when expanded
+        archived: Vec<ChatMessage>,
+        // Archived messages still only in the history file, read once the archive is expanded
+        unloaded_archive: usize,
         unread: bool,
         last_activity: SystemTime,
+        // Newest incoming message id we've sent a read receipt for
+        read_sent: Option<String>,
+        // Unsent text in the composer; cleared once it's sent
+        draft: String,
     }
 
     impl Default for Conversation {
         fn default() -> Self {
             Self {
                 messages: Vec::new(),
+                archived: Vec::new(),
+                unloaded_archive: 0,
                 unread: false,
                 last_activity: SystemTime::UNIX_EPOCH,
+                read_sent: None,
+                draft: String::new(),
             }
         }
     }
 
-    struct ChatApp {
-        tx: UnboundedSender<UiToNet>,
-        rx: UnboundedReceiver<NetToUi>,
-        // Hold the runtime to keep it alive for as long as the UI runs
-        _rt: std::sync::Arc<tokio::runtime::Runtime>,
-    conversations: HashMap<String, Conversation>,
-        users: HashMap<String, String>, // username -> PeerId
-        selected_user: Option<String>,
-        peer_to_username: HashMap<String, String>, // PeerId -> username (for labeling incoming)
-        message_input: String,
-        status: String,
-        // Login state
-        logged_in: bool,
-        username: String,
-        username_input: String,
-        password_input: String,
-        auth_feedback: String,
-        // Register page state
-        page: Page,
-        reg_username: String,
-        reg_password: String,
-        // Birthdate parts for a structured chooser
-        reg_birth_year: i32,
-        reg_birth_month: u32, // 1-12
-        reg_birth_day: u32,   // 1..=days_in_month
-        // Delete account view
-        show_delete_view: bool,
-        del_username: String,
-        del_password: String,
-        del_feedback: String,
+    impl Conversation {
+        // Moves live messages older than `cutoff` to the archived range.
+        // Messages are kept in arrival order, so only a prefix ever moves.
+        fn archive_older_than(&mut self, cutoff: SystemTime) {
+            let split = self.messages.iter().position(|m| m.timestamp >= cutoff).unwrap_or(self.messages.len());
+            if split > 0 {
+                self.archived.extend(self.messages.drain(..split));
+            }
+        }
+
+        // Appends in arrival order, except that an incoming message goes before the incoming ones
+        // with higher sequence numbers that overtook it (and our replies sent since). A gap in
+        // the numbers never holds a message back.
+        fn push_in_sequence(&mut self, message: ChatMessage) {
+            let mut at = self.messages.len();
+            if let (false, Some(seq)) = (message.from_self, message.seq) {
+                for (i, m) in self.messages.iter().enumerate().rev() {
+                    if m.from_self {
+                        continue;
+                    }
+                    match m.seq {
+                        Some(later) if later > seq => at = i,
+                        _ => break,
+                    }
+                }
+            }
+            self.messages.insert(at, message);
+        }
+
+        fn last_message(&self) -> Option<&ChatMessage> {
+            self.messages.last().or_else(|| self.archived.last())
+        }
+
+        // Merges imported messages into the timeline, skipping any already present
+        // (same timestamp and text). Returns how many were added.
+        fn merge(&mut self, imported: Vec<ChatMessage>) -> usize {
+            let mut seen: HashSet<(SystemTime, String)> = self
+                .archived
+                .iter()
+                .chain(self.messages.iter())
+                .map(|m| (m.timestamp, m.text.clone()))
+                .collect();
+            let fresh: Vec<ChatMessage> = imported
+                .into_iter()
+                .filter(|m| seen.insert((m.timestamp, m.text.clone())))
+                .collect();
+            let added = fresh.len();
+            if added == 0 {
+                return 0;
+            }
+            // Rebuild one ordered timeline; the archive split is re-applied on the next frame
+            let mut all: Vec<ChatMessage> = self.archived.drain(..).chain(self.messages.drain(..)).chain(fresh).collect();
+            all.sort_by_key(|m| m.timestamp);
+            if let Some(last) = all.last() {
+                self.last_activity = self.last_activity.max(last.timestamp);
+            }
+            self.messages = all;
+            added
+        }
     }
 
-    // UI pages
-    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-    enum Page { Login, Register }
+    // --- Transcript import ---
+    // JSON transcript: {"version":1,"peer":"bob","messages":[{"from_self":true,"text":"hi","timestamp_ms":1700000000000}]}
+    // Messages may also carry "forwarded": true.
+    #[derive(serde::Deserialize)]
+    struct TranscriptJson {
+        #[serde(default)]
+        peer: Option<String>,
+        messages: Vec<TranscriptMessageJson>,
+    }
 
-    impl ChatApp {
-        fn new(tx: UnboundedSender<UiToNet>, rx: UnboundedReceiver<NetToUi>, rt: std::sync::Arc<tokio::runtime::Runtime>) -> Self {
+    #[derive(serde::Deserialize)]
+    struct TranscriptMessageJson {
+        from_self: bool,
+        text: String,
+        // Milliseconds since the Unix epoch
+        timestamp_ms: u64,
+        #[serde(default)]
+        forwarded: bool,
+    }
+
+    // Parses a JSON transcript into messages; returns the peer it was exported for, if recorded
+    fn parse_transcript(json: &str) -> Result<(Option<String>, Vec<ChatMessage>), String> {
+        let transcript: TranscriptJson = serde_json::from_str(json).map_err(|e| format!("Invalid transcript: {}", e))?;
+        let messages = transcript
+            .messages
+            .into_iter()
+            .map(|m| ChatMessage {
+                from_self: m.from_self,
+                text: m.text,
+                timestamp: SystemTime::UNIX_EPOCH + Duration::from_millis(m.timestamp_ms),
+                forwarded: m.forwarded,
+                author: None,
+                msg_id: None,
+                seq: None,
+                delivered: false,
+                failed: false,
+                queued: false,
+                read: false,
+            })
+            .collect();
+        Ok((transcript.peer, messages))
+    }
+
+    // --- End-to-end encryption ---
+    // Chat payloads, sender name included, are sealed with ChaCha20-Poly1305 under a key both peers
+    // derive from X25519 Diffie-Hellman between their ed25519 identity keys. The recipient's
+    // username is the associated data, so a payload can't be replayed to another account that
+    // uses the same identity. The keys are long-lived, so a leaked identity key exposes past
+    // messages too (no forward secrecy).
+    const E2E_KEY_CONTEXT: &[u8] = b"neutral/e2e/v1";
+
+    fn e2e_secret(keypair: &libp2p::identity::Keypair) -> Option<x25519_dalek::StaticSecret> {
+        let keypair = keypair.clone().try_into_ed25519().ok()?;
+        let seed: [u8; 32] = keypair.secret().as_ref().try_into().ok()?;
+        Some(x25519_dalek::StaticSecret::from(ed25519_dalek::SigningKey::from_bytes(&seed).to_scalar_bytes()))
+    }
+
+    // ed25519 PeerIds embed the public key, the same one identify reports
+    fn peer_public_key(peer: &PeerId) -> Option<libp2p::identity::PublicKey> {
+        let multihash = peer.as_ref();
+        if multihash.code() != 0 {
+            return None;
+        }
+        libp2p::identity::PublicKey::try_decode_protobuf(multihash.digest()).ok()
+    }
+
+    fn e2e_cipher(secret: &x25519_dalek::StaticSecret, peer_key: &libp2p::identity::PublicKey) -> Option<chacha20poly1305::ChaCha20Poly1305> {
+        use chacha20poly1305::KeyInit;
+        use sha2::Digest;
+        let peer_key = peer_key.clone().try_into_ed25519().ok()?;
+        let montgomery = ed25519_dalek::VerifyingKey::from_bytes(&peer_key.to_bytes()).ok()?.to_montgomery();
+        let shared = secret.diffie_hellman(&x25519_dalek::PublicKey::from(montgomery.to_bytes()));
+        // A low-order peer key would give a predictable secret
+        if !shared.was_contributory() {
+            return None;
+        }
+        let key = sha2::Sha256::new().chain_update(E2E_KEY_CONTEXT).chain_update(shared.as_bytes()).finalize();
+        Some(chacha20poly1305::ChaCha20Poly1305::new(&key))
+    }
+
+    // Cipher shared with `peer`, from the key identify reported or else the one in its PeerId
+    fn peer_cipher(
+        secret: Option<&x25519_dalek::StaticSecret>,
+        keys: &HashMap<PeerId, libp2p::identity::PublicKey>,
+        peer: &PeerId,
+    ) -> Option<chacha20poly1305::ChaCha20Poly1305> {
+        let key = keys.get(peer).cloned().or_else(|| peer_public_key(peer))?;
+        e2e_cipher(secret?, &key)
+    }
+
+    // Hex of a random 12-byte nonce followed by the ciphertext, for `to` only
+    fn e2e_seal(cipher: &chacha20poly1305::ChaCha20Poly1305, plaintext: &str, to: &str) -> String {
+        use chacha20poly1305::aead::{Aead, AeadCore, OsRng, Payload};
+        let nonce = chacha20poly1305::ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let to = username_key(to);
+        let payload = Payload { msg: plaintext.as_bytes(), aad: to.as_bytes() };
+        let ciphertext = cipher.encrypt(&nonce, payload).expect("in-memory encryption doesn't fail");
+        hex::encode([nonce.as_slice(), &ciphertext].concat())
+    }
+
+    // Fails unless the payload was sealed for `to`
+    fn e2e_open(cipher: &chacha20poly1305::ChaCha20Poly1305, sealed: &str, to: &str) -> Option<String> {
+        use chacha20poly1305::aead::{Aead, Payload};
+        let bytes = hex::decode(sealed).ok()?;
+        if bytes.len() < 12 {
+            return None;
+        }
+        let (nonce, ciphertext) = bytes.split_at(12);
+        let to = username_key(to);
+        let payload = Payload { msg: ciphertext, aad: to.as_bytes() };
+        let plaintext = cipher.decrypt(chacha20poly1305::Nonce::from_slice(nonce), payload).ok()?;
+        String::from_utf8(plaintext).ok()
+    }
+
+    // --- Chat history ---
+    // Each conversation is appended to <config dir>/neutral/history/<user>/<conversation>.jsonl,
+    // one JSON message per line, and reloaded the next time the conversation opens.
+    #[derive(serde::Serialize, serde::Deserialize)]
+    struct HistoryEntry {
+        from_self: bool,
+        text: String,
+        // Milliseconds since the Unix epoch
+        timestamp_ms: u64,
+        #[serde(default)]
+        forwarded: bool,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        author: Option<String>,
+    }
+
+    impl From<&ChatMessage> for HistoryEntry {
+        fn from(m: &ChatMessage) -> Self {
             Self {
-                tx, rx, _rt: rt,
-                conversations: HashMap::new(),
-                users: HashMap::new(), selected_user: None, peer_to_username: HashMap::new(),
-                message_input: String::new(),
-                status: String::from("Please login or register"), logged_in: false,
-                
-                username: String::new(), username_input: String::new(), password_input: String::new(),
-                auth_feedback: String::new(),
-                page: Page::Login,
-                reg_username: String::new(), reg_password: String::new(),
-                // Sensible defaults
-                reg_birth_year: 2000,
-                reg_birth_month: 1,
-                reg_birth_day: 1,
-                show_delete_view: false,
-                del_username: String::new(),
-                del_password: String::new(),
-                del_feedback: String::new(),
+                from_self: m.from_self,
+                text: m.text.clone(),
+                timestamp_ms: unix_ms(m.timestamp),
+                forwarded: m.forwarded,
+                author: m.author.clone(),
             }
         }
     }
 
-    impl eframe::App for ChatApp {
-        fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
-            // Ensure regular repaint so incoming messages are processed promptly
-            ctx.request_repaint_after(std::time::Duration::from_millis(16));
-            // Drain messages from networking
-            while let Ok(msg) = self.rx.try_recv() {
-                match msg {
-                    NetToUi::Discovered(list) => {
-                        self.status = format!("Discovered {} peer(s)", list.len());
-                        ctx.request_repaint();
+    impl From<HistoryEntry> for ChatMessage {
+        fn from(e: HistoryEntry) -> Self {
+            Self {
+                from_self: e.from_self,
+                text: e.text,
+                timestamp: SystemTime::UNIX_EPOCH + Duration::from_millis(e.timestamp_ms),
+                forwarded: e.forwarded,
+                author: e.author,
+                msg_id: None,
+                seq: None,
+                delivered: false,
+                failed: false,
+                queued: false,
+                read: false,
+            }
+        }
+    }
+
+    // Message times are kept to the millisecond, the precision history and transcripts store,
+    // so a reloaded message still matches its in-memory copy
+    fn message_time_now() -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_millis(unix_ms(SystemTime::now()))
+    }
+
+    fn unix_ms(time: SystemTime) -> u64 {
+        time.duration_since(SystemTime::UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct ChatPayload {
+        from: String,
+        text: String,
+        sent: SystemTime,
+        // Echoed back as "ACK:<id>"; room messages and older clients send none
+        msg_id: Option<String>,
+        // Per-conversation sequence number; room messages and older clients send none
+        seq: Option<u64>,
+    }
+
+    // Splits a "<from>|<text>|<sent ms>|<msg id>|<seq>" chat or room payload. Older clients stop
+    // after the text, the time or the id, so those messages get the receive time; a sender clock
+    // running ahead is clamped to now. Fields after the seq are ignored, leaving room for later versions.
+    fn parse_chat_payload(payload: &str) -> Option<ChatPayload> {
+        let now = message_time_now();
+        let (from, text, sent, msg_id, seq) = match server::wire::split(payload)?.as_slice() {
+            [from, text] => (from.clone(), text.clone(), now, None, None),
+            [from, text, ms, rest @ ..] => {
+                let sent = SystemTime::UNIX_EPOCH.checked_add(Duration::from_millis(ms.parse().ok()?)).unwrap_or(now);
+                let msg_id = rest.first().filter(|id| !id.is_empty()).cloned();
+                (from.clone(), text.clone(), sent.min(now), msg_id, rest.get(1).and_then(|seq| seq.parse().ok()))
+            }
+            _ => return None,
+        };
+        Some(ChatPayload { from, text, sent, msg_id, seq })
+    }
+
+    fn history_root() -> Option<std::path::PathBuf> {
+        data_root().map(|dir| dir.join("history"))
+    }
+
+    // Per-account lists of usernames, each kept in its own directory under the data root
+    const BLOCKED_LIST: &str = "blocked";
+    const MUTED_LIST: &str = "muted";
+
+    // <data root>/<list>/<account>.json, a JSON array of usernames
+    fn name_list_path(list: &str, account: &str) -> Option<std::path::PathBuf> {
+        let file = format!("{}.json", history_file_stem(&username_key(account)));
+        data_root().map(|dir| dir.join(list).join(file))
+    }
+
+    // A missing or unreadable file is an empty list: no one blocked, nothing muted
+    fn load_name_list<T: serde::de::DeserializeOwned + Default>(list: &str, account: &str) -> T {
+        let Some(path) = name_list_path(list, account) else { return T::default() };
+        match std::fs::read_to_string(&path) {
+            Ok(text) => serde_json::from_str(&text).unwrap_or_else(|e| {
+                tracing::warn!("Ignoring unreadable {} list {}: {}", list, path.display(), e);
+                T::default()
+            }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => T::default(),
+            Err(e) => {
+                tracing::warn!("Could not read {} list {}: {}", list, path.display(), e);
+                T::default()
+            }
+        }
+    }
+
+    // Best effort, like chat history: a failed write is logged and the list stays in memory
+    fn save_name_list(list: &str, account: &str, names: &impl serde::Serialize) {
+        let Some(path) = name_list_path(list, account) else { return };
+        let result = path
+            .parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|_| std::fs::write(&path, serde_json::to_string(names).unwrap_or_default()));
+        if let Err(e) = result {
+            tracing::warn!("Could not save {} list to {}: {}", list, path.display(), e);
+        }
+    }
+
+    // Blocked users by username_key, with the PeerIds of the sessions they were seen on, so
+    // their traffic is dropped by PeerId whatever name a payload claims
+    type BlockList = BTreeMap<String, BTreeSet<String>>;
+
+    // Lists saved before PeerIds were kept hold just the names
+    #[derive(serde::Deserialize)]
+    #[serde(untagged)]
+    enum SavedBlockList {
+        Sessions(BlockList),
+        Names(BTreeSet<String>),
+    }
+
+    impl Default for SavedBlockList {
+        fn default() -> Self {
+            SavedBlockList::Sessions(BlockList::new())
+        }
+    }
+
+    fn load_block_list(account: &str) -> BlockList {
+        match load_name_list(BLOCKED_LIST, account) {
+            SavedBlockList::Sessions(blocked) => blocked,
+            SavedBlockList::Names(names) => names.iter().map(|name| (username_key(name), BTreeSet::new())).collect(),
+        }
+    }
+
+    // Whether `peer` belongs to a blocked user: one of the sessions recorded when they were
+    // blocked, or one the server's directory currently lists under their name
+    fn is_blocked_peer(blocked: &BlockList, directory_sessions: &HashMap<String, String>, peer: &str) -> bool {
+        blocked.values().any(|sessions| sessions.contains(peer))
+            || directory_sessions.get(peer).is_some_and(|name| blocked.contains_key(&username_key(name)))
+    }
+
+    // <data root>/drafts/<account>.json, a JSON object of chat -> unsent text
+    fn drafts_path(account: &str) -> Option<std::path::PathBuf> {
+        let file = format!("{}.json", history_file_stem(&username_key(account)));
+        data_root().map(|dir| dir.join("drafts").join(file))
+    }
+
+    fn load_drafts(account: &str) -> HashMap<String, String> {
+        let Some(path) = drafts_path(account) else { return HashMap::new() };
+        match std::fs::read_to_string(&path) {
+            Ok(text) => serde_json::from_str(&text).unwrap_or_else(|e| {
+                tracing::warn!("Ignoring unreadable drafts {}: {}", path.display(), e);
+                HashMap::new()
+            }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => {
+                tracing::warn!("Could not read drafts {}: {}", path.display(), e);
+                HashMap::new()
+            }
+        }
+    }
+
+    // Saved on exit, periodically and at logout; the file goes once no chat has a draft
+    fn save_drafts(account: &str, conversations: &HashMap<String, Conversation>) {
+        let Some(path) = drafts_path(account) else { return };
+        let drafts: HashMap<&String, &String> =
+            conversations.iter().filter(|(_, c)| !c.draft.trim().is_empty()).map(|(name, c)| (name, &c.draft)).collect();
+        let result = if drafts.is_empty() {
+            std::fs::remove_file(&path).or_else(|e| if e.kind() == io::ErrorKind::NotFound { Ok(()) } else { Err(e) })
+        } else {
+            path.parent()
+                .map_or(Ok(()), std::fs::create_dir_all)
+                .and_then(|_| std::fs::write(&path, serde_json::to_string(&drafts).unwrap_or_default()))
+        };
+        if let Err(e) = result {
+            tracing::warn!("Could not save drafts to {}: {}", path.display(), e);
+        }
+    }
+
+    // UI state kept in eframe's storage next to the window geometry, restored on the next launch.
+    // Only preferences and the open chat's name go here, never credentials or message text.
+    #[derive(serde::Serialize, serde::Deserialize)]
+    #[serde(default)]
+    struct UiState {
+        mode: ThemeMode,
+        variant: ThemeVariant,
+        // Account and the chat it last had open
+        last_chat: Option<(String, String)>,
+    }
+
+    impl Default for UiState {
+        fn default() -> Self {
+            Self { mode: ThemeMode::Dark, variant: ThemeVariant::Standard, last_chat: None }
+        }
+    }
+
+    const UI_STATE_KEY: &str = "neutral_ui_state";
+
+    // File-name-safe form of a username or room key. Lowercase letters, digits, '-' and '_'
+    // are kept; every other byte becomes %XX, so names can't leave the history directory
+    // and "Bob" and "bob" stay apart on case-insensitive file systems.
+    fn history_file_stem(name: &str) -> String {
+        let mut out = String::with_capacity(name.len());
+        for b in name.bytes() {
+            if b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-' || b == b'_' {
+                out.push(b as char);
+            } else {
+                out.push_str(&format!("%{:02X}", b));
+            }
+        }
+        out
+    }
+
+    // Missing files are empty histories; unreadable lines are skipped. The first `skip` bytes
+    // belong to a cleared chat. Messages from before `since` are only counted, so a long
+    // archive isn't held in memory until it is shown.
+    fn load_history(path: &std::path::Path, skip: u64, since: Option<SystemTime>) -> (Vec<ChatMessage>, usize) {
+        let data = match std::fs::read(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return (Vec::new(), 0),
+            Err(e) => {
+                tracing::warn!("Could not read chat history {}: {}", path.display(), e);
+                return (Vec::new(), 0);
+            }
+        };
+        let text = String::from_utf8_lossy(usize::try_from(skip).ok().and_then(|skip| data.get(skip..)).unwrap_or_default());
+        let mut older = 0;
+        let messages = text
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .filter_map(|line| match serde_json::from_str::<HistoryEntry>(line) {
+                Ok(entry) => Some(ChatMessage::from(entry)),
+                Err(e) => {
+                    tracing::warn!("Skipping unreadable line in {}: {}", path.display(), e);
+                    None
+                }
+            })
+            .filter(|m| {
+                let live = since.is_none_or(|since| m.timestamp >= since);
+                older += usize::from(!live);
+                live
+            })
+            .collect();
+        (messages, older)
+    }
+
+    // Best effort: a failed write is logged and the message stays in memory only
+    fn append_history(path: &std::path::Path, message: &ChatMessage) {
+        if let Err(e) = write_history(path, std::slice::from_ref(message), true) {
+            tracing::warn!("Could not save chat history to {}: {}", path.display(), e);
+        }
+    }
+
+    // Replaces the file's contents, for when a chat is cleared, reopened or imported into
+    fn save_history(path: &std::path::Path, messages: &[ChatMessage]) {
+        if let Err(e) = write_history(path, messages, false) {
+            tracing::warn!("Could not save chat history to {}: {}", path.display(), e);
+        }
+    }
+
+    fn write_history(path: &std::path::Path, messages: &[ChatMessage], append: bool) -> io::Result<()> {
+        let mut lines = String::new();
+        for message in messages {
+            lines.push_str(&serde_json::to_string(&HistoryEntry::from(message))?);
+            lines.push('\n');
+        }
+        write_history_bytes(path, lines.as_bytes(), append)
+    }
+
+    // A cleared chat is past undo: its messages leave the start of the file, and anything
+    // received since stays
+    fn drop_history_prefix(path: &std::path::Path, bytes: u64) {
+        let result = std::fs::read(path).and_then(|data| {
+            let rest = usize::try_from(bytes).ok().and_then(|bytes| data.get(bytes..)).unwrap_or_default();
+            write_history_bytes(path, rest, false)
+        });
+        if let Err(e) = result
+            && e.kind() != io::ErrorKind::NotFound
+        {
+            tracing::warn!("Could not trim chat history {}: {}", path.display(), e);
+        }
+    }
+
+    fn write_history_bytes(path: &std::path::Path, bytes: &[u8], append: bool) -> io::Result<()> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let mut options = std::fs::OpenOptions::new();
+        options.create(true);
+        if append {
+            options.append(true);
+        } else {
+            options.write(true).truncate(true);
+        }
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        io::Write::write_all(&mut options.open(path)?, bytes)
+    }
+
+    // Cleared chats and chats dropped with their contact stay here for a short while,
+    // so Ctrl+Shift+T can bring them back before they are gone for good
+    const RECENTLY_CLOSED_MAX: usize = 5;
+    const RECENTLY_CLOSED_SECS: u64 = 120;
+
+    struct ClosedConversation {
+        name: String,
+        conversation: Conversation,
+        closed_at: Instant,
+        // A cleared chat's messages are the first this many bytes of its history file until
+        // the undo window passes; None when the file doesn't hold them apart
+        history_prefix: Option<u64>,
+    }
+
+    // "Forward message" picker: the text being forwarded and the contacts ticked so far
+    struct ForwardPicker {
+        text: String,
+        recipients: HashSet<String>,
+    }
+
+    // "Import transcript" dialog state
+    #[derive(Debug, Clone, Default)]
+    struct ImportDialog {
+        path: String,
+        feedback: String,
+    }
+
+    // State of the in-conversation find bar
+    #[derive(Debug, Clone, Default)]
+    struct FindBar {
+        query: String,
+        // Query the matches were computed for
+        matched_query: String,
+        // Indices of matching messages, counting archived messages first
+        matches: Vec<usize>,
+        // Position in `matches` of the highlighted result
+        current: usize,
+        // Set when the current match changed and should be scrolled into view
+        scroll_pending: bool,
+        focus_pending: bool,
+    }
+
+    impl FindBar {
+        fn update_matches(&mut self, conversation: &Conversation) {
+            let needle = self.query.to_lowercase();
+            let query_changed = needle != self.matched_query;
+            self.matches = if needle.is_empty() {
+                Vec::new()
+            } else {
+                conversation
+                    .archived
+                    .iter()
+                    .chain(conversation.messages.iter())
+                    .enumerate()
+                    .filter(|(_, m)| m.text.to_lowercase().contains(&needle))
+                    .map(|(i, _)| i)
+                    .collect()
+            };
+            if query_changed {
+                // Start from the most recent match, like scrolling up from the bottom
+                self.current = self.matches.len().saturating_sub(1);
+                self.scroll_pending = !self.matches.is_empty();
+                self.matched_query = needle;
+            } else if self.current >= self.matches.len() {
+                self.current = self.matches.len().saturating_sub(1);
+            }
+        }
+
+        fn current_match(&self) -> Option<usize> {
+            self.matches.get(self.current).copied()
+        }
+
+        fn step(&mut self, forward: bool) {
+            let n = self.matches.len();
+            if n == 0 {
+                return;
+            }
+            self.current = if forward { (self.current + 1) % n } else { (self.current + n - 1) % n };
+            self.scroll_pending = true;
+        }
+    }
+
+    // User-tunable client settings, edited from the Settings view
+    #[derive(Debug, Clone)]
+    struct Settings {
+        // Messages older than this many days are collapsed into "Archived messages" (0 = never)
+        archive_after_days: u32,
+        // Log out after this many minutes without UI interaction (0 = disabled)
+        auto_logout_minutes: u32,
+        // Sustained outgoing message rate; short bursts of SEND_BURST are allowed
+        max_messages_per_sec: u32,
+        mode: ThemeMode,
+        theme: ThemeVariant,
+    }
+
+    impl Default for Settings {
+        fn default() -> Self {
+            Self {
+                archive_after_days: 30,
+                auto_logout_minutes: 0,
+                max_messages_per_sec: 5,
+                mode: ThemeMode::Dark,
+                theme: ThemeVariant::Standard,
+            }
+        }
+    }
+
+    // Messages that may be sent back-to-back before the rate limit applies
+    const SEND_BURST: f64 = 3.0;
+
+    // Token bucket for outgoing chat messages. Sends beyond the allowed rate are queued
+    // and released in order as tokens refill, so a stuck key or paste loop can't flood a peer.
+    struct SendLimiter {
+        tokens: f64,
+        last_refill: Instant,
+        queue: std::collections::VecDeque<UiToNet>,
+    }
+
+    impl SendLimiter {
+        fn new() -> Self {
+            Self { tokens: SEND_BURST, last_refill: Instant::now(), queue: std::collections::VecDeque::new() }
+        }
+
+        fn enqueue(&mut self, cmd: UiToNet) {
+            self.queue.push_back(cmd);
+        }
+
+        fn queued(&self) -> usize {
+            self.queue.len()
+        }
+
+        fn clear(&mut self) {
+            self.queue.clear();
+        }
+
+        fn pump(&mut self, tx: &Sender<UiToNet>, settings: &Settings) {
+            let rate = f64::from(settings.max_messages_per_sec.max(1));
+            let now = Instant::now();
+            let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+            self.last_refill = now;
+            self.tokens = (self.tokens + elapsed * rate).min(SEND_BURST);
+            while self.tokens >= 1.0 {
+                let Some(cmd) = self.queue.pop_front() else { break };
+                let _ = tx.send(cmd);
+                self.tokens -= 1.0;
+            }
+        }
+    }
+
+    // Window title; the unread count is appended while there is any
+    const APP_TITLE: &str = "P2P Chat Client";
+
+    // How long a toast stays up, including its fade-out
+    const TOAST_SECS: f32 = 3.0;
+    const TOAST_FADE_SECS: f32 = 0.5;
+
+    // Seconds before an inactivity logout during which the warning dialog is shown
+    const IDLE_WARNING_SECS: u64 = 30;
+
+    // How long a LOGIN or REGISTER may go unanswered, by the server or the networking task,
+    // before the login page says the server isn't responding
+    const AUTH_TIMEOUT_SECS: u64 = 15;
+
+    // A TYPING signal goes out at most this often per contact, and shows on the other side this long
+    const TYPING_THROTTLE_SECS: u64 = 2;
+    const TYPING_SHOW_SECS: u64 = 5;
+
+    // Longest message the composer sends, in characters. A counter shows from COUNTER_FROM_CHARS on.
+    const MAX_MESSAGE_CHARS: usize = 4000;
+    const COUNTER_FROM_CHARS: usize = 3500;
+
+    // Length of a draft as it would be sent: surrounding whitespace is trimmed first
+    fn message_length(draft: &str) -> usize {
+        draft.trim().chars().count()
+    }
+
+    impl Settings {
+        fn archive_cutoff(&self) -> Option<SystemTime> {
+            if self.archive_after_days == 0 {
+                return None;
+            }
+            let age = std::time::Duration::from_secs(u64::from(self.archive_after_days) * 24 * 60 * 60);
+            SystemTime::now().checked_sub(age)
+        }
+    }
+
+    enum TransferState {
+        Active,
+        // Where a received file was saved; None for files we sent
+        Done(Option<String>),
+        Failed(String),
+    }
+
+    // UI side of a file transfer, keyed by the id the network task gave it
+    struct Transfer {
+        id: u64,
+        // Contact username the file is going to or coming from
+        peer: String,
+        filename: String,
+        size: u64,
+        done: u64,
+        outgoing: bool,
+        state: TransferState,
+    }
+
+    // A directory entry; offline users keep the PeerId they last had, empty if never seen online.
+    // A user logged in on several devices has a session per device; messages go to `peer_id`,
+    // the first of them, and the network task copies them to the others.
+    #[derive(Debug, Clone, PartialEq)]
+    struct Contact {
+        peer_id: String,
+        online: bool,
+        sessions: Vec<String>,
+    }
+
+    // How a directory from the server differs from the roster
+    #[derive(Debug, Default, PartialEq)]
+    struct RosterDiff {
+        // No longer have an account; offline users stay in the directory
+        removed: Vec<String>,
+        // New users, and known ones whose sessions changed, with their updated entry
+        changed: Vec<(String, Contact)>,
+    }
+
+    // Our own entry stays in the roster; the server lists only the account's other sessions under
+    // it, so it's online while another device of ours is.
+    fn diff_roster(roster: &HashMap<String, Contact>, directory: HashMap<String, Vec<String>>) -> RosterDiff {
+        let mut removed: Vec<String> = roster.keys().filter(|name| !directory.contains_key(*name)).cloned().collect();
+        removed.sort();
+        let mut changed = Vec::new();
+        for (name, sessions) in directory {
+            let known = roster.get(&name);
+            let online = !sessions.is_empty();
+            if known.is_some_and(|c| c.online == online && c.sessions == sessions) {
+                continue;
+            }
+            // Offline users keep the session they last had
+            let peer_id = sessions.first().or(known.map(|c| &c.peer_id)).cloned().unwrap_or_default();
+            changed.push((name, Contact { peer_id, online, sessions }));
+        }
+        changed.sort_by(|a, b| a.0.cmp(&b.0));
+        RosterDiff { removed, changed }
+    }
+
+    // Connection quality from ping: the last round-trip time, or repeated timeouts
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum LinkQuality {
+        Rtt(u64),
+        Degraded,
+    }
+
+    impl LinkQuality {
+        // Signal-style bars and a hover text for the chat header
+        fn indicator(self) -> (&'static str, String) {
+            match self {
+                LinkQuality::Rtt(millis) => {
+                    let bars = match millis {
+                        0..=99 => "▂▄▆█",
+                        100..=249 => "▂▄▆",
+                        250..=599 => "▂▄",
+                        _ => "▂",
+                    };
+                    (bars, format!("Round trip {} ms", millis))
+                }
+                LinkQuality::Degraded => ("⚠", "Pings are timing out; messages may be delayed or lost".to_string()),
+            }
+        }
+    }
+
+    // Events kept for the diagnostics panel
+    const DIAGNOSTICS_MAX: usize = 500;
+
+    struct DiagnosticEntry {
+        at: SystemTime,
+        error: bool,
+        text: String,
+    }
+
+    impl DiagnosticEntry {
+        fn line(&self) -> String {
+            let time = chrono::DateTime::<chrono::Local>::from(self.at).format("%Y-%m-%d %H:%M:%S");
+            format!("{} {} {}", time, if self.error { "ERROR" } else { "INFO " }, self.text)
+        }
+    }
+
+    // Recent notices, errors and connection changes, newest last, so users can copy them into a
+    // bug report without running the client from a terminal with RUST_LOG
+    #[derive(Default)]
+    struct DiagnosticsLog {
+        entries: std::collections::VecDeque<DiagnosticEntry>,
+    }
+
+    impl DiagnosticsLog {
+        fn push(&mut self, error: bool, text: impl Into<String>) {
+            if self.entries.len() == DIAGNOSTICS_MAX {
+                self.entries.pop_front();
+            }
+            self.entries.push_back(DiagnosticEntry { at: SystemTime::now(), error, text: text.into() });
+        }
+
+        fn to_text(&self) -> String {
+            self.entries.iter().map(|entry| entry.line() + "\n").collect()
+        }
+    }
+
+    struct ChatApp {
+        tx: Sender<UiToNet>,
+        rx: Receiver<NetToUi>,
+        // Hold the runtime to keep it alive for as long as the UI runs
+        _rt: std::sync::Arc<tokio::runtime::Runtime>,
+        // Set by the renderer when the GPU surface was lost; handled at the start of the next frame
+        gpu_context_lost: Arc<AtomicBool>,
+    conversations: HashMap<String, Conversation>,
+        // Where chat history is kept; None when there is no config directory
+        history_root: Option<std::path::PathBuf>,
+        users: HashMap<String, Contact>, // username -> directory entry
+        // When offline users were last online, as far as the server knows
+        last_seen: HashMap<String, SystemTime>,
+        selected_user: Option<String>,
+        // Sidebar search; only names containing it (ignoring case) are listed
+        contact_filter: String,
+        peer_to_username: HashMap<String, String>, // PeerId -> username (for labeling incoming)
+        incompatible_peers: HashMap<String, String>, // PeerId -> advertised protocol version
+        // Peers on the local network (--mdns): PeerId -> identify protocol and agent versions
+        nearby: HashMap<String, (String, String)>,
+        // Connected peers' ping results: PeerId -> quality, shown next to the chat title
+        link_quality: HashMap<String, LinkQuality>,
+        // Gossip rooms joined this session; their conversations are keyed by room_key()
+        rooms: Vec<String>,
+        // Contact and time of the last TYPING we sent, for throttling
+        typing_sent: Option<(String, Instant)>,
+        // Contacts currently typing to us, until when the indicator stays
+        typing: HashMap<String, Instant>,
+        // File transfers this session, shown at the end of their contact's chat
+        transfers: Vec<Transfer>,
+        // Server connection state; only connection events update it
+        connection_status: String,
+        reachability: Reachability,
+        // Auto-fading notice and when it was raised
+        transient_toast: Option<(String, Instant)>,
+        // Unread count the window title shows; None until it has been set on this window
+        title_unread: Option<usize>,
+        // Last error, shown until dismissed or replaced
+        last_error: Option<String>,
+        // Kept across logins; shown at the bottom of every page while toggled on
+        diagnostics: DiagnosticsLog,
+        show_diagnostics: bool,
+        // Login state
+        logged_in: bool,
+        username: String,
+        session_token: Option<String>,
+        username_input: String,
+        password_input: String,
+        auth_feedback: String,
+        // Set while a LOGIN or REGISTER is unanswered; any auth reply clears it
+        auth_deadline: Option<Instant>,
+        // Register page state
+        page: Page,
+        reg_username: String,
+        reg_password: String,
+        // Birthdate parts for a structured chooser
+        reg_birth_year: i32,
+        reg_birth_month: u32, // 1-12
+        reg_birth_day: u32,   // 1..=days_in_month
+        // Delete account view
+        show_delete_view: bool,
+        // Settings view
+        show_settings_view: bool,
+        settings: Settings,
+        last_interaction: Instant,
+        // Ctrl+F search within the selected conversation
+        find: Option<FindBar>,
+        // Open "Import transcript" dialog for the selected conversation
+        import: Option<ImportDialog>,
+        // Open "Forward message" contact picker
+        forward: Option<ForwardPicker>,
+        // Chat whose deletion awaits confirmation
+        confirm_delete: Option<String>,
+        // Logout or quit held back because messages are still waiting to be sent
+        confirm_leave: Option<LeaveRequest>,
+        // Set once quitting was confirmed, so the close request isn't held back again
+        quit_confirmed: bool,
+        // Users whose messages are dropped and who are hidden from the sidebar, per account
+        blocked: BlockList,
+        // Chats that still get messages but no unread highlight or title count
+        muted: BTreeSet<String>,
+        // Space joined this session (None: the default one) and the sidebar's space name field
+        space: Option<String>,
+        space_input: String,
+        // False once the server turned out to predate spaces
+        server_spaces: bool,
+        // Account and chat open when the UI state was last saved; reselected when that account logs in
+        last_chat: Option<(String, String)>,
+        // Outgoing chat messages waiting for the rate limiter
+        send_limiter: SendLimiter,
+        // Most recently closed last; see RECENTLY_CLOSED_SECS
+        recently_closed: std::collections::VecDeque<ClosedConversation>,
+        del_username: String,
+        del_feedback: String,
+        // Change password form in the Account view
+        pw_old: String,
+        pw_new: String,
+        pw_confirm: String,
+        pw_feedback: String,
+    }
+
+    // UI pages
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum Page { Login, Register }
+
+    impl ChatApp {
+        fn new(
+            tx: Sender<UiToNet>,
+            rx: Receiver<NetToUi>,
+            rt: std::sync::Arc<tokio::runtime::Runtime>,
+            gpu_context_lost: Arc<AtomicBool>,
+        ) -> Self {
+            Self {
+                tx, rx, _rt: rt, gpu_context_lost,
+                conversations: HashMap::new(),
+                history_root: history_root(),
+                users: HashMap::new(), last_seen: HashMap::new(), selected_user: None, contact_filter: String::new(), peer_to_username: HashMap::new(),
+                incompatible_peers: HashMap::new(),
+                nearby: HashMap::new(),
+                link_quality: HashMap::new(),
+                rooms: Vec::new(),
+                typing_sent: None,
+                typing: HashMap::new(),
+                transfers: Vec::new(),
+                connection_status: String::from("Please login or register"),
+                reachability: Reachability::Unknown,
+                transient_toast: None, title_unread: None, last_error: None, logged_in: false,
+                diagnostics: DiagnosticsLog::default(),
+                show_diagnostics: false,
+                
+                username: String::new(), session_token: None, username_input: String::new(), password_input: String::new(),
+                auth_feedback: String::new(),
+                auth_deadline: None,
+                page: Page::Login,
+                reg_username: String::new(), reg_password: String::new(),
+                // Sensible defaults
+                reg_birth_year: 2000,
+                reg_birth_month: 1,
+                reg_birth_day: 1,
+                show_delete_view: false,
+                show_settings_view: false,
+                settings: Settings::default(),
+                last_interaction: Instant::now(),
+                find: None,
+                import: None,
+                forward: None,
+                confirm_delete: None,
+                confirm_leave: None,
+                quit_confirmed: false,
+                blocked: BlockList::new(),
+                muted: BTreeSet::new(),
+                space: None,
+                space_input: String::new(),
+                server_spaces: true,
+                last_chat: None,
+                send_limiter: SendLimiter::new(),
+                recently_closed: std::collections::VecDeque::new(),
+                del_username: String::new(),
+                del_feedback: String::new(),
+                pw_old: String::new(),
+                pw_new: String::new(),
+                pw_confirm: String::new(),
+                pw_feedback: String::new(),
+            }
+        }
+
+        // History file of a conversation for the logged-in user
+        fn history_path(&self, conversation: &str) -> Option<std::path::PathBuf> {
+            if self.username.is_empty() {
+                return None;
+            }
+            let dir = self.history_root.as_ref()?.join(history_file_stem(&self.username));
+            Some(dir.join(format!("{}.jsonl", history_file_stem(conversation))))
+        }
+
+        // Returns the conversation, loading its saved history the first time it is needed.
+        // Only the live range is read; the archive waits until it's expanded.
+        fn open_conversation(&mut self, name: &str) -> &mut Conversation {
+            if !self.conversations.contains_key(name) {
+                let mut conversation = Conversation::default();
+                if let Some(path) = self.history_path(name) {
+                    let (live, archived) = load_history(&path, self.cleared_prefix(name), self.settings.archive_cutoff());
+                    conversation.merge(live);
+                    conversation.unloaded_archive = archived;
+                }
+                self.conversations.insert(name.to_string(), conversation);
+            }
+            self.conversations.get_mut(name).expect("inserted above")
+        }
+
+        // Adds a message to a conversation and its history file
+        fn record_message(&mut self, name: &str, message: ChatMessage) -> &mut Conversation {
+            let path = self.history_path(name);
+            let conversation = self.open_conversation(name);
+            if let Some(path) = path {
+                append_history(&path, &message);
+            }
+            conversation.last_activity = message.timestamp;
+            conversation.push_in_sequence(message);
+            conversation
+        }
+
+        // PeerId to reach `name` at, while they're online
+        fn online_peer(&self, name: &str) -> Option<String> {
+            self.users.get(name).filter(|c| c.online).map(|c| c.peer_id.clone())
+        }
+
+        // Signals the contact that we're typing, at most once per TYPING_THROTTLE_SECS
+        fn send_typing(&mut self, name: &str) {
+            let Some(peer_id) = self.online_peer(name) else { return };
+            let throttled = self.typing_sent.as_ref().is_some_and(|(to, at)| {
+                to == name && at.elapsed() < Duration::from_secs(TYPING_THROTTLE_SECS)
+            });
+            if !throttled {
+                self.typing_sent = Some((name.to_string(), Instant::now()));
+                let _ = self.tx.send(UiToNet::Typing { peer_id });
+            }
+        }
+
+        // Tells the peer we've seen their messages, once per new incoming message
+        fn send_read_receipt(&mut self, name: &str) {
+            let Some(peer_id) = self.online_peer(name) else { return };
+            let Some(conversation) = self.conversations.get_mut(name) else { return };
+            let newest = conversation
+                .messages
+                .iter()
+                .rev()
+                .chain(conversation.archived.iter().rev())
+                .filter(|m| !m.from_self)
+                .find_map(|m| m.msg_id.clone());
+            if let Some(msg_id) = newest
+                && conversation.read_sent.as_ref() != Some(&msg_id)
+            {
+                conversation.read_sent = Some(msg_id.clone());
+                let _ = self.tx.send(UiToNet::Read { peer_id, msg_id });
+            }
+        }
+
+        // The outgoing message a delivery receipt refers to; searched newest first
+        fn sent_message(&mut self, name: &str, msg_id: &str) -> Option<&mut ChatMessage> {
+            let conversation = self.conversations.get_mut(name)?;
+            conversation
+                .messages
+                .iter_mut()
+                .rev()
+                .chain(conversation.archived.iter_mut().rev())
+                .find(|m| m.from_self && m.msg_id.as_deref() == Some(msg_id))
+        }
+
+        // Bytes at the start of the history file that belong to a cleared chat still open to undo
+        fn cleared_prefix(&self, name: &str) -> u64 {
+            self.recently_closed.iter().filter(|c| c.name == name).filter_map(|c| c.history_prefix).max().unwrap_or(0)
+        }
+
+        // Reads the archived range open_conversation left on disk
+        fn load_archive(&mut self, name: &str) {
+            if self.conversations.get(name).is_none_or(|c| c.unloaded_archive == 0) {
+                return;
+            }
+            let Some(path) = self.history_path(name) else { return };
+            let (all, _) = load_history(&path, self.cleared_prefix(name), None);
+            if let Some(conversation) = self.conversations.get_mut(name) {
+                conversation.merge(all);
+                conversation.unloaded_archive = 0;
+            }
+        }
+
+        // Rewrites a conversation's history file from memory
+        fn save_conversation(&mut self, name: &str) {
+            self.load_archive(name);
+            if let (Some(path), Some(conversation)) = (self.history_path(name), self.conversations.get(name)) {
+                let messages: Vec<ChatMessage> = conversation.archived.iter().chain(&conversation.messages).cloned().collect();
+                save_history(&path, &messages);
+                // A cleared chat's messages are no longer at the start of the file
+                for closed in self.recently_closed.iter_mut().filter(|c| c.name == name) {
+                    closed.history_prefix = None;
+                }
+            }
+        }
+
+        // Keeps a conversation that is being cleared or dropped so it can be reopened
+        fn close_conversation(&mut self, name: &str, conversation: Conversation, history_prefix: Option<u64>) {
+            if conversation.messages.is_empty() && conversation.archived.is_empty() && conversation.unloaded_archive == 0 {
+                return;
+            }
+            self.recently_closed.push_back(ClosedConversation {
+                name: name.to_string(),
+                conversation,
+                closed_at: Instant::now(),
+                history_prefix,
+            });
+            while self.recently_closed.len() > RECENTLY_CLOSED_MAX {
+                if let Some(oldest) = self.recently_closed.pop_front() {
+                    self.forget_closed(oldest);
+                }
+            }
+        }
+
+        // Past undo: a cleared chat's messages are trimmed from its history file
+        fn forget_closed(&mut self, closed: ClosedConversation) {
+            let (Some(bytes), Some(path)) = (closed.history_prefix, self.history_path(&closed.name)) else { return };
+            drop_history_prefix(&path, bytes);
+            for other in self.recently_closed.iter_mut().filter(|c| c.name == closed.name) {
+                other.history_prefix = other.history_prefix.map(|prefix| prefix.saturating_sub(bytes));
+            }
+        }
+
+        // Ends every undo window now, e.g. at logout or on quitting
+        fn forget_recently_closed(&mut self) {
+            while let Some(closed) = self.recently_closed.pop_front() {
+                self.forget_closed(closed);
+            }
+        }
+
+        // Empties a chat; its history file keeps the messages until Ctrl+Shift+T can no longer bring them back
+        fn clear_conversation(&mut self, name: &str) {
+            let history_prefix = self.history_path(name).and_then(|path| std::fs::metadata(path).ok()).map(|meta| meta.len());
+            let cleared = self.conversations.insert(name.to_string(), Conversation::default());
+            if let Some(conversation) = cleared {
+                self.close_conversation(name, conversation, history_prefix);
+            }
+            self.find = None;
+            self.toast("Chat cleared (Ctrl+Shift+T to undo)");
+        }
+
+        // Forgets a chat for good: its messages, its history file and any pending undo
+        fn delete_conversation(&mut self, name: &str) {
+            self.conversations.remove(name);
+            if self.muted.remove(name) {
+                save_name_list(MUTED_LIST, &self.username, &self.muted);
+            }
+            self.recently_closed.retain(|c| c.name != name);
+            if let Some(path) = self.history_path(name)
+                && let Err(e) = std::fs::remove_file(&path)
+                && e.kind() != io::ErrorKind::NotFound
+            {
+                tracing::warn!("Could not delete chat history {}: {}", path.display(), e);
+            }
+            if self.selected_user.as_deref() == Some(name) {
+                self.selected_user = None;
+                self.find = None;
+                self.import = None;
+            }
+            self.toast(format!("Deleted chat with {}", name));
+        }
+
+        // Entries shared by the chat header's "⋯" menu and the sidebar's right-click menu
+        fn conversation_menu(&mut self, ui: &mut egui::Ui, name: &str) {
+            if ui.button("Clear chat").clicked() {
+                self.clear_conversation(name);
+                ui.close_menu();
+            }
+            if ui.button("Delete chat…").clicked() {
+                self.confirm_delete = Some(name.to_string());
+                ui.close_menu();
+            }
+            let muted = self.muted.contains(name);
+            if ui.button(if muted { "Unmute" } else { "Mute" }).clicked() {
+                self.set_muted(name, !muted);
+                ui.close_menu();
+            }
+            if self.users.contains_key(name) && name != self.username && ui.button("Block").clicked() {
+                self.block_user(name);
+                ui.close_menu();
+            }
+        }
+
+        fn set_muted(&mut self, name: &str, muted: bool) {
+            let changed = if muted { self.muted.insert(name.to_string()) } else { self.muted.remove(name) };
+            if changed {
+                save_name_list(MUTED_LIST, &self.username, &self.muted);
+            }
+            self.toast(format!("{} {}", if muted { "Muted" } else { "Unmuted" }, name));
+        }
+
+        fn is_blocked(&self, name: &str) -> bool {
+            self.blocked.contains_key(&username_key(name))
+        }
+
+        // Hides the user and drops their messages from now on; undone from Settings
+        fn block_user(&mut self, name: &str) {
+            let sessions = self.users.get(name).map(|c| c.sessions.iter().chain([&c.peer_id]).filter(|pid| !pid.is_empty()).cloned().collect());
+            self.blocked.insert(username_key(name), sessions.unwrap_or_default());
+            self.save_block_list();
+            if self.selected_user.as_deref() == Some(name) {
+                self.selected_user = None;
+            }
+            self.typing.remove(name);
+            self.toast(format!("Blocked {}", name));
+        }
+
+        fn unblock_user(&mut self, name: &str) {
+            self.blocked.remove(&username_key(name));
+            self.save_block_list();
+            self.toast(format!("Unblocked {}", name));
+        }
+
+        fn save_block_list(&self) {
+            save_name_list(BLOCKED_LIST, &self.username, &self.blocked);
+            let _ = self.tx.send(UiToNet::Blocked(self.blocked.clone()));
+        }
+
+        // Deleting can't be undone, so it is confirmed first
+        fn show_delete_confirmation(&mut self, ctx: &egui::Context) {
+            let Some(name) = self.confirm_delete.clone() else { return };
+            let mut open = true;
+            let (mut confirmed, mut cancelled) = (false, false);
+            egui::Window::new("Delete chat?")
+                .collapsible(false)
+                .resizable(false)
+                .anchor(egui::Align2::CENTER_CENTER, egui::vec2(0.0, 0.0))
+                .open(&mut open)
+                .show(ctx, |ui| {
+                    ui.label(format!("Delete all messages with {} from this device? This can't be undone.", name));
+                    ui.add_space(8.0);
+                    ui.horizontal(|ui| {
+                        confirmed = ui.add_sized([BUTTON_WIDTH, UI_HEIGHT], egui::Button::new("Delete")).clicked();
+                        cancelled = ui.add_sized([BUTTON_WIDTH, UI_HEIGHT], egui::Button::new("Cancel")).clicked();
+                    });
+                });
+            if confirmed {
+                self.delete_conversation(&name);
+            }
+            if confirmed || cancelled || !open {
+                self.confirm_delete = None;
+            }
+        }
+
+        // A LOGIN or REGISTER was accepted; the networking task queries the user list itself
+        fn start_session(&mut self, username: String, token: String) {
+            self.logged_in = true;
+            self.session_token = Some(token);
+            self.username = username;
+            self.toast(format!("Logged in as {}", self.username));
+            self.blocked = load_block_list(&self.username);
+            let _ = self.tx.send(UiToNet::Blocked(self.blocked.clone()));
+            self.muted = load_name_list(MUTED_LIST, &self.username);
+            for (chat, draft) in load_drafts(&self.username) {
+                self.open_conversation(&chat).draft = draft;
+            }
+            if let Some((account, chat)) = &self.last_chat
+                && *account == self.username
+                && self.selected_user.is_none()
+            {
+                self.selected_user = Some(chat.clone());
+            }
+            self.last_interaction = Instant::now();
+            self.auth_feedback.clear();
+        }
+
+        // Outgoing messages that would be lost by logging out now: those still behind the rate
+        // limiter and those waiting for a connection. Ones handed to the server's offline queue
+        // are safe there.
+        fn unsent_count(&self) -> usize {
+            let waiting = self
+                .conversations
+                .values()
+                .flat_map(|c| c.messages.iter())
+                .filter(|m| m.from_self && m.queued && !m.delivered && !m.failed)
+                .count();
+            self.send_limiter.queued() + waiting
+        }
+
+        // Holds back a logout or quit while messages are unsent; returns true when the user
+        // confirmed a logout, which has then happened
+        fn show_leave_confirmation(&mut self, ctx: &egui::Context) -> bool {
+            let Some(request) = self.confirm_leave else { return false };
+            let unsent = self.unsent_count();
+            let (title, action) = match request {
+                LeaveRequest::Logout => ("Log out?", "Log out"),
+                LeaveRequest::Quit => ("Quit?", "Quit"),
+            };
+            let mut open = true;
+            let (mut confirmed, mut cancelled) = (false, false);
+            egui::Window::new(title)
+                .collapsible(false)
+                .resizable(false)
+                .anchor(egui::Align2::CENTER_CENTER, egui::vec2(0.0, 0.0))
+                .open(&mut open)
+                .show(ctx, |ui| {
+                    ui.label(format!("{} message(s) haven't been sent yet and will be lost.", unsent));
+                    ui.add_space(8.0);
+                    ui.horizontal(|ui| {
+                        confirmed = ui.add_sized([BUTTON_WIDTH, UI_HEIGHT], egui::Button::new(action)).clicked();
+                        cancelled = ui.add_sized([BUTTON_WIDTH, UI_HEIGHT], egui::Button::new("Cancel")).clicked();
+                    });
+                });
+            if confirmed || cancelled || !open {
+                self.confirm_leave = None;
+            }
+            // Also goes ahead once everything went out while the dialog was open
+            if !(confirmed || unsent == 0) || cancelled || !open {
+                return false;
+            }
+            self.confirm_leave = None;
+            match request {
+                LeaveRequest::Logout => {
+                    self.logout(String::new());
+                    true
+                }
+                LeaveRequest::Quit => {
+                    self.quit_confirmed = true;
+                    ctx.send_viewport_cmd(egui::ViewportCommand::Close);
+                    false
+                }
+            }
+        }
+
+        // Drops closed conversations whose undo window has passed; this is where they are finally discarded
+        fn purge_recently_closed(&mut self) {
+            let window = Duration::from_secs(RECENTLY_CLOSED_SECS);
+            while self.recently_closed.front().is_some_and(|c| c.closed_at.elapsed() >= window) {
+                if let Some(closed) = self.recently_closed.pop_front() {
+                    self.forget_closed(closed);
+                }
+            }
+        }
+
+        fn reopen_last_closed(&mut self) {
+            let Some(closed) = self.recently_closed.pop_back() else {
+                self.toast("No recently closed chats");
+                return;
+            };
+            let ClosedConversation { name, conversation, history_prefix, .. } = closed;
+            let Conversation { messages, archived, unloaded_archive, .. } = conversation;
+            let reopened = self.open_conversation(&name);
+            reopened.merge(archived.into_iter().chain(messages).collect());
+            reopened.unloaded_archive += unloaded_archive;
+            // A cleared chat's file still holds its messages, unless it was rewritten since
+            if history_prefix.is_none() {
+                self.save_conversation(&name);
+            }
+            if self.users.contains_key(&name) {
+                self.selected_user = Some(name.clone());
+                self.find = None;
+                self.import = None;
+            }
+            self.toast(format!("Reopened chat with {}", name));
+        }
+
+        // Sends a failed message again under its original id, so the result lands on the same bubble.
+        // An offline recipient gets it through the server's queue like any other message.
+        fn retry_message(&mut self, name: &str, msg_id: &str) {
+            let Some(peer_id) = self.users.get(name).map(|c| c.peer_id.clone()).filter(|p| !p.is_empty()) else {
+                self.toast(format!("{} hasn't been seen yet; try again later", name));
+                return;
+            };
+            let username = self.username.clone();
+            let Some(msg) = self.sent_message(name, msg_id) else { return };
+            msg.failed = false;
+            let resend = Some((msg_id.to_string(), msg.timestamp));
+            let (msg, forwarded) = (msg.text.clone(), msg.forwarded);
+            self.send_limiter.enqueue(UiToNet::Write { peer_id, from_username: username, to_username: name.to_string(), msg, forwarded, resend });
+        }
+
+        // Recent events, newest at the bottom, with a button to copy them all for a bug report
+        fn diagnostics_panel(&mut self, ctx: &egui::Context) {
+            egui::TopBottomPanel::bottom("diagnostics_panel").resizable(true).default_height(160.0).show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    ui.strong("Diagnostics");
+                    ui.label(egui::RichText::new(format!("last {} events", DIAGNOSTICS_MAX)).small().weak());
+                    ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
+                        if ui.small_button("✖").on_hover_text("Hide (F12)").clicked() {
+                            self.show_diagnostics = false;
+                        }
+                        if ui.small_button("Clear").clicked() {
+                            self.diagnostics.entries.clear();
+                        }
+                        if ui.add_enabled(!self.diagnostics.entries.is_empty(), egui::Button::new("Copy").small()).clicked() {
+                            let text = self.diagnostics.to_text();
+                            ui.output_mut(|o| o.copied_text = text);
+                            self.toast("Diagnostics copied");
+                        }
+                    });
+                });
+                ui.separator();
+                egui::ScrollArea::vertical().auto_shrink([false, false]).stick_to_bottom(true).show(ui, |ui| {
+                    if self.diagnostics.entries.is_empty() {
+                        ui.label(egui::RichText::new("Nothing yet. Notices, errors and connection changes show up here.").small().weak());
+                    }
+                    for entry in &self.diagnostics.entries {
+                        let line = egui::RichText::new(entry.line()).monospace().small();
+                        ui.label(if entry.error { line.color(ui.visuals().error_fg_color) } else { line });
+                    }
+                });
+            });
+        }
+
+        fn toast(&mut self, text: impl Into<String>) {
+            self.transient_toast = Some((text.into(), Instant::now()));
+        }
+
+        // The user list is replaced by the new space's once the server answers
+        fn join_space(&mut self, space: Option<String>) {
+            if let Some(name) = &space
+                && !valid_space_name(name)
+            {
+                self.last_error = Some("Space names are 1-32 letters, digits, '-', '_' or '.'".to_string());
+                return;
+            }
+            if space.is_some() && !self.server_spaces {
+                self.last_error = Some("This server is too old for spaces".to_string());
+                return;
+            }
+            self.space_input.clear();
+            self.space = space.clone();
+            let _ = self.tx.send(UiToNet::JoinSpace { space });
+        }
+
+        // Ends the session and returns to the login page, showing `feedback` there
+        fn logout(&mut self, feedback: String) {
+            self.forget_recently_closed();
+            if !self.username.is_empty()
+                && let Some(token) = self.session_token.take()
+            {
+                let _ = self.tx.send(UiToNet::Logout {
+                    username: self.username.clone(),
+                    token,
+                });
+            }
+            if self.logged_in {
+                save_drafts(&self.username, &self.conversations);
+            }
+            self.logged_in = false;
+            self.username.clear();
+            self.username_input.clear();
+            self.password_input.clear();
+            self.selected_user = None;
+            self.contact_filter.clear();
+            self.users.clear();
+            self.last_seen.clear();
+            self.peer_to_username.clear();
+            self.incompatible_peers.clear();
+            self.link_quality.clear();
+            self.rooms.clear();
+            self.conversations.clear();
+            self.connection_status = "Logged out".to_string();
+            self.transient_toast = None;
+            self.last_error = None;
+            self.page = Page::Login;
+            self.auth_feedback = feedback;
+            self.show_delete_view = false;
+            self.show_settings_view = false;
+            self.find = None;
+            self.import = None;
+            self.forward = None;
+            self.confirm_delete = None;
+            self.confirm_leave = None;
+            self.blocked.clear();
+            let _ = self.tx.send(UiToNet::Blocked(BlockList::new()));
+            self.muted.clear();
+            self.space = None;
+            self.space_input.clear();
+            self.send_limiter.clear();
+            self.recently_closed.clear();
+        }
+
+        // Tracks UI interaction and logs out after the configured idle period.
+        // Shows a countdown warning for the last IDLE_WARNING_SECS so an idle user can stay in.
+        // Returns true when the session was ended this frame.
+        fn check_inactivity(&mut self, ctx: &egui::Context) -> bool {
+            let active = ctx.input(|i| !i.events.is_empty() || i.pointer.is_moving() || i.pointer.any_down());
+            if active {
+                self.last_interaction = Instant::now();
+            }
+            if self.settings.auto_logout_minutes == 0 {
+                return false;
+            }
+            let limit = Duration::from_secs(u64::from(self.settings.auto_logout_minutes) * 60);
+            let idle = self.last_interaction.elapsed();
+            if idle >= limit {
+                tracing::info!("Auto-logout after {:?} of inactivity", idle);
+                self.logout("Logged out due to inactivity".to_string());
+                return true;
+            }
+            let remaining = limit - idle;
+            if remaining <= Duration::from_secs(IDLE_WARNING_SECS) {
+                egui::Window::new("Are you still there?")
+                    .collapsible(false)
+                    .resizable(false)
+                    .anchor(egui::Align2::CENTER_CENTER, egui::vec2(0.0, 0.0))
+                    .show(ctx, |ui| {
+                        ui.label(format!("You will be logged out in {} seconds due to inactivity.", remaining.as_secs() + 1));
+                        ui.add_space(8.0);
+                        if ui.add_sized([BUTTON_WIDTH, UI_HEIGHT], egui::Button::new("Stay logged in")).clicked() {
+                            self.last_interaction = Instant::now();
+                        }
+                    });
+            }
+            false
+        }
+
+        // Applies what the last run saved; called once, before the first frame
+        fn restore_ui_state(&mut self, storage: Option<&dyn eframe::Storage>) {
+            let Some(state) = storage.and_then(|storage| eframe::get_value::<UiState>(storage, UI_STATE_KEY)) else { return };
+            self.settings.mode = state.mode;
+            self.settings.theme = state.variant;
+            self.last_chat = state.last_chat;
+        }
+
+        // Called after the window had to be recreated. Only UI-side resources are reset;
+        // conversations, session state and the networking task are left untouched.
+        fn on_context_lost(&mut self) {
+            self.gpu_context_lost.store(true, Ordering::SeqCst);
+            // The new window starts with the plain title
+            self.title_unread = None;
+        }
+
+        // Re-applies UI resources on the (possibly new) egui context. The app caches no
+        // `TextureHandle`s yet; once it does, they belong to the old renderer and need rebuilding here.
+        fn recover_context(&mut self, ctx: &egui::Context) {
+            tracing::warn!("Recovering UI after GPU context loss");
+            configure_theme(ctx, self.settings.mode, self.settings.theme);
+            self.toast("Display was reset; UI recovered");
+            ctx.request_repaint();
+        }
+    }
+
+    impl eframe::App for ChatApp {
+        // eframe calls this periodically and on exit; it saves the window geometry itself
+        fn save(&mut self, storage: &mut dyn eframe::Storage) {
+            if self.logged_in
+                && let Some(chat) = &self.selected_user
+            {
+                self.last_chat = Some((self.username.clone(), chat.clone()));
+            }
+            if self.logged_in {
+                save_drafts(&self.username, &self.conversations);
+            }
+            let state = UiState { mode: self.settings.mode, variant: self.settings.theme, last_chat: self.last_chat.clone() };
+            eframe::set_value(storage, UI_STATE_KEY, &state);
+        }
+
+        fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+            if self.gpu_context_lost.swap(false, Ordering::SeqCst) {
+                self.recover_context(ctx);
+            }
+            // Ensure regular repaint so incoming messages are processed promptly
+            ctx.request_repaint_after(std::time::Duration::from_millis(16));
+            // Drain messages from networking, at most MAX_EVENTS_PER_FRAME of them
+            for handled in 0.. {
+                if handled == MAX_EVENTS_PER_FRAME {
+                    ctx.request_repaint();
+                    break;
+                }
+                let Some(msg) = self.rx.try_recv() else { break };
+                match msg {
+                    NetToUi::Discovered(list) => {
+                        self.connection_status = format!("Connected to server · {} peer(s) discovered", list.len());
+                        ctx.request_repaint();
+                    }
+                    NetToUi::Nearby { peer, version, agent } => {
+                        self.nearby.insert(peer, (version, agent));
+                        ctx.request_repaint();
+                    }
+                    NetToUi::NearbyGone(peer) => {
+                        self.nearby.remove(&peer);
+                        ctx.request_repaint();
+                    }
+                    NetToUi::Rtt { peer, millis } => {
+                        self.link_quality.insert(peer, LinkQuality::Rtt(millis));
+                    }
+                    NetToUi::LinkDegraded(peer) => {
+                        let name = self.peer_to_username.get(&peer).cloned().unwrap_or_else(|| peer.clone());
+                        self.diagnostics.push(false, format!("Pings to {} are timing out", name));
+                        self.link_quality.insert(peer, LinkQuality::Degraded);
+                        ctx.request_repaint();
                     }
                     NetToUi::Connected(pid) => {
                         // Do not expose peer IDs. Prefer username mapping if available.
@@ -406,102 +2550,351 @@ use eframe::egui;
                                 // Fallback: try reverse lookup from users map
                                 self.users
                                     .iter()
-                                    .find_map(|(uname, upid)| if upid == &pid { Some(uname.clone()) } else { None })
+                                    .find_map(|(uname, c)| if c.peer_id == pid { Some(uname.clone()) } else { None })
                             });
-                        self.status = match label {
-                            Some(name) => format!("Connected to {}", name),
-                            None => "Connected".to_string(),
+                        // Unnamed peers are the server, reported through NetToUi::Connection instead
+                        if let Some(name) = label {
+                            self.toast(format!("Connected to {}", name));
+                        }
+                        ctx.request_repaint();
+                    }
+                    NetToUi::Disconnected { peer: pid, reason, server } => {
+                        self.link_quality.remove(&pid);
+                        // Do not expose peer IDs.
+                        let label = self
+                            .peer_to_username
+                            .get(&pid)
+                            .cloned()
+                            .or_else(|| {
+                                self.users
+                                    .iter()
+                                    .find_map(|(uname, c)| if c.peer_id == pid { Some(uname.clone()) } else { None })
+                            });
+                        if server {
+                            self.toast(format!("Lost the server connection ({})", reason.describe()));
+                        } else if let Some(name) = label {
+                            self.toast(format!("Disconnected from {} ({})", name, reason.describe()));
+                        }
+                        ctx.request_repaint();
+                    }
+                    NetToUi::ChatMessage { peer, direction, text, forwarded, timestamp, msg_id, seq } => {
+                        let from_self = matches!(direction, MessageDirection::Outgoing);
+                        if !from_self && self.is_blocked(&peer) {
+                            tracing::debug!("Dropped a message from blocked user {}", peer);
+                            continue;
+                        }
+                        let unread = !(from_self || self.selected_user.as_ref() == Some(&peer));
+                        if !from_self {
+                            self.typing.remove(&peer);
+                        }
+                        let message = ChatMessage {
+                            from_self,
+                            text,
+                            timestamp,
+                            forwarded,
+                            author: None,
+                            msg_id,
+                            seq,
+                            delivered: false,
+                            failed: false,
+                            queued: false,
+                            read: false,
                         };
+                        self.record_message(&peer, message).unread = unread;
                         ctx.request_repaint();
                     }
-                    NetToUi::Disconnected(pid) => {
-                        // Do not expose peer IDs.
-                        let label = self
-                            .peer_to_username
-                            .get(&pid)
-                            .cloned()
-                            .or_else(|| {
-                                self.users
-                                    .iter()
-                                    .find_map(|(uname, upid)| if upid == &pid { Some(uname.clone()) } else { None })
-                            });
-                        self.status = match label {
-                            Some(name) => format!("Disconnected from {}", name),
-                            None => "Disconnected".to_string(),
-                        };
+                    NetToUi::Delivered { peer, msg_id } => {
+                        if let Some(msg) = self.sent_message(&peer, &msg_id) {
+                            msg.delivered = true;
+                            msg.failed = false;
+                            ctx.request_repaint();
+                        }
+                    }
+                    NetToUi::DeliveryFailed { peer, msg_id } => {
+                        if let Some(msg) = self.sent_message(&peer, &msg_id) {
+                            msg.failed = !msg.delivered;
+                            ctx.request_repaint();
+                        }
+                    }
+                    NetToUi::Queued { peer, msg_id, queued } => {
+                        if let Some(msg) = self.sent_message(&peer, &msg_id) {
+                            msg.queued = queued;
+                            ctx.request_repaint();
+                        }
+                    }
+                    NetToUi::FileTransfer { id, peer, filename, size, outgoing } => {
+                        self.transfers.push(Transfer { id, peer, filename, size, done: 0, outgoing, state: TransferState::Active });
+                        ctx.request_repaint();
+                    }
+                    NetToUi::FileProgress { id, done } => {
+                        if let Some(transfer) = self.transfers.iter_mut().find(|t| t.id == id) {
+                            transfer.done = done;
+                            ctx.request_repaint();
+                        }
+                    }
+                    NetToUi::FileDone { id, saved_to } => {
+                        if let Some(transfer) = self.transfers.iter_mut().find(|t| t.id == id) {
+                            transfer.done = transfer.size;
+                            transfer.state = TransferState::Done(saved_to);
+                            ctx.request_repaint();
+                        }
+                    }
+                    NetToUi::FileFailed { id, reason } => {
+                        if let Some(transfer) = self.transfers.iter_mut().find(|t| t.id == id) {
+                            transfer.state = TransferState::Failed(reason);
+                            ctx.request_repaint();
+                        }
+                    }
+                    NetToUi::Typing { peer } if self.is_blocked(&peer) => {}
+                    NetToUi::Typing { peer } => {
+                        self.typing.insert(peer, Instant::now() + Duration::from_secs(TYPING_SHOW_SECS));
                         ctx.request_repaint();
                     }
-                    NetToUi::ChatMessage { peer, direction, text } => {
-                        let entry = self.conversations.entry(peer.clone()).or_default();
-                        let from_self = matches!(direction, MessageDirection::Outgoing);
-                        entry.messages.push(ChatMessage { from_self, text });
-                        entry.last_activity = SystemTime::now();
-                        if from_self || self.selected_user.as_ref() == Some(&peer) {
-                            entry.unread = false;
-                        } else {
-                            entry.unread = true;
+                    NetToUi::Read { peer, up_to_id } => {
+                        if let Some(conversation) = self.conversations.get_mut(&peer) {
+                            let sent: Vec<&mut ChatMessage> =
+                                conversation.archived.iter_mut().chain(conversation.messages.iter_mut()).filter(|m| m.from_self).collect();
+                            // Everything we sent before the acknowledged message has been seen too
+                            if let Some(last) = sent.iter().position(|m| m.msg_id.as_deref() == Some(up_to_id.as_str())) {
+                                for msg in sent.into_iter().take(last + 1) {
+                                    msg.read = true;
+                                    msg.delivered = true;
+                                    msg.failed = false;
+                                }
+                                ctx.request_repaint();
+                            }
+                        }
+                    }
+                    NetToUi::Connection(s) => {
+                        if s != self.connection_status {
+                            self.diagnostics.push(false, &s);
+                        }
+                        self.connection_status = s;
+                    }
+                    NetToUi::Reachability(reachability) => {
+                        if reachability != self.reachability {
+                            self.diagnostics.push(false, format!("Reachability: {}", reachability.label()));
+                        }
+                        self.reachability = reachability;
+                    }
+                    NetToUi::Info(s) => {
+                        self.diagnostics.push(false, &s);
+                        self.toast(s);
+                    }
+                    NetToUi::Error(e) => {
+                        self.diagnostics.push(true, &e);
+                        self.last_error = Some(e);
+                    }
+                    NetToUi::AuthResult(result) => {
+                        self.auth_deadline = None;
+                        // Only a session token means a login; a plain OK acknowledges e.g. a logout
+                        if let Ok(Some(token)) = result {
+                            let username = self.username_input.trim().to_string();
+                            self.start_session(username, token);
+                        } else if let Err(failure) = result {
+                            self.auth_feedback = auth_failure_text(&failure);
                         }
                         ctx.request_repaint();
                     }
-                    NetToUi::Info(s) => self.status = s,
-                    NetToUi::Error(e) => self.status = format!("Error: {}", e),
-                    NetToUi::AuthResult { ok, message } => {
-                        if ok {
-                            self.logged_in = true;
-                            self.username = if self.page == Page::Register {
-                                self.reg_username.clone()
-                            } else {
-                                self.username_input.clone()
-                            };
-                            self.status = format!("Logged in as {}", self.username);
-                            self.auth_feedback.clear();
-                            // Networking task will query user list via auth protocol
-                        } else {
-                            self.auth_feedback = message;
+                    NetToUi::Registered(result) => {
+                        self.auth_deadline = None;
+                        match result {
+                            Ok(token) => {
+                                // The login page is what comes back after logout, filled in for the new account
+                                let username = self.reg_username.trim().to_string();
+                                self.username_input = username.clone();
+                                self.reg_password.clear();
+                                self.page = Page::Login;
+                                self.start_session(username, token);
+                            }
+                            // Stays on the register page, so the form can be corrected
+                            Err(failure) => self.auth_feedback = auth_failure_text(&failure),
                         }
                         ctx.request_repaint();
                     }
-                    NetToUi::Users(map) => {
-                        // Remove our own username from the directory so we can't select ourselves
-                        let mut map = map;
-                        if !self.username.is_empty() {
-                            map.remove(&self.username);
+                    NetToUi::SessionExpired => {
+                        if self.logged_in {
+                            self.session_token = None;
+                            self.logout("Session expired, please log in again".to_string());
+                        }
+                    }
+                    NetToUi::Users(map, last_seen) => {
+                        self.last_seen = last_seen;
+                        // Names match ignoring case, so a login typed as "ALICE" is the account "Alice"
+                        if !map.contains_key(&self.username)
+                            && let Some(display) = map.keys().find(|n| username_key(n) == username_key(&self.username))
+                        {
+                            self.username = display.clone();
+                        }
+                        // Patch the roster in place so unchanged entries keep their conversation,
+                        // selection and scroll state across refreshes. Our own entry is the saved
+                        // messages chat, pinned above the others.
+                        let diff = diff_roster(&self.users, map);
+                        let changed = !diff.removed.is_empty() || !diff.changed.is_empty();
+                        let mut blocked_sessions_added = false;
+                        for name in diff.removed {
+                            if let Some(contact) = self.users.remove(&name) {
+                                for pid in contact.sessions.iter().chain([&contact.peer_id]) {
+                                    self.peer_to_username.remove(pid);
+                                }
+                            }
+                            if let Some(conversation) = self.conversations.remove(&name) {
+                                self.close_conversation(&name, conversation, None);
+                            }
+                            if self.selected_user.as_ref() == Some(&name) {
+                                self.selected_user = None;
+                            }
+                        }
+                        for (name, contact) in diff.changed {
+                            match self.users.get(&name) {
+                                // Same user, other sessions: only the peer mapping changes
+                                Some(existing) => {
+                                    for pid in existing.sessions.iter().chain([&existing.peer_id]) {
+                                        self.peer_to_username.remove(pid);
+                                    }
+                                }
+                                None => {
+                                    self.open_conversation(&name);
+                                }
+                            }
+                            for pid in contact.sessions.iter().chain([&contact.peer_id]).filter(|pid| !pid.is_empty()) {
+                                self.peer_to_username.insert(pid.clone(), name.clone());
+                            }
+                            // Sessions of a blocked user stay blocked even if they come back under another name
+                            if let Some(sessions) = self.blocked.get_mut(&username_key(&name)) {
+                                let before = sessions.len();
+                                sessions.extend(contact.sessions.iter().chain([&contact.peer_id]).filter(|pid| !pid.is_empty()).cloned());
+                                blocked_sessions_added |= sessions.len() != before;
+                            }
+                            self.users.insert(name, contact);
+                        }
+                        if blocked_sessions_added {
+                            self.save_block_list();
                         }
-                        // Rebuild forward and reverse maps
-                        self.peer_to_username.clear();
-                        for (uname, pid) in &map { self.peer_to_username.insert(pid.clone(), uname.clone()); }
-                        self.conversations.retain(|user, _| map.contains_key(user));
-                        self.users = map;
-                        for name in self.users.keys() {
-                            self.conversations.entry(name.clone()).or_default();
+                        if changed {
+                            ctx.request_repaint();
+                        }
+                    }
+                    NetToUi::ServerVersion { version, spaces } => {
+                        tracing::info!("Server runs {}", version);
+                        self.server_spaces = spaces;
+                    }
+                    NetToUi::PeerCompatibility { peer, compatible, version } => {
+                        if compatible {
+                            self.incompatible_peers.remove(&peer);
+                        } else {
+                            self.incompatible_peers.insert(peer, version);
                         }
-                        // reset selected if missing
-                        if let Some(name) = self.selected_user.clone() {
-                            if !self.users.contains_key(&name) { self.selected_user = None; }
+                        ctx.request_repaint();
+                    }
+                    NetToUi::RoomJoined(room) => {
+                        if !self.rooms.contains(&room) {
+                            self.open_conversation(&room_key(&room));
+                            self.rooms.push(room);
                         }
+                    }
+                    NetToUi::RoomMessage { room, from, direction, text, timestamp } => {
+                        let key = room_key(&room);
+                        let from_self = matches!(direction, MessageDirection::Outgoing);
+                        let unread = !(from_self || self.selected_user.as_ref() == Some(&key));
+                        let message = ChatMessage {
+                            from_self,
+                            text,
+                            timestamp,
+                            forwarded: false,
+                            author: Some(from),
+                            msg_id: None,
+                            seq: None,
+                            delivered: false,
+                            failed: false,
+                            queued: false,
+                            read: false,
+                        };
+                        self.record_message(&key, message).unread = unread;
                         ctx.request_repaint();
                     }
-                    NetToUi::DeleteResult { ok, message } => {
-                        if ok {
+                    NetToUi::Presence { username, peer, online } => {
+                        // Show newly announced users right away; the next LIST confirms or drops them
+                        if online && username != self.username && !self.users.get(&username).is_some_and(|c| c.online) {
+                            self.open_conversation(&username);
+                            self.peer_to_username.insert(peer.clone(), username.clone());
+                            self.users.insert(username, Contact { peer_id: peer.clone(), online: true, sessions: vec![peer] });
+                            ctx.request_repaint();
+                        } else if !online {
+                            self.toast(format!("{} went offline", username));
+                        }
+                    }
+                    NetToUi::DeleteResult(result) => {
+                        if let Err(failure) = result {
+                            self.del_feedback = auth_failure_text(&failure);
+                        } else {
+                            // The account is gone, and so is its local history
+                            if let Some(dir) = self.history_path("").as_deref().and_then(std::path::Path::parent)
+                                && let Err(e) = std::fs::remove_dir_all(dir)
+                                && e.kind() != io::ErrorKind::NotFound
+                            {
+                                tracing::warn!("Could not remove chat history {}: {}", dir.display(), e);
+                            }
+                            if let Some(path) = drafts_path(&self.username)
+                                && let Err(e) = std::fs::remove_file(&path)
+                                && e.kind() != io::ErrorKind::NotFound
+                            {
+                                tracing::warn!("Could not remove drafts {}: {}", path.display(), e);
+                            }
+                            if let Some(path) = name_list_path(MUTED_LIST, &self.username)
+                                && let Err(e) = std::fs::remove_file(&path)
+                                && e.kind() != io::ErrorKind::NotFound
+                            {
+                                tracing::warn!("Could not remove muted chats {}: {}", path.display(), e);
+                            }
                             // Reset to login
                             self.logged_in = false;
                             self.username.clear();
+                            self.session_token = None;
                             self.selected_user = None;
                             self.users.clear();
+                            self.last_seen.clear();
                             self.peer_to_username.clear();
-                            self.message_input.clear();
                             self.conversations.clear();
                             self.show_delete_view = false;
                             self.page = Page::Login;
                             self.auth_feedback = "Account deleted".to_string();
-                        } else {
-                            self.del_feedback = message;
+                        }
+                        ctx.request_repaint();
+                    }
+                    NetToUi::PasswordChanged(result) => {
+                        self.pw_feedback = match &result {
+                            Ok(()) => "Password changed".to_string(),
+                            Err(failure) => auth_failure_text(failure),
+                        };
+                        if result.is_ok() {
+                            self.pw_old.clear();
+                            self.pw_new.clear();
+                            self.pw_confirm.clear();
                         }
                         ctx.request_repaint();
                     }
                 }
             }
 
+            // Release rate-limited outgoing messages
+            self.send_limiter.pump(&self.tx, &self.settings);
+            self.purge_recently_closed();
+            // A dial to an unreachable server can fail silently, so don't wait on it forever
+            if self.auth_deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                self.auth_deadline = None;
+                self.auth_feedback = "Server not responding — check connection".to_string();
+                self.diagnostics.push(true, format!("No answer to the login within {}s", AUTH_TIMEOUT_SECS));
+            }
+
+            // F12 toggles the diagnostics panel, on any page
+            if ctx.input_mut(|i| i.consume_key(egui::Modifiers::NONE, egui::Key::F12)) {
+                self.show_diagnostics = !self.show_diagnostics;
+            }
+            if self.show_diagnostics {
+                self.diagnostics_panel(ctx);
+            }
+
             // Login/Register gate UI
             if !self.logged_in {
                 egui::CentralPanel::default().show(ctx, |ui| {
@@ -542,6 +2935,7 @@ use eframe::egui;
                                             } else {
                                                 let _ = self.tx.send(UiToNet::Login { username: self.username_input.trim().to_string(), password: self.password_input.clone() });
                                                 self.auth_feedback = "Logging in...".to_string();
+                                                self.auth_deadline = Some(Instant::now() + Duration::from_secs(AUTH_TIMEOUT_SECS));
                                             }
                                         }
                                         if register {
@@ -551,7 +2945,11 @@ use eframe::egui;
                                     });
                                 });
                                 ui.add_space(6.0);
-                                if !self.auth_feedback.is_empty() { ui.colored_label(egui::Color32::YELLOW, &self.auth_feedback); }
+                                if !self.auth_feedback.is_empty() { ui.colored_label(ui.visuals().warn_fg_color, &self.auth_feedback); }
+                                ui.add_space(12.0);
+                                if ui.small_button("Diagnostics").on_hover_text("Recent connection events (F12)").clicked() {
+                                    self.show_diagnostics = !self.show_diagnostics;
+                                }
                             }
                             Page::Register => {
                                 ui.heading("Register");
@@ -579,15 +2977,16 @@ use eframe::egui;
                                         let total = combo_w * 3.0 + 2.0 * ui.spacing().item_spacing.x;
                                         let left_pad = (ui.available_width() - total).max(0.0) / 2.0;
                                         ui.add_space(left_pad);
-                                        // Year selector (1900..=2025)
+                                        // Year selector (1900 to this year)
+                                        let this_year = server::dates::today().0;
                                         egui::ComboBox::from_id_source("year_combo").width(combo_w)
                                             .selected_text(format!("Year: {}", self.reg_birth_year))
                                             .show_ui(ui, |ui| {
-                                                for y in (1900..=2025).rev() {
+                                                for y in (1900..=this_year).rev() {
                                                     if ui.selectable_label(self.reg_birth_year == y, y.to_string()).clicked() {
                                                         self.reg_birth_year = y;
                                                         // Clamp day when year changes (for Feb/leap year)
-                                                        let max_day = days_in_month(self.reg_birth_year, self.reg_birth_month);
+                                                        let max_day = server::dates::days_in_month(self.reg_birth_year, self.reg_birth_month);
                                                         if self.reg_birth_day > max_day { self.reg_birth_day = max_day; }
                                                     }
                                                 }
@@ -608,14 +3007,14 @@ use eframe::egui;
                                                     if ui.selectable_label(self.reg_birth_month == m, label).clicked() {
                                                         self.reg_birth_month = m;
                                                         // Clamp day when month changes
-                                                        let max_day = days_in_month(self.reg_birth_year, self.reg_birth_month);
+                                                        let max_day = server::dates::days_in_month(self.reg_birth_year, self.reg_birth_month);
                                                         if self.reg_birth_day > max_day { self.reg_birth_day = max_day; }
                                                     }
                                                 }
                                             });
 
                                         // Day selector based on month/year
-                                        let max_day = days_in_month(self.reg_birth_year, self.reg_birth_month);
+                                        let max_day = server::dates::days_in_month(self.reg_birth_year, self.reg_birth_month);
                                         egui::ComboBox::from_id_source("day_combo").width(combo_w)
                                             .selected_text(format!("Day: {}", self.reg_birth_day))
                                             .show_ui(ui, |ui| {
@@ -648,6 +3047,11 @@ use eframe::egui;
                                         );
                                         if self.reg_username.trim().is_empty() || self.reg_password.is_empty() {
                                             self.auth_feedback = "Fill all fields".to_string();
+                                        } else if let Err(reason) = server::validate_username(self.reg_username.trim()) {
+                                            self.auth_feedback = format!("Invalid username: {}", reason);
+                                        } else if let Err(reason) = server::validate_password(&self.reg_password) {
+                                            // Same rules as the server, so this never round-trips
+                                            self.auth_feedback = format!("Weak password: {}", reason);
                                         } else {
                                             let _ = self.tx.send(UiToNet::Register {
                                                 username: self.reg_username.trim().to_string(),
@@ -655,40 +3059,89 @@ use eframe::egui;
                                                 birthdate,
                                             });
                                             self.auth_feedback = "Registering...".to_string();
+                                            self.auth_deadline = Some(Instant::now() + Duration::from_secs(AUTH_TIMEOUT_SECS));
                                         }
                                         }
                                         if back { self.page = Page::Login; }
                                     });
                                 });
                                 ui.add_space(6.0);
-                                if !self.auth_feedback.is_empty() { ui.colored_label(egui::Color32::YELLOW, &self.auth_feedback); }
+                                if !self.auth_feedback.is_empty() { ui.colored_label(ui.visuals().warn_fg_color, &self.auth_feedback); }
                             }
                         }
+                        // Startup problems (e.g. a bad server PeerId) would otherwise only show after login
+                        if let Some(error) = &self.last_error {
+                            ui.add_space(6.0);
+                            ui.colored_label(ui.visuals().error_fg_color, format!("Error: {}", error));
+                        }
                     });
                 });
                 return;
             }
 
-            // Account deletion modal takes over the layout when toggled
+            if self.check_inactivity(ctx) {
+                return;
+            }
+            if ctx.input(|i| i.viewport().close_requested()) {
+                if !self.quit_confirmed && self.unsent_count() > 0 {
+                    ctx.send_viewport_cmd(egui::ViewportCommand::CancelClose);
+                    self.confirm_leave = Some(LeaveRequest::Quit);
+                } else {
+                    self.forget_recently_closed();
+                }
+            }
+            if self.show_leave_confirmation(ctx) {
+                return;
+            }
+
+            // Account view (change password, delete account) takes over the layout when toggled
             if self.show_delete_view {
                 egui::CentralPanel::default().show(ctx, |ui| {
                     ui.vertical_centered(|ui| {
+                        ui.add_space(24.0);
+                        ui.heading("Change Password");
+                        ui.add_space(12.0);
+                        for (value, hint) in [
+                            (&mut self.pw_old, "Current password"),
+                            (&mut self.pw_new, "New password"),
+                            (&mut self.pw_confirm, "Confirm new password"),
+                        ] {
+                            ui.add(egui::TextEdit::singleline(value).hint_text(hint).password(true).desired_width(360.0));
+                            ui.add_space(6.0);
+                        }
+                        ui.add_space(6.0);
+                        if ui.add_sized([BUTTON_WIDTH, UI_HEIGHT], egui::Button::new("Change")).clicked() {
+                            if self.pw_old.is_empty() || self.pw_new.is_empty() {
+                                self.pw_feedback = "Current and new password required".to_string();
+                            } else if self.pw_new != self.pw_confirm {
+                                self.pw_feedback = "New passwords don't match".to_string();
+                            } else if let Err(reason) = server::validate_password(&self.pw_new) {
+                                self.pw_feedback = format!("Weak password: {}", reason);
+                            } else {
+                                let _ = self.tx.send(UiToNet::ChangePassword {
+                                    username: self.username.clone(),
+                                    old_password: self.pw_old.clone(),
+                                    new_password: self.pw_new.clone(),
+                                });
+                                self.pw_feedback = "Changing password...".to_string();
+                            }
+                        }
+                        ui.add_space(8.0);
+                        if !self.pw_feedback.is_empty() {
+                            ui.colored_label(ui.visuals().warn_fg_color, &self.pw_feedback);
+                        }
+
+                        ui.add_space(24.0);
+                        ui.separator();
                         ui.add_space(24.0);
                         ui.heading("Delete Account");
-                        ui.label("Enter your credentials to permanently delete your account.");
+                        ui.label("Type your username to permanently delete your account.");
                         ui.add_space(12.0);
                         ui.add(
                             egui::TextEdit::singleline(&mut self.del_username)
                                 .hint_text("Username")
                                 .desired_width(360.0),
                         );
-                        ui.add_space(6.0);
-                        ui.add(
-                            egui::TextEdit::singleline(&mut self.del_password)
-                                .hint_text("Password")
-                                .password(true)
-                                .desired_width(360.0),
-                        );
                         ui.add_space(12.0);
                         ui.with_layout(egui::Layout::top_down(egui::Align::Center), |ui| {
                             ui.set_width(360.0);
@@ -703,25 +3156,96 @@ use eframe::egui;
                                     .add_sized([BUTTON_WIDTH, UI_HEIGHT], egui::Button::new("Cancel"))
                                     .clicked();
                                 if confirm {
-                                    if self.del_username.trim().is_empty() || self.del_password.is_empty() {
-                                        self.del_feedback = "Username and password required".to_string();
-                                    } else {
-                                        let _ = self.tx.send(UiToNet::DeleteAccount {
-                                            username: self.del_username.trim().to_string(),
-                                            password: self.del_password.clone(),
-                                        });
+                                    if self.del_username.trim() != self.username {
+                                        self.del_feedback = "Username doesn't match this account".to_string();
+                                    } else if let Some(token) = self.session_token.clone() {
+                                        let _ = self.tx.send(UiToNet::DeleteAccount { token });
                                         self.del_feedback = "Deleting account...".to_string();
+                                    } else {
+                                        self.del_feedback = "Not logged in".to_string();
                                     }
                                 }
                                 if cancel {
                                     self.show_delete_view = false;
                                     self.del_feedback.clear();
+                                    self.pw_old.clear();
+                                    self.pw_new.clear();
+                                    self.pw_confirm.clear();
+                                    self.pw_feedback.clear();
                                 }
                             });
                         });
                         ui.add_space(8.0);
                         if !self.del_feedback.is_empty() {
-                            ui.colored_label(egui::Color32::YELLOW, &self.del_feedback);
+                            ui.colored_label(ui.visuals().warn_fg_color, &self.del_feedback);
+                        }
+                    });
+                });
+                return;
+            }
+
+            // Settings view takes over the layout when toggled
+            if self.show_settings_view {
+                egui::CentralPanel::default().show(ctx, |ui| {
+                    ui.vertical_centered(|ui| {
+                        ui.add_space(24.0);
+                        ui.heading("Settings");
+                        ui.add_space(12.0);
+                        ui.with_layout(egui::Layout::top_down(egui::Align::Min), |ui| {
+                            ui.set_width(360.0);
+                            ui.horizontal(|ui| {
+                                ui.label("Archive messages older than");
+                                ui.add(egui::DragValue::new(&mut self.settings.archive_after_days).clamp_range(0..=3650).suffix(" days"));
+                            });
+                            ui.label(egui::RichText::new("Archived messages are collapsed at the top of a chat. 0 disables archiving.").small());
+                            ui.add_space(8.0);
+                            ui.horizontal(|ui| {
+                                ui.label("Auto-logout after");
+                                ui.add(egui::DragValue::new(&mut self.settings.auto_logout_minutes).clamp_range(0..=1440).suffix(" min"));
+                            });
+                            ui.label(egui::RichText::new("Logs out after this long without activity, for shared computers. 0 disables it.").small());
+                            ui.add_space(8.0);
+                            ui.horizontal(|ui| {
+                                ui.label("Send at most");
+                                ui.add(egui::DragValue::new(&mut self.settings.max_messages_per_sec).clamp_range(1..=50).suffix(" msg/s"));
+                            });
+                            ui.label(egui::RichText::new("Faster sends are queued and released at this rate.").small());
+                            ui.add_space(8.0);
+                            let before = (self.settings.mode, self.settings.theme);
+                            ui.horizontal(|ui| {
+                                ui.label("Theme");
+                                ui.radio_value(&mut self.settings.mode, ThemeMode::Dark, "Dark");
+                                ui.radio_value(&mut self.settings.mode, ThemeMode::Light, "Light");
+                            });
+                            ui.horizontal(|ui| {
+                                ui.label("Colors");
+                                ui.radio_value(&mut self.settings.theme, ThemeVariant::Standard, "Standard");
+                                ui.radio_value(&mut self.settings.theme, ThemeVariant::ColorBlind, "Color-blind friendly");
+                            });
+                            if (self.settings.mode, self.settings.theme) != before {
+                                configure_theme(ctx, self.settings.mode, self.settings.theme);
+                            }
+                            ui.add_space(8.0);
+                            ui.label("Blocked users");
+                            if self.blocked.is_empty() {
+                                ui.label(egui::RichText::new("No one is blocked. Right-click a contact to block them.").small());
+                            }
+                            let mut unblock = None;
+                            for name in self.blocked.keys() {
+                                ui.horizontal(|ui| {
+                                    ui.label(name);
+                                    if ui.small_button("Unblock").clicked() {
+                                        unblock = Some(name.clone());
+                                    }
+                                });
+                            }
+                            if let Some(name) = unblock {
+                                self.unblock_user(&name);
+                            }
+                        });
+                        ui.add_space(12.0);
+                        if ui.add_sized([BUTTON_WIDTH, UI_HEIGHT], egui::Button::new("Close")).clicked() {
+                            self.show_settings_view = false;
                         }
                     });
                 });
@@ -738,7 +3262,19 @@ use eframe::egui;
                         ui.horizontal(|ui| {
                             ui.vertical(|ui| {
                                 ui.label(egui::RichText::new(&self.username).heading());
-                                ui.label(egui::RichText::new(&self.status).small());
+                                ui.label(egui::RichText::new(&self.connection_status).small());
+                                ui.label(egui::RichText::new(self.reachability.label()).small().weak())
+                                    .on_hover_text(self.reachability.explain());
+                                let mut dismiss_error = false;
+                                if let Some(error) = &self.last_error {
+                                    ui.horizontal(|ui| {
+                                        ui.label(egui::RichText::new(format!("Error: {}", error)).small().color(ui.visuals().error_fg_color));
+                                        dismiss_error = ui.small_button("✖").on_hover_text("Dismiss").clicked();
+                                    });
+                                }
+                                if dismiss_error {
+                                    self.last_error = None;
+                                }
                             });
                             ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
                                 if ui
@@ -753,9 +3289,32 @@ use eframe::egui;
                                     .clicked()
                                 {
                                     self.show_delete_view = true;
-                                    self.del_username = self.username.clone();
-                                    self.del_password.clear();
+                                    self.del_username.clear();
                                     self.del_feedback.clear();
+                                    self.pw_feedback.clear();
+                                }
+
+                                if ui
+                                    .add_sized([BUTTON_WIDTH, UI_HEIGHT], egui::Button::new("Settings"))
+                                    .clicked()
+                                {
+                                    self.show_settings_view = true;
+                                }
+
+                                let (icon, hint, other) = match self.settings.mode {
+                                    ThemeMode::Dark => ("☀", "Switch to the light theme", ThemeMode::Light),
+                                    ThemeMode::Light => ("🌙", "Switch to the dark theme", ThemeMode::Dark),
+                                };
+                                if ui.add_sized([UI_HEIGHT, UI_HEIGHT], egui::Button::new(icon)).on_hover_text(hint).clicked() {
+                                    self.settings.mode = other;
+                                    configure_theme(ctx, self.settings.mode, self.settings.theme);
+                                }
+                                if ui
+                                    .add_sized([UI_HEIGHT, UI_HEIGHT], egui::SelectableLabel::new(self.show_diagnostics, "🔧"))
+                                    .on_hover_text("Diagnostics (F12)")
+                                    .clicked()
+                                {
+                                    self.show_diagnostics = !self.show_diagnostics;
                                 }
                             });
                         });
@@ -763,25 +3322,12 @@ use eframe::egui;
             });
 
             if logout_requested {
-                if !self.username.is_empty() {
-                    let _ = self.tx.send(UiToNet::Logout {
-                        username: self.username.clone(),
-                    });
+                if self.unsent_count() > 0 {
+                    self.confirm_leave = Some(LeaveRequest::Logout);
+                } else {
+                    self.logout(String::new());
+                    return;
                 }
-                self.logged_in = false;
-                self.username.clear();
-                self.username_input.clear();
-                self.password_input.clear();
-                self.selected_user = None;
-                self.users.clear();
-                self.peer_to_username.clear();
-                self.message_input.clear();
-                self.conversations.clear();
-                self.status = "Logged out".to_string();
-                self.page = Page::Login;
-                self.auth_feedback.clear();
-                self.show_delete_view = false;
-                return;
             }
 
             egui::SidePanel::left("chat_sidebar")
@@ -790,23 +3336,132 @@ use eframe::egui;
                 .show(ctx, |ui| {
                     ui.heading("Chats");
                     ui.add_space(8.0);
+                    ui.horizontal(|ui| {
+                        let clear = !self.contact_filter.is_empty() && ui.small_button("✖").on_hover_text("Clear search").clicked();
+                        ui.add_sized(
+                            [ui.available_width(), UI_HEIGHT],
+                            egui::TextEdit::singleline(&mut self.contact_filter).hint_text("🔍 Search"),
+                        );
+                        if clear {
+                            self.contact_filter.clear();
+                        }
+                    });
+                    ui.horizontal(|ui| {
+                        match self.space.clone() {
+                            Some(space) => {
+                                ui.label(format!("Space: {}", space));
+                                if ui.small_button("Leave").on_hover_text("Back to the space everyone shares").clicked() {
+                                    self.join_space(None);
+                                }
+                            }
+                            None => {
+                                let join = ui.small_button("Join").on_hover_text("Only see users in this space").clicked();
+                                let field = ui.add_sized(
+                                    [ui.available_width(), UI_HEIGHT],
+                                    egui::TextEdit::singleline(&mut self.space_input).hint_text("Space name"),
+                                );
+                                let entered = field.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter));
+                                if (join || entered) && !self.space_input.trim().is_empty() {
+                                    let space = self.space_input.trim().to_string();
+                                    self.join_space(Some(space));
+                                }
+                            }
+                        }
+                    });
+                    ui.add_space(8.0);
+                    // The open conversation stays listed even when it doesn't match
+                    let filter = self.contact_filter.trim().to_lowercase();
+                    let shown = |name: &str, selected: &Option<String>| {
+                        filter.is_empty() || name.to_lowercase().contains(&filter) || selected.as_deref() == Some(name)
+                    };
+
+                    // Our own entry, pinned first: saved messages, which also reach our other devices
+                    if !self.username.is_empty() && shown(&self.username, &self.selected_user) {
+                        let key = self.username.clone();
+                        let is_selected = self.selected_user.as_ref() == Some(&key);
+                        let is_unread = self.conversations.get(&key).is_some_and(|c| c.unread);
+                        let others = self.users.get(&key).map_or(0, |c| c.sessions.len());
+                        let label = match others {
+                            0 => "You · saved messages".to_string(),
+                            n => format!("You · saved messages · {} other device(s)", n),
+                        };
+                        let label = if is_unread { format!("✉ {}", label) } else { label };
+                        let label = if self.muted.contains(&key) { format!("{} 🔇", label) } else { label };
+                        let response = ui
+                            .add_sized([ui.available_width(), UI_HEIGHT], egui::SelectableLabel::new(is_selected, egui::RichText::new(label).strong()))
+                            .on_hover_text(match others {
+                                0 => "Notes kept on this device",
+                                _ => "Notes kept on this device and sent to your other online devices",
+                            });
+                        response.context_menu(|ui| self.conversation_menu(ui, &key));
+                        if response.clicked() {
+                            self.open_conversation(&key).unread = false;
+                            if !is_selected {
+                                self.selected_user = Some(key);
+                                self.find = None;
+                                self.import = None;
+                            }
+                        }
+                        ui.separator();
+                    }
+
+                    // Gossip rooms, listed above direct chats
+                    for room in self.rooms.clone() {
+                        let key = room_key(&room);
+                        if !shown(&key, &self.selected_user) {
+                            continue;
+                        }
+                        let is_selected = self.selected_user.as_ref() == Some(&key);
+                        let is_unread = self.conversations.get(&key).map(|c| c.unread).unwrap_or(false);
+                        let label = if is_unread { format!("✉ {}", key) } else { key.clone() };
+                        let label = if self.muted.contains(&key) { format!("{} 🔇", label) } else { label };
+                        let label = egui::RichText::new(label).strong();
+                        let response = ui.add_sized([ui.available_width(), UI_HEIGHT], egui::SelectableLabel::new(is_selected, label));
+                        response.context_menu(|ui| self.conversation_menu(ui, &key));
+                        if response.clicked() {
+                            if let Some(conv) = self.conversations.get_mut(&key) {
+                                conv.unread = false;
+                            }
+                            if !is_selected {
+                                self.selected_user = Some(key);
+                                self.find = None;
+                                self.import = None;
+                            }
+                        }
+                    }
+                    if !self.rooms.is_empty() {
+                        ui.separator();
+                    }
 
-                    if self.users.is_empty() {
+                    if self.users.keys().all(|name| *name == self.username) {
                         ui.label("No peers available yet. Stay tuned while discovery runs...");
                     }
 
-                    let mut names: Vec<String> = self.users.keys().cloned().collect();
+                    let mut names: Vec<String> = self
+                        .users
+                        .keys()
+                        .filter(|name| **name != self.username && !self.is_blocked(name) && shown(name, &self.selected_user))
+                        .cloned()
+                        .collect();
+                    if names.is_empty() && !self.users.is_empty() && !self.contact_filter.trim().is_empty() {
+                        ui.label(egui::RichText::new(format!("No contacts match \"{}\"", self.contact_filter.trim())).small());
+                    }
                     names.sort_by(|a, b| {
                         let convo_a = self.conversations.get(a);
                         let convo_b = self.conversations.get(b);
 
-                        let unread_a = convo_a.map(|c| c.unread).unwrap_or(false);
-                        let unread_b = convo_b.map(|c| c.unread).unwrap_or(false);
+                        // Muted chats don't move up for unread messages
+                        let unread_a = convo_a.is_some_and(|c| c.unread) && !self.muted.contains(a);
+                        let unread_b = convo_b.is_some_and(|c| c.unread) && !self.muted.contains(b);
                         let time_a = convo_a.map(|c| c.last_activity).unwrap_or(SystemTime::UNIX_EPOCH);
                         let time_b = convo_b.map(|c| c.last_activity).unwrap_or(SystemTime::UNIX_EPOCH);
 
-                        unread_b
-                            .cmp(&unread_a)
+                        let online_a = self.users.get(a).is_some_and(|c| c.online);
+                        let online_b = self.users.get(b).is_some_and(|c| c.online);
+
+                        online_b
+                            .cmp(&online_a)
+                            .then_with(|| unread_b.cmp(&unread_a))
                             .then_with(|| time_b.cmp(&time_a))
                             .then_with(|| a.to_lowercase().cmp(&b.to_lowercase()))
                     });
@@ -814,7 +3469,7 @@ use eframe::egui;
                     for name in names {
                         let conversation = self.conversations.get(&name);
                         let preview = conversation
-                            .and_then(|conv| conv.messages.last())
+                            .and_then(|conv| conv.last_message())
                             .map(|msg| {
                                 let prefix = if msg.from_self { "You" } else { name.as_str() };
                                 format!("{}: {}", prefix, truncate_preview(&msg.text))
@@ -827,13 +3482,15 @@ use eframe::egui;
                             .map(|selected| selected == &name)
                             .unwrap_or(false);
                         let is_unread = conversation.map(|c| c.unread).unwrap_or(false);
+                        let is_muted = self.muted.contains(&name);
 
-                        let desired_size = egui::vec2(ui.available_width(), 70.0);
+                        let desired_size = egui::vec2(ui.available_width(), 86.0);
                         let (rect, response) = ui.allocate_exact_size(desired_size, egui::Sense::click());
                         let mut visuals = ui.style().interact_selectable(&response, is_selected);
-                        if is_unread && !is_selected {
-                            visuals.bg_fill = egui::Color32::from_rgb(56, 142, 60);
-                            visuals.bg_stroke = egui::Stroke { width: 1.0, color: egui::Color32::from_rgb(67, 160, 71) };
+                        if is_unread && !is_selected && !is_muted {
+                            let colors = palette(self.settings.theme);
+                            visuals.bg_fill = colors.unread_fill;
+                            visuals.bg_stroke = egui::Stroke { width: 1.5, color: colors.unread_stroke };
                         }
                         ui.painter().rect(
                             rect,
@@ -842,28 +3499,83 @@ use eframe::egui;
                             visuals.bg_stroke,
                         );
 
+                        // Presence dot in the left margin: green while online, grey while offline
+                        let online = self.users.get(&name).is_some_and(|c| c.online);
+                        let dot = if online { egui::Color32::from_rgb(76, 175, 80) } else { egui::Color32::GRAY };
+                        ui.painter().circle_filled(egui::pos2(rect.left() + 10.0, rect.top() + 19.0), 4.0, dot);
+
                         let inner = rect.shrink2(egui::vec2(12.0, 10.0));
+                        let inner = egui::Rect::from_min_max(inner.min + egui::vec2(8.0, 0.0), inner.max);
                         let mut child_ui = ui.child_ui(inner, egui::Layout::top_down(egui::Align::LEFT));
-                        child_ui.label(egui::RichText::new(&name).strong());
-                        child_ui.label(egui::RichText::new(preview).small());
+                        let incompatible = self
+                            .users
+                            .get(&name)
+                            .and_then(|c| self.incompatible_peers.get(&c.peer_id));
+                        // Contacts that mDNS also found on the local network
+                        let title = match self.users.get(&name) {
+                            Some(c) if self.nearby.contains_key(&c.peer_id) => format!("{} · LAN", name),
+                            _ => name.clone(),
+                        };
+                        let title = if is_muted { format!("{} 🔇", title) } else { title };
+                        // Never a stale time for someone who is online now
+                        let presence = match self.last_seen.get(&name) {
+                            _ if online => "online".to_string(),
+                            Some(seen) => last_seen_label(*seen, SystemTime::now()),
+                            None => "offline".to_string(),
+                        };
+                        // Unread is also marked by an icon and bold preview, not only by the row color
+                        if let Some(version) = incompatible {
+                            child_ui.label(egui::RichText::new(format!("⚠ {}", title)).strong());
+                            child_ui.label(egui::RichText::new(presence).small().weak());
+                            child_ui.label(egui::RichText::new(format!("Incompatible version ({})", version)).small());
+                        } else if is_unread {
+                            child_ui.label(egui::RichText::new(format!("✉ {}", title)).strong());
+                            child_ui.label(egui::RichText::new(presence).small().weak());
+                            child_ui.label(egui::RichText::new(preview).small().strong());
+                        } else {
+                            child_ui.label(egui::RichText::new(&title).strong());
+                            child_ui.label(egui::RichText::new(presence).small().weak());
+                            child_ui.label(egui::RichText::new(preview).small());
+                        }
 
                         if response.clicked() {
-                            let conv = self.conversations.entry(name.clone()).or_default();
-                            conv.unread = false;
+                            self.open_conversation(&name).unread = false;
                             if self.selected_user.as_ref() != Some(&name) {
                                 self.selected_user = Some(name.clone());
-                                self.status = format!("Connecting to {}...", name);
-                                if let Some(pid) = self.users.get(&name).cloned() {
+                                self.find = None;
+                                self.import = None;
+                                if let Some(pid) = self.online_peer(&name) {
+                                    self.toast(format!("Connecting to {}...", name));
                                     let _ = self.tx.send(UiToNet::Connect { peer_id: pid });
                                 }
                             }
                             ui.ctx().request_repaint();
                         }
+                        response.context_menu(|ui| self.conversation_menu(ui, &name));
                         ui.add_space(6.0);
                     }
+
+                    // LAN peers that aren't in this server's directory; shown, but there's no one to chat with
+                    let mut strangers: Vec<(&String, &(String, String))> =
+                        self.nearby.iter().filter(|(pid, _)| !self.peer_to_username.contains_key(*pid)).collect();
+                    if !strangers.is_empty() {
+                        strangers.sort();
+                        ui.separator();
+                        ui.label(egui::RichText::new("Nearby").small().strong());
+                        for (pid, (version, agent)) in strangers {
+                            let short = &pid[pid.len().saturating_sub(8)..];
+                            ui.label(egui::RichText::new(format!("…{} · {}", short, version)).small())
+                                .on_hover_text(format!("{}\n{}\nNot in this server's directory", pid, agent));
+                        }
+                    }
                 });
 
             let selected_user = self.selected_user.clone();
+            // The composer edits the selected chat's draft, which is put back after the panel
+            let mut draft = match &selected_user {
+                Some(name) => std::mem::take(&mut self.open_conversation(name).draft),
+                None => String::new(),
+            };
 
             egui::TopBottomPanel::bottom("chat_input_panel").show(ctx, |ui| {
                 egui::Frame::none()
@@ -871,32 +3583,69 @@ use eframe::egui;
                     .inner_margin(egui::Margin::same(10.0))
                     .show(ui, |ui| {
                         ui.separator();
-                        let can_chat = selected_user.is_some();
+                        let incompatible_version = selected_user
+                            .as_ref()
+                            .and_then(|name| self.users.get(name))
+                            .and_then(|c| self.incompatible_peers.get(&c.peer_id))
+                            .cloned();
+                        // Rooms aren't in `users` and are always open, and so are saved messages
+                        let saved_messages = selected_user.as_deref().is_some_and(|name| name == self.username);
+                        let offline = !saved_messages && selected_user.as_ref().and_then(|name| self.users.get(name)).is_some_and(|c| !c.online);
+                        let can_chat = selected_user.is_some() && incompatible_version.is_none() && !offline;
+                        let length = message_length(&draft);
+                        let too_long = length > MAX_MESSAGE_CHARS;
                         ui.add_space(4.0);
                         ui.add_enabled_ui(can_chat, |ui| {
                             ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
+                                let input_id = egui::Id::new("chat_input_field");
+                                // Enter sends and Shift+Enter adds a newline. The key is consumed before the
+                                // text edit sees it, and the send paths below ignore an empty draft.
+                                let enter_pressed = ui.memory(|m| m.has_focus(input_id))
+                                    && ui.input_mut(|i| !i.modifiers.shift && i.consume_key(egui::Modifiers::NONE, egui::Key::Enter));
+                                let send_button = egui::Button::new(egui::RichText::new("Send").color(egui::Color32::WHITE))
+                                    .fill(palette(self.settings.theme).highlight)
+                                    .rounding(egui::Rounding::same(RADIUS))
+                                    .stroke(egui::Stroke { width: 1.0, color: palette(self.settings.theme).highlight_dark });
                                 let send_clicked = ui
-                                    .add_sized(
-                                        [BUTTON_WIDTH, UI_HEIGHT],
-                                        egui::Button::new(egui::RichText::new("Send").color(egui::Color32::WHITE))
-                                            .fill(egui::Color32::from_rgb(255, 152, 0))
-                                            .rounding(egui::Rounding::same(RADIUS))
-                                            .stroke(egui::Stroke { width: 1.0, color: egui::Color32::from_rgb(230, 130, 0) }),
-                                    )
+                                    .add_enabled_ui(!too_long, |ui| ui.add_sized([BUTTON_WIDTH, UI_HEIGHT], send_button))
+                                    .inner
+                                    .on_disabled_hover_text(match &incompatible_version {
+                                        Some(version) => format!("This contact runs an incompatible client ({}); messages can't be exchanged", version),
+                                        None if offline => "This contact is offline".to_string(),
+                                        None if too_long => format!("Too long to send: shorten it to {} characters", MAX_MESSAGE_CHARS),
+                                        None => "Select a conversation first".to_string(),
+                                    })
+                                    .clicked()
+                                    || (enter_pressed && !too_long);
+                                // Files go straight to one contact, so rooms have no attach button
+                                let file_peer = selected_user
+                                    .as_ref()
+                                    .filter(|name| room_from_key(name).is_none())
+                                    .and_then(|name| self.online_peer(name).map(|pid| (name.clone(), pid)));
+                                let file_clicked = ui
+                                    .add_enabled(file_peer.is_some(), egui::Button::new("📎").min_size(egui::vec2(UI_HEIGHT, UI_HEIGHT)))
+                                    .on_hover_text("Send a file")
                                     .clicked();
+                                if file_clicked
+                                    && let Some((to_username, peer_id)) = file_peer
+                                    && let Some(path) = rfd::FileDialog::new().set_title("Send a file").pick_file()
+                                {
+                                    let _ = self.tx.send(UiToNet::SendFile { peer_id, to_username, path });
+                                }
 
-                                let input_id = egui::Id::new("chat_input_field");
-                                let text_edit = egui::TextEdit::multiline(&mut self.message_input)
+                                let mut input_changed = false;
+                                let text_edit = egui::TextEdit::multiline(&mut draft)
                                     .id_source(input_id)
                                     .desired_rows(5)
                                     .desired_width(f32::INFINITY)
                                     .hint_text("Type a message...")
                                     .frame(false);
 
-                                let inner = egui::Frame::none()
-                                    .fill(egui::Color32::from_rgb(38, 43, 50))
+                                let theme = Theme::of(self.settings.mode);
+                                egui::Frame::none()
+                                    .fill(theme.surface_fill)
                                     .rounding(egui::Rounding::same(RADIUS))
-                                    .stroke(egui::Stroke { width: 1.0, color: egui::Color32::from_rgb(55, 61, 69) })
+                                    .stroke(egui::Stroke { width: 1.0, color: theme.surface_stroke })
                                     .inner_margin(egui::Margin::symmetric(10.0, 8.0))
                                     .show(ui, |ui| {
                                         let w = ui.available_width();
@@ -911,267 +3660,1312 @@ use eframe::egui;
                                             .max_height(fixed_h)
                                             .show(ui, |ui| {
                                                 ui.set_width(w);
-                                                ui.add(text_edit);
+                                                input_changed = ui.add(text_edit).changed();
                                             });
                                     });
-                                let _ = inner.inner;
-
-                                if send_clicked {
-                                    if let Some(name) = selected_user.clone() {
-                                        if let Some(peer_id) = self.users.get(&name).cloned() {
-                                            let message = self.message_input.trim();
-                                            if !message.is_empty() {
-                                                let message = message.to_string();
-                                                let _ = self.tx.send(UiToNet::Write {
-                                                    peer_id,
-                                                    from_username: self.username.clone(),
-                                                    to_username: name.clone(),
-                                                    msg: message,
-                                                });
-                                                self.message_input.clear();
-                                            }
-                                        }
+
+                                if input_changed
+                                    && !draft.trim().is_empty()
+                                    && let Some(name) = selected_user.as_deref()
+                                {
+                                    self.send_typing(name);
+                                }
+                                if send_clicked
+                                    && let Some(room) = selected_user.as_deref().and_then(room_from_key)
+                                {
+                                    let message = draft.trim();
+                                    if !message.is_empty() {
+                                        self.send_limiter.enqueue(UiToNet::RoomMessage {
+                                            room: room.to_string(),
+                                            from_username: self.username.clone(),
+                                            text: message.to_string(),
+                                        });
+                                        draft.clear();
+                                    }
+                                } else if send_clicked
+                                    && saved_messages
+                                    && self.online_peer(&self.username).is_none()
+                                {
+                                    // No other device of ours is online, so the note is only kept here
+                                    let message = draft.trim();
+                                    if !message.is_empty() {
+                                        let note = ChatMessage {
+                                            from_self: true,
+                                            text: message.to_string(),
+                                            timestamp: message_time_now(),
+                                            forwarded: false,
+                                            author: None,
+                                            msg_id: None,
+                                            seq: None,
+                                            delivered: true,
+                                            failed: false,
+                                            queued: false,
+                                            read: false,
+                                        };
+                                        let name = self.username.clone();
+                                        self.record_message(&name, note);
+                                        draft.clear();
+                                    }
+                                } else if send_clicked
+                                    && let Some(name) = selected_user.clone()
+                                    && let Some(peer_id) = self.online_peer(&name)
+                                {
+                                    let message = draft.trim();
+                                    if !message.is_empty() {
+                                        let message = message.to_string();
+                                        self.send_limiter.enqueue(UiToNet::Write {
+                                            peer_id,
+                                            from_username: self.username.clone(),
+                                            to_username: name.clone(),
+                                            msg: message,
+                                            forwarded: false,
+                                            resend: None,
+                                        });
+                                        draft.clear();
                                     }
                                 }
                             });
                         });
-                        if !can_chat {
+                        if let Some(version) = &incompatible_version {
+                            ui.label(format!(
+                                "This contact uses an incompatible protocol version ({}, you have {}). Sending is disabled.",
+                                version, CLIENT_PROTOCOL_VERSION
+                            ));
+                        } else if offline {
+                            ui.label("This contact is offline. You can send once they're back.");
+                        } else if !can_chat {
                             ui.label("Select a conversation to start chatting.");
                         }
+                        if length >= COUNTER_FROM_CHARS {
+                            let counter = egui::RichText::new(format!("{} / {}", length, MAX_MESSAGE_CHARS)).small();
+                            if too_long {
+                                ui.colored_label(ui.visuals().error_fg_color, counter);
+                            } else {
+                                ui.label(counter.weak());
+                            }
+                        }
+                        let queued = self.send_limiter.queued();
+                        if queued > 0 {
+                            ui.label(egui::RichText::new(format!("Sending… ({} queued)", queued)).small().weak());
+                        }
                     });
             });
+            if let Some(name) = &selected_user {
+                self.open_conversation(name).draft = draft;
+            }
+
+            // Ctrl+Shift+T reopens the most recently cleared or dropped chat
+            let reopen_shortcut = egui::Modifiers { shift: true, ..egui::Modifiers::COMMAND };
+            if ctx.input_mut(|i| i.consume_key(reopen_shortcut, egui::Key::T)) {
+                self.reopen_last_closed();
+            }
+            let mut reopen_requested = false;
+
+            // Ctrl+F opens the find bar for the selected chat, Esc closes it
+            if selected_user.is_some() && ctx.input_mut(|i| i.consume_key(egui::Modifiers::COMMAND, egui::Key::F)) {
+                let find = self.find.get_or_insert_with(FindBar::default);
+                find.focus_pending = true;
+            }
+            if self.find.is_some() && ctx.input_mut(|i| i.consume_key(egui::Modifiers::NONE, egui::Key::Escape)) {
+                self.find = None;
+            }
 
             egui::CentralPanel::default().show(ctx, |ui| {
                 ui.set_width(ui.available_width());
                 ui.add_space(8.0);
                 if let Some(name) = selected_user {
-                    ui.heading(&name);
+                    let now = Instant::now();
+                    self.typing.retain(|_, until| *until > now);
+                    let typing = self.typing.contains_key(&name);
+                    let link = self.users.get(&name).and_then(|c| self.link_quality.get(&c.peer_id)).copied();
+                    ui.horizontal(|ui| {
+                        if name == self.username {
+                            ui.heading("Saved messages");
+                        } else {
+                            ui.heading(&name);
+                        }
+                        if let Some(link) = link {
+                            let (bars, hint) = link.indicator();
+                            let color = match link {
+                                LinkQuality::Degraded => ui.visuals().error_fg_color,
+                                LinkQuality::Rtt(_) => ui.visuals().weak_text_color(),
+                            };
+                            ui.label(egui::RichText::new(bars).small().color(color)).on_hover_text(hint);
+                        }
+                        if typing {
+                            ui.label(egui::RichText::new(format!("{} is typing...", name)).italics().weak());
+                        }
+                        ui.menu_button("⋯", |ui| {
+                            if ui.button("Import transcript…").clicked() {
+                                self.import = Some(ImportDialog::default());
+                                ui.close_menu();
+                            }
+                            self.conversation_menu(ui, &name);
+                            if ui
+                                .add_enabled(!self.recently_closed.is_empty(), egui::Button::new("Reopen last closed chat"))
+                                .clicked()
+                            {
+                                reopen_requested = true;
+                                ui.close_menu();
+                            }
+                        });
+                    });
                     ui.add_space(4.0);
+                    if let Some(import) = self.import.as_mut() {
+                        let mut open = true;
+                        let mut import_clicked = false;
+                        egui::Window::new("Import transcript")
+                            .collapsible(false)
+                            .resizable(false)
+                            .open(&mut open)
+                            .show(ui.ctx(), |ui| {
+                                ui.label("Path to a JSON transcript (the format is described in the README):");
+                                ui.add(egui::TextEdit::singleline(&mut import.path).desired_width(320.0));
+                                if ui.add_sized([BUTTON_WIDTH, UI_HEIGHT], egui::Button::new("Import")).clicked() {
+                                    import_clicked = true;
+                                }
+                                if !import.feedback.is_empty() {
+                                    ui.colored_label(ui.visuals().warn_fg_color, &import.feedback);
+                                }
+                            });
+                        if import_clicked {
+                            let parsed = std::fs::read_to_string(import.path.trim())
+                                .map_err(|e| format!("Cannot read file: {}", e))
+                                .and_then(|json| parse_transcript(&json));
+                            let feedback = match parsed {
+                                Ok((Some(peer), _)) if username_key(&peer) != username_key(&name) => {
+                                    format!("This transcript belongs to {}", peer)
+                                }
+                                Ok((_, messages)) => {
+                                    let added = self.open_conversation(&name).merge(messages);
+                                    if added > 0 {
+                                        self.save_conversation(&name);
+                                    }
+                                    format!("Imported {} new message(s)", added)
+                                }
+                                Err(e) => e,
+                            };
+                            if let Some(import) = self.import.as_mut() {
+                                import.feedback = feedback;
+                            }
+                        }
+                        if !open {
+                            self.import = None;
+                        }
+                    }
+                    if let Some(find) = self.find.as_mut() {
+                        let mut close = false;
+                        ui.horizontal(|ui| {
+                            let response = ui.add(
+                                egui::TextEdit::singleline(&mut find.query)
+                                    .hint_text("Find in this chat")
+                                    .desired_width(240.0),
+                            );
+                            if find.focus_pending {
+                                response.request_focus();
+                                find.focus_pending = false;
+                            }
+                            let enter = response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter));
+                            let counter = match find.matches.len() {
+                                0 if find.query.is_empty() => String::new(),
+                                0 => "No results".to_string(),
+                                n => format!("{} of {}", find.current + 1, n),
+                            };
+                            ui.label(counter);
+                            if ui.add_enabled(!find.matches.is_empty(), egui::Button::new("▲")).on_hover_text("Previous match").clicked() {
+                                find.step(false);
+                            }
+                            if ui.add_enabled(!find.matches.is_empty(), egui::Button::new("▼")).on_hover_text("Next match").clicked() || enter {
+                                find.step(true);
+                                if enter {
+                                    find.focus_pending = true;
+                                }
+                            }
+                            if ui.button("✕").on_hover_text("Close (Esc)").clicked() {
+                                close = true;
+                            }
+                        });
+                        if close {
+                            self.find = None;
+                        }
+                        ui.add_space(4.0);
+                    }
+                    let mut forward_request: Option<String> = None;
+                    let mut retry_request: Option<String> = None;
+                    let mut copied = false;
+                    let mut expand_archive = false;
+                    // Find searches the archive too
+                    if self.find.is_some() {
+                        self.load_archive(&name);
+                    }
                     egui::ScrollArea::vertical()
                         .id_source("chat_scroll")
                         .scroll_bar_visibility(egui::scroll_area::ScrollBarVisibility::AlwaysVisible)
                         .auto_shrink([false, false])
                         .stick_to_bottom(true)
                         .show(ui, |ui| {
-                            if let Some(conversation) = self.conversations.get(&name) {
-                                for msg in &conversation.messages {
-                                    let row_width = ui.available_width();
-                                    let layout = if msg.from_self {
-                                        egui::Layout::right_to_left(egui::Align::Min)
-                                    } else {
-                                        egui::Layout::left_to_right(egui::Align::Min)
-                                    };
-                                    ui.allocate_ui_with_layout(egui::vec2(row_width, 0.0), layout, |ui| {
-                                        let (fill, stroke) = if msg.from_self {
-                                            (
-                                                egui::Color32::from_rgb(25, 118, 210),
-                                                egui::Color32::from_rgb(21, 101, 192),
-                                            )
-                                        } else {
-                                            (
-                                                egui::Color32::from_rgb(38, 43, 50),
-                                                egui::Color32::from_rgb(55, 61, 69),
-                                            )
-                                        };
-                                        egui::Frame::none()
-                                            .fill(fill)
-                                            .rounding(egui::Rounding::same(RADIUS))
-                                            .stroke(egui::Stroke { width: 1.0, color: stroke })
-                                            .inner_margin(egui::Margin::symmetric(12.0, 8.0))
-                                            .show(ui, |ui| {
-                                                let author = if msg.from_self { "You" } else { name.as_str() };
-                                                ui.colored_label(egui::Color32::WHITE, egui::RichText::new(author).small());
-                                                ui.add_space(2.0);
-                                                ui.colored_label(egui::Color32::WHITE, &msg.text);
-                                            });
+                            if let Some(conversation) = self.conversations.get_mut(&name) {
+                                if let Some(cutoff) = self.settings.archive_cutoff() {
+                                    conversation.archive_older_than(cutoff);
+                                }
+                                if let Some(find) = self.find.as_mut() {
+                                    find.update_matches(conversation);
+                                }
+                                let find = self.find.as_ref();
+                                let current_match = find.and_then(|f| f.current_match());
+                                let scroll_to = find.filter(|f| f.scroll_pending).and_then(|f| f.current_match());
+                                let highlight = |index: usize| match find {
+                                    Some(_) if current_match == Some(index) => Highlight::Current,
+                                    Some(f) if f.matches.binary_search(&index).is_ok() => Highlight::Match,
+                                    _ => Highlight::None,
+                                };
+                                let colors = palette(self.settings.theme);
+                                let theme = Theme::of(self.settings.mode);
+                                let archived_len = conversation.archived.len();
+                                let stored_len = conversation.unloaded_archive;
+                                let is_seen = |m: &ChatMessage| m.from_self && m.read;
+                                let last_seen = match conversation.messages.iter().rposition(is_seen) {
+                                    Some(offset) => Some(archived_len + offset),
+                                    None => conversation.archived.iter().rposition(is_seen),
+                                };
+                                if archived_len + stored_len > 0 {
+                                    let mut header = egui::CollapsingHeader::new(format!("Archived messages ({})", archived_len + stored_len))
+                                        .id_source(("archived", &name))
+                                        .default_open(false);
+                                    // Expand the archive when navigating to a match inside it
+                                    if scroll_to.is_some_and(|i| i < archived_len) {
+                                        header = header.open(Some(true));
+                                    }
+                                    header.show(ui, |ui| {
+                                        expand_archive = stored_len > 0;
+                                        let mut last_day = None;
+                                        for (index, msg) in conversation.archived.iter().enumerate() {
+                                            let day = local_day(msg.timestamp);
+                                            if last_day != Some(day) {
+                                                date_separator(ui, day);
+                                                last_day = Some(day);
+                                            }
+                                            let response = render_message(ui, msg, &name, highlight(index), last_seen == Some(index), colors, &theme);
+                                            message_context_menu(&response, msg, &mut forward_request, &mut copied);
+                                            if retry_button(ui, msg) {
+                                                retry_request = msg.msg_id.clone();
+                                            }
+                                            if scroll_to == Some(index) {
+                                                response.scroll_to_me(Some(egui::Align::Center));
+                                            }
+                                        }
                                     });
                                     ui.add_space(6.0);
                                 }
-                            } else {
+                                // Each list starts with a separator, since the archive above may be collapsed
+                                let mut last_day = None;
+                                for (offset, msg) in conversation.messages.iter().enumerate() {
+                                    let index = archived_len + offset;
+                                    let day = local_day(msg.timestamp);
+                                    if last_day != Some(day) {
+                                        date_separator(ui, day);
+                                        last_day = Some(day);
+                                    }
+                                    let response = render_message(ui, msg, &name, highlight(index), last_seen == Some(index), colors, &theme);
+                                    message_context_menu(&response, msg, &mut forward_request, &mut copied);
+                                    if retry_button(ui, msg) {
+                                        retry_request = msg.msg_id.clone();
+                                    }
+                                    if scroll_to == Some(index) {
+                                        response.scroll_to_me(Some(egui::Align::Center));
+                                    }
+                                }
+                                if let Some(find) = self.find.as_mut() {
+                                    find.scroll_pending = false;
+                                }
+                            } else if !self.transfers.iter().any(|t| t.peer == name) {
                                 ui.vertical_centered(|ui| {
                                     ui.add_space(40.0);
                                     ui.label("No messages yet. Say hi!");
                                 });
                             }
+                            for transfer in self.transfers.iter().filter(|t| t.peer == name) {
+                                render_transfer(ui, transfer, &Theme::of(self.settings.mode));
+                            }
                         });
+                    if expand_archive {
+                        self.load_archive(&name);
+                        ui.ctx().request_repaint();
+                    }
+                    if copied {
+                        self.toast("Copied");
+                    }
+                    if let Some(text) = forward_request {
+                        self.forward = Some(ForwardPicker { text, recipients: HashSet::new() });
+                    }
+                    if let Some(msg_id) = retry_request {
+                        self.retry_message(&name, &msg_id);
+                    }
+                    // The open chat's messages count as seen only while the window has focus
+                    if ui.ctx().input(|i| i.focused) {
+                        self.send_read_receipt(&name);
+                    }
                 } else {
                     ui.vertical_centered(|ui| {
                         ui.add_space(80.0);
                         ui.heading("No chat selected");
                         ui.label("Pick a user from the left to begin chatting.");
+                        if !self.recently_closed.is_empty() {
+                            ui.add_space(12.0);
+                            if ui.button("Reopen last closed chat (Ctrl+Shift+T)").clicked() {
+                                reopen_requested = true;
+                            }
+                        }
                     });
                 }
             });
+            if reopen_requested {
+                self.reopen_last_closed();
+            }
+
+            // Contact picker for forwarding a message to one or more users
+            if let Some(picker) = self.forward.as_mut() {
+                let mut open = true;
+                let mut send = false;
+                let mut names: Vec<&String> =
+                    self.users.iter().filter(|(name, c)| c.online && !self.blocked.contains_key(&username_key(name))).map(|(name, _)| name).collect();
+                names.sort_by_key(|n| n.to_lowercase());
+                egui::Window::new("Forward message")
+                    .collapsible(false)
+                    .resizable(false)
+                    .open(&mut open)
+                    .show(ctx, |ui| {
+                        ui.label(egui::RichText::new(truncate_preview(&picker.text)).italics());
+                        ui.separator();
+                        if names.is_empty() {
+                            ui.label("No contacts online");
+                        }
+                        for name in names {
+                            let mut ticked = picker.recipients.contains(name);
+                            if ui.checkbox(&mut ticked, name.as_str()).changed() {
+                                if ticked {
+                                    picker.recipients.insert(name.clone());
+                                } else {
+                                    picker.recipients.remove(name);
+                                }
+                            }
+                        }
+                        ui.add_space(6.0);
+                        let label = format!("Forward to {}", picker.recipients.len());
+                        if ui.add_enabled(!picker.recipients.is_empty(), egui::Button::new(label)).clicked() {
+                            send = true;
+                        }
+                    });
+                if send {
+                    let mut recipients: Vec<String> = picker.recipients.iter().cloned().collect();
+                    recipients.sort();
+                    for to in &recipients {
+                        if let Some(peer_id) = self.users.get(to).filter(|c| c.online).map(|c| c.peer_id.clone()) {
+                            // Through the rate limiter like any other send; the network task echoes it into that chat
+                            self.send_limiter.enqueue(UiToNet::Write {
+                                peer_id,
+                                from_username: self.username.clone(),
+                                to_username: to.clone(),
+                                msg: picker.text.clone(),
+                                forwarded: true,
+                                resend: None,
+                            });
+                        }
+                    }
+                    self.toast(format!("Forwarded to {}", recipients.join(", ")));
+                }
+                if send || !open {
+                    self.forward = None;
+                }
+            }
+
+            self.show_delete_confirmation(ctx);
+            self.update_window_title(ctx);
+            self.show_toast(ctx);
+        }
+
+    }
+
+    impl ChatApp {
+        // Shows the number of unread conversations in the title, e.g. "P2P Chat Client (3)",
+        // so it's visible from the taskbar. Only sent to the window when it changes.
+        fn update_window_title(&mut self, ctx: &egui::Context) {
+            let unread = if self.logged_in { unread_count(&self.conversations, &self.muted) } else { 0 };
+            if self.title_unread == Some(unread) {
+                return;
+            }
+            self.title_unread = Some(unread);
+            let title = if unread == 0 { APP_TITLE.to_string() } else { format!("{} ({})", APP_TITLE, unread) };
+            ctx.send_viewport_cmd(egui::ViewportCommand::Title(title));
         }
 
+        // Draws the current toast above the input bar, fading it out before it expires
+        fn show_toast(&mut self, ctx: &egui::Context) {
+            let Some((text, raised)) = &self.transient_toast else { return };
+            let age = raised.elapsed().as_secs_f32();
+            if age >= TOAST_SECS {
+                self.transient_toast = None;
+                return;
+            }
+            let opacity = ((TOAST_SECS - age) / TOAST_FADE_SECS).min(1.0);
+            egui::Area::new(egui::Id::new("transient_toast"))
+                .anchor(egui::Align2::CENTER_BOTTOM, [0.0, -(UI_HEIGHT * 2.5)])
+                .interactable(false)
+                .order(egui::Order::Tooltip)
+                .show(ctx, |ui| {
+                    let visuals = ui.visuals().clone();
+                    egui::Frame::popup(ui.style())
+                        .fill(visuals.window_fill.gamma_multiply(opacity))
+                        .stroke(egui::Stroke::new(1.0, visuals.window_stroke.color.gamma_multiply(opacity)))
+                        .shadow(egui::epaint::Shadow::NONE)
+                        .show(ui, |ui| {
+                            ui.label(egui::RichText::new(text).color(visuals.text_color().gamma_multiply(opacity)));
+                        });
+                });
+        }
     }
 
     impl Drop for ChatApp {
         fn drop(&mut self) {
             // Best-effort: attempt to inform server we're logging out.
-            if self.logged_in && !self.username.is_empty() {
-                let _ = self.tx.send(UiToNet::Logout { username: self.username.clone() });
+            if self.logged_in
+                && !self.username.is_empty()
+                && let Some(token) = self.session_token.take()
+            {
+                let _ = self.tx.send(UiToNet::Logout { username: self.username.clone(), token });
             }
         }
     }
 
     // --- Networking task ---
-    async fn network_task(mut rx: UnboundedReceiver<UiToNet>, tx: UnboundedSender<NetToUi>, rendezvous_point_address: Multiaddr) {
-        let _ = tx.send(NetToUi::Info("Starting networking...".into()));
+    // PeerId of the public rendezvous server (derived from its fixed development key)
+    const RENDEZVOUS_PEER_ID: &str = "12D3KooWDpJ7As7BWAwRMfu1VU2WCqNjvq387JEYKDBj4kx6nXTN";
+
+    // Optional behaviours and addresses, set by CLI flags
+    #[derive(Debug, Clone, Default)]
+    struct NetFeatures {
+        gossip: bool,
+        mdns: bool,
+        // Listen here instead of an ephemeral TCP port on every interface
+        listen: Option<Multiaddr>,
+        // Advertised as reachable, e.g. a port forwarded to `listen` on a static NAT
+        external: Vec<Multiaddr>,
+        // Also advertise loopback and private listen addresses to a public server
+        advertise_private: bool,
+        // Idle timeout for connections to other peers; None uses PEER_IDLE_TIMEOUT_SECS
+        peer_idle_timeout: Option<Duration>,
+    }
+
+    async fn network_task(
+        mut rx: Receiver<UiToNet>,
+        tx: Sender<NetToUi>,
+        rendezvous_point_address: Multiaddr,
+        rendezvous_point_peer_id: PeerId,
+        local_key: libp2p::identity::Keypair,
+        features: NetFeatures,
+        download_dir: std::path::PathBuf,
+    ) {
+        let _ = tx.send(NetToUi::Connection("Starting networking...".into()));
 
-        let local_key = libp2p::identity::Keypair::generate_ed25519();
     let local_peer_id = PeerId::from(local_key.public());
+    let e2e_secret = e2e_secret(&local_key);
     // Intentionally do not send local peer id to UI
+    // Largest chat/auth frame in bytes; NEUTRAL_MAX_FRAME_BYTES overrides the 1 MiB default
+    let max_frame_bytes = std::env::var("NEUTRAL_MAX_FRAME_BYTES")
+        .ok()
+        .and_then(|v| v.trim().parse().ok())
+        .unwrap_or(DEFAULT_MAX_FRAME_BYTES);
+    // Largest file sent or accepted; NEUTRAL_MAX_FILE_BYTES overrides the 100 MiB default
+    let max_file_bytes = std::env::var("NEUTRAL_MAX_FILE_BYTES")
+        .ok()
+        .and_then(|v| v.trim().parse().ok())
+        .unwrap_or(DEFAULT_MAX_FILE_BYTES);
+    // Leaves room for the CBOR framing of a Chunk request under the frame limit
+    let file_chunk_bytes = FILE_CHUNK_BYTES.min(max_frame_bytes / 2).max(1);
+    // The swarm's idle timeout applies to every connection; the server's is kept open by a
+    // keep-alive sent inside both that timeout and the server's, so only quiet peer connections close
+    let peer_idle_timeout = features.peer_idle_timeout.unwrap_or(Duration::from_secs(PEER_IDLE_TIMEOUT_SECS));
 
         let mut swarm = match libp2p::SwarmBuilder::with_existing_identity(local_key)
             .with_tokio()
             .with_tcp(
-                tcp::Config::default(),
+                // Dial from the listening port so NAT mappings created while listening are reused
+                tcp::Config::default().port_reuse(true),
                 noise::Config::new,
                 yamux::Config::default,
-            ) {
+            )
+            .map_err(|e| e.to_string())
+            // Resolves /dns addresses, e.g. a server given by host name
+            .and_then(|builder| builder.with_dns().map_err(|e| e.to_string()))
+            // Circuits through the server's relay, for peers a direct dial can't reach
+            .and_then(|builder| builder.with_relay_client(noise::Config::new, yamux::Config::default).map_err(|e| e.to_string())) {
             Ok(builder) => {
-                let builder = match builder.with_behaviour(|key| {
+                let builder = match builder.with_behaviour(|key, relay_client| {
                     let rr_cfg = request_response::Config::default()
                         .with_request_timeout(std::time::Duration::from_secs(30))
-                        .with_max_concurrent_streams(usize::MAX);
+                        // Bounded so one peer can't hold an unlimited number of partly sent frames
+                        .with_max_concurrent_streams(64);
                     let auth_cfg = request_response::Config::default()
-                        .with_request_timeout(std::time::Duration::from_secs(15))
+                        .with_request_timeout(std::time::Duration::from_secs(AUTH_TIMEOUT_SECS))
                         .with_max_concurrent_streams(16);
                     ClientBehaviour {
                         rendezvous: rendezvous::client::Behaviour::new(key.clone()),
                         ping: ping::Behaviour::new(ping::Config::default()),
                         identify: identify::Behaviour::new(identify::Config::new(
-                            "/p2p-client/1.0.0".to_string(),
+                            CLIENT_PROTOCOL_VERSION.to_string(),
                             key.public(),
                         )),
-                        request_response: request_response::Behaviour::new(
+                        request_response: request_response::Behaviour::with_codec(
+                            HelloCodec::new(max_frame_bytes),
                             std::iter::once((HelloProtocol(), request_response::ProtocolSupport::Full)),
                             rr_cfg,
                         ),
-                        auth: request_response::Behaviour::new(
+                        auth: request_response::Behaviour::with_codec(
+                            AuthCodec::new(max_frame_bytes),
                             std::iter::once((AuthProtocol(), request_response::ProtocolSupport::Full)),
                             auth_cfg,
                         ),
+                        relay: request_response::Behaviour::with_codec(
+                            RelayCodec::new(max_frame_bytes),
+                            std::iter::once((RelayProtocol(), request_response::ProtocolSupport::Full)),
+                            request_response::Config::default(),
+                        ),
+                        file: request_response::Behaviour::with_codec(
+                            FileCodec::new(max_frame_bytes),
+                            std::iter::once((FileProtocol(), request_response::ProtocolSupport::Full)),
+                            request_response::Config::default(),
+                        ),
+                        gossipsub: libp2p::swarm::behaviour::toggle::Toggle::from(if features.gossip {
+                            let gossip_cfg = gossipsub::ConfigBuilder::default()
+                                .heartbeat_interval(std::time::Duration::from_secs(1))
+                                .validation_mode(gossipsub::ValidationMode::Strict)
+                                .build()
+                                .expect("valid gossipsub config");
+                            Some(
+                                gossipsub::Behaviour::new(gossipsub::MessageAuthenticity::Signed(key.clone()), gossip_cfg)
+                                    .expect("gossipsub with signed messages"),
+                            )
+                        } else {
+                            None
+                        }),
+                        relay_client,
+                        dcutr: dcutr::Behaviour::new(key.public().to_peer_id()),
+                        mdns: libp2p::swarm::behaviour::toggle::Toggle::from(if features.mdns {
+                            mdns::tokio::Behaviour::new(mdns::Config::default(), key.public().to_peer_id())
+                                .map_err(|e| tracing::warn!("mDNS unavailable: {}", e))
+                                .ok()
+                        } else {
+                            None
+                        }),
                     }
                 }) {
                     Ok(b) => b,
                     Err(e) => { let _ = tx.send(NetToUi::Error(format!("Behaviour: {}", e))); return; }
                 };
                 builder
-                    .with_swarm_config(|c: libp2p::swarm::Config| c.with_idle_connection_timeout(std::time::Duration::from_secs(60)))
+                    .with_swarm_config(|c: libp2p::swarm::Config| c.with_idle_connection_timeout(peer_idle_timeout))
                     .build()
             }
             Err(e) => { let _ = tx.send(NetToUi::Error(format!("Transport: {}", e))); return; }
         };
 
-        if let Err(e) = swarm.listen_on("/ip4/0.0.0.0/tcp/0".parse().unwrap()) {
+        let listen = features.listen.clone().unwrap_or_else(|| "/ip4/0.0.0.0/tcp/0".parse().unwrap());
+        if let Err(e) = swarm.listen_on(listen) {
             let _ = tx.send(NetToUi::Error(format!("listen_on error: {}", e)));
         }
+        for address in &features.external {
+            tracing::info!("Advertising external address {}", address);
+            swarm.add_external_address(address.clone());
+        }
+        // Peers of a server on the same host or LAN can use our private addresses too
+        let advertise_private = features.advertise_private || !is_public_address(&rendezvous_point_address);
 
-    let rendezvous_point_peer_id = PeerId::from_str("12D3KooWDpJ7As7BWAwRMfu1VU2WCqNjvq387JEYKDBj4kx6nXTN").unwrap();
+        // Dial by PeerId so failed attempts are reported against the rendezvous server
+        let rendezvous_dial = || {
+            libp2p::swarm::dial_opts::DialOpts::peer_id(rendezvous_point_peer_id)
+                .addresses(vec![rendezvous_point_address.clone()])
+                .build()
+        };
+        let jitter = std::env::var("NEUTRAL_RECONNECT_JITTER")
+            .ok()
+            .and_then(|v| v.parse::<f64>().ok())
+            .unwrap_or(RECONNECT_JITTER);
+        let seed = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0)
+            ^ local_peer_id.to_bytes().iter().fold(0u64, |h, b| h.rotate_left(8) ^ u64::from(*b));
+        let mut backoff = ReconnectBackoff::new(jitter, seed);
+        let mut redial_at: Option<tokio::time::Instant> = None;
 
-        if let Err(e) = swarm.dial(rendezvous_point_address.clone()) {
+        if let Err(e) = swarm.dial(rendezvous_dial()) {
             let _ = tx.send(NetToUi::Error(format!("Dial rendezvous failed: {}", e)));
+            redial_at = Some(schedule_redial(&mut backoff, &tx, "Server unreachable"));
         }
 
-    let mut discovered: HashMap<PeerId, Vec<Multiaddr>> = HashMap::new();
+    // Bounded per peer; dial failures and successes below keep it pruned and ordered
+    let mut discovered = AddressBook::default();
     let mut connected: HashSet<PeerId> = HashSet::new();
+    // Peers dialed directly for UiToNet::Connect; if that fails they're retried through the relay
+    let mut direct_dials: HashSet<PeerId> = HashSet::new();
+    // Peers a Connect found undiscovered, until when we wait for a discovery to turn them up
+    let mut awaiting_discovery: HashMap<PeerId, Instant> = HashMap::new();
+    // The server's (major, minor) version from identify, or why it's unusable; None until it identifies
+    let mut server_version: Option<Result<(u32, u32), String>> = None;
+    // Peers reached through the relay, awaiting the hole punching result
+    let mut punching: HashSet<PeerId> = HashSet::new();
+    // Unanswered pings in a row per peer, reset by any answer
+    let mut ping_failures: HashMap<PeerId, u32> = HashMap::new();
+    // Peers found by mDNS on the local network, with the addresses it reported for them
+    let mut local_peers: HashMap<PeerId, Vec<Multiaddr>> = HashMap::new();
+    // Listener on our circuit address at the server's relay, so others can reach us through it
+    let mut relay_listener: Option<libp2p::core::transport::ListenerId> = None;
+    // Local TCP ports we listen on; outbound dials share them via port reuse
+    let mut listen_ports: HashSet<u16> = HashSet::new();
+    // Our address as the server last saw it, whether its relay holds a reservation for us, and
+    // the reachability last reported from those
+    let mut observed_addr: Option<Multiaddr> = None;
+    let mut relay_reserved = false;
+    let mut reachability = Reachability::Unknown;
     let mut is_registered = false;
+    // Space whose rendezvous namespace we register and discover in, and whose users LIST returns
+    let mut space: Option<String> = None;
     let mut is_authenticated = false;
+    // Token from AuthResponse::Session; sent instead of credentials once logged in
+    let mut session_token: Option<String> = None;
+    let mut pending_auth: HashMap<request_response::OutboundRequestId, PendingAuth> = HashMap::new();
+    // Chat messages sent directly but not yet acknowledged.
+    // If the peer can't be reached they are handed to the server's relay queue instead.
+    let mut pending_chats: HashMap<request_response::OutboundRequestId, OutgoingChat> = HashMap::new();
+    // Chat messages waiting until the peer is connected and identified (so we know whether to encrypt),
+    // with the deadline after which they go to the server's relay queue instead. Sent in order.
+    let mut outbox: HashMap<PeerId, (Instant, Vec<OutgoingChat>)> = HashMap::new();
+    let mut outbox_interval = tokio::time::interval(Duration::from_secs(1));
+    // Relay Store requests awaiting the server's answer, by recipient and message id
+    let mut pending_relay: HashMap<request_response::OutboundRequestId, (String, String)> = HashMap::new();
+    // Ids for delivery receipts; seeded per run so they don't repeat across restarts
+    let mut next_msg_id = seed;
+    // Last sequence number sent per recipient. Each conversation's numbers start from the send
+    // time in milliseconds, so they keep increasing across restarts too.
+    let mut last_seq: HashMap<String, u64> = HashMap::new();
+    // Identify protocol version of each peer, for features added after our major version
+    let mut peer_versions: HashMap<PeerId, String> = HashMap::new();
+    // Identity keys from identify, checked against the PeerId
+    let mut peer_keys: HashMap<PeerId, libp2p::identity::PublicKey> = HashMap::new();
+    // Messages are encrypted unless identify showed a client too old to open them
+    let sealing_cipher = |versions: &HashMap<PeerId, String>, keys: &HashMap<PeerId, libp2p::identity::PublicKey>, peer: &PeerId| {
+        if versions.get(peer).is_some_and(|version| !is_compatible_peer(version, E2E_SINCE)) {
+            return None;
+        }
+        peer_cipher(e2e_secret.as_ref(), keys, peer)
+    };
+    // Newest read receipt per peer whose version isn't known yet; sent once identify confirms support
+    let mut unsent_reads: HashMap<PeerId, String> = HashMap::new();
+    // READ: and TYPING requests, chat copies for a contact's other sessions and server keep-alives, in flight; their failures aren't reported
+    let mut pending_signals: HashSet<request_response::OutboundRequestId> = HashSet::new();
+    // File transfers by local id; an outgoing transfer uses it as its wire transfer_id too
+    let mut next_transfer: u64 = 0;
+    let mut outgoing_files: HashMap<u64, OutgoingTransfer> = HashMap::new();
+    let mut incoming_files: HashMap<(PeerId, u64), (u64, IncomingFile)> = HashMap::new();
+    let mut pending_file_requests: HashMap<request_response::OutboundRequestId, u64> = HashMap::new();
+    // Outgoing files are hashed off the swarm loop and come back here
+    let (prepared_tx, mut prepared_rx) = tokio::sync::mpsc::unbounded_channel::<PreparedFile>();
     // Reverse map of PeerId -> username for display of incoming messages
     let mut peer_to_username_net: HashMap<String, String> = HashMap::new();
+    // The same, but only as the server's directory lists it: what gossip authors are checked against
+    let mut directory_sessions: HashMap<String, String> = HashMap::new();
+    // PeerIds the server resolved the directory's handles to, with what waits for an answer
+    let mut routes: Routes<Deferred> = Routes::default();
+    let mut pending_routes: HashMap<request_response::OutboundRequestId, String> = HashMap::new();
+    // Commands whose session was just resolved, carried out before new ones from the UI
+    let mut retry: std::collections::VecDeque<UiToNet> = std::collections::VecDeque::new();
+    // Username of the pending or current session, for gossip presence
+    let mut session_username: Option<String> = None;
+    // The UI's block list; traffic from these users' sessions is dropped here
+    let mut blocked = BlockList::new();
+    let mut presence_interval = tokio::time::interval(std::time::Duration::from_secs(PRESENCE_REPUBLISH_SECS));
+    // Peers whose identify protocol version failed is_compatible_peer
+    let mut incompatible: HashSet<PeerId> = HashSet::new();
+    let min_peer_version = std::env::var("NEUTRAL_MIN_PEER_VERSION")
+        .ok()
+        .and_then(|v| parse_protocol_version(&format!("{}{}", CLIENT_PROTOCOL_PREFIX, v)))
+        .unwrap_or((parse_protocol_version(CLIENT_PROTOCOL_VERSION).expect("valid own protocol version").0, 0));
 
         // Periodic rediscovery every 5s for a more responsive UI
     let mut rediscover_interval = tokio::time::interval(std::time::Duration::from_secs(5));
-    let mut users_refresh_interval = tokio::time::interval(std::time::Duration::from_secs(5));
+    // The server pushes directory changes; polling only covers a push that got lost
+    let mut users_refresh_interval = tokio::time::interval(std::time::Duration::from_secs(USERS_POLL_SECS));
+    let mut server_keepalive_interval =
+        tokio::time::interval((peer_idle_timeout / 2).clamp(Duration::from_secs(1), Duration::from_secs(SERVER_KEEPALIVE_SECS)));
         loop {
             tokio::select! {
-                Some(cmd) = rx.recv() => {
+                cmd = async {
+                    match retry.pop_front() {
+                        Some(cmd) => Some((cmd, true)),
+                        None => rx.recv().await.map(|cmd| (cmd, false)),
+                    }
+                } => {
+                    let Some((cmd, retried)) = cmd else {
+                        // The UI dropped its sender: wait briefly for the LOGOUT it sent on the way out
+                        let deadline = tokio::time::sleep(Duration::from_secs(SHUTDOWN_FLUSH_SECS));
+                        tokio::pin!(deadline);
+                        while pending_auth.values().any(|p| *p == PendingAuth::Logout) {
+                            tokio::select! {
+                                _ = &mut deadline => {
+                                    tracing::warn!("Logout not acknowledged before shutdown");
+                                    break;
+                                }
+                                event = swarm.select_next_some() => {
+                                    if let SwarmEvent::Behaviour(ClientBehaviourEvent::Auth(
+                                        request_response::Event::Message { message: request_response::Message::Response { request_id, .. }, .. }
+                                        | request_response::Event::OutboundFailure { request_id, .. },
+                                    )) = event
+                                    {
+                                        pending_auth.remove(&request_id);
+                                    }
+                                }
+                            }
+                        }
+                        tracing::info!("Network task stopped");
+                        return;
+                    };
+                    // A session the directory lists by handle is resolved first; the command comes back through `retry`
+                    if !retried
+                        && let Some(session) = cmd.session()
+                        && routes.peer(session).is_none()
+                        && directory_sessions.contains_key(session)
+                        && !routes.full()
+                        && let Some(token) = &session_token
+                    {
+                        let session = session.to_string();
+                        resolve_session(&mut swarm, rendezvous_point_peer_id, token, &session, &mut routes, &mut pending_routes);
+                        routes.wait(vec![session], Deferred::Command(cmd));
+                        continue;
+                    }
                     match cmd {
                         UiToNet::Connect { peer_id } => {
-                            if let Ok(peer) = PeerId::from_str(&peer_id) {
-                                if peer == rendezvous_point_peer_id { let _=tx.send(NetToUi::Info("Cannot connect to rendezvous server".into())); continue; }
-                                if let Some(addrs) = discovered.get(&peer) {
-                                    for addr in addrs {
-                                        // Feed address to swarm peer address book and dial
-                                        swarm.add_peer_address(peer, addr.clone());
-                                        let _=swarm.dial(addr.clone());
+                            let Some(peer) = routes.peer(&peer_id) else {
+                                let _ = tx.send(NetToUi::Info(format!("{} can't be reached right now", display_name(&peer_to_username_net, &peer_id))));
+                                continue;
+                            };
+                            if peer == rendezvous_point_peer_id { let _=tx.send(NetToUi::Info("Cannot connect to rendezvous server".into())); continue; }
+                            if discovered.contains(&peer) {
+                                // One dial by PeerId, so a failure names the peer and can fall back to the relay
+                                if !swarm.is_connected(&peer) {
+                                    if dial_discovered(&mut swarm, &discovered, peer) {
+                                        direct_dials.insert(peer);
+                                    } else {
+                                        let _ = tx.send(NetToUi::Info("No working address for peer yet, waiting for rediscovery".into()));
                                     }
-                                } else { let _=tx.send(NetToUi::Info("Peer not discovered yet".into())); }
-                            } else { let _=tx.send(NetToUi::Error("Invalid PeerId".into())); }
-                        }
-                        UiToNet::Write { peer_id, from_username, to_username, msg } => {
-                            if let Ok(peer) = PeerId::from_str(&peer_id) {
-                                if !connected.contains(&peer) {
-                                    if let Some(addrs) = discovered.get(&peer) { for addr in addrs { let _=swarm.dial(addr.clone()); } }
-                                }
-                                // Echo to local chat window immediately
-                                let _ = tx.send(NetToUi::ChatMessage {
-                                    peer: to_username.clone(),
-                                    direction: MessageDirection::Outgoing,
-                                    text: msg.clone(),
-                                });
-                                // Wrap the message with the sender's username so the receiver can always display name
-                                let payload = format!("MSG:{}|{}", from_username, msg);
-                                swarm.behaviour_mut().request_response.send_request(&peer, payload);
-                            } else { let _=tx.send(NetToUi::Error("Invalid PeerId".into())); }
+                                }
+                            } else if swarm.is_connected(&rendezvous_point_peer_id) {
+                                // Ask now rather than at the next rediscovery tick; the dial follows the results
+                                awaiting_discovery.insert(peer, Instant::now() + Duration::from_secs(DISCOVERY_WAIT_SECS));
+                                swarm.behaviour_mut().rendezvous.discover(Some(space_namespace(space.as_deref())), None, None, rendezvous_point_peer_id);
+                                let request = AuthRequest::Discover { namespace: space_namespace(space.as_deref()).to_string() };
+                                pending_auth.insert(swarm.behaviour_mut().auth.send_request(&rendezvous_point_peer_id, request), PendingAuth::Discover);
+                            } else {
+                                let _ = tx.send(NetToUi::Info(format!("{} can't be looked up while the server is unreachable", display_name(&peer_to_username_net, &peer_id))));
+                            }
+                        }
+                        UiToNet::Write { peer_id, from_username, to_username, msg, forwarded, resend } => {
+                            let route = routes.peer(&peer_id);
+                            if route.is_some_and(|peer| incompatible.contains(&peer)) {
+                                // It would misread our frames; refuse rather than corrupt the chat
+                                let _ = tx.send(NetToUi::Error(format!("{} uses an incompatible protocol version", to_username)));
+                                continue;
+                            }
+                            // Lets their read receipts be matched to the conversation
+                            peer_to_username_net.insert(peer_id.clone(), to_username.clone());
+                            let (msg_id, sent_at) = match resend {
+                                Some(original) => original,
+                                None => {
+                                    // Echo to local chat window immediately
+                                    let sent_at = message_time_now();
+                                    next_msg_id = next_msg_id.wrapping_add(1);
+                                    let msg_id = format!("{:016x}", next_msg_id);
+                                    let _ = tx.send(NetToUi::ChatMessage {
+                                        peer: to_username.clone(),
+                                        direction: MessageDirection::Outgoing,
+                                        text: msg.clone(),
+                                        forwarded,
+                                        timestamp: sent_at,
+                                        msg_id: Some(msg_id.clone()),
+                                        seq: None,
+                                    });
+                                    (msg_id, sent_at)
+                                }
+                            };
+                            // A retry gets a fresh number too: the recipient never saw the failed attempt
+                            let last = last_seq.entry(to_username.clone()).or_insert(0);
+                            *last = (*last + 1).max(unix_ms(message_time_now()));
+                            let chat = OutgoingChat { from: from_username, to: to_username, text: msg, forwarded, sent_at, msg_id, seq: *last };
+                            let Some(peer) = route else {
+                                // The server couldn't say where the session is; the bubble can be retried
+                                let _ = tx.send(NetToUi::DeliveryFailed { peer: chat.to.clone(), msg_id: chat.msg_id });
+                                let _ = tx.send(NetToUi::Error(format!("{} can't be reached right now", chat.to)));
+                                continue;
+                            };
+                            // The recipient's other sessions get a best-effort copy over live connections;
+                            // delivery is tracked, and falls back to the relay, for `peer` only
+                            let copies: Vec<PeerId> = peer_to_username_net
+                                .iter()
+                                .filter(|(session, name)| **name == chat.to && **session != peer_id)
+                                .filter_map(|(session, _)| routes.peer(session))
+                                .filter(|pid| connected.contains(pid) && peer_versions.contains_key(pid) && !incompatible.contains(pid))
+                                .collect();
+                            for copy in copies {
+                                let payload = chat.payload(sealing_cipher(&peer_versions, &peer_keys, &copy).as_ref());
+                                pending_signals.insert(swarm.behaviour_mut().request_response.send_request(&copy, payload));
+                            }
+                            if connected.contains(&peer) && peer_versions.contains_key(&peer) {
+                                let payload = chat.payload(sealing_cipher(&peer_versions, &peer_keys, &peer).as_ref());
+                                pending_chats.insert(swarm.behaviour_mut().request_response.send_request(&peer, payload), chat);
+                            } else if !connected.contains(&peer) && !dial_discovered(&mut swarm, &discovered, peer) {
+                                // No address to try, so no point waiting for a connection
+                                let cipher = sealing_cipher(&peer_versions, &peer_keys, &peer);
+                                let token = session_token.as_ref();
+                                relay_or_fail(&mut swarm, rendezvous_point_peer_id, token, chat, cipher.as_ref(), &mut pending_relay, &tx);
+                            } else {
+                                let _ = tx.send(NetToUi::Queued { peer: chat.to.clone(), msg_id: chat.msg_id.clone(), queued: true });
+                                let deadline = Instant::now() + Duration::from_secs(CHAT_CONNECT_TIMEOUT_SECS);
+                                outbox.entry(peer).or_insert_with(|| (deadline, Vec::new())).1.push(chat);
+                            }
+                        }
+                        UiToNet::RoomMessage { room, from_username, text } => {
+                            let Some(gossipsub) = swarm.behaviour_mut().gossipsub.as_mut() else {
+                                let _ = tx.send(NetToUi::Error("Rooms need --gossip".into()));
+                                continue;
+                            };
+                            let sent_at = message_time_now();
+                            let payload = server::wire::join(&[&from_username, &text, &unix_ms(sent_at).to_string()]);
+                            match gossipsub.publish(room_topic(&room), payload.into_bytes()) {
+                                Ok(_) => {
+                                    // Gossipsub doesn't deliver our own messages back; echo locally
+                                    let _ = tx.send(NetToUi::RoomMessage {
+                                        room,
+                                        from: from_username,
+                                        direction: MessageDirection::Outgoing,
+                                        text,
+                                        timestamp: sent_at,
+                                    });
+                                }
+                                Err(e) => {
+                                    let _ = tx.send(NetToUi::Error(format!("Room message not sent: {}", e)));
+                                }
+                            }
+                        }
+                        UiToNet::Read { peer_id, msg_id } => {
+                            let Some(peer) = routes.peer(&peer_id) else { continue };
+                            if peer_supports(&peer_versions, &peer, READ_RECEIPTS_SINCE) {
+                                pending_signals.insert(swarm.behaviour_mut().request_response.send_request(&peer, format!("READ:{}", msg_id)));
+                            } else if !peer_versions.contains_key(&peer) {
+                                unsent_reads.insert(peer, msg_id);
+                            }
+                        }
+                        UiToNet::Typing { peer_id } => {
+                            // Only worth sending over a live connection; it would be stale after a dial
+                            if let Some(peer) = routes.peer(&peer_id)
+                                && connected.contains(&peer)
+                                && peer_supports(&peer_versions, &peer, TYPING_SINCE)
+                            {
+                                pending_signals.insert(swarm.behaviour_mut().request_response.send_request(&peer, "TYPING".to_string()));
+                            }
+                        }
+                        UiToNet::Register { .. } | UiToNet::Login { .. } if matches!(server_version, Some(Err(_))) => {
+                            let failure = AuthFailure::Unreachable(server_version.clone().and_then(Result::err).unwrap_or_default());
+                            let event = if matches!(cmd, UiToNet::Register { .. }) {
+                                NetToUi::Registered(Err(failure))
+                            } else {
+                                NetToUi::AuthResult(Err(failure))
+                            };
+                            let _ = tx.send(event);
                         }
                         UiToNet::Register { username, password, birthdate } => {
-                            let payload = format!("REGISTER:{}|{}|{}", username, password, birthdate);
-                            swarm.behaviour_mut().auth.send_request(&rendezvous_point_peer_id, payload);
+                            session_username = Some(username.clone());
+                            let request = AuthRequest::Register { username, password, birthdate };
+                            pending_auth.insert(swarm.behaviour_mut().auth.send_request(&rendezvous_point_peer_id, request), PendingAuth::Register);
                         }
                         UiToNet::Login { username, password } => {
-                            let payload = format!("LOGIN:{}|{}", username, password);
-                            swarm.behaviour_mut().auth.send_request(&rendezvous_point_peer_id, payload);
+                            session_username = Some(username.clone());
+                            let request = AuthRequest::Login { username, password };
+                            pending_auth.insert(swarm.behaviour_mut().auth.send_request(&rendezvous_point_peer_id, request), PendingAuth::Login);
+                        }
+                        UiToNet::Logout { username, token } => {
+                            if let Some(gossipsub) = swarm.behaviour_mut().gossipsub.as_mut() {
+                                let _ = gossipsub.publish(presence_topic(), format!("OFFLINE:{}", username).into_bytes());
+                                let _ = gossipsub.unsubscribe(&presence_topic());
+                                let _ = gossipsub.unsubscribe(&room_topic(DEFAULT_ROOM));
+                            }
+                            session_username = None;
+                            session_token = None;
+                            is_authenticated = false;
+                            if space.is_some() {
+                                move_space(&mut swarm, rendezvous_point_peer_id, &mut space, None, &mut is_registered);
+                            }
+                            let request = AuthRequest::Logout { token };
+                            pending_auth.insert(swarm.behaviour_mut().auth.send_request(&rendezvous_point_peer_id, request), PendingAuth::Logout);
+                        }
+                        UiToNet::JoinSpace { space: next } => {
+                            if next.is_some() && matches!(server_version, Some(Ok(version)) if version < SPACES_SINCE) {
+                                tracing::warn!("Not joining space {:?}: the server predates spaces", next);
+                            } else if next != space {
+                                move_space(&mut swarm, rendezvous_point_peer_id, &mut space, next, &mut is_registered);
+                                if is_authenticated && let Some(token) = &session_token {
+                                    let request = AuthRequest::List { token: token.clone(), space: space.clone(), last_seen: true };
+                                    pending_auth.insert(swarm.behaviour_mut().auth.send_request(&rendezvous_point_peer_id, request), PendingAuth::List);
+                                }
+                            }
+                        }
+                        UiToNet::Blocked(list) => {
+                            blocked = list;
+                            let stopped: Vec<(PeerId, u64)> = incoming_files
+                                .keys()
+                                .filter(|(peer, _)| is_blocked_peer(&blocked, &directory_sessions, &routes.session(peer)))
+                                .copied()
+                                .collect();
+                            for key in stopped {
+                                if let Some((id, file)) = incoming_files.remove(&key) {
+                                    file.abort();
+                                    let _ = tx.send(NetToUi::FileFailed { id, reason: "Sender blocked".to_string() });
+                                }
+                            }
                         }
-                        UiToNet::Logout { username } => {
-                            let payload = format!("LOGOUT:{}", username);
-                            let _ = swarm.behaviour_mut().auth.send_request(&rendezvous_point_peer_id, payload);
+                        UiToNet::ChangePassword { username, old_password, new_password } => {
+                            let request = AuthRequest::ChangePassword { username, old_password, new_password };
+                            pending_auth.insert(swarm.behaviour_mut().auth.send_request(&rendezvous_point_peer_id, request), PendingAuth::ChangePassword);
                         }
-                        UiToNet::DeleteAccount { username, password } => {
-                            let payload = format!("DELETE:{}|{}", username, password);
-                            let _ = swarm.behaviour_mut().auth.send_request(&rendezvous_point_peer_id, payload);
+                        UiToNet::DeleteAccount { token } => {
+                            let request = AuthRequest::Delete { token };
+                            pending_auth.insert(swarm.behaviour_mut().auth.send_request(&rendezvous_point_peer_id, request), PendingAuth::Delete);
+                        }
+                        UiToNet::SendFile { peer_id, to_username, path } => {
+                            let Some(peer) = routes.peer(&peer_id) else {
+                                let _ = tx.send(NetToUi::Error(format!("{} can't be reached right now", to_username)));
+                                continue;
+                            };
+                            if !connected.contains(&peer) {
+                                dial_discovered(&mut swarm, &discovered, peer);
+                            }
+                            next_transfer += 1;
+                            let (id, prepared_tx) = (next_transfer, prepared_tx.clone());
+                            tokio::task::spawn_blocking(move || {
+                                let file = OutgoingFile::open(&path, max_file_bytes);
+                                let _ = prepared_tx.send(PreparedFile { id, peer, to_username, file });
+                            });
                         }
                     }
                 }
+                Some(prepared) = prepared_rx.recv() => match prepared.file {
+                    Ok(file) => {
+                        let _ = tx.send(NetToUi::FileTransfer {
+                            id: prepared.id,
+                            peer: prepared.to_username,
+                            filename: file.filename.clone(),
+                            size: file.size,
+                            outgoing: true,
+                        });
+                        let offer = FileRequest::Offer {
+                            transfer_id: prepared.id,
+                            filename: file.filename.clone(),
+                            size: file.size,
+                            sha256: file.sha256.clone(),
+                        };
+                        pending_file_requests.insert(swarm.behaviour_mut().file.send_request(&prepared.peer, offer), prepared.id);
+                        outgoing_files.insert(prepared.id, OutgoingTransfer { peer: prepared.peer, file, finishing: false });
+                    }
+                    Err(e) => {
+                        let _ = tx.send(NetToUi::Error(format!("File not sent: {}", e)));
+                    }
+                },
                 event = swarm.select_next_some() => {
+                    // Per-address dial results, so dead addresses stop being dialed
+                    match &event {
+                        SwarmEvent::ConnectionEstablished { peer_id, endpoint: libp2p::core::ConnectedPoint::Dialer { address, .. }, .. } => {
+                            discovered.dial_succeeded(peer_id, address);
+                        }
+                        SwarmEvent::OutgoingConnectionError { peer_id: Some(peer_id), error: libp2p::swarm::DialError::Transport(failed), .. } => {
+                            for (address, _) in failed {
+                                discovered.dial_failed(peer_id, address);
+                            }
+                        }
+                        _ => {}
+                    }
                     match event {
                         SwarmEvent::NewListenAddr { address, .. } => {
                             tracing::info!("Local node is listening on {}", address);
-                            swarm.add_external_address(address);
+                            // A circuit address carries the relay's port, not ours
+                            let relayed = address.iter().any(|p| p == libp2p::multiaddr::Protocol::P2pCircuit);
+                            if !relayed && let Some(port) = tcp_port(&address) {
+                                listen_ports.insert(port);
+                            }
+                            if advertise_private || is_public_address(&address) {
+                                swarm.add_external_address(address);
+                            } else {
+                                tracing::debug!("Not advertising private address {}", address);
+                            }
                         }
                         SwarmEvent::ConnectionEstablished { peer_id, endpoint, .. } => {
                             tracing::info!("Connected to {} on {:?}", peer_id, endpoint.get_remote_address());
+                            if peer_id == rendezvous_point_peer_id {
+                                backoff.reset();
+                                redial_at = None;
+                                let _ = tx.send(NetToUi::Connection("Connected to server".into()));
+                                // Back after a drop: a LIST resumes the session if the server still has it
+                                if !is_authenticated && let Some(token) = &session_token {
+                                    let request = AuthRequest::List { token: token.clone(), space: space.clone(), last_seen: true };
+                                    pending_auth.insert(swarm.behaviour_mut().auth.send_request(&rendezvous_point_peer_id, request), PendingAuth::List);
+                                }
+                            }
+                            direct_dials.remove(&peer_id);
                             connected.insert(peer_id);
-                            let _ = tx.send(NetToUi::Connected(peer_id.to_string()));
+                            let _ = tx.send(NetToUi::Connected(routes.session(&peer_id)));
                         }
-                        SwarmEvent::ConnectionClosed { peer_id, .. } => {
-                            tracing::info!("Disconnected from {}", peer_id);
+                        SwarmEvent::ConnectionClosed { peer_id, num_established, cause, .. } => {
+                            tracing::info!("Disconnected from {} ({:?})", peer_id, cause);
                             connected.remove(&peer_id);
-                            let _ = tx.send(NetToUi::Disconnected(peer_id.to_string()));
+                            // Transfers need the connection throughout; a dropped peer ends them
+                            if num_established == 0 {
+                                let (dropped, kept): (Vec<_>, Vec<_>) = incoming_files.drain().partition(|((from, _), _)| *from == peer_id);
+                                incoming_files.extend(kept);
+                                for (_, (id, file)) in dropped {
+                                    file.abort();
+                                    let _ = tx.send(NetToUi::FileFailed { id, reason: "Sender disconnected".to_string() });
+                                }
+                                outgoing_files.retain(|id, transfer| {
+                                    let keep = transfer.peer != peer_id;
+                                    if !keep {
+                                        let _ = tx.send(NetToUi::FileFailed { id: *id, reason: "Recipient disconnected".to_string() });
+                                    }
+                                    keep
+                                });
+                            }
+                            if num_established == 0 {
+                                ping_failures.remove(&peer_id);
+                                let _ = tx.send(NetToUi::Disconnected {
+                                    peer: routes.session(&peer_id),
+                                    reason: DisconnectReason::from_cause(cause.as_ref()),
+                                    server: peer_id == rendezvous_point_peer_id,
+                                });
+                            }
                             // If this was the rendezvous server, clear our user list (will repopulate if we reconnect)
                             if peer_id == rendezvous_point_peer_id {
-                                let _ = tx.send(NetToUi::Users(HashMap::new()));
+                                let _ = tx.send(NetToUi::Users(HashMap::new(), HashMap::new()));
                                 peer_to_username_net.clear();
+                                directory_sessions.clear();
+                                if num_established == 0 {
+                                    // Register, rediscover and resume the session once the server is back
+                                    is_registered = false;
+                                    is_authenticated = false;
+                                    redial_at = Some(schedule_redial(&mut backoff, &tx, "Server connection lost"));
+                                }
+                            }
+                        }
+                        SwarmEvent::OutgoingConnectionError { peer_id: Some(peer_id), error, .. }
+                            if peer_id == rendezvous_point_peer_id && !swarm.is_connected(&peer_id) =>
+                        {
+                            tracing::warn!("Rendezvous dial failed: {}", error);
+                            redial_at = Some(schedule_redial(&mut backoff, &tx, "Server unreachable"));
+                        }
+                        SwarmEvent::OutgoingConnectionError { peer_id: Some(peer_id), error, .. }
+                            if direct_dials.contains(&peer_id) && !swarm.is_connected(&peer_id) && !discovered.relay() =>
+                        {
+                            direct_dials.remove(&peer_id);
+                            // No relay to fall back on; messages still reach them through the server's queue
+                            tracing::info!("Direct dial to {} failed ({}) and the server doesn't relay", peer_id, error);
+                            let name = display_name(&peer_to_username_net, &routes.session(&peer_id));
+                            let _ = tx.send(NetToUi::Info(format!("No direct route to {}", name)));
+                        }
+                        SwarmEvent::OutgoingConnectionError { peer_id: Some(peer_id), error, .. }
+                            if direct_dials.contains(&peer_id) && !swarm.is_connected(&peer_id) =>
+                        {
+                            direct_dials.remove(&peer_id);
+                            // Likely both sides are behind NAT: go through the server's relay, where DCUtR
+                            // then tries to punch a direct connection
+                            tracing::info!("Direct dial to {} failed ({}), trying the relay", peer_id, error);
+                            let name = display_name(&peer_to_username_net, &routes.session(&peer_id));
+                            let circuit = rendezvous_point_address
+                                .clone()
+                                .with(libp2p::multiaddr::Protocol::P2p(rendezvous_point_peer_id))
+                                .with(libp2p::multiaddr::Protocol::P2pCircuit)
+                                .with(libp2p::multiaddr::Protocol::P2p(peer_id));
+                            match swarm.dial(circuit) {
+                                Ok(()) => {
+                                    punching.insert(peer_id);
+                                    let _ = tx.send(NetToUi::Info(format!("No direct route to {}, connecting through the server...", name)));
+                                }
+                                Err(e) => {
+                                    let _ = tx.send(NetToUi::Error(format!("Could not connect to {}: {}", name, e)));
+                                }
+                            }
+                        }
+                        SwarmEvent::OutgoingConnectionError { peer_id: Some(peer_id), error, .. }
+                            if punching.contains(&peer_id) && !swarm.is_connected(&peer_id) =>
+                        {
+                            punching.remove(&peer_id);
+                            let name = display_name(&peer_to_username_net, &routes.session(&peer_id));
+                            tracing::warn!("Relayed dial to {} failed: {}", peer_id, error);
+                            let _ = tx.send(NetToUi::Error(format!("Could not reach {} directly or through the server", name)));
+                        }
+                        SwarmEvent::Behaviour(ClientBehaviourEvent::Ping(ping::Event { peer, result, .. })) => match result {
+                            Ok(rtt) => {
+                                ping_failures.remove(&peer);
+                                let _ = tx.send(NetToUi::Rtt { peer: routes.session(&peer), millis: rtt.as_millis() as u64 });
+                            }
+                            // Peers that don't speak ping just get no latency shown
+                            Err(ping::Failure::Unsupported) => {}
+                            Err(e) => {
+                                let failures = ping_failures.entry(peer).or_default();
+                                *failures += 1;
+                                tracing::debug!("Ping to {} failed ({} in a row): {}", peer, failures, e);
+                                if *failures == PING_DEGRADED_AFTER {
+                                    let _ = tx.send(NetToUi::LinkDegraded(routes.session(&peer)));
+                                }
+                            }
+                        },
+                        SwarmEvent::Behaviour(ClientBehaviourEvent::Dcutr(dcutr::Event { remote_peer_id, result })) => {
+                            // Only peers we asked to reach are reported; the other side just logs
+                            let asked = punching.remove(&remote_peer_id);
+                            let name = display_name(&peer_to_username_net, &routes.session(&remote_peer_id));
+                            match result {
+                                Ok(_) => {
+                                    tracing::info!("Hole punched a direct connection to {}", remote_peer_id);
+                                    if asked {
+                                        let _ = tx.send(NetToUi::Info(format!("Direct connection to {} established", name)));
+                                    }
+                                }
+                                Err(e) => {
+                                    tracing::warn!("Hole punching to {} failed: {}", remote_peer_id, e);
+                                    if asked {
+                                        let _ = tx.send(NetToUi::Error(format!("No direct path to {}; chatting through the server relay", name)));
+                                    }
+                                }
+                            }
+                        }
+                        SwarmEvent::Behaviour(ClientBehaviourEvent::RelayClient(relay::client::Event::ReservationReqAccepted { renewal: false, .. })) => {
+                            // Register again so peers discover our circuit address too
+                            tracing::info!("Reachable through the server's relay");
+                            relay_reserved = true;
+                            let next = Reachability::assess(observed_addr.as_ref(), &listen_ports, !features.external.is_empty(), relay_reserved);
+                            report_reachability(&tx, &mut reachability, next);
+                            if let Err(e) = swarm.behaviour_mut().rendezvous.register(
+                                space_namespace(space.as_deref()),
+                                rendezvous_point_peer_id,
+                                None,
+                            ) {
+                                tracing::error!("Failed to send registration request: {:?}", e);
                             }
                         }
+                        SwarmEvent::ListenerClosed { listener_id, reason, .. } if relay_listener == Some(listener_id) => {
+                            tracing::info!("Relay listener closed: {:?}", reason);
+                            relay_listener = None;
+                            relay_reserved = false;
+                            let next = Reachability::assess(observed_addr.as_ref(), &listen_ports, !features.external.is_empty(), relay_reserved);
+                            report_reachability(&tx, &mut reachability, next);
+                        }
                         SwarmEvent::Behaviour(ClientBehaviourEvent::Identify(identify::Event::Received { peer_id, info, })) => {
                             tracing::info!("Received identify info from {}: observed address {:?}", peer_id, info.observed_addr);
-                            if peer_id == rendezvous_point_peer_id && !is_registered {
-                                if let Err(e) = swarm.behaviour_mut().rendezvous.register(
-                                    rendezvous::Namespace::new(RENDEZVOUS_NAMESPACE.to_string()).unwrap(),
+                            if local_peers.contains_key(&peer_id) {
+                                let _ = tx.send(NetToUi::Nearby {
+                                    peer: routes.session(&peer_id),
+                                    version: info.protocol_version.clone(),
+                                    agent: info.agent_version.clone(),
+                                });
+                            }
+                            if peer_id != rendezvous_point_peer_id {
+                                peer_versions.insert(peer_id, info.protocol_version.clone());
+                                if info.public_key.to_peer_id() == peer_id {
+                                    peer_keys.insert(peer_id, info.public_key.clone());
+                                }
+                                if let Some(msg_id) = unsent_reads.remove(&peer_id)
+                                    && peer_supports(&peer_versions, &peer_id, READ_RECEIPTS_SINCE)
+                                {
+                                    pending_signals.insert(swarm.behaviour_mut().request_response.send_request(&peer_id, format!("READ:{}", msg_id)));
+                                }
+                                let compatible = is_compatible_peer(&info.protocol_version, min_peer_version);
+                                let changed = if compatible { incompatible.remove(&peer_id) } else { incompatible.insert(peer_id) };
+                                if changed {
+                                    if !compatible {
+                                        tracing::warn!("Peer {} advertises incompatible protocol {}", peer_id, info.protocol_version);
+                                    }
+                                    let _ = tx.send(NetToUi::PeerCompatibility {
+                                        peer: routes.session(&peer_id),
+                                        compatible,
+                                        version: info.protocol_version.clone(),
+                                    });
+                                }
+                                // Now we know whether to encrypt: send what waited for this peer, in order
+                                if let Some((_, queued)) = outbox.remove(&peer_id) {
+                                    let cipher = sealing_cipher(&peer_versions, &peer_keys, &peer_id);
+                                    for chat in queued {
+                                        let _ = tx.send(NetToUi::Queued { peer: chat.to.clone(), msg_id: chat.msg_id.clone(), queued: false });
+                                        if !compatible {
+                                            let _ = tx.send(NetToUi::DeliveryFailed { peer: chat.to, msg_id: chat.msg_id });
+                                            continue;
+                                        }
+                                        let payload = chat.payload(cipher.as_ref());
+                                        pending_chats.insert(swarm.behaviour_mut().request_response.send_request(&peer_id, payload), chat);
+                                    }
+                                }
+                            }
+                            if peer_id == rendezvous_point_peer_id {
+                                // Circuit addresses of other peers are only worth keeping while the server relays
+                                discovered.set_relay(info.protocols.contains(&relay::HOP_PROTOCOL_NAME));
+                                observed_addr = Some(info.observed_addr.clone());
+                                let next = Reachability::assess(observed_addr.as_ref(), &listen_ports, !features.external.is_empty(), relay_reserved);
+                                report_reachability(&tx, &mut reachability, next);
+                                let speaks_auth = info.protocols.iter().any(|p| p.as_ref() == AuthProtocol().as_ref());
+                                let checked = server_compatibility(&info.protocol_version, speaks_auth);
+                                if server_version.as_ref() != Some(&checked) {
+                                    match &checked {
+                                        Ok(version) => {
+                                            let _ = tx.send(NetToUi::ServerVersion {
+                                                version: info.protocol_version.clone(),
+                                                spaces: *version >= SPACES_SINCE,
+                                            });
+                                        }
+                                        Err(reason) => {
+                                            tracing::warn!("{}", reason);
+                                            let _ = tx.send(NetToUi::Error(reason.clone()));
+                                        }
+                                    }
+                                }
+                                server_version = Some(checked);
+                            }
+                            if peer_id == rendezvous_point_peer_id
+                                && relay_listener.is_none()
+                                && info.protocols.contains(&relay::HOP_PROTOCOL_NAME)
+                            {
+                                let circuit = rendezvous_point_address
+                                    .clone()
+                                    .with(libp2p::multiaddr::Protocol::P2p(rendezvous_point_peer_id))
+                                    .with(libp2p::multiaddr::Protocol::P2pCircuit);
+                                match swarm.listen_on(circuit) {
+                                    Ok(id) => relay_listener = Some(id),
+                                    Err(e) => tracing::warn!("Could not listen through the server's relay: {}", e),
+                                }
+                            }
+                            if peer_id == rendezvous_point_peer_id
+                                && !is_registered
+                                && let Err(e) = swarm.behaviour_mut().rendezvous.register(
+                                    space_namespace(space.as_deref()),
                                     rendezvous_point_peer_id,
                                     None,
-                                ) {
-                                    tracing::error!("Failed to send registration request: {:?}", e);
-                                }
+                                )
+                            {
+                                tracing::error!("Failed to send registration request: {:?}", e);
                             }
                         }
                         SwarmEvent::Behaviour(ClientBehaviourEvent::Rendezvous(rendezvous::client::Event::Registered { .. })) => {
+                            if !is_registered {
+                                // Ask for records the server restored from disk; peers that haven't
+                                // re-registered since a server restart are missing from rendezvous discovery.
+                                let request = AuthRequest::Discover { namespace: space_namespace(space.as_deref()).to_string() };
+                                pending_auth.insert(swarm.behaviour_mut().auth.send_request(&rendezvous_point_peer_id, request), PendingAuth::Discover);
+                            }
                             is_registered = true;
-                            let _ = swarm.behaviour_mut().rendezvous.discover(
-                                Some(rendezvous::Namespace::new(RENDEZVOUS_NAMESPACE.to_string()).unwrap()),
+                            swarm.behaviour_mut().rendezvous.discover(
+                                Some(space_namespace(space.as_deref())),
                                 None,
                                 None,
                                 rendezvous_point_peer_id
@@ -1181,146 +4975,920 @@ use eframe::egui;
                             for registration in registrations {
                                 let discovered_peer = registration.record.peer_id();
                                 if discovered_peer == local_peer_id { continue; }
-                                let entry = discovered.entry(discovered_peer).or_default();
                                 for address in registration.record.addresses() {
-                                    if !entry.contains(address) {
-                                        entry.push(address.clone());
+                                    if discovered.insert(discovered_peer, address.clone()) {
                                         swarm.add_peer_address(discovered_peer, address.clone());
                                     }
                                 }
                             }
-                            let list: Vec<String> = discovered.keys().map(|p| p.to_string()).collect();
+                            resume_connects(&mut swarm, &discovered, &mut awaiting_discovery, &mut direct_dials);
+                            let list: Vec<String> = discovered.peers().map(|p| p.to_string()).collect();
                             let _ = tx.send(NetToUi::Discovered(list));
                         }
+                        // Peers on the local network; merged into `discovered` like rendezvous records
+                        SwarmEvent::Behaviour(ClientBehaviourEvent::Mdns(mdns::Event::Discovered(found))) => {
+                            for (peer, address) in found {
+                                if peer == local_peer_id || peer == rendezvous_point_peer_id { continue; }
+                                if discovered.insert(peer, address.clone()) {
+                                    swarm.add_peer_address(peer, address.clone());
+                                }
+                                let local = local_peers.entry(peer).or_default();
+                                if !local.contains(&address) {
+                                    local.push(address);
+                                }
+                                // Connect so identify tells us what the peer runs
+                                if !swarm.is_connected(&peer) {
+                                    dial_discovered(&mut swarm, &discovered, peer);
+                                }
+                            }
+                            let list: Vec<String> = discovered.peers().map(|p| p.to_string()).collect();
+                            let _ = tx.send(NetToUi::Discovered(list));
+                        }
+                        SwarmEvent::Behaviour(ClientBehaviourEvent::Mdns(mdns::Event::Expired(gone))) => {
+                            for (peer, address) in gone {
+                                let Some(local) = local_peers.get_mut(&peer) else { continue };
+                                local.retain(|a| *a != address);
+                                discovered.remove(&peer, &address);
+                                if local.is_empty() {
+                                    local_peers.remove(&peer);
+                                    let _ = tx.send(NetToUi::NearbyGone(routes.session(&peer)));
+                                }
+                            }
+                        }
+                        // Gossip presence and room messages
+                        SwarmEvent::Behaviour(ClientBehaviourEvent::Gossipsub(gossipsub::Event::Message { message, .. })) => {
+                            let Some(source) = message.source else { continue };
+                            let Ok(payload) = String::from_utf8(message.data) else { continue };
+                            let topic = message.topic.into_string();
+                            let Err(claimed) = deliver_gossip(&tx, &directory_sessions, &routes.session(&source), &topic, &payload, space.is_some()) else {
+                                continue;
+                            };
+                            // The author may be a session of the claimed name we haven't resolved yet: ask, then check again
+                            let unresolved: Vec<String> = directory_sessions
+                                .iter()
+                                .filter(|(session, name)| username_key(name) == username_key(&claimed) && routes.peer(session).is_none())
+                                .map(|(session, _)| session.clone())
+                                .collect();
+                            match &session_token {
+                                Some(token) if !unresolved.is_empty() && !routes.full() => {
+                                    for session in &unresolved {
+                                        resolve_session(&mut swarm, rendezvous_point_peer_id, token, session, &mut routes, &mut pending_routes);
+                                    }
+                                    routes.wait(unresolved, Deferred::Gossip { source, topic, payload });
+                                }
+                                _ => tracing::debug!("Ignoring gossip signed as '{}' from {}, not one of its sessions", claimed, source),
+                            }
+                        }
                         // Chat RequestResponse
                         SwarmEvent::Behaviour(ClientBehaviourEvent::RequestResponse(event)) => match event {
                             request_response::Event::Message { peer, message } => {
                                 match message {
+                                    // Chat, typing and read receipts from a blocked user's sessions: a plain reply, so
+                                    // the sender doesn't fall back to the relay, but no receipt
+                                    request_response::Message::Request { channel, .. } if is_blocked_peer(&blocked, &directory_sessions, &routes.session(&peer)) => {
+                                        tracing::debug!("Dropped a request from blocked peer {}", peer);
+                                        let _ = swarm.behaviour_mut().request_response.send_response(channel, "ok".to_string());
+                                    }
                                     request_response::Message::Request { request, channel, .. } => {
-                                        let request_str = request.to_string();
-                                        // Try to parse embedded username: format "MSG:<from_username>|<text>"
-                                        if let Some(rest) = request_str.strip_prefix("MSG:") {
-                                            if let Some((from_name, text)) = rest.split_once('|') {
+                                        let request_str = match request.strip_prefix("ENC:") {
+                                            Some(sealed) => {
+                                                let opened = peer_cipher(e2e_secret.as_ref(), &peer_keys, &peer)
+                                                    .zip(session_username.as_ref())
+                                                    .and_then(|(cipher, me)| e2e_open(&cipher, sealed, me));
+                                                let Some(opened) = opened else {
+                                                    let from_label = peer_to_username_net.get(&routes.session(&peer)).cloned().unwrap_or_else(|| "Unknown".to_string());
+                                                    let _ = tx.send(NetToUi::Error(format!("Couldn't decrypt a message from {}", from_label)));
+                                                    // No ACK, so the sender doesn't mark it delivered
+                                                    let _ = swarm.behaviour_mut().request_response.send_response(channel, "undecryptable".to_string());
+                                                    continue;
+                                                };
+                                                opened
+                                            }
+                                            // A peer that encrypts never sends chat in the clear, so this didn't come from it
+                                            None if peer_supports(&peer_versions, &peer, E2E_SINCE) && (request.starts_with("MSG:") || request.starts_with("FWD:")) => {
+                                                let from_label = peer_to_username_net.get(&routes.session(&peer)).cloned().unwrap_or_else(|| "Unknown".to_string());
+                                                let _ = tx.send(NetToUi::Error(format!("Ignored an unencrypted message from {}", from_label)));
+                                                let _ = swarm.behaviour_mut().request_response.send_response(channel, "unencrypted".to_string());
+                                                continue;
+                                            }
+                                            None => request,
+                                        };
+                                        // Try to parse embedded username: format "MSG:<from_username>|<text>|<sent ms>|<msg id>" (or "FWD:" when forwarded)
+                                        let wrapped = request_str
+                                            .strip_prefix("MSG:")
+                                            .map(|rest| (rest, false))
+                                            .or_else(|| request_str.strip_prefix("FWD:").map(|rest| (rest, true)));
+                                        // Older senders without a message id get the plain "ok" ack
+                                        let mut ack = "ok".to_string();
+                                        if let Some((rest, forwarded)) = wrapped {
+                                            if let Some(chat) = parse_chat_payload(rest) {
                                                 // Update reverse map for future lookups and display
-                                                let peer_key = peer.to_string();
-                                                peer_to_username_net.insert(peer_key, from_name.to_string());
+                                                peer_to_username_net.insert(routes.session(&peer), chat.from.clone());
+                                                if let Some(id) = &chat.msg_id {
+                                                    ack = format!("ACK:{}", id);
+                                                }
                                                 let _ = tx.send(NetToUi::ChatMessage {
-                                                    peer: from_name.to_string(),
+                                                    peer: chat.from,
                                                     direction: MessageDirection::Incoming,
-                                                    text: text.to_string(),
+                                                    text: chat.text,
+                                                    forwarded,
+                                                    timestamp: chat.sent,
+                                                    msg_id: chat.msg_id,
+                                                    seq: chat.seq,
                                                 });
                                             } else {
                                                 // Malformed payload, fallback to known mapping without exposing PeerId
-                                                let peer_key = peer.to_string();
-                                                let from_label = peer_to_username_net.get(&peer_key).cloned().unwrap_or_else(|| "Unknown".to_string());
+                                                let from_label = peer_to_username_net.get(&routes.session(&peer)).cloned().unwrap_or_else(|| "Unknown".to_string());
                                                 let _ = tx.send(NetToUi::ChatMessage {
                                                     peer: from_label.clone(),
                                                     direction: MessageDirection::Incoming,
                                                     text: request_str.clone(),
+                                                    forwarded: false,
+                                                    timestamp: message_time_now(),
+                                                    msg_id: None,
+                                                    seq: None,
                                                 });
                                             }
+                                        } else if request_str == "TYPING" {
+                                            if let Some(name) = peer_to_username_net.get(&routes.session(&peer)) {
+                                                let _ = tx.send(NetToUi::Typing { peer: name.clone() });
+                                            }
+                                        } else if let Some(up_to_id) = request_str.strip_prefix("READ:") {
+                                            // Read receipt for messages we sent; only peers we've written to have a name here
+                                            if let Some(name) = peer_to_username_net.get(&routes.session(&peer)) {
+                                                let _ = tx.send(NetToUi::Read { peer: name.clone(), up_to_id: up_to_id.to_string() });
+                                            }
                                         } else {
                                             // Backward compatibility: old clients may send plain text. Use mapping if available, otherwise show "Unknown".
-                                            let peer_key = peer.to_string();
-                                            let from_label = peer_to_username_net.get(&peer_key).cloned().unwrap_or_else(|| "Unknown".to_string());
+                                            let from_label = peer_to_username_net.get(&routes.session(&peer)).cloned().unwrap_or_else(|| "Unknown".to_string());
                                             let _ = tx.send(NetToUi::ChatMessage {
                                                 peer: from_label,
                                                 direction: MessageDirection::Incoming,
                                                 text: request_str.clone(),
+                                                forwarded: false,
+                                                timestamp: message_time_now(),
+                                                msg_id: None,
+                                                seq: None,
                                             });
                                         }
                                         // Respond with a small ack so the sender gets a response per message
-                                        if let Err(e) = swarm.behaviour_mut().request_response.send_response(channel, "ok".to_string()) {
+                                        if let Err(e) = swarm.behaviour_mut().request_response.send_response(channel, ack) {
                                             tracing::error!("Failed to send response: {}", e);
                                         }
                                     }
-                                    request_response::Message::Response { response, .. } => {
-                                        // Surface responses without exposing peer id
-                                        let _ = tx.send(NetToUi::Info(format!("Response received: {}", response)));
+                                    request_response::Message::Response { request_id, response } => {
+                                        pending_signals.remove(&request_id);
+                                        // The receipt must echo the id we sent; a plain "ok" comes from an older
+                                        // client that doesn't confirm which message it got
+                                        if let Some(chat) = pending_chats.remove(&request_id)
+                                            && response.strip_prefix("ACK:") == Some(chat.msg_id.as_str())
+                                        {
+                                            let _ = tx.send(NetToUi::Delivered { peer: chat.to, msg_id: chat.msg_id });
+                                        }
+                                    }
+                                }
+                            }
+                            request_response::Event::OutboundFailure { peer, error, request_id } => {
+                                tracing::error!("Outbound request to {} failed: {:?}", peer, error);
+                                // A lost read receipt or typing signal isn't worth an error
+                                if pending_signals.remove(&request_id) {
+                                    continue;
+                                }
+                                match (pending_chats.remove(&request_id), &session_token) {
+                                    // Unreachable peer: let the server hold the message until they log in
+                                    (Some(chat), Some(token)) => {
+                                        let cipher = sealing_cipher(&peer_versions, &peer_keys, &peer);
+                                        let request = chat.store_request(token.clone(), cipher.as_ref());
+                                        pending_relay.insert(swarm.behaviour_mut().relay.send_request(&rendezvous_point_peer_id, request), (chat.to, chat.msg_id));
+                                    }
+                                    (pending, _) => {
+                                        if let Some(chat) = pending {
+                                            let _ = tx.send(NetToUi::DeliveryFailed { peer: chat.to, msg_id: chat.msg_id });
+                                        }
+                                        let _ = tx.send(NetToUi::Error(format!("Outbound request failed: {:?}", error)));
+                                    }
+                                }
+                            }
+                            request_response::Event::InboundFailure { peer, error, request_id: _ } => {
+                                tracing::error!("Inbound with {} failed: {:?}", peer, error);
+                                let _ = tx.send(NetToUi::Error(format!("Inbound request failed: {:?}", error)));
+                            }
+                            request_response::Event::ResponseSent { peer, .. } => {
+                                tracing::debug!("Response sent to {}", peer);
+                            }
+                        },
+                        // File RequestResponse: we receive files as requests and send them through responses' acks
+                        SwarmEvent::Behaviour(ClientBehaviourEvent::File(event)) => match event {
+                            request_response::Event::Message { peer, message: request_response::Message::Request { request, channel, .. } } => {
+                                let from = peer_to_username_net.get(&routes.session(&peer)).cloned().unwrap_or_else(|| "Unknown".to_string());
+                                let response = match request {
+                                    _ if is_blocked_peer(&blocked, &directory_sessions, &routes.session(&peer)) => {
+                                        FileResponse::Rejected { reason: "Declined".to_string() }
+                                    }
+                                    FileRequest::Offer { transfer_id, .. } if incoming_files.contains_key(&(peer, transfer_id)) => {
+                                        FileResponse::Rejected { reason: "Duplicate transfer".to_string() }
+                                    }
+                                    // Only from someone on the server's roster, so a stranger who dials us can't fill the disk
+                                    FileRequest::Offer { filename, .. } if !directory_sessions.contains_key(&routes.session(&peer)) => {
+                                        tracing::info!("Refused {} from {}: not a contact", filename, peer);
+                                        FileResponse::Rejected { reason: "Not a contact".to_string() }
+                                    }
+                                    FileRequest::Offer { filename, .. }
+                                        if incoming_files.len() >= MAX_INCOMING_TRANSFERS
+                                            || incoming_files.keys().filter(|(from_peer, _)| *from_peer == peer).count() >= MAX_INCOMING_PER_PEER =>
+                                    {
+                                        let _ = tx.send(NetToUi::Error(format!("Refused {} from {}: too many transfers at once", filename, from)));
+                                        FileResponse::Rejected { reason: "Too many transfers".to_string() }
+                                    }
+                                    FileRequest::Offer { transfer_id, filename, size, sha256 } => {
+                                        match IncomingFile::create(&download_dir, &filename, size, &sha256, max_file_bytes) {
+                                            Ok(file) => {
+                                                next_transfer += 1;
+                                                let _ = tx.send(NetToUi::FileTransfer {
+                                                    id: next_transfer,
+                                                    peer: from,
+                                                    filename: file.filename.clone(),
+                                                    size,
+                                                    outgoing: false,
+                                                });
+                                                incoming_files.insert((peer, transfer_id), (next_transfer, file));
+                                                FileResponse::Ok
+                                            }
+                                            Err(reason) => {
+                                                let _ = tx.send(NetToUi::Error(format!("Refused {} from {}: {}", filename, from, reason)));
+                                                FileResponse::Rejected { reason }
+                                            }
+                                        }
+                                    }
+                                    FileRequest::Chunk { transfer_id, offset, data } => match incoming_files.get_mut(&(peer, transfer_id)) {
+                                        Some((id, file)) => match file.write_chunk(offset, &data) {
+                                            Ok(()) => {
+                                                let _ = tx.send(NetToUi::FileProgress { id: *id, done: file.received });
+                                                FileResponse::Ok
+                                            }
+                                            Err(reason) => {
+                                                if let Some((id, file)) = incoming_files.remove(&(peer, transfer_id)) {
+                                                    file.abort();
+                                                    let _ = tx.send(NetToUi::FileFailed { id, reason: reason.clone() });
+                                                }
+                                                FileResponse::Rejected { reason }
+                                            }
+                                        },
+                                        None => FileResponse::Rejected { reason: "Unknown transfer".to_string() },
+                                    },
+                                    FileRequest::Finish { transfer_id } => match incoming_files.remove(&(peer, transfer_id)) {
+                                        Some((id, file)) => match file.finish() {
+                                            Ok(path) => {
+                                                let _ = tx.send(NetToUi::FileDone { id, saved_to: Some(path.display().to_string()) });
+                                                FileResponse::Ok
+                                            }
+                                            Err(reason) => {
+                                                let _ = tx.send(NetToUi::FileFailed { id, reason: reason.clone() });
+                                                FileResponse::Rejected { reason }
+                                            }
+                                        },
+                                        None => FileResponse::Rejected { reason: "Unknown transfer".to_string() },
+                                    },
+                                };
+                                let _ = swarm.behaviour_mut().file.send_response(channel, response);
+                            }
+                            request_response::Event::Message { message: request_response::Message::Response { request_id, response }, .. } => {
+                                let Some(id) = pending_file_requests.remove(&request_id) else { continue };
+                                let Some(transfer) = outgoing_files.get_mut(&id) else { continue };
+                                // One request at a time: each Ok moves the transfer to its next step
+                                let next = match response {
+                                    FileResponse::Rejected { reason } => Err(reason),
+                                    FileResponse::Ok if transfer.finishing => {
+                                        outgoing_files.remove(&id);
+                                        let _ = tx.send(NetToUi::FileDone { id, saved_to: None });
+                                        continue;
+                                    }
+                                    FileResponse::Ok => {
+                                        let _ = tx.send(NetToUi::FileProgress { id, done: transfer.file.sent });
+                                        match transfer.file.next_chunk(file_chunk_bytes) {
+                                            Ok(Some((offset, data))) => Ok(FileRequest::Chunk { transfer_id: id, offset, data }),
+                                            Ok(None) => {
+                                                transfer.finishing = true;
+                                                Ok(FileRequest::Finish { transfer_id: id })
+                                            }
+                                            Err(e) => Err(format!("Cannot read file: {}", e)),
+                                        }
+                                    }
+                                };
+                                match next {
+                                    Ok(request) => {
+                                        let peer = transfer.peer;
+                                        pending_file_requests.insert(swarm.behaviour_mut().file.send_request(&peer, request), id);
+                                    }
+                                    Err(reason) => {
+                                        outgoing_files.remove(&id);
+                                        let _ = tx.send(NetToUi::FileFailed { id, reason });
+                                    }
+                                }
+                            }
+                            request_response::Event::OutboundFailure { request_id, error, .. } => {
+                                if let Some(id) = pending_file_requests.remove(&request_id)
+                                    && outgoing_files.remove(&id).is_some()
+                                {
+                                    let reason = match error {
+                                        request_response::OutboundFailure::UnsupportedProtocols => "The contact's client can't receive files".to_string(),
+                                        error => format!("{:?}", error),
+                                    };
+                                    let _ = tx.send(NetToUi::FileFailed { id, reason });
+                                }
+                            }
+                            _ => {}
+                        },
+                        // Relay RequestResponse
+                        SwarmEvent::Behaviour(ClientBehaviourEvent::Relay(event)) => match event {
+                            request_response::Event::Message { peer, message: request_response::Message::Request { request, channel, .. } } => {
+                                let response = match request {
+                                    RelayRequest::Deliver { messages } if peer == rendezvous_point_peer_id => {
+                                        for m in messages {
+                                            let from_peer = PeerId::from_str(&m.from_peer).ok();
+                                            if blocked.contains_key(&username_key(&m.from))
+                                                || from_peer.is_some_and(|from| is_blocked_peer(&blocked, &directory_sessions, &routes.session(&from)))
+                                            {
+                                                tracing::debug!("Dropped a queued message from blocked user {}", m.from);
+                                                continue;
+                                            }
+                                            let text = if m.encrypted {
+                                                // The sealed payload names the sender, which has to match the name the server gives
+                                                let opened = from_peer
+                                                    .and_then(|from| peer_cipher(e2e_secret.as_ref(), &peer_keys, &from))
+                                                    .zip(session_username.as_ref())
+                                                    .and_then(|(cipher, me)| e2e_open(&cipher, &m.text, me))
+                                                    .and_then(|opened| {
+                                                        let rest = opened.strip_prefix("MSG:").or_else(|| opened.strip_prefix("FWD:"))?;
+                                                        parse_chat_payload(rest)
+                                                    })
+                                                    .filter(|chat| username_key(&chat.from) == username_key(&m.from));
+                                                let Some(opened) = opened else {
+                                                    let _ = tx.send(NetToUi::Error(format!("Couldn't decrypt a queued message from {}", m.from)));
+                                                    continue;
+                                                };
+                                                opened.text
+                                            } else if from_peer.is_some_and(|from| peer_supports(&peer_versions, &from, E2E_SINCE)) {
+                                                let _ = tx.send(NetToUi::Error(format!("Ignored an unencrypted queued message from {}", m.from)));
+                                                continue;
+                                            } else {
+                                                m.text
+                                            };
+                                            let _ = tx.send(NetToUi::ChatMessage {
+                                                peer: m.from,
+                                                direction: MessageDirection::Incoming,
+                                                text,
+                                                forwarded: m.forwarded,
+                                                timestamp: SystemTime::UNIX_EPOCH + Duration::from_millis(m.sent_at_ms),
+                                                msg_id: None,
+                                                seq: None,
+                                            });
+                                        }
+                                        RelayResponse::Ok
+                                    }
+                                    _ => RelayResponse::Error { reason: "Unexpected relay request".to_string() },
+                                };
+                                let _ = swarm.behaviour_mut().relay.send_response(channel, response);
+                            }
+                            request_response::Event::Message { message: request_response::Message::Response { request_id, response }, .. } => {
+                                let (to, msg_id) = pending_relay.remove(&request_id).unwrap_or_default();
+                                match response {
+                                    // Stays unconfirmed: the server gives no receipt once it delivers
+                                    RelayResponse::Ok => {
+                                        let _ = tx.send(NetToUi::Info(format!("{} is unreachable; the server will deliver the message at their next login", to)));
+                                    }
+                                    RelayResponse::Routed => {
+                                        let _ = tx.send(NetToUi::Info(format!("No direct connection to {}; the message went through the server", to)));
+                                    }
+                                    RelayResponse::Error { reason } => {
+                                        let _ = tx.send(NetToUi::DeliveryFailed { peer: to.clone(), msg_id });
+                                        let _ = tx.send(NetToUi::Error(format!("Message to {} not delivered: {}", to, reason)));
+                                    }
+                                    RelayResponse::InvalidSession => {
+                                        let _ = tx.send(NetToUi::DeliveryFailed { peer: to, msg_id });
+                                        is_authenticated = false;
+                                        session_token = None;
+                                        let _ = tx.send(NetToUi::SessionExpired);
                                     }
                                 }
                             }
-                            request_response::Event::OutboundFailure { peer, error, request_id: _ } => {
-                                tracing::error!("Outbound request to {} failed: {:?}", peer, error);
-                                let _ = tx.send(NetToUi::Error(format!("Outbound request failed: {:?}", error)));
-                            }
-                            request_response::Event::InboundFailure { peer, error, request_id: _ } => {
-                                tracing::error!("Inbound with {} failed: {:?}", peer, error);
-                                let _ = tx.send(NetToUi::Error(format!("Inbound request failed: {:?}", error)));
-                            }
-                            request_response::Event::ResponseSent { peer, .. } => {
-                                tracing::debug!("Response sent to {}", peer);
+                            request_response::Event::OutboundFailure { request_id, error, .. } => {
+                                let (to, msg_id) = pending_relay.remove(&request_id).unwrap_or_default();
+                                let _ = tx.send(NetToUi::DeliveryFailed { peer: to.clone(), msg_id });
+                                let _ = tx.send(NetToUi::Error(format!("Message to {} not delivered: {:?}", to, error)));
                             }
+                            _ => {}
                         },
                         // Auth RequestResponse
                         SwarmEvent::Behaviour(ClientBehaviourEvent::Auth(event)) => match event {
-                            request_response::Event::Message { peer: _, message } => {
-                                if let request_response::Message::Response { response, .. } = message {
-                                    if let Some(rest) = response.strip_prefix("AUTH:") {
-                                        let ok = rest.starts_with("OK");
-                                        let msg = if ok { "Authenticated".to_string() } else { rest.strip_prefix("ERR:").unwrap_or(rest).to_string() };
-                                        let _ = tx.send(NetToUi::AuthResult { ok, message: msg });
-                                        if ok {
-                                            is_authenticated = true;
-                                            // After successful auth, request the user list via auth protocol
-                                            let _ = swarm.behaviour_mut().auth.send_request(&rendezvous_point_peer_id, "LIST".to_string());
+                            request_response::Event::Message { peer, message: request_response::Message::Request { request, channel, .. } } => {
+                                let response = match request {
+                                    AuthRequest::Presence { users, last_seen } if peer == rendezvous_point_peer_id && is_authenticated => {
+                                        peer_to_username_net = online_names(&users);
+                                        directory_sessions = peer_to_username_net.clone();
+                                        let _ = tx.send(NetToUi::Users(users, seen_times(last_seen)));
+                                        AuthResponse::Ok
+                                    }
+                                    // A session that resolved ours and is about to reach us
+                                    AuthRequest::Introduce { session, peer: introduced } if peer == rendezvous_point_peer_id && is_authenticated => {
+                                        if let Ok(introduced) = PeerId::from_str(&introduced) {
+                                            routes.learn(session, introduced);
+                                        }
+                                        AuthResponse::Ok
+                                    }
+                                    _ => AuthResponse::Error(AuthError::UnexpectedRequest),
+                                };
+                                let _ = swarm.behaviour_mut().auth.send_response(channel, response);
+                            }
+                            request_response::Event::Message { peer: _, message: request_response::Message::Response { request_id, response } } => {
+                                if let Some(session) = pending_routes.remove(&request_id) {
+                                    let peer = match response {
+                                        AuthResponse::Route { session: resolved, peer } if resolved == session => PeerId::from_str(&peer).ok(),
+                                        other => {
+                                            tracing::info!("Server couldn't resolve session {}: {:?}", session, other);
+                                            None
+                                        }
+                                    };
+                                    route_answered(&session, peer, &mut routes, &mut retry, &tx, &directory_sessions, space.is_some());
+                                    continue;
+                                }
+                                match (pending_auth.remove(&request_id), response) {
+                                    (Some(PendingAuth::ChangePassword), AuthResponse::Ok) => {
+                                        let _ = tx.send(NetToUi::PasswordChanged(Ok(())));
+                                    }
+                                    (Some(PendingAuth::ChangePassword), AuthResponse::Error(error)) => {
+                                        let _ = tx.send(NetToUi::PasswordChanged(Err(AuthFailure::Refused(error))));
+                                    }
+                                    // No session: acknowledges the logout without logging in
+                                    (Some(PendingAuth::Logout), AuthResponse::Ok) => {
+                                        let _ = tx.send(NetToUi::AuthResult(Ok(None)));
+                                    }
+                                    (Some(PendingAuth::Delete), AuthResponse::Ok) => {
+                                        let _ = tx.send(NetToUi::DeleteResult(Ok(())));
+                                    }
+                                    (Some(PendingAuth::Delete), AuthResponse::Error(error)) => {
+                                        let _ = tx.send(NetToUi::DeleteResult(Err(AuthFailure::Refused(error))));
+                                    }
+                                    (_, AuthResponse::InvalidSession) => {
+                                        is_authenticated = false;
+                                        session_token = None;
+                                        let _ = tx.send(NetToUi::SessionExpired);
+                                    }
+                                    (pending, AuthResponse::Session { token }) => {
+                                        let event = match pending {
+                                            Some(PendingAuth::Register) => NetToUi::Registered(Ok(token.clone())),
+                                            _ => NetToUi::AuthResult(Ok(Some(token.clone()))),
+                                        };
+                                        let _ = tx.send(event);
+                                        is_authenticated = true;
+                                        // After successful auth, request the user list via auth protocol
+                                        let request = AuthRequest::List { token: token.clone(), space: space.clone(), last_seen: true };
+                                        pending_auth.insert(swarm.behaviour_mut().auth.send_request(&rendezvous_point_peer_id, request), PendingAuth::List);
+                                        session_token = Some(token);
+                                        if let Some(gossipsub) = swarm.behaviour_mut().gossipsub.as_mut() {
+                                            let subscribed = gossipsub.subscribe(&presence_topic()).and_then(|_| gossipsub.subscribe(&room_topic(DEFAULT_ROOM)));
+                                            match subscribed {
+                                                Ok(_) => {
+                                                    let _ = tx.send(NetToUi::RoomJoined(DEFAULT_ROOM.to_string()));
+                                                }
+                                                Err(e) => tracing::error!("Gossip subscribe failed: {:?}", e),
+                                            }
+                                            if let Some(name) = &session_username {
+                                                // May fail with no peers yet; the periodic republish covers that
+                                                let _ = gossipsub.publish(presence_topic(), format!("ONLINE:{}", name).into_bytes());
+                                            }
                                         }
-                                    } else if let Some(rest) = response.strip_prefix("LIST:") {
-                                        // Parse username=peerid pairs separated by commas
-                                        let mut map = HashMap::new();
-                                        peer_to_username_net.clear();
-                                        if !rest.is_empty() {
-                                            for pair in rest.split(',') {
-                                                if let Some((name, pid)) = pair.split_once('=') {
-                                                    let uname = name.to_string();
-                                                    let pid_str = pid.to_string();
-                                                    map.insert(uname.clone(), pid_str.clone());
-                                                    peer_to_username_net.insert(pid_str, uname);
+                                    }
+                                    (_, AuthResponse::Directory { users, last_seen }) => {
+                                        // Also answers the LIST sent after a reconnect, which proves the session is still valid
+                                        is_authenticated = session_token.is_some();
+                                        peer_to_username_net = online_names(&users);
+                                        directory_sessions = peer_to_username_net.clone();
+                                        let _ = tx.send(NetToUi::Users(users, seen_times(last_seen)));
+                                    }
+                                    // From servers that don't record last-seen times
+                                    (_, AuthResponse::Users(map)) => {
+                                        is_authenticated = session_token.is_some();
+                                        peer_to_username_net = online_names(&map);
+                                        directory_sessions = peer_to_username_net.clone();
+                                        let _ = tx.send(NetToUi::Users(map, HashMap::new()));
+                                    }
+                                    (_, AuthResponse::Discovered(envelopes)) => {
+                                        // Signed peer records; only records with a valid signature are used
+                                        for bytes in envelopes {
+                                            let record = libp2p::core::SignedEnvelope::from_protobuf_encoding(&bytes)
+                                                .ok()
+                                                .and_then(|envelope| libp2p::core::PeerRecord::from_signed_envelope(envelope).ok());
+                                            let Some(record) = record else {
+                                                tracing::warn!("Ignoring invalid restored peer record");
+                                                continue;
+                                            };
+                                            let discovered_peer = record.peer_id();
+                                            if discovered_peer == local_peer_id { continue; }
+                                            for address in record.addresses() {
+                                                if discovered.insert(discovered_peer, address.clone()) {
+                                                    swarm.add_peer_address(discovered_peer, address.clone());
                                                 }
                                             }
                                         }
-                                        let _ = tx.send(NetToUi::Users(map));
-                                    } else if let Some(rest) = response.strip_prefix("DELETE:") {
-                                        // DELETE:OK or DELETE:ERR:reason
-                                        let ok = rest.starts_with("OK");
-                                        let msg = if ok { "Account deleted".to_string() } else { rest.strip_prefix("ERR:").unwrap_or(rest).to_string() };
-                                        let _ = tx.send(NetToUi::DeleteResult { ok, message: msg });
-                                    } else {
-                                        // Backward-compat: older server without AUTH: prefix
-                                        let ok = response.starts_with("OK");
-                                        let msg = if ok { "Authenticated".to_string() } else { response.trim_start_matches("ERR:").to_string() };
-                                        let _ = tx.send(NetToUi::AuthResult { ok, message: msg });
+                                        resume_connects(&mut swarm, &discovered, &mut awaiting_discovery, &mut direct_dials);
+                                        let list: Vec<String> = discovered.peers().map(|p| p.to_string()).collect();
+                                        let _ = tx.send(NetToUi::Discovered(list));
+                                    }
+                                    (Some(PendingAuth::Register), AuthResponse::Error(error)) => {
+                                        let _ = tx.send(NetToUi::Registered(Err(AuthFailure::Refused(error))));
+                                    }
+                                    (_, AuthResponse::Error(error)) => {
+                                        let _ = tx.send(NetToUi::AuthResult(Err(AuthFailure::Refused(error))));
                                     }
+                                    (_, AuthResponse::Ok) => {}
+                                    // Never requested: Users already includes offline accounts
+                                    (_, AuthResponse::Accounts { .. }) => {}
+                                    // Only answers a Resolve, handled above
+                                    (_, AuthResponse::Route { .. }) => {}
                                 }
                             }
-                            request_response::Event::OutboundFailure { peer: _, error, .. } => {
-                                let _ = tx.send(NetToUi::AuthResult { ok: false, message: format!("Auth request failed: {:?}", error) });
+                            request_response::Event::OutboundFailure { peer: _, request_id, error } => {
+                                if let Some(session) = pending_routes.remove(&request_id) {
+                                    tracing::info!("Resolving session {} failed: {:?}", session, error);
+                                    route_answered(&session, None, &mut routes, &mut retry, &tx, &directory_sessions, space.is_some());
+                                    continue;
+                                }
+                                let failure = AuthFailure::Unreachable(format!("{:?}", error));
+                                match pending_auth.remove(&request_id) {
+                                    Some(PendingAuth::ChangePassword) => {
+                                        let _ = tx.send(NetToUi::PasswordChanged(Err(failure)));
+                                    }
+                                    Some(PendingAuth::Register) => {
+                                        let _ = tx.send(NetToUi::Registered(Err(failure)));
+                                    }
+                                    _ => {
+                                        let _ = tx.send(NetToUi::AuthResult(Err(failure)));
+                                    }
+                                }
                             }
                             _ => {}
                         },
                         _ => {}
                     }
                 }
+                // Reconnect to the rendezvous server after a backoff
+                _ = async move {
+                    match redial_at {
+                        Some(at) => tokio::time::sleep_until(at).await,
+                        None => std::future::pending().await,
+                    }
+                } => {
+                    redial_at = None;
+                    tracing::info!("Redialing rendezvous server (attempt {})", backoff.attempt);
+                    let _ = tx.send(NetToUi::Connection(format!("Reconnecting (attempt {})...", backoff.attempt)));
+                    if let Err(e) = swarm.dial(rendezvous_dial()) {
+                        tracing::warn!("Rendezvous redial failed: {}", e);
+                        redial_at = Some(schedule_redial(&mut backoff, &tx, "Server unreachable"));
+                    }
+                }
+                // Gossip: re-announce presence so late joiners learn about us
+                _ = presence_interval.tick() => {
+                    if is_authenticated
+                        && let Some(name) = &session_username
+                        && let Some(gossipsub) = swarm.behaviour_mut().gossipsub.as_mut()
+                    {
+                        let _ = gossipsub.publish(presence_topic(), format!("ONLINE:{}", name).into_bytes());
+                    }
+                }
+                // Queued chats whose peer never became reachable go to the server's relay queue
+                _ = outbox_interval.tick() => {
+                    let now = Instant::now();
+                    // Neither rendezvous nor the server's restored records know them
+                    let missing: Vec<PeerId> = awaiting_discovery.iter().filter(|(_, deadline)| **deadline <= now).map(|(peer, _)| *peer).collect();
+                    for peer in missing {
+                        awaiting_discovery.remove(&peer);
+                        let _ = tx.send(NetToUi::Info(format!("{} appears to be offline", display_name(&peer_to_username_net, &routes.session(&peer)))));
+                    }
+                    let expired: Vec<PeerId> = outbox.iter().filter(|(_, (deadline, _))| *deadline <= now).map(|(peer, _)| *peer).collect();
+                    for peer in expired {
+                        let Some((_, queued)) = outbox.remove(&peer) else { continue };
+                        tracing::info!("No connection to {} after {}s; {} message(s) go to the relay", peer, CHAT_CONNECT_TIMEOUT_SECS, queued.len());
+                        let cipher = sealing_cipher(&peer_versions, &peer_keys, &peer);
+                        for chat in queued {
+                            let _ = tx.send(NetToUi::Queued { peer: chat.to.clone(), msg_id: chat.msg_id.clone(), queued: false });
+                            let token = session_token.as_ref();
+                            relay_or_fail(&mut swarm, rendezvous_point_peer_id, token, chat, cipher.as_ref(), &mut pending_relay, &tx);
+                        }
+                    }
+                }
                 // Periodic rediscovery tick
                 _ = rediscover_interval.tick() => {
+                    // Gossip only flows over open connections, so keep connections to discovered peers
+                    if swarm.behaviour().gossipsub.is_enabled() {
+                        let idle: Vec<PeerId> = discovered.peers().filter(|peer| !connected.contains(peer)).copied().collect();
+                        for peer in idle {
+                            dial_discovered(&mut swarm, &discovered, peer);
+                        }
+                    }
                     if is_registered {
-                        let _ = swarm.behaviour_mut().rendezvous.discover(
-                            Some(rendezvous::Namespace::new(RENDEZVOUS_NAMESPACE.to_string()).unwrap()),
+                        swarm.behaviour_mut().rendezvous.discover(
+                            Some(space_namespace(space.as_deref())),
                             None,
                             None,
                             rendezvous_point_peer_id
                         );
                     }
                 }
+                _ = server_keepalive_interval.tick() => {
+                    if swarm.is_connected(&rendezvous_point_peer_id) {
+                        let request_id = swarm.behaviour_mut().request_response.send_request(&rendezvous_point_peer_id, KEEPALIVE.to_string());
+                        pending_signals.insert(request_id);
+                    }
+                }
                 // Periodic user list refresh after authentication
                 _ = users_refresh_interval.tick() => {
-                    if is_authenticated {
-                        let _ = swarm.behaviour_mut().auth.send_request(&rendezvous_point_peer_id, "LIST".to_string());
+                    if is_authenticated && let Some(token) = &session_token {
+                        let request = AuthRequest::List { token: token.clone(), space: space.clone(), last_seen: true };
+                        pending_auth.insert(swarm.behaviour_mut().auth.send_request(&rendezvous_point_peer_id, request), PendingAuth::List);
+                    }
+                }
+            }
+        }
+    }
+    // PeerId -> username of the directory's online users
+    fn online_names(users: &HashMap<String, Vec<String>>) -> HashMap<String, String> {
+        users.iter().flat_map(|(name, peers)| peers.iter().map(|pid| (pid.clone(), name.clone()))).collect()
+    }
+
+    // The directory name of a gossip author, if `session` is one of the sessions the server lists
+    // for the name it claims. Gossip payloads are written by the sender, so a name alone proves nothing.
+    fn verified_author(directory_sessions: &HashMap<String, String>, session: &str, claimed: &str) -> Option<String> {
+        directory_sessions
+            .get(session)
+            .filter(|name| username_key(name) == username_key(claimed))
+            .cloned()
+    }
+
+    // Passes a gossip presence announcement or room message from `session` to the UI. Fails with the
+    // name the author claims when the directory doesn't list `session` under it.
+    fn deliver_gossip(
+        tx: &Sender<NetToUi>,
+        directory_sessions: &HashMap<String, String>,
+        session: &str,
+        topic: &str,
+        payload: &str,
+        named_space: bool,
+    ) -> Result<(), String> {
+        // Announcements reach every space; a named one relies on the server's list
+        if topic == presence_topic().hash().as_str() && !named_space {
+            let parsed = payload
+                .strip_prefix("ONLINE:")
+                .map(|name| (name, true))
+                .or_else(|| payload.strip_prefix("OFFLINE:").map(|name| (name, false)));
+            if let Some((name, online)) = parsed {
+                let username = verified_author(directory_sessions, session, name).ok_or_else(|| name.to_string())?;
+                let _ = tx.send(NetToUi::Presence { username, peer: session.to_string(), online });
+            }
+        } else if let Some(room) = topic.strip_prefix(&format!("{}/room/", RENDEZVOUS_NAMESPACE))
+            && let Some(chat) = parse_chat_payload(payload)
+        {
+            let from = verified_author(directory_sessions, session, &chat.from).ok_or(chat.from)?;
+            let _ = tx.send(NetToUi::RoomMessage {
+                room: room.to_string(),
+                from,
+                direction: MessageDirection::Incoming,
+                text: chat.text,
+                timestamp: chat.sent,
+            });
+        }
+        Ok(())
+    }
+
+    // Work waiting for the server to resolve a session handle
+    enum Deferred {
+        Command(UiToNet),
+        Gossip { source: PeerId, topic: String, payload: String },
+    }
+
+    // Asks the server for the PeerId behind `session`, unless that's already been asked
+    fn resolve_session(
+        swarm: &mut libp2p::Swarm<ClientBehaviour>,
+        server: PeerId,
+        token: &str,
+        session: &str,
+        routes: &mut Routes<Deferred>,
+        pending_routes: &mut HashMap<request_response::OutboundRequestId, String>,
+    ) {
+        if routes.ask(session) {
+            let request = AuthRequest::Resolve { token: token.to_string(), session: session.to_string() };
+            pending_routes.insert(swarm.behaviour_mut().auth.send_request(&server, request), session.to_string());
+        }
+    }
+
+    // Carries on with what waited for the server's answer about `session`: commands are queued to
+    // run again, held gossip is checked against the routes now known
+    fn route_answered(
+        session: &str,
+        peer: Option<PeerId>,
+        routes: &mut Routes<Deferred>,
+        retry: &mut std::collections::VecDeque<UiToNet>,
+        tx: &Sender<NetToUi>,
+        directory_sessions: &HashMap<String, String>,
+        named_space: bool,
+    ) {
+        for deferred in routes.answered(session, peer) {
+            match deferred {
+                Deferred::Command(cmd) => retry.push_back(cmd),
+                Deferred::Gossip { source, topic, payload } => {
+                    if let Err(claimed) = deliver_gossip(tx, directory_sessions, &routes.session(&source), &topic, &payload, named_space) {
+                        tracing::debug!("Ignoring gossip signed as '{}' from {}, not one of its sessions", claimed, source);
                     }
                 }
             }
         }
     }
+
+    // The server's last-seen times are Unix milliseconds
+    fn seen_times(last_seen: HashMap<String, u64>) -> HashMap<String, SystemTime> {
+        last_seen.into_iter().map(|(name, ms)| (name, SystemTime::UNIX_EPOCH + Duration::from_millis(ms))).collect()
+    }
+
+    // Fallback LIST poll while logged in
+    const USERS_POLL_SECS: u64 = 60;
+    // Peer connections without open streams close after this long; the server's stays open
+    const PEER_IDLE_TIMEOUT_SECS: u64 = 30;
+    // KEEPALIVE period for the server connection, well inside the server's own idle timeout
+    const SERVER_KEEPALIVE_SECS: u64 = 20;
+    const _: () = assert!(SERVER_KEEPALIVE_SECS < server::DEFAULT_IDLE_TIMEOUT.as_secs());
+    // How long the network task waits for the LOGOUT reply when the app closes
+    const SHUTDOWN_FLUSH_SECS: u64 = 2;
+    // How long a chat message waits for a connection to its recipient before going to the relay
+    const CHAT_CONNECT_TIMEOUT_SECS: u64 = 10;
+    // How long a Connect to an undiscovered peer waits for an on-demand discovery to find it
+    const DISCOVERY_WAIT_SECS: u64 = 5;
+    // Unanswered pings in a row before a connection is shown as degraded
+    const PING_DEGRADED_AFTER: u32 = 2;
+    // Rendezvous reconnect backoff: doubles from RECONNECT_BASE_SECS up to RECONNECT_MAX_SECS
+    const RECONNECT_BASE_SECS: f64 = 1.0;
+    const RECONNECT_MAX_SECS: f64 = 60.0;
+    // Each delay is scaled by a random factor in 1 ± jitter so clients that lost the server
+    // together don't all retry at the same instant. Override with NEUTRAL_RECONNECT_JITTER.
+    const RECONNECT_JITTER: f64 = 0.5;
+
+    struct ReconnectBackoff {
+        attempt: u32,
+        jitter: f64,
+        rng: u64,
+    }
+
+    impl ReconnectBackoff {
+        fn new(jitter: f64, seed: u64) -> Self {
+            Self { attempt: 0, jitter: jitter.clamp(0.0, 1.0), rng: seed | 1 }
+        }
+
+        fn reset(&mut self) {
+            self.attempt = 0;
+        }
+
+        // xorshift64*, mapped to [0, 1); plenty for spreading retries
+        fn next_unit(&mut self) -> f64 {
+            self.rng ^= self.rng >> 12;
+            self.rng ^= self.rng << 25;
+            self.rng ^= self.rng >> 27;
+            (self.rng.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 11) as f64 / (1u64 << 53) as f64
+        }
+
+        fn next_delay(&mut self) -> Duration {
+            let base = (RECONNECT_BASE_SECS * 2f64.powi(self.attempt.min(16) as i32)).min(RECONNECT_MAX_SECS);
+            self.attempt += 1;
+            let factor = 1.0 + self.jitter * (2.0 * self.next_unit() - 1.0);
+            // Jitter never pushes a delay past the cap
+            Duration::from_secs_f64((base * factor).clamp(0.1, RECONNECT_MAX_SECS))
+        }
+
+        // Returns the delay before the next reconnect attempt
+        fn schedule(&mut self) -> Duration {
+            let delay = self.next_delay();
+            tracing::info!("Reconnecting to rendezvous server in {:.2}s (attempt {})", delay.as_secs_f64(), self.attempt);
+            delay
+        }
+    }
+
+    // Picks the next redial time and shows the countdown in the status line
+    fn schedule_redial(backoff: &mut ReconnectBackoff, tx: &Sender<NetToUi>, reason: &str) -> tokio::time::Instant {
+        let delay = backoff.schedule();
+        let _ = tx.send(NetToUi::Connection(format!("{}, reconnecting in {}s...", reason, delay.as_secs_f64().ceil())));
+        tokio::time::Instant::now() + delay
+    }
+
+    // Leaves the current space's rendezvous namespace for `next`'s. Registration is confirmed
+    // by a Registered event, which then discovers peers in the new namespace.
+    fn move_space(
+        swarm: &mut libp2p::Swarm<ClientBehaviour>,
+        server: PeerId,
+        space: &mut Option<String>,
+        next: Option<String>,
+        is_registered: &mut bool,
+    ) {
+        if *is_registered {
+            swarm.behaviour_mut().rendezvous.unregister(space_namespace(space.as_deref()), server);
+        }
+        *space = next;
+        *is_registered = false;
+        if let Err(e) = swarm.behaviour_mut().rendezvous.register(space_namespace(space.as_deref()), server, None) {
+            tracing::error!("Failed to send registration request: {:?}", e);
+        }
+    }
+
+    // Dials the peers a Connect was waiting on, now that discovery has turned them up
+    fn resume_connects(
+        swarm: &mut libp2p::Swarm<ClientBehaviour>,
+        discovered: &AddressBook,
+        awaiting: &mut HashMap<PeerId, Instant>,
+        direct_dials: &mut HashSet<PeerId>,
+    ) {
+        let found: Vec<PeerId> = awaiting.keys().filter(|peer| discovered.contains(peer)).copied().collect();
+        for peer in found {
+            awaiting.remove(&peer);
+            if !swarm.is_connected(&peer) && dial_discovered(swarm, discovered, peer) {
+                direct_dials.insert(peer);
+            }
+        }
+    }
+
+    // The username the directory gives a peer, for messages about it
+    fn display_name(peer_to_username: &HashMap<String, String>, session: &str) -> String {
+        peer_to_username.get(session).cloned().unwrap_or_else(|| "This user".to_string())
+    }
+
+    // Dials a discovered peer on its still-working addresses, best first. Only those addresses are
+    // tried, not whatever the behaviours remember, so pruned ones stay pruned.
+    fn dial_discovered(swarm: &mut libp2p::Swarm<ClientBehaviour>, discovered: &AddressBook, peer: PeerId) -> bool {
+        let addrs = discovered.dialable(&peer);
+        if addrs.is_empty() {
+            return false;
+        }
+        let opts = libp2p::swarm::dial_opts::DialOpts::peer_id(peer).addresses(addrs).build();
+        swarm.dial(opts).is_ok()
+    }
+
+    // A chat message on its way to a peer, kept until it is acknowledged or handed to the relay
+    struct OutgoingChat {
+        from: String,
+        to: String,
+        text: String,
+        forwarded: bool,
+        sent_at: SystemTime,
+        msg_id: String,
+        // Orders the message among the others to the same recipient, see ChatMessage::seq
+        seq: u64,
+    }
+
+    impl OutgoingChat {
+        // Wraps the message with the sender's username and send time so the receiver can always
+        // display both, and an id the receiver echoes back as the delivery receipt.
+        // Forwarded messages use FWD: instead of MSG: so the receiver can mark them.
+        fn payload(&self, cipher: Option<&chacha20poly1305::ChaCha20Poly1305>) -> String {
+            match cipher {
+                Some(cipher) => format!("ENC:{}", e2e_seal(cipher, &self.plain_payload(), &self.to)),
+                None => self.plain_payload(),
+            }
+        }
+
+        fn plain_payload(&self) -> String {
+            let kind = if self.forwarded { "FWD" } else { "MSG" };
+            let fields = [self.from.as_str(), &self.text, &unix_ms(self.sent_at).to_string(), &self.msg_id, &self.seq.to_string()];
+            format!("{}:{}", kind, server::wire::join(&fields))
+        }
+
+        // Sealed for the peer we tried, so the server only holds ciphertext. The whole payload is
+        // sealed, so the recipient can check the sender name the server attaches.
+        fn store_request(&self, token: String, cipher: Option<&chacha20poly1305::ChaCha20Poly1305>) -> RelayRequest {
+            let (text, encrypted) = match cipher {
+                Some(cipher) => (e2e_seal(cipher, &self.plain_payload(), &self.to), true),
+                None => (self.text.clone(), false),
+            };
+            RelayRequest::Store { token, to: self.to.clone(), text, forwarded: self.forwarded, encrypted }
+        }
+    }
+
+    // Hands a chat the peer can't take to the server's relay queue, or fails it when there's no session
+    fn relay_or_fail(
+        swarm: &mut libp2p::Swarm<ClientBehaviour>,
+        server: PeerId,
+        token: Option<&String>,
+        chat: OutgoingChat,
+        cipher: Option<&chacha20poly1305::ChaCha20Poly1305>,
+        pending_relay: &mut HashMap<request_response::OutboundRequestId, (String, String)>,
+        tx: &Sender<NetToUi>,
+    ) {
+        match token {
+            Some(token) => {
+                let request = chat.store_request(token.clone(), cipher);
+                pending_relay.insert(swarm.behaviour_mut().relay.send_request(&server, request), (chat.to, chat.msg_id));
+            }
+            None => {
+                let _ = tx.send(NetToUi::DeliveryFailed { peer: chat.to, msg_id: chat.msg_id });
+            }
+        }
+    }
+
     // --- Network Behaviour Definition ---
     #[derive(NetworkBehaviour)]
     struct ClientBehaviour {
@@ -1329,36 +5897,952 @@ use eframe::egui;
         identify: identify::Behaviour,
         request_response: request_response::Behaviour<HelloCodec>,
         auth: request_response::Behaviour<AuthCodec>,
+        // Offline messages through the server
+        relay: request_response::Behaviour<RelayCodec>,
+        // Peer-to-peer file transfer
+        file: request_response::Behaviour<FileCodec>,
+        // Enabled with --gossip
+        gossipsub: libp2p::swarm::behaviour::toggle::Toggle<gossipsub::Behaviour>,
+        // Circuits through the rendezvous server when it also runs a relay (server --relay)
+        relay_client: relay::client::Behaviour,
+        // Upgrades a relayed connection to a direct one by hole punching
+        dcutr: dcutr::Behaviour,
+        // Enabled with --mdns
+        mdns: libp2p::swarm::behaviour::toggle::Toggle<mdns::tokio::Behaviour>,
     }
 
-    fn truncate_preview(text: &str) -> String {
-        const MAX_LEN: usize = 48;
-        let mut cleaned = String::with_capacity(text.len());
-        for ch in text.chars() {
-            if ch == '\n' {
-                cleaned.push(' ');
+    // An outgoing /file/1.0 transfer; `finishing` once the Finish request is out
+    struct OutgoingTransfer {
+        peer: PeerId,
+        file: OutgoingFile,
+        finishing: bool,
+    }
+
+    // Result of opening and hashing a file to send, for transfer `id`
+    struct PreparedFile {
+        id: u64,
+        peer: PeerId,
+        to_username: String,
+        file: Result<OutgoingFile, String>,
+    }
+
+    fn report_reachability(tx: &Sender<NetToUi>, reported: &mut Reachability, next: Reachability) {
+        if *reported != next {
+            tracing::info!("Reachability: {}", next.label());
+            let _ = tx.send(NetToUi::Reachability(next.clone()));
+            *reported = next;
+        }
+    }
+
+    fn tcp_port(addr: &Multiaddr) -> Option<u16> {
+        addr.iter().find_map(|p| match p {
+            libp2p::multiaddr::Protocol::Tcp(port) => Some(port),
+            _ => None,
+        })
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum Highlight {
+        None,
+        Match,
+        Current,
+    }
+
+    // `seen` marks the newest outgoing message covered by a read receipt
+    fn render_message(
+        ui: &mut egui::Ui,
+        msg: &ChatMessage,
+        peer_name: &str,
+        highlight: Highlight,
+        seen: bool,
+        colors: &Palette,
+        theme: &Theme,
+    ) -> egui::Response {
+        let row_width = ui.available_width();
+        let layout = if msg.from_self {
+            egui::Layout::right_to_left(egui::Align::Min)
+        } else {
+            egui::Layout::left_to_right(egui::Align::Min)
+        };
+        let bubble = ui.allocate_ui_with_layout(egui::vec2(row_width, 0.0), layout, |ui| {
+            // Our bubbles are white on the accent in both themes; incoming ones follow the theme
+            let (fill, stroke, text, weak_text) = if msg.from_self {
+                (colors.accent, colors.accent_dark, egui::Color32::WHITE, egui::Color32::from_gray(225))
             } else {
-                cleaned.push(ch);
+                (theme.surface_fill, theme.surface_stroke, theme.surface_text, theme.surface_weak_text)
+            };
+            // Find-bar matches get an orange outline; the current match a thicker one and a marker
+            let stroke = match highlight {
+                Highlight::None => egui::Stroke { width: 1.0, color: stroke },
+                Highlight::Match => egui::Stroke { width: 1.5, color: colors.highlight },
+                Highlight::Current => egui::Stroke { width: 3.0, color: colors.highlight },
+            };
+            egui::Frame::none()
+                .fill(fill)
+                .rounding(egui::Rounding::same(RADIUS))
+                .stroke(stroke)
+                .inner_margin(egui::Margin::symmetric(12.0, 8.0))
+                .show(ui, |ui| {
+                    let author = if msg.from_self { "You" } else { msg.author.as_deref().unwrap_or(peer_name) };
+                    let author = if highlight == Highlight::Current { format!("▶ {}", author) } else { author.to_string() };
+                    ui.colored_label(text, egui::RichText::new(author).small());
+                    if msg.forwarded {
+                        ui.colored_label(weak_text, egui::RichText::new("↪ Forwarded").small().italics());
+                    }
+                    ui.add_space(2.0);
+                    ui.colored_label(text, &msg.text);
+                    // Right-aligned within the text's width so the label doesn't widen the bubble
+                    let mut time = chrono::DateTime::<chrono::Local>::from(msg.timestamp).format("%H:%M").to_string();
+                    if msg.delivered {
+                        time.push_str(" ✔");
+                    } else if msg.queued {
+                        time.push_str(" sending…");
+                    }
+                    let width = ui.min_rect().width();
+                    ui.allocate_ui_with_layout(egui::vec2(width, 0.0), egui::Layout::right_to_left(egui::Align::Min), |ui| {
+                        ui.colored_label(weak_text, egui::RichText::new(time).small());
+                    });
+                })
+                .response
+        });
+        if seen {
+            ui.with_layout(egui::Layout::right_to_left(egui::Align::Min), |ui| {
+                ui.label(egui::RichText::new("Seen").small().weak());
+            });
+        }
+        ui.add_space(6.0);
+        // Clickable so the bubble can have a context menu
+        bubble.inner.interact(egui::Sense::click())
+    }
+
+    // A file row below the chat: progress while running, then where it went or why it stopped
+    fn render_transfer(ui: &mut egui::Ui, transfer: &Transfer, theme: &Theme) {
+        let direction = if transfer.outgoing { "Sending" } else { "Receiving" };
+        egui::Frame::none()
+            .stroke(egui::Stroke { width: 1.0, color: theme.surface_stroke })
+            .rounding(egui::Rounding::same(RADIUS))
+            .inner_margin(egui::Margin::symmetric(12.0, 8.0))
+            .show(ui, |ui| {
+                ui.label(format!("📎 {} {} ({})", direction, transfer.filename, format_size(transfer.size)));
+                match &transfer.state {
+                    TransferState::Active => {
+                        let fraction = if transfer.size == 0 { 1.0 } else { transfer.done as f32 / transfer.size as f32 };
+                        ui.add(egui::ProgressBar::new(fraction).show_percentage());
+                    }
+                    TransferState::Done(Some(path)) => {
+                        ui.label(egui::RichText::new(format!("Saved to {}", path)).small().weak());
+                    }
+                    TransferState::Done(None) => {
+                        ui.label(egui::RichText::new("Sent ✔").small().weak());
+                    }
+                    TransferState::Failed(reason) => {
+                        ui.colored_label(ui.visuals().error_fg_color, egui::RichText::new(format!("⚠ failed: {}", reason)).small());
+                    }
+                }
+            });
+        ui.add_space(6.0);
+    }
+
+    fn local_day(time: SystemTime) -> chrono::NaiveDate {
+        chrono::DateTime::<chrono::Local>::from(time).date_naive()
+    }
+
+    // Shown under an offline contact's name: how long ago it was online, or the date after a week.
+    // A time ahead of our clock counts as just now.
+    fn last_seen_label(seen: SystemTime, now: SystemTime) -> String {
+        let secs = now.duration_since(seen).unwrap_or_default().as_secs();
+        match secs {
+            0..60 => "last seen just now".to_string(),
+            60..3_600 => format!("last seen {}m ago", secs / 60),
+            3_600..86_400 => format!("last seen {}h ago", secs / 3_600),
+            86_400..604_800 => format!("last seen {}d ago", secs / 86_400),
+            _ => format!("last seen {}", chrono::DateTime::<chrono::Local>::from(seen).format("%Y-%m-%d")),
+        }
+    }
+
+    fn day_label(day: chrono::NaiveDate, today: chrono::NaiveDate) -> String {
+        if day == today {
+            "Today".to_string()
+        } else if today.pred_opt() == Some(day) {
+            "Yesterday".to_string()
+        } else {
+            day.format("%A, %-d %B %Y").to_string()
+        }
+    }
+
+    // Centered date row shown before the first message of each day
+    fn date_separator(ui: &mut egui::Ui, day: chrono::NaiveDate) {
+        let today = local_day(SystemTime::now());
+        ui.vertical_centered(|ui| {
+            ui.label(egui::RichText::new(day_label(day, today)).small().weak());
+        });
+        ui.add_space(4.0);
+    }
+
+    // Shown under an outgoing message that neither the recipient nor the server accepted; true when clicked
+    fn retry_button(ui: &mut egui::Ui, msg: &ChatMessage) -> bool {
+        if !(msg.from_self && msg.failed && msg.msg_id.is_some()) {
+            return false;
+        }
+        ui.with_layout(egui::Layout::right_to_left(egui::Align::Min), |ui| {
+            let label = egui::RichText::new("⚠ Failed — retry").small().color(ui.visuals().error_fg_color);
+            ui.small_button(label).on_hover_text("Send this message again").clicked()
+        })
+        .inner
+    }
+
+    // Right-click menu of a message bubble; sets `forward` to the text when "Forward…" is picked
+    fn message_context_menu(response: &egui::Response, msg: &ChatMessage, forward: &mut Option<String>, copied: &mut bool) {
+        response.context_menu(|ui| {
+            if ui.button("Copy").clicked() {
+                ui.output_mut(|o| o.copied_text = msg.text.clone());
+                *copied = true;
+                ui.close_menu();
             }
-            if cleaned.len() >= MAX_LEN {
-                cleaned.truncate(MAX_LEN);
-                cleaned.push('…');
-                return cleaned;
+            if ui.button("Forward…").clicked() {
+                *forward = Some(msg.text.clone());
+                ui.close_menu();
             }
+        });
+    }
+
+    fn truncate_preview(text: &str) -> String {
+        // Counted in chars, so emoji and accented or CJK text are never cut mid-character
+        const MAX_CHARS: usize = 48;
+        let mut chars = text.chars().map(|ch| if ch == '\n' { ' ' } else { ch });
+        let mut preview: String = chars.by_ref().take(MAX_CHARS).collect();
+        if chars.next().is_some() {
+            preview.push('…');
         }
-        cleaned
+        preview
     }
 
-    // --- Utilities for Register date picker ---
-    fn is_leap_year(year: i32) -> bool {
-        (year % 4 == 0 && year % 100 != 0) || (year % 400 == 0)
+    // Conversations with unread messages that the window title counts; muted ones aren't
+    fn unread_count(conversations: &HashMap<String, Conversation>, muted: &BTreeSet<String>) -> usize {
+        conversations.iter().filter(|(name, c)| c.unread && !muted.contains(*name)).count()
     }
 
-    fn days_in_month(year: i32, month: u32) -> u32 {
-        match month {
-            1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
-            4 | 6 | 9 | 11 => 30,
-            2 => if is_leap_year(year) { 29 } else { 28 },
-            _ => 30,
+    // --- End-to-end test: real server + two client networking tasks in one process ---
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use server::{run_server, ServerConfig};
+
+        #[test]
+        fn disconnect_reasons_from_connection_errors() {
+            use libp2p::swarm::ConnectionError;
+            assert_eq!(DisconnectReason::from_cause(None), DisconnectReason::Closed);
+            assert_eq!(DisconnectReason::from_cause(Some(&ConnectionError::KeepAliveTimeout)), DisconnectReason::KeepAliveTimeout);
+            let reset = ConnectionError::IO(io::Error::from(io::ErrorKind::ConnectionReset));
+            assert_eq!(DisconnectReason::from_cause(Some(&reset)), DisconnectReason::RemoteClosed);
+            let other = ConnectionError::IO(io::Error::other("noise handshake failed"));
+            assert!(matches!(DisconnectReason::from_cause(Some(&other)), DisconnectReason::Transport(e) if e.contains("noise")));
+        }
+
+        #[test]
+        fn roster_diff_only_touches_what_changed() {
+            let contact = |peer_id: &str, sessions: &[&str]| Contact {
+                peer_id: peer_id.to_string(),
+                online: !sessions.is_empty(),
+                sessions: sessions.iter().map(|s| s.to_string()).collect(),
+            };
+            let roster: HashMap<String, Contact> = [
+                ("alice".to_string(), contact("a1", &["a1"])),
+                ("bob".to_string(), contact("b1", &[])),
+                ("carol".to_string(), contact("c1", &["c1"])),
+            ]
+            .into();
+
+            // Same directory: nothing to do
+            let unchanged: HashMap<String, Vec<String>> =
+                [("alice".to_string(), vec!["a1".to_string()]), ("bob".to_string(), vec![]), ("carol".to_string(), vec!["c1".to_string()])].into();
+            assert_eq!(diff_roster(&roster, unchanged), RosterDiff::default());
+
+            // Carol's account is gone, dave is new, bob came online and alice went offline
+            let directory: HashMap<String, Vec<String>> =
+                [("alice".to_string(), vec![]), ("bob".to_string(), vec!["b2".to_string()]), ("dave".to_string(), vec!["d1".to_string()])].into();
+            let diff = diff_roster(&roster, directory);
+            assert_eq!(diff.removed, ["carol"]);
+            assert_eq!(
+                diff.changed,
+                [
+                    ("alice".to_string(), contact("a1", &[])),
+                    ("bob".to_string(), contact("b2", &["b2"])),
+                    ("dave".to_string(), contact("d1", &["d1"])),
+                ]
+            );
+
+            // Our own entry is kept, listing only our other devices
+            let directory: HashMap<String, Vec<String>> = [("alice".to_string(), vec!["a2".to_string()])].into();
+            let diff = diff_roster(&HashMap::new(), directory);
+            assert_eq!(diff.changed, [("alice".to_string(), contact("a2", &["a2"]))]);
+            let alone: HashMap<String, Vec<String>> = [("alice".to_string(), vec![])].into();
+            let diff = diff_roster(&HashMap::new(), alone);
+            assert_eq!(diff.changed, [("alice".to_string(), contact("", &[]))]);
+        }
+
+        #[test]
+        fn server_versions_gate_what_the_client_asks_for() {
+            use server::protocol::SERVER_PROTOCOL_VERSION;
+            let ours = server_compatibility(SERVER_PROTOCOL_VERSION, true).unwrap();
+            assert!(ours >= SPACES_SINCE);
+            // Before versioned identify: usable, but without spaces
+            assert_eq!(server_compatibility("rendezvous-example/1.0.0", true), Ok((1, 0)));
+            assert!(server_compatibility("/neutral-server/2.0.0", true).unwrap_err().starts_with("Server version incompatible"));
+            assert!(server_compatibility("/neutral-server/x", true).is_err());
+            assert!(server_compatibility(SERVER_PROTOCOL_VERSION, false).unwrap_err().contains(AuthProtocol().as_ref()));
+        }
+
+        #[test]
+        fn reachability_follows_the_observed_port_and_relay() {
+            let ports: HashSet<u16> = [4001].into();
+            let seen = |port: u16| -> Multiaddr { format!("/ip4/203.0.113.9/tcp/{}", port).parse().unwrap() };
+            assert_eq!(Reachability::assess(None, &ports, false, false), Reachability::Unknown);
+            assert_eq!(Reachability::assess(Some(&seen(4001)), &ports, false, false), Reachability::Reachable);
+            // The NAT rewrote our port
+            assert_eq!(Reachability::assess(Some(&seen(50123)), &ports, false, false), Reachability::BehindNat);
+            assert_eq!(Reachability::assess(Some(&seen(50123)), &ports, false, true), Reachability::Relayed);
+            // --external vouches for itself
+            assert_eq!(Reachability::assess(Some(&seen(50123)), &ports, true, true), Reachability::Reachable);
+        }
+
+        #[test]
+        fn previews_truncate_on_char_boundaries() {
+            let exact = "a".repeat(48);
+            assert_eq!(truncate_preview(&exact), exact);
+            assert_eq!(truncate_preview(&format!("{}b", exact)), format!("{}…", exact));
+            // 4-byte emoji and 3-byte CJK characters straddle the old byte limit
+            let emoji = "😀".repeat(60);
+            assert_eq!(truncate_preview(&emoji), format!("{}…", "😀".repeat(48)));
+            let cjk = "你好".repeat(30);
+            assert_eq!(truncate_preview(&cjk).chars().count(), 49);
+            assert_eq!(truncate_preview("é\nü"), "é ü");
+        }
+
+        #[test]
+        fn last_seen_is_relative_until_a_week_has_passed() {
+            let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+            let ago = |secs| last_seen_label(now - Duration::from_secs(secs), now);
+            assert_eq!(ago(5), "last seen just now");
+            assert_eq!(ago(5 * 60 + 59), "last seen 5m ago");
+            assert_eq!(ago(3 * 3_600), "last seen 3h ago");
+            assert_eq!(ago(2 * 86_400), "last seen 2d ago");
+            assert!(ago(30 * 86_400).starts_with("last seen 2023-"));
+            // A server clock ahead of ours
+            assert_eq!(last_seen_label(now + Duration::from_secs(90), now), "last seen just now");
+        }
+
+        #[test]
+        fn diagnostics_keep_only_the_newest_entries() {
+            let mut log = DiagnosticsLog::default();
+            for n in 0..DIAGNOSTICS_MAX + 3 {
+                log.push(n % 2 == 1, format!("event {}", n));
+            }
+            assert_eq!(log.entries.len(), DIAGNOSTICS_MAX);
+            let text = log.to_text();
+            let lines: Vec<&str> = text.lines().collect();
+            assert!(lines[0].ends_with("ERROR event 3"), "{}", lines[0]);
+            assert!(lines[DIAGNOSTICS_MAX - 1].ends_with(&format!("INFO  event {}", DIAGNOSTICS_MAX + 2)));
+        }
+
+        #[test]
+        fn gossip_authors_must_be_a_listed_session_of_the_name() {
+            let directory = online_names(&HashMap::from([("Alice".to_string(), vec!["a1".to_string()]), ("bob".to_string(), Vec::new())]));
+            assert_eq!(verified_author(&directory, "a1", "alice"), Some("Alice".to_string()));
+            // Claiming an offline contact, or someone else's name, from a session that isn't theirs
+            assert_eq!(verified_author(&directory, "m1", "bob"), None);
+            assert_eq!(verified_author(&directory, "a1", "bob"), None);
+            assert_eq!(verified_author(&directory, "m1", "Alice"), None);
+
+            // An unverified room message is held back with the name it claims, to check again once resolved
+            let (tx, mut rx) = channel::channel(8);
+            let room = format!("{}/room/{}", RENDEZVOUS_NAMESPACE, DEFAULT_ROOM);
+            let payload = server::wire::join(&["Alice", "hi", "0"]);
+            assert_eq!(deliver_gossip(&tx, &directory, "m1", &room, &payload, false), Err("Alice".to_string()));
+            assert!(rx.try_recv().is_none());
+            assert_eq!(deliver_gossip(&tx, &directory, "a1", &room, &payload, false), Ok(()));
+            assert!(matches!(rx.try_recv(), Some(NetToUi::RoomMessage { from, .. }) if from == "Alice"));
+        }
+
+        #[test]
+        fn blocked_users_are_matched_by_session_not_claimed_name() {
+            let (old_session, new_session, stranger) = (PeerId::random().to_string(), PeerId::random().to_string(), PeerId::random().to_string());
+            let blocked = BlockList::from([("mallory".to_string(), BTreeSet::from([old_session.clone()]))]);
+            let directory = online_names(&HashMap::from([("Mallory".to_string(), vec![new_session.clone()]), ("bob".to_string(), vec![stranger.clone()])]));
+            assert!(is_blocked_peer(&blocked, &directory, &old_session));
+            // A session the directory lists under the blocked name, in any casing
+            assert!(is_blocked_peer(&blocked, &directory, &new_session));
+            assert!(!is_blocked_peer(&blocked, &directory, &stranger));
+            // Lists saved as bare names still load, keyed by username_key
+            let SavedBlockList::Names(names) = serde_json::from_str(r#"["Mallory"]"#).unwrap() else { panic!("expected a name list") };
+            assert_eq!(names, BTreeSet::from(["Mallory".to_string()]));
+            let saved = serde_json::to_string(&blocked).unwrap();
+            assert!(matches!(serde_json::from_str(&saved).unwrap(), SavedBlockList::Sessions(list) if list == blocked));
+        }
+
+        #[test]
+        fn message_length_ignores_surrounding_whitespace() {
+            let limit = "é".repeat(MAX_MESSAGE_CHARS);
+            assert_eq!(message_length(&format!("{}  \n\n", limit)), MAX_MESSAGE_CHARS);
+            assert!(message_length(&format!("{}x", limit)) > MAX_MESSAGE_CHARS);
+            assert_eq!(message_length(" \t\n"), 0);
+        }
+
+        #[test]
+        fn muted_chats_keep_unread_but_leave_the_title_count() {
+            let mut conversations: HashMap<String, Conversation> = HashMap::new();
+            for name in ["alice", "bob", "carol"] {
+                conversations.entry(name.to_string()).or_default().unread = name != "carol";
+            }
+            let mut muted = BTreeSet::new();
+            assert_eq!(unread_count(&conversations, &muted), 2);
+            muted.insert("bob".to_string());
+            assert_eq!(unread_count(&conversations, &muted), 1);
+            assert!(conversations["bob"].unread);
+        }
+
+        #[test]
+        fn chat_payloads_carry_the_send_time_and_id() {
+            let sent = SystemTime::UNIX_EPOCH + Duration::from_millis(1_700_000_000_123);
+            let payload = server::wire::join(&["alice", "hi | there", &unix_ms(sent).to_string(), "00ff", "42", "later"]);
+            let expected = ChatPayload {
+                from: "alice".to_string(),
+                text: "hi | there".to_string(),
+                sent,
+                msg_id: Some("00ff".to_string()),
+                seq: Some(42),
+            };
+            assert_eq!(parse_chat_payload(&payload), Some(expected));
+            // Older clients send no time or id; a sender clock ahead of ours is clamped to now
+            let before = message_time_now();
+            let legacy = parse_chat_payload(&server::wire::join(&["alice", "hi"])).unwrap();
+            let ahead = parse_chat_payload(&server::wire::join(&["alice", "hi", &u64::MAX.to_string()])).unwrap();
+            assert!(legacy.sent >= before && ahead.sent >= before && ahead.sent <= message_time_now());
+            assert_eq!((ahead.msg_id, ahead.seq), (None, None));
+            assert_eq!(parse_chat_payload(&server::wire::join(&["alice", "hi", "soon"])), None);
         }
-    }
\ No newline at end of file
+
+        #[test]
+        fn out_of_order_messages_render_in_sequence() {
+            let message = |from_self: bool, text: &str, seq: Option<u64>| ChatMessage {
+                from_self,
+                text: text.to_string(),
+                timestamp: message_time_now(),
+                forwarded: false,
+                author: None,
+                msg_id: None,
+                seq,
+                delivered: false,
+                failed: false,
+                queued: false,
+                read: false,
+            };
+            let mut conversation = Conversation::default();
+            conversation.push_in_sequence(message(false, "one", Some(1)));
+            // 3 overtakes 2, and we reply before 2 turns up
+            conversation.push_in_sequence(message(false, "three", Some(3)));
+            conversation.push_in_sequence(message(true, "reply", None));
+            conversation.push_in_sequence(message(false, "two", Some(2)));
+            // 4 is missing; 5 isn't held back for it, and a sender without numbers appends
+            conversation.push_in_sequence(message(false, "five", Some(5)));
+            conversation.push_in_sequence(message(false, "legacy", None));
+            let texts: Vec<&str> = conversation.messages.iter().map(|m| m.text.as_str()).collect();
+            assert_eq!(texts, ["one", "two", "three", "reply", "five", "legacy"]);
+        }
+
+        #[test]
+        fn imported_transcripts_merge_in_order_without_duplicates() {
+            let json = r#"{"version":1,"peer":"Bob","messages":[
+                {"from_self":false,"text":"hi","timestamp_ms":1700000001000},
+                {"from_self":true,"text":"fwd","timestamp_ms":1700000003000,"forwarded":true}
+            ]}"#;
+            let (peer, imported) = parse_transcript(json).unwrap();
+            assert_eq!(peer.as_deref(), Some("Bob"));
+            let at = |ms: u64| SystemTime::UNIX_EPOCH + Duration::from_millis(ms);
+            let fields: Vec<_> = imported.iter().map(|m| (m.from_self, m.text.as_str(), m.timestamp, m.forwarded)).collect();
+            assert_eq!(fields, [(false, "hi", at(1700000001000), false), (true, "fwd", at(1700000003000), true)]);
+            // The peer is optional; missing fields and other shapes are rejected
+            let (peer, messages) = parse_transcript(r#"{"messages":[]}"#).unwrap();
+            assert!(peer.is_none() && messages.is_empty());
+            for bad in ["", "[]", r#"{"peer":"bob"}"#, r#"{"messages":[{"text":"hi","timestamp_ms":1}]}"#, r#"{"messages":[{"from_self":true,"text":"hi","timestamp_ms":-1}]}"#] {
+                assert!(parse_transcript(bad).is_err(), "{}", bad);
+            }
+
+            // Same timestamp and text count as already present, even from the other side
+            let mut conversation = Conversation::default();
+            conversation.merge(vec![ChatMessage { timestamp: at(1700000002000), text: "between".to_string(), ..imported[1].clone() }]);
+            conversation.merge(vec![ChatMessage { from_self: true, ..imported[0].clone() }]);
+            assert_eq!(conversation.merge(imported.clone()), 1);
+            assert_eq!(conversation.merge(imported), 0);
+            let texts: Vec<&str> = conversation.messages.iter().map(|m| m.text.as_str()).collect();
+            assert_eq!(texts, ["hi", "between", "fwd"]);
+        }
+
+        #[test]
+        fn e2e_keys_agree_and_reject_tampering() {
+            let alice = libp2p::identity::Keypair::generate_ed25519();
+            let bob = libp2p::identity::Keypair::generate_ed25519();
+            let keys = HashMap::new();
+            let cipher = |me: &libp2p::identity::Keypair, peer: &libp2p::identity::Keypair| {
+                peer_cipher(e2e_secret(me).as_ref(), &keys, &PeerId::from(peer.public())).unwrap()
+            };
+            let sealed = e2e_seal(&cipher(&alice, &bob), "MSG:alice|hi", "Bob");
+            assert_eq!(e2e_open(&cipher(&bob, &alice), &sealed, "bob").as_deref(), Some("MSG:alice|hi"));
+            // Sealed for bob, so it doesn't open for another account on the same identity
+            assert_eq!(e2e_open(&cipher(&bob, &alice), &sealed, "carol"), None);
+            // Fresh nonces make equal texts differ on the wire
+            assert_ne!(e2e_seal(&cipher(&alice, &bob), "MSG:alice|hi", "bob"), sealed);
+
+            let mut tampered = hex::decode(&sealed).unwrap();
+            *tampered.last_mut().unwrap() ^= 1;
+            assert_eq!(e2e_open(&cipher(&bob, &alice), &hex::encode(tampered), "bob"), None);
+            let eve = libp2p::identity::Keypair::generate_ed25519();
+            assert_eq!(e2e_open(&cipher(&eve, &alice), &sealed, "bob"), None);
+            assert_eq!(e2e_open(&cipher(&bob, &alice), "00ff", "bob"), None);
+        }
+
+        #[test]
+        fn history_survives_reload_and_skips_bad_lines() {
+            assert_eq!(history_file_stem("../Bob"), "%2E%2E%2F%42ob");
+            let dir = std::env::temp_dir().join(format!("neutral-history-{}", std::process::id()));
+            let path = dir.join(format!("{}.jsonl", history_file_stem("../Bob")));
+            let message = |from_self: bool, text: &str, forwarded: bool, author: Option<&str>| ChatMessage {
+                from_self,
+                text: text.to_string(),
+                timestamp: message_time_now(),
+                forwarded,
+                author: author.map(str::to_string),
+                msg_id: None,
+                seq: None,
+                delivered: false,
+                failed: false,
+                queued: false,
+                read: false,
+            };
+            let mut sent = message(true, "hi", false, None);
+            sent.timestamp -= Duration::from_secs(60);
+            let received = message(false, "a\nb", true, Some("bob"));
+            append_history(&path, &sent);
+            io::Write::write_all(&mut std::fs::OpenOptions::new().append(true).open(&path).unwrap(), b"not json\n").unwrap();
+            append_history(&path, &received);
+
+            let (loaded, _) = load_history(&path, 0, None);
+            assert_eq!(loaded.len(), 2);
+            for (loaded, original) in loaded.iter().zip([&sent, &received]) {
+                assert_eq!(loaded.from_self, original.from_self);
+                assert_eq!(loaded.text, original.text);
+                assert_eq!(loaded.timestamp, original.timestamp);
+                assert_eq!(loaded.forwarded, original.forwarded);
+                assert_eq!(loaded.author, original.author);
+            }
+
+            // Only newer messages are read when the older ones are archived
+            let (live, archived) = load_history(&path, 0, Some(received.timestamp));
+            assert_eq!((live.len(), archived), (1, 1));
+            // A cleared prefix is skipped, and trimming it keeps what came after
+            let cleared = std::fs::metadata(&path).unwrap().len();
+            append_history(&path, &received);
+            assert_eq!(load_history(&path, cleared, None).0.len(), 1);
+            drop_history_prefix(&path, cleared);
+            assert_eq!(load_history(&path, 0, None).0.len(), 1);
+
+            save_history(&path, &[]);
+            assert!(load_history(&path, 0, None).0.is_empty());
+            let _ = std::fs::remove_dir_all(&dir);
+        }
+
+        struct TestClient {
+            tx: Sender<UiToNet>,
+            rx: Receiver<NetToUi>,
+            peer_id: PeerId,
+            // Received files land here
+            download_dir: std::path::PathBuf,
+            key: libp2p::identity::Keypair,
+        }
+
+        fn start_client(server_addr: &Multiaddr, server_peer_id: PeerId) -> TestClient {
+            start_client_as(server_addr, server_peer_id, libp2p::identity::Keypair::generate_ed25519())
+        }
+
+        // A client with a given identity, e.g. to restart one under the same PeerId
+        fn start_client_as(server_addr: &Multiaddr, server_peer_id: PeerId, key: libp2p::identity::Keypair) -> TestClient {
+            start_client_with(server_addr, server_peer_id, key, NetFeatures::default())
+        }
+
+        fn start_client_with(
+            server_addr: &Multiaddr,
+            server_peer_id: PeerId,
+            key: libp2p::identity::Keypair,
+            features: NetFeatures,
+        ) -> TestClient {
+            let (ui_tx, net_rx) = channel::channel::<UiToNet>(UI_TO_NET_CAPACITY);
+            let (net_tx, ui_rx) = channel::channel::<NetToUi>(NET_TO_UI_CAPACITY);
+            let peer_id = PeerId::from(key.public());
+            let download_dir = std::env::temp_dir().join(format!("neutral-downloads-{}", peer_id));
+            tokio::spawn(network_task(net_rx, net_tx, server_addr.clone(), server_peer_id, key.clone(), features, download_dir.clone()));
+            TestClient { tx: ui_tx, rx: ui_rx, peer_id, download_dir, key }
+        }
+
+        // Waits for the first message `pick` accepts, skipping unrelated ones
+        async fn expect<T>(client: &mut TestClient, what: &str, mut pick: impl FnMut(&NetToUi) -> Option<T>) -> T {
+            let wait = async {
+                while let Some(msg) = client.rx.recv().await {
+                    if let Some(found) = pick(&msg) {
+                        return found;
+                    }
+                }
+                panic!("network task stopped while waiting for {}", what);
+            };
+            tokio::time::timeout(Duration::from_secs(30), wait)
+                .await
+                .unwrap_or_else(|_| panic!("timed out waiting for {}", what))
+        }
+
+        // Returns the session token the new account is logged in with
+        async fn expect_registered(client: &mut TestClient, what: &str) -> String {
+            let result = expect(client, what, |m| match m {
+                NetToUi::Registered(result) => Some(result.clone()),
+                _ => None,
+            })
+            .await;
+            result.unwrap_or_else(|failure| panic!("{} failed: {}", what, auth_failure_text(&failure)))
+        }
+
+        // Returns the session token, which only LOGIN replies carry
+        async fn expect_auth_ok(client: &mut TestClient, what: &str) -> Option<String> {
+            let result = expect(client, what, |m| match m {
+                NetToUi::AuthResult(result) => Some(result.clone()),
+                _ => None,
+            })
+            .await;
+            result.unwrap_or_else(|failure| panic!("{} failed: {}", what, auth_failure_text(&failure)))
+        }
+
+        // Runs a server on an ephemeral loopback port; returns its address once it listens
+        async fn start_server(data_dir: &std::path::Path, idle_timeout: Duration) -> (Multiaddr, PeerId) {
+            let server_key = libp2p::identity::Keypair::generate_ed25519();
+            let server_peer_id = PeerId::from(server_key.public());
+            let (ready_tx, ready_rx) = tokio::sync::oneshot::channel();
+            tokio::spawn(run_server(
+                ServerConfig {
+                    keypair: server_key,
+                    listen_addr: "/ip4/127.0.0.1/tcp/0".parse().unwrap(),
+                    data_dir: data_dir.to_path_buf(),
+                    persist_registrations: false,
+                    admin_token: None,
+                    protocol_probes: None,
+                    strict_protocols: false,
+                    shutdown: None,
+                    password_hashing: server::PasswordHashing::default(),
+                    max_frame_bytes: DEFAULT_MAX_FRAME_BYTES,
+                    circuit_relay: false,
+                    metrics_addr: None,
+                    idle_timeout,
+                    max_connections: server::DEFAULT_MAX_CONNECTIONS,
+                    max_connections_per_peer: server::DEFAULT_MAX_CONNECTIONS_PER_PEER,
+                },
+                Some(ready_tx),
+            ));
+            let server_addr = tokio::time::timeout(Duration::from_secs(10), ready_rx)
+                .await
+                .expect("server did not start listening")
+                .unwrap();
+            (server_addr, server_peer_id)
+        }
+
+        // Names can't contain the old protocol delimiters, but the text uses them all
+        const ALICE: &str = "alice.a-1";
+        const BOB: &str = "Bob_2";
+        const MESSAGE: &str = "hello bob | a:b, c=d %2C";
+
+        #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+        async fn register_login_list_chat_logout() {
+            let data_dir = std::env::temp_dir().join(format!("neutral-e2e-{}", std::process::id()));
+            std::fs::create_dir_all(&data_dir).unwrap();
+
+            let (server_addr, server_peer_id) = start_server(&data_dir, server::DEFAULT_IDLE_TIMEOUT).await;
+
+            let mut alice = start_client(&server_addr, server_peer_id);
+            let mut bob = start_client(&server_addr, server_peer_id);
+
+            // Connections are dialed asynchronously; wait until both reach the server
+            for client in [&mut alice, &mut bob] {
+                expect(client, "connection to server", |m| match m {
+                    NetToUi::Connected(pid) if *pid == server_peer_id.to_string() => Some(()),
+                    _ => None,
+                })
+                .await;
+            }
+
+            let register = |username: &str| UiToNet::Register {
+                username: username.to_string(),
+                password: "secret-42".to_string(),
+                birthdate: "2000-01-01".to_string(),
+            };
+            alice.tx.send(register(ALICE)).unwrap();
+            let alice_token = expect_registered(&mut alice, "alice register").await;
+            bob.tx.send(register(BOB)).unwrap();
+            let bob_token = expect_registered(&mut bob, "bob register").await;
+            let alice_session = expect(&mut bob, "alice in bob's user list", |m| match m {
+                NetToUi::Users(users, _) => users.get(ALICE).filter(|sessions| sessions.len() == 1).map(|sessions| sessions[0].clone()),
+                _ => None,
+            })
+            .await;
+
+            // LIST: alice sees bob under a handle, not his PeerId; dialing resolves it
+            let bob_session = expect(&mut alice, "bob in user list", |m| match m {
+                NetToUi::Users(users, _) => users.get(BOB).filter(|sessions| sessions.len() == 1).map(|sessions| sessions[0].clone()),
+                _ => None,
+            })
+            .await;
+            assert_ne!(bob_session, bob.peer_id.to_string());
+            assert_ne!(alice_session, alice.peer_id.to_string());
+            expect(&mut alice, "bob discovered", |m| match m {
+                NetToUi::Discovered(peers) if peers.contains(&bob.peer_id.to_string()) => Some(()),
+                _ => None,
+            })
+            .await;
+
+            alice.tx.send(UiToNet::Connect { peer_id: bob_session.clone() }).unwrap();
+            // Like the UI, connect when the chat is opened and write once the peer is reachable
+            expect(&mut alice, "connection to bob", |m| match m {
+                NetToUi::Connected(pid) if *pid == bob_session => Some(()),
+                _ => None,
+            })
+            .await;
+            alice
+                .tx
+                .send(UiToNet::Write {
+                    peer_id: bob_session.clone(),
+                    from_username: ALICE.to_string(),
+                    to_username: BOB.to_string(),
+                    msg: MESSAGE.to_string(),
+                    forwarded: false,
+                    resend: None,
+                })
+                .unwrap();
+            let (peer, text, received_id) = expect(&mut bob, "message from alice", |m| match m {
+                NetToUi::ChatMessage { peer, direction: MessageDirection::Incoming, text, msg_id, .. } => {
+                    Some((peer.clone(), text.clone(), msg_id.clone()))
+                }
+                _ => None,
+            })
+            .await;
+            assert_eq!(peer, ALICE);
+            assert_eq!(text, MESSAGE);
+            let sent_id = expect(&mut alice, "echo of the sent message", |m| match m {
+                NetToUi::ChatMessage { direction: MessageDirection::Outgoing, msg_id, .. } => msg_id.clone(),
+                _ => None,
+            })
+            .await;
+            let (peer, msg_id) = expect(&mut alice, "delivery receipt from bob", |m| match m {
+                NetToUi::Delivered { peer, msg_id } => Some((peer.clone(), msg_id.clone())),
+                _ => None,
+            })
+            .await;
+            assert_eq!((peer.as_str(), msg_id), (BOB, sent_id.clone()));
+            assert_eq!(received_id, Some(sent_id.clone()));
+
+            // Opening the chat makes bob's client send a read receipt for the newest message
+            bob.tx.send(UiToNet::Read { peer_id: alice_session.clone(), msg_id: sent_id.clone() }).unwrap();
+            let (peer, up_to_id) = expect(&mut alice, "read receipt from bob", |m| match m {
+                NetToUi::Read { peer, up_to_id } => Some((peer.clone(), up_to_id.clone())),
+                _ => None,
+            })
+            .await;
+            assert_eq!((peer.as_str(), up_to_id), (BOB, sent_id));
+            // Bob's read receipt went out, so his client knows alice's version and can signal typing too
+            bob.tx.send(UiToNet::Typing { peer_id: alice_session.clone() }).unwrap();
+            let peer = expect(&mut alice, "typing signal from bob", |m| match m {
+                NetToUi::Typing { peer } => Some(peer.clone()),
+                _ => None,
+            })
+            .await;
+            assert_eq!(peer, BOB);
+
+            // A file bigger than one chunk arrives whole in bob's download folder
+            let contents: Vec<u8> = (0..FILE_CHUNK_BYTES as u32 * 2 + 17).map(|i| (i % 251) as u8).collect();
+            let source = alice.download_dir.with_extension("upload.bin");
+            std::fs::write(&source, &contents).unwrap();
+            alice
+                .tx
+                .send(UiToNet::SendFile { peer_id: bob_session.clone(), to_username: BOB.to_string(), path: source.clone() })
+                .unwrap();
+            let (filename, size) = expect(&mut bob, "file offer from alice", |m| match m {
+                NetToUi::FileTransfer { peer, filename, size, outgoing: false, .. } if peer == ALICE => Some((filename.clone(), *size)),
+                _ => None,
+            })
+            .await;
+            assert_eq!((filename.as_str(), size), (source.file_name().unwrap().to_str().unwrap(), contents.len() as u64));
+            let saved_to = expect(&mut bob, "received file", |m| match m {
+                NetToUi::FileDone { saved_to, .. } => saved_to.clone(),
+                NetToUi::FileFailed { reason, .. } => panic!("file transfer failed: {}", reason),
+                _ => None,
+            })
+            .await;
+            assert_eq!(std::fs::read(&saved_to).unwrap(), contents);
+            expect(&mut alice, "file sent", |m| match m {
+                NetToUi::FileDone { saved_to: None, .. } => Some(()),
+                NetToUi::FileFailed { reason, .. } => panic!("file transfer failed: {}", reason),
+                _ => None,
+            })
+            .await;
+            let _ = std::fs::remove_file(&source);
+            let _ = std::fs::remove_dir_all(&bob.download_dir);
+
+            // Logging out frees the session so the same account can log in again
+            alice.tx.send(UiToNet::Logout { username: ALICE.to_string(), token: alice_token.clone() }).unwrap();
+            expect_auth_ok(&mut alice, "alice logout").await;
+            alice
+                .tx
+                .send(UiToNet::Login { username: ALICE.to_string(), password: "secret-42".to_string() })
+                .unwrap();
+            let new_token = expect_auth_ok(&mut alice, "alice login").await.expect("login issues a session token");
+            assert_ne!(new_token, alice_token);
+
+            alice
+                .tx
+                .send(UiToNet::ChangePassword {
+                    username: ALICE.to_string(),
+                    old_password: "secret-42".to_string(),
+                    new_password: "new secret!".to_string(),
+                })
+                .unwrap();
+            let result = expect(&mut alice, "password change", |m| match m {
+                NetToUi::PasswordChanged(result) => Some(result.clone()),
+                _ => None,
+            })
+            .await;
+            if let Err(failure) = result {
+                panic!("password change failed: {}", auth_failure_text(&failure));
+            }
+
+            // Usernames are unique ignoring case
+            alice.tx.send(register(&BOB.to_uppercase())).unwrap();
+            let taken = expect(&mut alice, "differently cased register refused", |m| match m {
+                NetToUi::Registered(Err(failure)) => Some(auth_failure_text(failure)),
+                _ => None,
+            })
+            .await;
+            assert_eq!(taken, "Username taken");
+
+            // The same account can be online from a second device at once
+            let mut alice_phone = start_client(&server_addr, server_peer_id);
+            expect(&mut alice_phone, "second device's connection to server", |m| match m {
+                NetToUi::Connected(pid) if *pid == server_peer_id.to_string() => Some(()),
+                _ => None,
+            })
+            .await;
+            alice_phone
+                .tx
+                .send(UiToNet::Login { username: ALICE.to_string(), password: "new secret!".to_string() })
+                .unwrap();
+            expect_auth_ok(&mut alice_phone, "alice login from a second device").await;
+            // Handles don't depend on who asks; the phone's own session isn't listed
+            expect(&mut alice_phone, "alice's other session online", |m| match m {
+                NetToUi::Users(users, _) if users.get(ALICE) == Some(&vec![alice_session.clone()]) => Some(()),
+                _ => None,
+            })
+            .await;
+
+            // A message to an unreachable peer is queued on the server, sealed for the PeerId alice
+            // tried, and delivered when bob next logs in with that identity
+            bob.tx.send(UiToNet::Logout { username: BOB.to_string(), token: bob_token }).unwrap();
+            expect_auth_ok(&mut bob, "bob logout").await;
+            let bob_key = bob.key.clone();
+            drop(bob);
+            expect(&mut alice, "bob's client gone", |m| match m {
+                NetToUi::Disconnected { peer, .. } if *peer == bob_session => Some(()),
+                _ => None,
+            })
+            .await;
+            alice
+                .tx
+                .send(UiToNet::Write {
+                    peer_id: bob_session.clone(),
+                    from_username: ALICE.to_string(),
+                    to_username: BOB.to_string(),
+                    msg: "while you were away".to_string(),
+                    forwarded: false,
+                    resend: None,
+                })
+                .unwrap();
+            expect(&mut alice, "message queued on the server", |m| match m {
+                NetToUi::Info(info) if info.contains("unreachable") => Some(()),
+                _ => None,
+            })
+            .await;
+            let mut bob = start_client_as(&server_addr, server_peer_id, bob_key);
+            expect(&mut bob, "bob's restarted client connected to server", |m| match m {
+                NetToUi::Connected(pid) if *pid == server_peer_id.to_string() => Some(()),
+                _ => None,
+            })
+            .await;
+            // Any casing logs in to the same account
+            bob.tx.send(UiToNet::Login { username: BOB.to_uppercase(), password: "secret-42".to_string() }).unwrap();
+            expect_auth_ok(&mut bob, "bob login").await;
+            let (peer, text) = expect(&mut bob, "queued message from alice", |m| match m {
+                NetToUi::ChatMessage { peer, direction: MessageDirection::Incoming, text, .. } => {
+                    Some((peer.clone(), text.clone()))
+                }
+                _ => None,
+            })
+            .await;
+            assert_eq!(peer, ALICE);
+            assert_eq!(text, "while you were away");
+
+            let _ = std::fs::remove_dir_all(&data_dir);
+        }
+
+        #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+        async fn quiet_clients_keep_their_server_connection() {
+            let data_dir = std::env::temp_dir().join(format!("neutral-keepalive-{}", std::process::id()));
+            std::fs::create_dir_all(&data_dir).unwrap();
+            // The server gives up on a quiet connection just after one keep-alive period; the
+            // client's own idle timeout is far longer, so it alone would keep quiet too long
+            let idle_timeout = Duration::from_secs(SERVER_KEEPALIVE_SECS + 3);
+            let (server_addr, server_peer_id) = start_server(&data_dir, idle_timeout).await;
+            let features = NetFeatures { peer_idle_timeout: Some(Duration::from_secs(600)), ..NetFeatures::default() };
+            let mut alice = start_client_with(&server_addr, server_peer_id, libp2p::identity::Keypair::generate_ed25519(), features);
+            expect(&mut alice, "connection to server", |m| match m {
+                NetToUi::Connected(pid) if *pid == server_peer_id.to_string() => Some(()),
+                _ => None,
+            })
+            .await;
+            let register = |username: &str| UiToNet::Register {
+                username: username.to_string(),
+                password: "secret-42".to_string(),
+                birthdate: "2000-01-01".to_string(),
+            };
+            alice.tx.send(register(ALICE)).unwrap();
+            expect_registered(&mut alice, "alice register").await;
+
+            // No chat for longer than the server's idle timeout
+            let quiet = async {
+                while let Some(msg) = alice.rx.recv().await {
+                    if let NetToUi::Disconnected { server: true, reason, .. } = msg {
+                        panic!("server connection closed while quiet: {:?}", reason);
+                    }
+                }
+            };
+            let _ = tokio::time::timeout(idle_timeout + Duration::from_secs(5), quiet).await;
+
+            // The roster still reaches alice over the same session
+            let mut bob = start_client(&server_addr, server_peer_id);
+            expect(&mut bob, "bob's connection to server", |m| match m {
+                NetToUi::Connected(pid) if *pid == server_peer_id.to_string() => Some(()),
+                _ => None,
+            })
+            .await;
+            bob.tx.send(register(BOB)).unwrap();
+            expect_registered(&mut bob, "bob register").await;
+            expect(&mut alice, "bob in user list", |m| match m {
+                NetToUi::Users(users, _) => users.get(BOB).is_some_and(|sessions| sessions.len() == 1).then_some(()),
+                _ => None,
+            })
+            .await;
+
+            let _ = std::fs::remove_dir_all(&bob.download_dir);
+            let _ = std::fs::remove_dir_all(&data_dir);
+        }
+    }
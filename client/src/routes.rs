@@ -0,0 +1,111 @@
+// Where the sessions in the server's directory are. The directory lists every session under an
+// opaque handle, and the server resolves a handle to its PeerId only when we are about to reach
+// that session, telling it who asked. Work for a handle that isn't resolved yet waits here for
+// the server's answer. Routes are kept across logins: a handle always means the same session.
+use libp2p::PeerId;
+use std::{
+    collections::{HashMap, HashSet},
+    str::FromStr,
+};
+
+// Items held at once for unresolved handles; past that, new ones aren't held
+pub const MAX_WAITING: usize = 256;
+
+pub struct Routes<T> {
+    peers: HashMap<String, PeerId>,
+    sessions: HashMap<PeerId, String>,
+    // Handles the server has been asked about and hasn't answered yet
+    asking: HashSet<String>,
+    // Each held item with the handles it still waits for
+    waiting: Vec<(Vec<String>, T)>,
+}
+
+impl<T> Default for Routes<T> {
+    fn default() -> Self {
+        Self { peers: HashMap::new(), sessions: HashMap::new(), asking: HashSet::new(), waiting: Vec::new() }
+    }
+}
+
+impl<T> Routes<T> {
+    // What the UI calls `peer`: its handle, or the PeerId itself when none resolved to it
+    pub fn session(&self, peer: &PeerId) -> String {
+        self.sessions.get(peer).cloned().unwrap_or_else(|| peer.to_string())
+    }
+
+    // Where to dial a session the UI names, by handle or by PeerId
+    pub fn peer(&self, session: &str) -> Option<PeerId> {
+        self.peers.get(session).copied().or_else(|| PeerId::from_str(session).ok())
+    }
+
+    pub fn learn(&mut self, session: String, peer: PeerId) {
+        if let Some(old) = self.sessions.insert(peer, session.clone()) {
+            self.peers.remove(&old);
+        }
+        if let Some(old) = self.peers.insert(session, peer)
+            && old != peer
+        {
+            self.sessions.remove(&old);
+        }
+    }
+
+    // Whether a Resolve for `session` should go out, i.e. none is in flight yet
+    pub fn ask(&mut self, session: &str) -> bool {
+        self.asking.insert(session.to_string())
+    }
+
+    pub fn full(&self) -> bool {
+        self.waiting.len() >= MAX_WAITING
+    }
+
+    // Holds `item` until the server has answered for each of `sessions`
+    pub fn wait(&mut self, sessions: Vec<String>, item: T) {
+        self.waiting.push((sessions, item));
+    }
+
+    // The server's answer for `session`, None if it couldn't resolve it. Returns the held
+    // items that no longer wait for anything, in the order they were held.
+    pub fn answered(&mut self, session: &str, peer: Option<PeerId>) -> Vec<T> {
+        self.asking.remove(session);
+        if let Some(peer) = peer {
+            self.learn(session.to_string(), peer);
+        }
+        for (sessions, _) in &mut self.waiting {
+            sessions.retain(|s| s != session);
+        }
+        let (ready, waiting) = std::mem::take(&mut self.waiting).into_iter().partition(|(sessions, _)| sessions.is_empty());
+        self.waiting = waiting;
+        ready.into_iter().map(|(_, item)| item).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn held_items_wait_for_every_handle_they_name() {
+        let (alice, bob) = (PeerId::random(), PeerId::random());
+        let mut routes = Routes::default();
+        assert!(routes.ask("a") && routes.ask("b"));
+        // Already in flight
+        assert!(!routes.ask("a"));
+        routes.wait(vec!["a".to_string()], 1);
+        routes.wait(vec!["a".to_string(), "b".to_string()], 2);
+        routes.wait(vec!["b".to_string()], 3);
+
+        assert_eq!(routes.answered("a", Some(alice)), [1]);
+        assert_eq!(routes.peer("a"), Some(alice));
+        assert_eq!(routes.session(&alice), "a");
+        // An answer without a route still releases what waited
+        assert_eq!(routes.answered("b", None), [2, 3]);
+        assert_eq!(routes.peer("b"), None);
+        assert_eq!(routes.session(&bob), bob.to_string());
+        assert!(routes.ask("b"));
+
+        // PeerIds work as they are, and a session that moved forgets its old handle
+        assert_eq!(routes.peer(&bob.to_string()), Some(bob));
+        routes.learn("a2".to_string(), alice);
+        assert_eq!(routes.session(&alice), "a2");
+        assert_eq!(routes.peer("a"), None);
+    }
+}
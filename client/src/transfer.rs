@@ -0,0 +1,226 @@
+// File transfer state for the /file/1.0 protocol: outgoing files are hashed up front and
+// read chunk by chunk; incoming ones go to a .part file until their size and hash check out.
+use sha2::{Digest, Sha256};
+use std::{
+    fs,
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+};
+
+// Largest file sent or accepted unless NEUTRAL_MAX_FILE_BYTES says otherwise
+pub const DEFAULT_MAX_FILE_BYTES: u64 = 100 * 1024 * 1024;
+
+// Incoming transfers accepted at once from one peer, and from everyone together; further
+// offers are rejected until one finishes
+pub const MAX_INCOMING_PER_PEER: usize = 2;
+pub const MAX_INCOMING_TRANSFERS: usize = 8;
+
+pub struct OutgoingFile {
+    file: fs::File,
+    pub filename: String,
+    pub size: u64,
+    // Lowercase hex of the whole file
+    pub sha256: String,
+    // Bytes handed out by next_chunk so far
+    pub sent: u64,
+}
+
+impl OutgoingFile {
+    // Reads the whole file once for the hash, so this blocks; keep it off the swarm loop
+    pub fn open(path: &Path, max_bytes: u64) -> Result<Self, String> {
+        let filename = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(sanitize_filename)
+            .ok_or_else(|| format!("{} has no usable file name", path.display()))?;
+        let mut file = fs::File::open(path).map_err(|e| format!("Cannot open {}: {}", path.display(), e))?;
+        let size = file.metadata().map_err(|e| format!("Cannot read {}: {}", path.display(), e))?.len();
+        if size > max_bytes {
+            return Err(format!("{} is too large ({}, the limit is {})", filename, format_size(size), format_size(max_bytes)));
+        }
+        let mut hasher = Sha256::new();
+        io::copy(&mut file, &mut hasher).map_err(|e| format!("Cannot read {}: {}", path.display(), e))?;
+        // Reopen rather than seek, so chunks start from the beginning on every platform
+        let file = fs::File::open(path).map_err(|e| format!("Cannot open {}: {}", path.display(), e))?;
+        Ok(Self { file, filename, size, sha256: hex::encode(hasher.finalize()), sent: 0 })
+    }
+
+    // The next chunk and its offset, or None once the whole file has been read
+    pub fn next_chunk(&mut self, chunk_bytes: usize) -> io::Result<Option<(u64, Vec<u8>)>> {
+        if self.sent >= self.size {
+            return Ok(None);
+        }
+        let len = (self.size - self.sent).min(chunk_bytes as u64) as usize;
+        let mut data = vec![0; len];
+        self.file.read_exact(&mut data)?;
+        let offset = self.sent;
+        self.sent += len as u64;
+        Ok(Some((offset, data)))
+    }
+}
+
+pub struct IncomingFile {
+    file: fs::File,
+    dir: PathBuf,
+    part_path: PathBuf,
+    pub filename: String,
+    pub size: u64,
+    sha256: String,
+    hasher: Sha256,
+    pub received: u64,
+}
+
+impl IncomingFile {
+    pub fn create(dir: &Path, filename: &str, size: u64, sha256: &str, max_bytes: u64) -> Result<Self, String> {
+        if size > max_bytes {
+            return Err("File too large".to_string());
+        }
+        let filename = sanitize_filename(filename).ok_or("Invalid file name")?;
+        fs::create_dir_all(dir).map_err(|e| format!("Cannot create {}: {}", dir.display(), e))?;
+        let part_path = unique_path(dir, &format!("{}.part", filename));
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&part_path)
+            .map_err(|e| format!("Cannot create {}: {}", part_path.display(), e))?;
+        Ok(Self {
+            file,
+            dir: dir.to_path_buf(),
+            part_path,
+            filename,
+            size,
+            sha256: sha256.to_ascii_lowercase(),
+            hasher: Sha256::new(),
+            received: 0,
+        })
+    }
+
+    // Chunks must arrive in order and stay within the offered size
+    pub fn write_chunk(&mut self, offset: u64, data: &[u8]) -> Result<(), String> {
+        if offset != self.received {
+            return Err("Chunk out of order".to_string());
+        }
+        if self.received + data.len() as u64 > self.size {
+            return Err("More data than offered".to_string());
+        }
+        self.file.write_all(data).map_err(|e| format!("Cannot write {}: {}", self.part_path.display(), e))?;
+        self.hasher.update(data);
+        self.received += data.len() as u64;
+        Ok(())
+    }
+
+    // Checks the size and hash, then moves the file to a free name next to the .part file
+    pub fn finish(self) -> Result<PathBuf, String> {
+        let IncomingFile { file, dir, part_path, filename, size, sha256, hasher, received } = self;
+        let result = if received != size {
+            Err("Transfer incomplete".to_string())
+        } else if hex::encode(hasher.finalize()) != sha256 {
+            Err("Checksum mismatch".to_string())
+        } else {
+            file.sync_all().map_err(|e| format!("Cannot write {}: {}", part_path.display(), e))
+        };
+        // Closed before renaming or removing, which Windows requires
+        drop(file);
+        if let Err(e) = result {
+            let _ = fs::remove_file(&part_path);
+            return Err(e);
+        }
+        let path = unique_path(&dir, &filename);
+        fs::rename(&part_path, &path).map_err(|e| format!("Cannot save {}: {}", path.display(), e))?;
+        Ok(path)
+    }
+
+    pub fn abort(self) {
+        let IncomingFile { file, part_path, .. } = self;
+        drop(file);
+        let _ = fs::remove_file(&part_path);
+    }
+}
+
+// The last path component of a sender-supplied name, so it can't point outside the download folder
+pub fn sanitize_filename(name: &str) -> Option<String> {
+    let base = name.rsplit(['/', '\\']).next()?;
+    let base: String = base.chars().filter(|c| !c.is_control()).collect();
+    let base = base.trim();
+    if base.is_empty() || base == "." || base == ".." {
+        return None;
+    }
+    Some(base.to_string())
+}
+
+// `dir/name`, or `dir/name (1).ext` and so on when that exists
+fn unique_path(dir: &Path, name: &str) -> PathBuf {
+    let candidate = dir.join(name);
+    if !candidate.exists() {
+        return candidate;
+    }
+    let (stem, ext) = match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => (stem, format!(".{}", ext)),
+        _ => (name, String::new()),
+    };
+    (1..)
+        .map(|n| dir.join(format!("{} ({}){}", stem, n, ext)))
+        .find(|path| !path.exists())
+        .expect("some numbered name is free")
+}
+
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KB", "MB", "GB", "TB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn files_round_trip_and_bad_transfers_leave_nothing_behind() {
+        let dir = std::env::temp_dir().join(format!("neutral-transfer-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("out")).unwrap();
+        let source = dir.join("out").join("photo.jpg");
+        let contents: Vec<u8> = (0..200_000u32).map(|i| i as u8).collect();
+        fs::write(&source, &contents).unwrap();
+        let inbox = dir.join("in");
+
+        let send = |max_bytes| {
+            let mut outgoing = OutgoingFile::open(&source, max_bytes).unwrap();
+            let mut incoming =
+                IncomingFile::create(&inbox, "../../photo.jpg", outgoing.size, &outgoing.sha256, max_bytes).unwrap();
+            while let Some((offset, data)) = outgoing.next_chunk(64 * 1024).unwrap() {
+                incoming.write_chunk(offset, &data).unwrap();
+            }
+            incoming
+        };
+        let saved = send(DEFAULT_MAX_FILE_BYTES).finish().unwrap();
+        assert_eq!(saved, inbox.join("photo.jpg"));
+        assert_eq!(fs::read(&saved).unwrap(), contents);
+        // A second copy gets a new name instead of overwriting
+        assert_eq!(send(DEFAULT_MAX_FILE_BYTES).finish().unwrap(), inbox.join("photo (1).jpg"));
+
+        assert!(OutgoingFile::open(&source, 1000).err().unwrap().contains("too large"));
+        assert_eq!(IncomingFile::create(&inbox, "big", 1001, "", 1000).err().as_deref(), Some("File too large"));
+        let mut wrong_hash = IncomingFile::create(&inbox, "photo.jpg", 3, &"0".repeat(64), 1000).unwrap();
+        assert_eq!(wrong_hash.write_chunk(1, b"ab").unwrap_err(), "Chunk out of order");
+        wrong_hash.write_chunk(0, b"abc").unwrap();
+        assert_eq!(wrong_hash.write_chunk(3, b"d").unwrap_err(), "More data than offered");
+        assert_eq!(wrong_hash.finish().unwrap_err(), "Checksum mismatch");
+        IncomingFile::create(&inbox, "photo.jpg", 3, "", 1000).unwrap().abort();
+        let mut left: Vec<String> = fs::read_dir(&inbox).unwrap().map(|e| e.unwrap().file_name().into_string().unwrap()).collect();
+        left.sort();
+        assert_eq!(left, ["photo (1).jpg", "photo.jpg"]);
+
+        assert_eq!(sanitize_filename("..\\evil\\..\\a.txt").as_deref(), Some("a.txt"));
+        assert_eq!(sanitize_filename("dir/.."), None);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,98 @@
+//! Calendar helpers shared by the server's REGISTER validation and the client's date picker.
+
+pub fn is_leap_year(year: i32) -> bool {
+    (year % 4 == 0 && year % 100 != 0) || (year % 400 == 0)
+}
+
+pub fn days_in_month(year: i32, month: u32) -> u32 {
+    match month {
+        1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+        4 | 6 | 9 | 11 => 30,
+        2 => if is_leap_year(year) { 29 } else { 28 },
+        _ => 30,
+    }
+}
+
+/// Parses a strict `YYYY-MM-DD` date, rejecting days that don't exist in that month.
+pub fn parse_birthdate(s: &str) -> Option<(i32, u32, u32)> {
+    let mut parts = s.split('-');
+    let (year, month, day) = (parts.next()?, parts.next()?, parts.next()?);
+    if parts.next().is_some() || year.len() != 4 || month.len() != 2 || day.len() != 2 {
+        return None;
+    }
+    let year: i32 = year.parse().ok()?;
+    let month: u32 = month.parse().ok()?;
+    let day: u32 = day.parse().ok()?;
+    if !(1..=12).contains(&month) || day == 0 || day > days_in_month(year, month) {
+        return None;
+    }
+    Some((year, month, day))
+}
+
+/// Today's date in UTC.
+pub fn today() -> (i32, u32, u32) {
+    let secs = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    civil_from_days((secs / 86_400) as i64)
+}
+
+/// Completed years between `birth` and `on`; 0 if `birth` is in the future.
+pub fn age_on(birth: (i32, u32, u32), on: (i32, u32, u32)) -> u32 {
+    let mut years = on.0 - birth.0;
+    if (on.1, on.2) < (birth.1, birth.2) {
+        years -= 1;
+    }
+    years.max(0) as u32
+}
+
+// Days since 1970-01-01 to a (year, month, day) in the proleptic Gregorian calendar
+// (Howard Hinnant's civil_from_days)
+fn civil_from_days(days: i64) -> (i32, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = (yoe + era * 400 + if month <= 2 { 1 } else { 0 }) as i32;
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn leap_day_birthdate() {
+        assert_eq!(parse_birthdate("2000-02-29"), Some((2000, 2, 29)));
+        assert_eq!(parse_birthdate("1900-02-29"), None);
+        // A leap-day birthday is only reached on March 1st in common years
+        assert_eq!(age_on((2000, 2, 29), (2013, 2, 28)), 12);
+        assert_eq!(age_on((2000, 2, 29), (2013, 3, 1)), 13);
+    }
+
+    #[test]
+    fn impossible_dates_are_rejected() {
+        for s in ["9999-99-99", "2001-02-29", "2020-04-31", "2020-00-10", "2020-1-1", "20-01-01", "2020-01-01-01", ""] {
+            assert_eq!(parse_birthdate(s), None, "{}", s);
+        }
+    }
+
+    #[test]
+    fn underage_user() {
+        assert_eq!(age_on((2014, 6, 15), (2027, 6, 14)), 12);
+        assert_eq!(age_on((2014, 6, 15), (2027, 6, 15)), 13);
+        assert_eq!(age_on((2030, 1, 1), (2027, 6, 15)), 0);
+    }
+
+    #[test]
+    fn civil_dates() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(11_016), (2000, 2, 29));
+        assert_eq!(civil_from_days(20_740), (2026, 10, 14));
+    }
+}
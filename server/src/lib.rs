@@ -0,0 +1,2561 @@
+use futures::StreamExt;
+use libp2p::{
+    connection_limits, identify, multiaddr::Protocol, noise, ping, rendezvous, request_response,
+    swarm::{behaviour::toggle::Toggle, ListenError, NetworkBehaviour, SwarmEvent},
+    tcp, yamux,
+    PeerId,
+};
+use std::{error::Error, io, collections::{HashMap, HashSet, VecDeque}, fs, path::{Path, PathBuf}, str::FromStr, time::{Duration, Instant}};
+use libp2p::{identity::Keypair, Multiaddr};
+use protocol::{
+    AdminCommand, AuthCodec, AuthError, AuthProtocol, AuthRequest, AuthResponse, HelloCodec, HelloProtocol, KEEPALIVE,
+    QueuedMessage, RelayCodec, RelayProtocol, RelayRequest, RelayResponse, SERVER_PROTOCOL_VERSION,
+    username_key,
+};
+use serde::{Serialize, Deserialize};
+use sha2::{Sha256, Digest};
+use subtle::ConstantTimeEq;
+use argon2::{
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Algorithm, Argon2, Params, Version,
+};
+
+// Failed LOGIN attempts allowed per peer, and per username from one peer, within the sliding
+// window; further attempts are refused without checking the password
+const MAX_LOGIN_FAILURES: usize = 5;
+const MAX_ACCOUNT_LOGIN_FAILURES: usize = 3;
+const LOGIN_FAILURE_WINDOW_SECS: u64 = 5 * 60;
+
+pub mod dates;
+mod metrics;
+pub mod protocol;
+pub mod wire;
+
+// Youngest age allowed to REGISTER, checked against the server's current date
+const MIN_REGISTRATION_AGE: u32 = 13;
+
+// Offline messages kept per recipient; further relay requests get "Queue full"
+const MAX_QUEUED_MESSAGES: usize = 100;
+
+// Accounts per ListAll page, well under the default frame limit even with the longest names
+const ACCOUNTS_PAGE_SIZE: usize = 500;
+
+// Resolve requests allowed per peer within the sliding window: plenty for opening chats and
+// checking gossip authors, too few to map a large directory to PeerIds
+const MAX_RESOLVES: usize = 30;
+const RESOLVE_WINDOW_SECS: u64 = 60;
+
+// --- Server Entry Point ---
+
+/// Everything `run_server` needs, so the server can be started from `main`,
+/// embedded in another process, or driven from tests.
+pub struct ServerConfig {
+    /// Identity of the rendezvous point; clients must know its PeerId
+    pub keypair: Keypair,
+    /// Multiaddr to listen on, e.g. `/ip4/0.0.0.0/tcp/62649` (port 0 picks an ephemeral port)
+    pub listen_addr: Multiaddr,
+    /// Directory holding users.xml, registrations.xml and offline_messages.xml
+    pub data_dir: PathBuf,
+    /// Snapshot rendezvous registrations to disk and restore them on startup
+    pub persist_registrations: bool,
+    /// Operator token for ADMIN: commands; admin commands are disabled when `None`
+    pub admin_token: Option<String>,
+    /// Peers that opened streams for protocols the server doesn't speak, reported by
+    /// the layer from `protocol_probe_layer`; `None` disables probe tracking
+    pub protocol_probes: Option<tokio::sync::mpsc::UnboundedReceiver<PeerId>>,
+    /// Disconnect peers once they reach `MAX_PROTOCOL_PROBES` unsupported-protocol attempts
+    pub strict_protocols: bool,
+    /// Stops the server (after flushing pending writes) when signalled or dropped
+    pub shutdown: Option<tokio::sync::oneshot::Receiver<()>>,
+    /// Argon2id cost used for newly stored passwords
+    pub password_hashing: PasswordHashing,
+    /// Largest chat or auth frame accepted or sent, see `protocol::DEFAULT_MAX_FRAME_BYTES`
+    pub max_frame_bytes: usize,
+    /// Also act as a libp2p circuit relay, so clients behind NAT can reach each other
+    /// through the server and then hole punch a direct connection
+    pub circuit_relay: bool,
+    /// Serve Prometheus metrics over HTTP on this address; `None` binds nothing
+    pub metrics_addr: Option<std::net::SocketAddr>,
+    /// Connections without open streams are closed after this long, see `DEFAULT_IDLE_TIMEOUT`
+    pub idle_timeout: Duration,
+    /// Established client connections accepted in total, see `DEFAULT_MAX_CONNECTIONS`
+    pub max_connections: u32,
+    /// Established connections accepted from any one peer, see `DEFAULT_MAX_CONNECTIONS_PER_PEER`
+    pub max_connections_per_peer: u32,
+}
+
+/// Idle timeout for client connections. Logged-in clients send a keep-alive well within it,
+/// so it mostly closes connections from peers that never logged in.
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Connection caps, so one host can't exhaust the server's file descriptors and memory.
+/// A client needs one connection; a few per peer leave room for reconnects overlapping
+/// a connection that hasn't timed out yet.
+pub const DEFAULT_MAX_CONNECTIONS: u32 = 1024;
+pub const DEFAULT_MAX_CONNECTIONS_PER_PEER: u32 = 4;
+
+// Incoming connections still in their handshake; the peer isn't known yet, so this is the only
+// cap on connections that never finish one
+const MAX_PENDING_CONNECTIONS: u32 = 128;
+
+// Streams one connection may have open at once, per protocol (request_response allows 100).
+// Clients only need a few at a time; more means a peer is flooding requests.
+const MAX_CHAT_STREAMS: usize = 32;
+const MAX_AUTH_STREAMS: usize = 8;
+const MAX_RELAY_STREAMS: usize = 8;
+
+/// Parses an address given on the command line: a multiaddr such as `/dns4/example.org/tcp/62649`,
+/// `ip:port` with IPv6 in brackets (`[::1]:62649`), or `host:port`, which becomes a `/dns` address.
+/// The server's listen argument and the client's server argument both go through it.
+pub fn parse_tcp_address(arg: &str) -> Result<Multiaddr, String> {
+    let arg = arg.trim();
+    if arg.starts_with('/') {
+        return arg.parse().map_err(|e| format!("{}", e));
+    }
+    if let Ok(socket) = arg.parse::<std::net::SocketAddr>() {
+        return Ok(Multiaddr::empty().with(socket.ip().into()).with(Protocol::Tcp(socket.port())));
+    }
+    match arg.rsplit_once(':') {
+        Some((host, _)) if host.contains(':') => Err("put IPv6 addresses in brackets, e.g. [::1]:62649".to_string()),
+        Some((host, port)) if !host.is_empty() => {
+            let port: u16 = port.parse().map_err(|_| format!("'{}' is not a port", port))?;
+            Ok(Multiaddr::empty().with(Protocol::Dns(host.to_string().into())).with(Protocol::Tcp(port)))
+        }
+        _ => Err("expected ip:port, host:port or a multiaddr".to_string()),
+    }
+}
+
+// Password policy for REGISTER and CHANGEPW
+const MIN_PASSWORD_LEN: usize = 8;
+const MIN_PASSWORD_CLASSES: usize = 2;
+
+/// Checks a new password against the server's policy: at least `MIN_PASSWORD_LEN`
+/// characters from at least two of lowercase, uppercase, digits and other characters.
+/// The client runs the same check before sending, so both sides agree.
+pub fn validate_password(pw: &str) -> Result<(), String> {
+    if pw.chars().count() < MIN_PASSWORD_LEN {
+        return Err(format!("must be at least {} characters", MIN_PASSWORD_LEN));
+    }
+    let classes = [
+        pw.chars().any(|c| c.is_lowercase()),
+        pw.chars().any(|c| c.is_uppercase()),
+        pw.chars().any(|c| c.is_ascii_digit()),
+        pw.chars().any(|c| !c.is_alphanumeric()),
+    ];
+    if classes.iter().filter(|present| **present).count() < MIN_PASSWORD_CLASSES {
+        return Err("mix letters with digits, capitals or symbols".to_string());
+    }
+    Ok(())
+}
+
+// Username policy for REGISTER
+const MIN_USERNAME_LEN: usize = 3;
+const MAX_USERNAME_LEN: usize = 32;
+const USERNAME_SYMBOLS: &[char] = &['_', '-', '.'];
+
+/// Checks a new username: `MIN_USERNAME_LEN` to `MAX_USERNAME_LEN` letters, digits and
+/// `USERNAME_SYMBOLS`, starting with a letter or digit. Like `validate_password`, the client
+/// runs it before sending. Existing accounts are not checked.
+pub fn validate_username(name: &str) -> Result<(), String> {
+    let len = name.chars().count();
+    if !(MIN_USERNAME_LEN..=MAX_USERNAME_LEN).contains(&len) {
+        return Err(format!("must be {} to {} characters", MIN_USERNAME_LEN, MAX_USERNAME_LEN));
+    }
+    if let Some(c) = name.chars().find(|c| !c.is_alphanumeric() && !USERNAME_SYMBOLS.contains(c)) {
+        return Err(format!("'{}' is not allowed; use letters, digits, _ - or .", c));
+    }
+    if !name.starts_with(char::is_alphanumeric) {
+        return Err("must start with a letter or digit".to_string());
+    }
+    Ok(())
+}
+
+/// Argon2id cost parameters. Existing hashes keep the parameters they were created with.
+#[derive(Debug, Clone, Copy)]
+pub struct PasswordHashing {
+    pub memory_kib: u32,
+    pub iterations: u32,
+}
+
+impl Default for PasswordHashing {
+    /// The argon2 crate's defaults (19 MiB, 2 passes), in line with the OWASP recommendation
+    fn default() -> Self {
+        Self { memory_kib: Params::DEFAULT_M_COST, iterations: Params::DEFAULT_T_COST }
+    }
+}
+
+impl PasswordHashing {
+    fn hasher(&self) -> Result<Argon2<'static>, argon2::Error> {
+        let params = Params::new(self.memory_kib, self.iterations, 1, None)?;
+        Ok(Argon2::new(Algorithm::Argon2id, Version::V0x13, params))
+    }
+}
+
+/// Runs the rendezvous/auth server until the swarm stops.
+/// `listening` receives the first bound listen address, which is how callers
+/// learn the real port when listening on port 0.
+pub async fn run_server(
+    config: ServerConfig,
+    listening: Option<tokio::sync::oneshot::Sender<Multiaddr>>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let ServerConfig {
+        keypair,
+        listen_addr,
+        data_dir,
+        persist_registrations,
+        admin_token,
+        mut protocol_probes,
+        strict_protocols,
+        mut shutdown,
+        password_hashing,
+        max_frame_bytes,
+        circuit_relay,
+        metrics_addr,
+        idle_timeout,
+        max_connections,
+        max_connections_per_peer,
+    } = config;
+    let hasher = password_hashing
+        .hasher()
+        .map_err(|e| format!("Invalid password hashing parameters: {}", e))?;
+    let route_key = route_key(&keypair);
+    let mut swarm = libp2p::SwarmBuilder::with_existing_identity(keypair)
+        .with_tokio()
+        .with_tcp(
+            tcp::Config::default(),
+            noise::Config::new,
+            yamux::Config::default,
+        )?
+        .with_behaviour(|key| MyBehaviour {
+            limits: connection_limits::Behaviour::new(
+                connection_limits::ConnectionLimits::default()
+                    .with_max_pending_incoming(Some(MAX_PENDING_CONNECTIONS))
+                    .with_max_established_incoming(Some(max_connections))
+                    .with_max_established_per_peer(Some(max_connections_per_peer)),
+            ),
+            identify: identify::Behaviour::new(identify::Config::new(
+                SERVER_PROTOCOL_VERSION.to_string(),
+                key.public(),
+            )),
+            rendezvous: rendezvous::server::Behaviour::new(rendezvous::server::Config::default()),
+            ping: ping::Behaviour::new(ping::Config::default()),
+            request_response: request_response::Behaviour::with_codec(
+                HelloCodec::new(max_frame_bytes),
+                std::iter::once((HelloProtocol(), request_response::ProtocolSupport::Full)),
+                request_response::Config::default().with_max_concurrent_streams(MAX_CHAT_STREAMS),
+            ),
+            auth: request_response::Behaviour::with_codec(
+                AuthCodec::new(max_frame_bytes),
+                std::iter::once((AuthProtocol(), request_response::ProtocolSupport::Full)),
+                request_response::Config::default().with_max_concurrent_streams(MAX_AUTH_STREAMS),
+            ),
+            relay: request_response::Behaviour::with_codec(
+                RelayCodec::new(max_frame_bytes),
+                std::iter::once((RelayProtocol(), request_response::ProtocolSupport::Full)),
+                request_response::Config::default().with_max_concurrent_streams(MAX_RELAY_STREAMS),
+            ),
+            circuit_relay: Toggle::from(circuit_relay.then(|| libp2p::relay::Behaviour::new(key.public().to_peer_id(), Default::default()))),
+        })?
+        .with_swarm_config(|c: libp2p::swarm::Config| c.with_idle_connection_timeout(idle_timeout))
+        .build();
+
+    swarm.listen_on(listen_addr.clone())?;
+    println!("Listening on {}", listen_addr);
+    let metrics = std::sync::Arc::new(metrics::Metrics::default());
+    if let Some(addr) = metrics_addr {
+        let listener = tokio::net::TcpListener::bind(addr)
+            .await
+            .map_err(|e| format!("Cannot serve metrics on {}: {}", addr, e))?;
+        println!("Serving metrics on http://{}/metrics", addr);
+        tokio::spawn(metrics::serve(listener, metrics.clone()));
+    }
+    let mut listening = listening;
+
+    // Persistent user store
+    let users_path: PathBuf = data_dir.join("users.xml");
+    let mut users_xml = load_users(&users_path);
+    let mut users_by_name: HashMap<String, (String, String)> = HashMap::new();
+    // username_key -> display name; requests are resolved through it, everything else is keyed by display name
+    let mut usernames: HashMap<String, String> = HashMap::new();
+    for u in &users_xml.users {
+        users_by_name.insert(u.username.clone(), (u.password_hash.clone(), u.birthdate.clone()));
+        // Accounts from before names were case-insensitive may clash; the first one keeps the name
+        if let Some(kept) = usernames.get(&username_key(&u.username)) {
+            tracing::warn!("Username '{}' clashes with '{}' ignoring case; it can no longer log in", u.username, kept);
+        } else {
+            usernames.insert(username_key(&u.username), u.username.clone());
+        }
+    }
+    // Every peer a user is logged in from; a user can be online on several devices at once
+    let mut username_to_peer: HashMap<String, HashSet<PeerId>> = HashMap::new();
+    // Space each peer joined with LIST; peers not in here share the default space
+    let mut peer_spaces: HashMap<PeerId, String> = HashMap::new();
+    // Directory and spaces as last pushed to clients; any difference is pushed after the event that caused it
+    let mut presence_sent: HashMap<String, HashSet<PeerId>> = HashMap::new();
+    let mut spaces_sent: HashMap<PeerId, String> = HashMap::new();
+    // When each peer last sent an auth request or answered a ping, to reap sessions whose
+    // connection died without closing
+    let mut last_seen: HashMap<PeerId, Instant> = HashMap::new();
+    // Recent Resolve requests per peer, see MAX_RESOLVES
+    let mut resolves: HashMap<PeerId, VecDeque<Instant>> = HashMap::new();
+
+    if admin_token.is_some() {
+        println!("Admin commands enabled");
+    }
+
+    // Shadow copy of rendezvous registrations, keyed by (namespace, peer), so they can be
+    // snapshotted to disk. The rendezvous behaviour keeps its own store and cannot be seeded,
+    // so restored records are served to clients over the auth protocol (DISCOVER:<namespace>).
+    let registrations_path: PathBuf = data_dir.join("registrations.xml");
+    let mut registrations: HashMap<(String, PeerId), RegistrationXml> = HashMap::new();
+    if persist_registrations {
+        let now = unix_now();
+        for r in load_registrations(&registrations_path).registrations {
+            if r.expires_at <= now {
+                continue;
+            }
+            if let Ok(pid) = r.peer_id.parse::<PeerId>() {
+                registrations.insert((r.namespace.clone(), pid), r);
+            }
+        }
+        println!("Restored {} rendezvous registration(s)", registrations.len());
+    }
+    let mut registrations_dirty = false;
+    // Set when users.xml couldn't be written; retried on the snapshot tick
+    let mut users_dirty = false;
+    // Session tokens handed out on LOGIN/REGISTER, so later commands don't carry the password
+    let mut sessions: HashMap<String, Session> = HashMap::new();
+    let mut login_limiter = LoginLimiter::default();
+    // Usernames (by username_key) with a registration under way. The name is reserved from the
+    // first check until the account is stored, so a second REGISTER for it in between is refused
+    // instead of both succeeding.
+    let mut registering: HashSet<String> = HashSet::new();
+    // Outcomes of password work running off the loop, see `PasswordDone`
+    let (password_tx, mut password_rx) = tokio::sync::mpsc::unbounded_channel::<PasswordDone>();
+    let hashing_slots = std::sync::Arc::new(tokio::sync::Semaphore::new(hashing_slots()));
+    // Chat messages for users their senders couldn't reach, oldest first, delivered on the next LOGIN
+    let queue_path: PathBuf = data_dir.join("offline_messages.xml");
+    let mut offline_queue = load_offline_queue(&queue_path);
+    // Set when offline_messages.xml couldn't be written; retried on the snapshot tick
+    let mut queue_dirty = false;
+    // Deliveries awaiting the client's acknowledgement: recipient and number of messages sent
+    let mut pending_deliveries: HashMap<request_response::OutboundRequestId, (String, usize)> = HashMap::new();
+    // Unsupported-protocol attempts seen per peer
+    let mut probe_counts: HashMap<PeerId, u32> = HashMap::new();
+    if strict_protocols {
+        tracing::info!("Strict protocol mode: peers probing unknown protocols {} times are disconnected", MAX_PROTOCOL_PROBES);
+    }
+    let mut snapshot_interval = tokio::time::interval(std::time::Duration::from_secs(REGISTRATION_SNAPSHOT_SECS));
+
+    loop {
+        let event = tokio::select! {
+            event = swarm.select_next_some() => event,
+            _ = async {
+                match shutdown.as_mut() {
+                    Some(rx) => { let _ = rx.await; }
+                    None => std::future::pending().await,
+                }
+            } => {
+                // Everyone still logged in goes offline now. A final save even if nothing is
+                // dirty, so users.xml on disk matches memory.
+                record_last_seen(&username_to_peer, &HashMap::new(), &mut users_xml);
+                persist_users(&users_path, &users_xml, &mut users_dirty);
+                if queue_dirty {
+                    persist_offline_queue(&queue_path, &offline_queue, &mut queue_dirty);
+                }
+                if persist_registrations && registrations_dirty {
+                    save_registrations(&registrations_path, registrations.values());
+                }
+                let mut online: Vec<&String> = username_to_peer.keys().collect();
+                online.sort();
+                tracing::info!("Server shutting down with {} user(s) logged in: {:?}", online.len(), online);
+                return Ok(());
+            }
+            Some(peer) = async {
+                match protocol_probes.as_mut() {
+                    Some(rx) => rx.recv().await,
+                    None => std::future::pending().await,
+                }
+            } => {
+                // Counts survive reconnects so a scanner can't reset them by redialing
+                let count = probe_counts.entry(peer).or_insert(0);
+                *count += 1;
+                tracing::warn!("Peer {} opened a stream for an unsupported protocol ({} so far)", peer, count);
+                if strict_protocols && *count >= MAX_PROTOCOL_PROBES && swarm.is_connected(&peer) {
+                    tracing::warn!("Disconnecting {}: repeated unsupported-protocol probes", peer);
+                    let _ = swarm.disconnect_peer_id(peer);
+                }
+                continue;
+            }
+            Some(PasswordDone { peer, channel, outcome }) = password_rx.recv() => {
+                let (action, name, reply) = match outcome {
+                    PasswordOutcome::Registered { name, dob, reclaimed, hash } => {
+                        registering.remove(&username_key(&name));
+                        users_by_name.insert(name.clone(), (hash.clone(), dob.clone()));
+                        if reclaimed && let Some(u) = users_xml.users.iter_mut().find(|u| u.username == name) {
+                            u.password_hash = hash;
+                            u.birthdate = dob;
+                            // The new owner hasn't been seen yet
+                            u.last_seen = None;
+                        } else {
+                            usernames.insert(username_key(&name), name.clone());
+                            users_xml.users.push(UserXml { username: name.clone(), password_hash: hash, birthdate: dob, last_seen: None });
+                        }
+                        let reply = if persist_users(&users_path, &users_xml, &mut users_dirty) {
+                            let token = issue_session(&mut sessions, &name, peer);
+                            if swarm.is_connected(&peer) {
+                                username_to_peer.entry(name.clone()).or_default().insert(peer);
+                            }
+                            AuthResponse::Session { token }
+                        } else {
+                            AuthResponse::Error(AuthError::Storage)
+                        };
+                        ("register", name, reply)
+                    }
+                    PasswordOutcome::LoggedIn { name, checked, verified, upgraded } => {
+                        let reply = match users_by_name.get(&name) {
+                            None => AuthResponse::Error(AuthError::UnknownUser),
+                            // A password changed while it was being checked doesn't count
+                            Some((hash, _)) if !verified || checked.as_ref() != Some(hash) => AuthResponse::Error(AuthError::InvalidPassword),
+                            Some(_) => {
+                                login_limiter.succeeded(peer, &name);
+                                if swarm.is_connected(&peer) {
+                                    username_to_peer.entry(name.clone()).or_default().insert(peer);
+                                }
+                                AuthResponse::Session { token: issue_session(&mut sessions, &name, peer) }
+                            }
+                        };
+                        if matches!(reply, AuthResponse::Session { .. }) {
+                            // A failed save only delays the upgrade; the login itself still succeeds
+                            if let Some(new_hash) = upgraded
+                                && upgrade_legacy_hash(&mut users_by_name, &mut users_xml, &name, new_hash)
+                            {
+                                persist_users(&users_path, &users_xml, &mut users_dirty);
+                            }
+                            deliver_queued(&mut swarm, &offline_queue, &mut pending_deliveries, &username_to_peer, &name);
+                        }
+                        ("login", name, reply)
+                    }
+                    PasswordOutcome::PasswordChanged { name, checked, new_hash } => {
+                        let current = users_by_name.get(&name).map(|(hash, _)| hash.clone());
+                        let reply = match (current, new_hash) {
+                            (None, _) => AuthResponse::Error(AuthError::UnknownUser),
+                            (Some(hash), Some(new_hash)) if checked.as_ref() == Some(&hash) => {
+                                login_limiter.succeeded(peer, &name);
+                                if let Some(entry) = users_by_name.get_mut(&name) {
+                                    entry.0 = new_hash.clone();
+                                }
+                                if let Some(u) = users_xml.users.iter_mut().find(|u| u.username == name) {
+                                    u.password_hash = new_hash;
+                                }
+                                if persist_users(&users_path, &users_xml, &mut users_dirty) {
+                                    tracing::info!("Password changed for '{}'", name);
+                                    AuthResponse::Ok
+                                } else {
+                                    AuthResponse::Error(AuthError::Storage)
+                                }
+                            }
+                            _ => AuthResponse::Error(AuthError::InvalidPassword),
+                        };
+                        ("change_password", name, reply)
+                    }
+                    PasswordOutcome::Failed { action, name, reserved } => {
+                        if reserved {
+                            registering.remove(&username_key(&name));
+                        }
+                        (action, name, AuthResponse::Error(AuthError::Storage))
+                    }
+                };
+                audit(action, &name, peer, &reply);
+                if swarm.behaviour_mut().auth.send_response(channel, reply).is_err() {
+                    tracing::error!("Failed to send auth response to {}", peer);
+                }
+                metrics.set_users(users_by_name.len(), username_to_peer.len());
+                if username_to_peer != presence_sent || peer_spaces != spaces_sent {
+                    users_dirty |= record_last_seen(&presence_sent, &username_to_peer, &mut users_xml);
+                    push_presence(&mut swarm, &username_to_peer, &users_by_name, &users_xml, &peer_spaces, &route_key);
+                    presence_sent = username_to_peer.clone();
+                    spaces_sent = peer_spaces.clone();
+                }
+                continue;
+            }
+            _ = snapshot_interval.tick() => {
+                // Save last-seen times, or retry a users.xml write that failed earlier; memory is
+                // authoritative until it succeeds
+                if users_dirty {
+                    persist_users(&users_path, &users_xml, &mut users_dirty);
+                }
+                if queue_dirty {
+                    persist_offline_queue(&queue_path, &offline_queue, &mut queue_dirty);
+                }
+                let now = Instant::now();
+                sessions.retain(|_, s| s.expires_at > now);
+                login_limiter.prune(now);
+                resolves.retain(|_, times| {
+                    times.retain(|at| now.duration_since(*at) < Duration::from_secs(RESOLVE_WINDOW_SECS));
+                    !times.is_empty()
+                });
+                let reaped = reap_stale_sessions(&mut username_to_peer, &last_seen, now);
+                for (name, pid) in &reaped {
+                    tracing::info!("Reaped stale session of '{}' on {}: silent for {}s", name, pid, PRESENCE_TIMEOUT_SECS);
+                    let _ = swarm.disconnect_peer_id(*pid);
+                }
+                if username_to_peer != presence_sent || peer_spaces != spaces_sent {
+                    users_dirty |= record_last_seen(&presence_sent, &username_to_peer, &mut users_xml);
+                    push_presence(&mut swarm, &username_to_peer, &users_by_name, &users_xml, &peer_spaces, &route_key);
+                    presence_sent = username_to_peer.clone();
+                    spaces_sent = peer_spaces.clone();
+                }
+                if persist_registrations {
+                    let now = unix_now();
+                    let before = registrations.len();
+                    registrations.retain(|_, r| r.expires_at > now);
+                    if registrations_dirty || registrations.len() != before {
+                        save_registrations(&registrations_path, registrations.values());
+                        registrations_dirty = false;
+                    }
+                }
+                continue;
+            }
+        };
+        match event {
+            SwarmEvent::NewListenAddr { address, .. } => {
+                tracing::info!("Server listening on {}", address);
+                // Relay reservations must list at least one address of the relay
+                if circuit_relay {
+                    swarm.add_external_address(address.clone());
+                }
+                if let Some(ready) = listening.take() {
+                    let _ = ready.send(address);
+                }
+            }
+            SwarmEvent::ConnectionEstablished { peer_id, .. } => {
+                tracing::info!("Connected to {}", peer_id);
+            }
+            SwarmEvent::IncomingConnectionError { send_back_addr, error: ListenError::Denied { cause }, .. } => {
+                match cause.downcast_ref::<connection_limits::Exceeded>() {
+                    Some(exceeded) => tracing::warn!("Refused connection from {}: {}", send_back_addr, exceeded),
+                    None => tracing::debug!("Refused connection from {}: {}", send_back_addr, cause),
+                }
+            }
+            SwarmEvent::ConnectionClosed { peer_id, .. } => {
+                tracing::info!("Disconnected from {}", peer_id);
+                last_seen.remove(&peer_id);
+                peer_spaces.remove(&peer_id);
+                // Remove any usernames associated with this peer so LIST stays accurate
+                let mut removed: Vec<String> = Vec::new();
+                username_to_peer.retain(|name, peers| {
+                    if peers.remove(&peer_id) { removed.push(name.clone()); }
+                    !peers.is_empty()
+                });
+                if !removed.is_empty() {
+                    tracing::info!("Removed usernames on disconnect: {:?}", removed);
+                }
+            }
+            SwarmEvent::Behaviour(MyBehaviourEvent::Rendezvous(
+                rendezvous::server::Event::PeerRegistered { peer, registration },
+            )) => {
+                tracing::info!(
+                    "Peer {} registered for namespace '{}'",
+                    peer,
+                    registration.namespace
+                );
+                let namespace = registration.namespace.to_string();
+                registrations.insert(
+                    (namespace.clone(), peer),
+                    RegistrationXml {
+                        namespace,
+                        peer_id: peer.to_string(),
+                        envelope: hex::encode(registration.record.into_signed_envelope().into_protobuf_encoding()),
+                        expires_at: unix_now() + registration.ttl,
+                    },
+                );
+                registrations_dirty = true;
+            }
+            SwarmEvent::Behaviour(MyBehaviourEvent::Rendezvous(
+                rendezvous::server::Event::PeerUnregistered { peer, namespace },
+            )) => {
+                registrations.remove(&(namespace.to_string(), peer));
+                registrations_dirty = true;
+            }
+            SwarmEvent::Behaviour(MyBehaviourEvent::Rendezvous(
+                rendezvous::server::Event::RegistrationExpired(registration),
+            )) => {
+                registrations.remove(&(registration.namespace.to_string(), registration.record.peer_id()));
+                registrations_dirty = true;
+            }
+            SwarmEvent::Behaviour(MyBehaviourEvent::Rendezvous(
+                rendezvous::server::Event::DiscoverServed {
+                    enquirer,
+                    registrations,
+                },
+            )) => {
+                metrics.discover_served();
+                tracing::info!(
+                    "Served peer {} with {} registrations",
+                    enquirer,
+                    registrations.len()
+                );
+            }
+            // Chat protocol
+            SwarmEvent::Behaviour(MyBehaviourEvent::RequestResponse(
+                request_response::Event::Message { peer, message },
+            )) => match message {
+                request_response::Message::Request {
+                    request, channel, ..
+                } => {
+                    let reply = if request == KEEPALIVE {
+                        last_seen.insert(peer, Instant::now());
+                        tracing::trace!("Keep-alive from {}", peer);
+                        KEEPALIVE.to_string()
+                    } else {
+                        tracing::info!("Received request: '{}' from peer {}", request, peer);
+                        "Hello Back from Server".to_string()
+                    };
+                    if let Err(e) = swarm.behaviour_mut().request_response.send_response(channel, reply) {
+                        tracing::error!("Failed to send response: {}", e);
+                    }
+                }
+                request_response::Message::Response { response, .. } => {
+                    tracing::warn!(
+                        "Received unexpected response: '{}' from peer {}",
+                        response,
+                        peer
+                    );
+                }
+            },
+            // Auth protocol
+            SwarmEvent::Behaviour(MyBehaviourEvent::Auth(request_response::Event::Message {
+                peer,
+                message: request_response::Message::Request { request, channel, .. },
+            })) => {
+                last_seen.insert(peer, Instant::now());
+                metrics.auth_request(&request);
+                let resp = match request {
+                    AuthRequest::Register { username, password, birthdate } => {
+                        let name = resolve_username(&usernames, &username);
+                        let dob = birthdate.trim().to_string();
+                        let birth = dates::parse_birthdate(&dob);
+                        let reply = if let Err(reason) = validate_username(username.trim()) {
+                            AuthResponse::Error(AuthError::InvalidUsername(reason))
+                        } else if let Err(reason) = validate_password(&password) {
+                            AuthResponse::Error(AuthError::WeakPassword(reason))
+                        } else if birth.is_none() {
+                            AuthResponse::Error(AuthError::InvalidBirthdate)
+                        } else if birth.is_some_and(|b| dates::age_on(b, dates::today()) < MIN_REGISTRATION_AGE) {
+                            AuthResponse::Error(AuthError::TooYoung { min_age: MIN_REGISTRATION_AGE })
+                        } else {
+                            match users_by_name.get(&name) {
+                                // Credentials reset by an operator (an empty hash) let the first registration claim the name
+                                Some((hash, _)) if !hash.is_empty() => AuthResponse::Error(AuthError::UsernameTaken),
+                                _ if !registering.insert(username_key(&name)) => AuthResponse::Error(AuthError::UsernameTaken),
+                                existing => {
+                                    let (name, reclaimed, hasher) = (name.clone(), existing.is_some(), hasher.clone());
+                                    let failed = PasswordOutcome::Failed { action: "register", name: name.clone(), reserved: true };
+                                    spawn_password_work(&password_tx, &hashing_slots, peer, channel, failed, move || {
+                                        PasswordOutcome::Registered { hash: hash_password(&hasher, &password), name, dob, reclaimed }
+                                    });
+                                    continue;
+                                }
+                            }
+                        };
+                        audit("register", &name, peer, &reply);
+                        reply
+                    }
+                    AuthRequest::Login { username, password } => {
+                        let name = resolve_username(&usernames, &username);
+                        let stored = users_by_name.get(&name).map(|(hash, _dob)| hash.clone());
+                        let reply = if stored.as_deref() == Some("") {
+                            AuthResponse::Error(AuthError::AwaitingOwner)
+                        // Unknown usernames count as failures too, so names can't be probed freely
+                        } else if !login_limiter.begin(peer, &name, Instant::now()) {
+                            tracing::warn!("Throttled login for '{}' from {}", name, peer);
+                            AuthResponse::Error(AuthError::RateLimited)
+                        } else {
+                            let hasher = hasher.clone();
+                            let failed = PasswordOutcome::Failed { action: "login", name: name.clone(), reserved: false };
+                            spawn_password_work(&password_tx, &hashing_slots, peer, channel, failed, move || {
+                                let verified = stored.as_deref().is_some_and(|hash| verify_password(hash, &password));
+                                let legacy = verified && stored.as_deref().is_some_and(is_legacy_hash);
+                                let upgraded = legacy.then(|| hash_password(&hasher, &password));
+                                PasswordOutcome::LoggedIn { name, checked: stored, verified, upgraded }
+                            });
+                            continue;
+                        };
+                        audit("login", &name, peer, &reply);
+                        reply
+                    }
+                    AuthRequest::ChangePassword { new_password, .. } if let Err(reason) = validate_password(&new_password) => {
+                        AuthResponse::Error(AuthError::WeakPassword(reason))
+                    }
+                    AuthRequest::ChangePassword { username, old_password, new_password } => {
+                        let name = resolve_username(&usernames, &username);
+                        if !login_limiter.begin(peer, &name, Instant::now()) {
+                            let reply = AuthResponse::Error(AuthError::RateLimited);
+                            audit("change_password", &name, peer, &reply);
+                            reply
+                        } else {
+                            let stored = users_by_name.get(&name).map(|(hash, _dob)| hash.clone());
+                            let hasher = hasher.clone();
+                            let failed = PasswordOutcome::Failed { action: "change_password", name: name.clone(), reserved: false };
+                            spawn_password_work(&password_tx, &hashing_slots, peer, channel, failed, move || {
+                                let verified = stored.as_deref().is_some_and(|hash| verify_password(hash, &old_password));
+                                let new_hash = verified.then(|| hash_password(&hasher, &new_password));
+                                PasswordOutcome::PasswordChanged { name, checked: stored, new_hash }
+                            });
+                            continue;
+                        }
+                    }
+                    AuthRequest::Logout { token } => match resolve_session(&mut sessions, &token, peer) {
+                        Some(name) => {
+                            sessions.remove(&token);
+                            // Only this device goes offline; other sessions of the user stay
+                            if let Some(peers) = username_to_peer.get_mut(&name) {
+                                peers.remove(&peer);
+                                if peers.is_empty() {
+                                    username_to_peer.remove(&name);
+                                }
+                            }
+                            audit("logout", &name, peer, &AuthResponse::Ok);
+                            AuthResponse::Ok
+                        }
+                        None => AuthResponse::InvalidSession,
+                    },
+                    AuthRequest::Delete { token } => match resolve_session(&mut sessions, &token, peer) {
+                        None => AuthResponse::InvalidSession,
+                        Some(name) if users_by_name.remove(&name).is_none() => AuthResponse::Error(AuthError::UnknownUser),
+                        Some(name) => {
+                            usernames.remove(&username_key(&name));
+                            // The account's other devices are disconnected; their sessions are gone too
+                            for other in username_to_peer.remove(&name).unwrap_or_default() {
+                                if other != peer {
+                                    let _ = swarm.disconnect_peer_id(other);
+                                }
+                            }
+                            sessions.retain(|_, s| s.username != name);
+                            clear_offline_queue(&queue_path, &mut offline_queue, &mut queue_dirty, &mut pending_deliveries, &name);
+                            // Remove from XML list and persist
+                            users_xml.users.retain(|u| u.username != name);
+                            let reply = if persist_users(&users_path, &users_xml, &mut users_dirty) {
+                                AuthResponse::Ok
+                            } else {
+                                AuthResponse::Error(AuthError::Storage)
+                            };
+                            audit("delete", &name, peer, &reply);
+                            reply
+                        }
+                    },
+                    // Wrong operator tokens count against the peer like failed logins
+                    AuthRequest::Admin { command, .. } if login_limiter.peer_limited(peer, Instant::now()) => {
+                        let reply = AuthResponse::Error(AuthError::RateLimited);
+                        audit("admin", command.username(), peer, &reply);
+                        reply
+                    }
+                    AuthRequest::Admin { token, command } if admin_token_matches(admin_token.as_deref(), &token) => match command {
+                        AdminCommand::Transfer { username, password_hash } => {
+                            let name = &resolve_username(&usernames, &username);
+                            // No hash clears the credentials so the next Register claims the name;
+                            // otherwise the operator hands the new owner a pre-hashed password.
+                            let new_hash = match password_hash.as_deref().map(str::trim) {
+                                None => Some(String::new()),
+                                Some(hash) if hash.len() == 64 && hash.chars().all(|c| c.is_ascii_hexdigit()) => Some(hash.to_ascii_lowercase()),
+                                Some(_) => None,
+                            };
+                            match (new_hash, users_by_name.get_mut(name)) {
+                                (None, _) => AuthResponse::Error(AuthError::InvalidCredential),
+                                (_, None) => AuthResponse::Error(AuthError::UnknownUser),
+                                (Some(new_hash), Some(entry)) => {
+                                    entry.0 = new_hash.clone();
+                                    if let Some(u) = users_xml.users.iter_mut().find(|u| u.username == *name) {
+                                        u.password_hash = new_hash;
+                                    }
+                                    let saved = persist_users(&users_path, &users_xml, &mut users_dirty);
+                                    end_session(&mut swarm, &mut username_to_peer, &mut sessions, name);
+                                    // Messages queued for the previous owner are not the new owner's to read
+                                    clear_offline_queue(&queue_path, &mut offline_queue, &mut queue_dirty, &mut pending_deliveries, name);
+                                    tracing::info!("Admin transferred account '{}'", name);
+                                    let reply = if saved { AuthResponse::Ok } else { AuthResponse::Error(AuthError::Storage) };
+                                    audit("admin_transfer", name, peer, &reply);
+                                    reply
+                                }
+                            }
+                        }
+                        AdminCommand::Release { username } => {
+                            let name = &resolve_username(&usernames, &username);
+                            if users_by_name.remove(name).is_some() {
+                                usernames.remove(&username_key(name));
+                                users_xml.users.retain(|u| u.username != *name);
+                                let saved = persist_users(&users_path, &users_xml, &mut users_dirty);
+                                end_session(&mut swarm, &mut username_to_peer, &mut sessions, name);
+                                clear_offline_queue(&queue_path, &mut offline_queue, &mut queue_dirty, &mut pending_deliveries, name);
+                                tracing::info!("Admin released username '{}'", name);
+                                let reply = if saved { AuthResponse::Ok } else { AuthResponse::Error(AuthError::Storage) };
+                                audit("admin_release", name, peer, &reply);
+                                reply
+                            } else {
+                                AuthResponse::Error(AuthError::UnknownUser)
+                            }
+                        }
+                    },
+                    AuthRequest::Admin { command, .. } => {
+                        login_limiter.failed(peer, Instant::now());
+                        tracing::warn!("Rejected admin command from {}", peer);
+                        let reply = AuthResponse::Error(AuthError::NotAuthorized);
+                        audit("admin", command.username(), peer, &reply);
+                        reply
+                    }
+                    AuthRequest::Discover { namespace } => {
+                        // Signed peer records known for the namespace, including ones restored
+                        // from disk that the rendezvous store lost on restart
+                        let now = unix_now();
+                        let envelopes = registrations
+                            .iter()
+                            .filter(|((ns, pid), r)| *ns == namespace.trim() && *pid != peer && r.expires_at > now)
+                            .filter_map(|(_, r)| hex::decode(&r.envelope).ok())
+                            .collect();
+                        metrics.discover_served();
+                        AuthResponse::Discovered(envelopes)
+                    }
+                    AuthRequest::List { token, space, last_seen } => {
+                        if let Some(name) = resolve_session(&mut sessions, &token, peer) {
+                            // A client that reconnected resumes its session and shows as online again
+                            username_to_peer.entry(name).or_default().insert(peer);
+                            match space.map(|s| s.trim().to_string()).filter(|s| !s.is_empty()) {
+                                Some(space) => peer_spaces.insert(peer, space),
+                                None => peer_spaces.remove(&peer),
+                            };
+                            let users = directory(&username_to_peer, &users_by_name, &peer_spaces, &route_key, peer);
+                            if last_seen {
+                                let offline = users.keys().filter(|name| !username_to_peer.contains_key(*name));
+                                let last_seen = last_seen_of(offline, &users_xml);
+                                AuthResponse::Directory { users, last_seen }
+                            } else {
+                                AuthResponse::Users(users)
+                            }
+                        } else {
+                            // The roster shows who is online, so it's only served to a live session
+                            tracing::warn!("Rejected LIST from {}: no valid session", peer);
+                            AuthResponse::InvalidSession
+                        }
+                    }
+                    AuthRequest::ListAll { token, offset } => {
+                        if resolve_session(&mut sessions, &token, peer).is_some() {
+                            accounts_page(&users_by_name, &username_to_peer, &users_xml, offset as usize)
+                        } else {
+                            tracing::warn!("Rejected LISTALL from {}: no valid session", peer);
+                            AuthResponse::InvalidSession
+                        }
+                    }
+                    AuthRequest::Resolve { token, session } => {
+                        if resolve_session(&mut sessions, &token, peer).is_none() {
+                            tracing::warn!("Rejected RESOLVE from {}: no valid session", peer);
+                            AuthResponse::InvalidSession
+                        } else if !allow_resolve(&mut resolves, peer, Instant::now()) {
+                            tracing::warn!("Rejected RESOLVE from {}: too many lookups", peer);
+                            AuthResponse::Error(AuthError::RateLimited)
+                        } else {
+                            // Only a session the caller's own directory lists
+                            let space = peer_spaces.get(&peer);
+                            let target = username_to_peer
+                                .values()
+                                .flatten()
+                                .filter(|pid| **pid != peer && space.is_none_or(|space| peer_spaces.get(pid) == Some(space)))
+                                .find(|pid| session_handle(&route_key, pid) == session)
+                                .copied();
+                            match target {
+                                Some(target) => {
+                                    // So the target can put a name to the dial that follows
+                                    let introduce = AuthRequest::Introduce { session: session_handle(&route_key, &peer), peer: peer.to_string() };
+                                    swarm.behaviour_mut().auth.send_request(&target, introduce);
+                                    tracing::debug!("{} resolved session {} to {}", peer, session, target);
+                                    AuthResponse::Route { session, peer: target.to_string() }
+                                }
+                                None => AuthResponse::Error(AuthError::UnknownUser),
+                            }
+                        }
+                    }
+                    AuthRequest::Presence { .. } | AuthRequest::Introduce { .. } => AuthResponse::Error(AuthError::UnexpectedRequest),
+                };
+                if swarm.behaviour_mut().auth.send_response(channel, resp).is_err() {
+                    tracing::error!("Failed to send auth response to {}", peer);
+                }
+            }
+            // Relay protocol: queue messages for users the sender couldn't reach
+            SwarmEvent::Behaviour(MyBehaviourEvent::Relay(request_response::Event::Message {
+                peer,
+                message: request_response::Message::Request { request, channel, .. },
+            })) => {
+                let resp = match request {
+                    RelayRequest::Store { token, to, text, forwarded, encrypted } => match resolve_session(&mut sessions, &token, peer) {
+                        None => RelayResponse::InvalidSession,
+                        Some(_) if users_by_name.get(&resolve_username(&usernames, &to)).is_none_or(|(hash, _dob)| hash.is_empty()) => {
+                            RelayResponse::Error { reason: "Unknown user".to_string() }
+                        }
+                        Some(from) => {
+                            let to = resolve_username(&usernames, &to);
+                            let queue = offline_queue.entry(to.clone()).or_default();
+                            if queue.len() >= MAX_QUEUED_MESSAGES {
+                                RelayResponse::Error { reason: "Queue full".to_string() }
+                            } else {
+                                queue.push(QueuedMessage {
+                                    from,
+                                    text,
+                                    forwarded,
+                                    sent_at_ms: unix_now_ms(),
+                                    encrypted,
+                                    from_peer: peer.to_string(),
+                                });
+                                // A failed save keeps the message in memory; the snapshot tick retries it
+                                persist_offline_queue(&queue_path, &offline_queue, &mut queue_dirty);
+                                // An online recipient the sender couldn't dial gets it through us right away
+                                if username_to_peer.get(&to).is_some_and(|sessions| !sessions.is_empty()) {
+                                    deliver_queued(&mut swarm, &offline_queue, &mut pending_deliveries, &username_to_peer, &to);
+                                    tracing::info!("Routing a message from {} to '{}' through the server", peer, to);
+                                    RelayResponse::Routed
+                                } else {
+                                    RelayResponse::Ok
+                                }
+                            }
+                        }
+                    },
+                    RelayRequest::Deliver { .. } => RelayResponse::Error { reason: "Only the server delivers messages".to_string() },
+                };
+                if swarm.behaviour_mut().relay.send_response(channel, resp).is_err() {
+                    tracing::error!("Failed to send relay response to {}", peer);
+                }
+            }
+            SwarmEvent::Behaviour(MyBehaviourEvent::Relay(request_response::Event::Message {
+                peer,
+                message: request_response::Message::Response { request_id, response },
+            })) => {
+                if let Some((name, delivered)) = pending_deliveries.remove(&request_id)
+                    && response == RelayResponse::Ok
+                    && let Some(queue) = offline_queue.get_mut(&name)
+                {
+                    // The other sessions got the same messages; their acknowledgements are ignored
+                    pending_deliveries.retain(|_, (pending, _)| *pending != name);
+                    // Only the delivered prefix; messages queued meanwhile go out in the next delivery
+                    queue.drain(..delivered.min(queue.len()));
+                    if queue.is_empty() {
+                        offline_queue.remove(&name);
+                    }
+                    persist_offline_queue(&queue_path, &offline_queue, &mut queue_dirty);
+                    tracing::info!("Delivered {} queued message(s) to '{}'", delivered, name);
+                    if username_to_peer.get(&name).is_some_and(|peers| peers.contains(&peer)) {
+                        deliver_queued(&mut swarm, &offline_queue, &mut pending_deliveries, &username_to_peer, &name);
+                    }
+                }
+            }
+            SwarmEvent::Behaviour(MyBehaviourEvent::Relay(request_response::Event::OutboundFailure { request_id, error, .. })) => {
+                if let Some((name, _)) = pending_deliveries.remove(&request_id) {
+                    tracing::warn!("Queued messages for '{}' not delivered ({:?}); kept for the next login", name, error);
+                }
+            }
+            SwarmEvent::Behaviour(MyBehaviourEvent::Ping(ping::Event { peer, result, .. })) => match result {
+                Ok(rtt) => {
+                    last_seen.insert(peer, Instant::now());
+                    tracing::trace!("Ping to {}: {:?}", peer, rtt);
+                }
+                Err(e) => tracing::debug!("Ping to {} failed: {}", peer, e),
+            },
+            other => {
+                tracing::debug!("Unhandled {:?}", other);
+            }
+        }
+        metrics.set_users(users_by_name.len(), username_to_peer.len());
+        if username_to_peer != presence_sent || peer_spaces != spaces_sent {
+            users_dirty |= record_last_seen(&presence_sent, &username_to_peer, &mut users_xml);
+            push_presence(&mut swarm, &username_to_peer, &users_by_name, &users_xml, &peer_spaces, &route_key);
+            presence_sent = username_to_peer.clone();
+            spaces_sent = peer_spaces.clone();
+        }
+    }
+}
+
+// Sends `name`'s queued messages to each of its sessions. They stay queued until a client
+// acknowledges them, so only one delivery per user is in flight; the first acknowledgement
+// drains exactly the messages it carried, and anything queued since goes out in the next one.
+fn deliver_queued(
+    swarm: &mut libp2p::Swarm<MyBehaviour>,
+    offline_queue: &HashMap<String, Vec<QueuedMessage>>,
+    pending_deliveries: &mut HashMap<request_response::OutboundRequestId, (String, usize)>,
+    username_to_peer: &HashMap<String, HashSet<PeerId>>,
+    name: &str,
+) {
+    if pending_deliveries.values().any(|(pending, _)| pending == name) {
+        return;
+    }
+    let Some(sessions) = username_to_peer.get(name) else { return };
+    if let Some(messages) = offline_queue.get(name).filter(|queue| !queue.is_empty()) {
+        for peer in sessions {
+            let request = RelayRequest::Deliver { messages: messages.clone() };
+            let request_id = swarm.behaviour_mut().relay.send_request(peer, request);
+            pending_deliveries.insert(request_id, (name.to_string(), messages.len()));
+        }
+    }
+}
+
+// Sends each logged-in peer the directory as it sees it; older clients reject the request,
+// which is only logged
+fn push_presence(
+    swarm: &mut libp2p::Swarm<MyBehaviour>,
+    username_to_peer: &HashMap<String, HashSet<PeerId>>,
+    users_by_name: &HashMap<String, (String, String)>,
+    users_xml: &UsersXml,
+    peer_spaces: &HashMap<PeerId, String>,
+    route_key: &[u8; 32],
+) {
+    let offline = users_by_name.keys().filter(|name| !username_to_peer.contains_key(*name));
+    let last_seen = last_seen_of(offline, users_xml);
+    let peers: HashSet<PeerId> = username_to_peer.values().flatten().copied().collect();
+    for peer in peers {
+        let users = directory(username_to_peer, users_by_name, peer_spaces, route_key, peer);
+        // Named spaces leave offline accounts out, and their last-seen times with them
+        let last_seen = if peer_spaces.contains_key(&peer) { HashMap::new() } else { last_seen.clone() };
+        swarm.behaviour_mut().auth.send_request(&peer, AuthRequest::Presence { users, last_seen });
+    }
+}
+
+// Stamps users who were online in `before` but aren't in `after` with the current time as
+// their last-seen time; returns whether any were, so users.xml needs saving
+fn record_last_seen(
+    before: &HashMap<String, HashSet<PeerId>>,
+    after: &HashMap<String, HashSet<PeerId>>,
+    users_xml: &mut UsersXml,
+) -> bool {
+    let now = unix_now_ms();
+    let mut recorded = false;
+    for user in users_xml.users.iter_mut().filter(|u| before.contains_key(&u.username) && !after.contains_key(&u.username)) {
+        user.last_seen = Some(now);
+        recorded = true;
+    }
+    recorded
+}
+
+// Last-seen times of the named accounts, for those that have one
+fn last_seen_of<'a>(names: impl IntoIterator<Item = &'a String>, users_xml: &UsersXml) -> HashMap<String, u64> {
+    let names: HashSet<&String> = names.into_iter().collect();
+    users_xml
+        .users
+        .iter()
+        .filter(|u| names.contains(&u.username))
+        .filter_map(|u| Some((u.username.clone(), u.last_seen?)))
+        .collect()
+}
+
+// The directory as `viewer` sees it: every account with the handles of its sessions, sorted,
+// and none while offline; released accounts (no password) are left out. The default space sees
+// everything, and a named space only its own sessions, so accounts without one there (offline
+// ones included) are left out. The viewer's own session isn't listed, but its account is.
+fn directory(
+    username_to_peer: &HashMap<String, HashSet<PeerId>>,
+    users_by_name: &HashMap<String, (String, String)>,
+    peer_spaces: &HashMap<PeerId, String>,
+    route_key: &[u8; 32],
+    viewer: PeerId,
+) -> HashMap<String, Vec<String>> {
+    let space = peer_spaces.get(&viewer);
+    let mut users: HashMap<String, Vec<String>> = users_by_name
+        .iter()
+        .filter(|(_, (hash, _dob))| space.is_none() && !hash.is_empty())
+        .map(|(name, _)| (name.clone(), Vec::new()))
+        .collect();
+    for (name, peers) in username_to_peer {
+        let visible: Vec<&PeerId> = peers.iter().filter(|pid| space.is_none_or(|space| peer_spaces.get(pid) == Some(space))).collect();
+        if visible.is_empty() {
+            continue;
+        }
+        let mut handles: Vec<String> = visible.into_iter().filter(|pid| **pid != viewer).map(|pid| session_handle(route_key, pid)).collect();
+        handles.sort();
+        users.insert(name.clone(), handles);
+    }
+    users
+}
+
+// Secret behind the session handles, derived from the server's key so a session keeps its
+// handle across restarts
+fn route_key(keypair: &Keypair) -> [u8; 32] {
+    let secret = keypair.to_protobuf_encoding().unwrap_or_else(|_| rand::random::<[u8; 32]>().to_vec());
+    Sha256::new().chain_update(b"neutral session handles").chain_update(secret).finalize().into()
+}
+
+// The opaque handle the directory lists `peer`'s session under: 16 bytes of a keyed hash of
+// its PeerId, hex-encoded. Only the server can map it back, through Resolve.
+fn session_handle(route_key: &[u8; 32], peer: &PeerId) -> String {
+    let digest = Sha256::new().chain_update(route_key).chain_update(peer.to_bytes()).finalize();
+    hex::encode(&digest[..16])
+}
+
+// Counts a Resolve from `peer`, unless it already made MAX_RESOLVES within the window
+fn allow_resolve(resolves: &mut HashMap<PeerId, VecDeque<Instant>>, peer: PeerId, now: Instant) -> bool {
+    let times = resolves.entry(peer).or_default();
+    while times.front().is_some_and(|at| now.duration_since(*at) >= Duration::from_secs(RESOLVE_WINDOW_SECS)) {
+        times.pop_front();
+    }
+    if times.len() >= MAX_RESOLVES {
+        return false;
+    }
+    times.push_back(now);
+    true
+}
+
+// One page of the address book: registered accounts sorted by name, each with whether it has
+// a session; released accounts are left out, as in `directory`
+fn accounts_page(
+    users_by_name: &HashMap<String, (String, String)>,
+    username_to_peer: &HashMap<String, HashSet<PeerId>>,
+    users_xml: &UsersXml,
+    offset: usize,
+) -> AuthResponse {
+    let mut names: Vec<&String> = users_by_name.iter().filter(|(_, (hash, _dob))| !hash.is_empty()).map(|(name, _)| name).collect();
+    names.sort_by_key(|name| username_key(name));
+    let users: Vec<(String, bool)> = names
+        .iter()
+        .skip(offset)
+        .take(ACCOUNTS_PAGE_SIZE)
+        .map(|name| ((*name).clone(), username_to_peer.contains_key(*name)))
+        .collect();
+    let end = offset.saturating_add(users.len());
+    let next = (end < names.len()).then_some(end as u32);
+    let last_seen = last_seen_of(users.iter().filter(|(_, online)| !online).map(|(name, _)| name), users_xml);
+    AuthResponse::Accounts { users, next, last_seen }
+}
+
+// Sliding-window counts of recent failed logins, keyed by peer and by peer and username.
+// Failures against a username only count for the peer that made them, so other peers can't
+// lock the owner out.
+#[derive(Default)]
+struct LoginLimiter {
+    by_peer: HashMap<PeerId, VecDeque<Instant>>,
+    by_account: HashMap<(PeerId, String), VecDeque<Instant>>,
+}
+
+impl LoginLimiter {
+    // Starts a password check unless `peer` or `username` is over the limit. The attempt counts
+    // as a failure until `succeeded` clears it, so checks still running count against the limit.
+    fn begin(&mut self, peer: PeerId, username: &str, now: Instant) -> bool {
+        let window = Duration::from_secs(LOGIN_FAILURE_WINDOW_SECS);
+        let peer_failures = self.by_peer.entry(peer).or_default();
+        let account_failures = self.by_account.entry((peer, username.to_string())).or_default();
+        for failures in [&mut *peer_failures, &mut *account_failures] {
+            while failures.front().is_some_and(|at| now.duration_since(*at) >= window) {
+                failures.pop_front();
+            }
+        }
+        if peer_failures.len() >= MAX_LOGIN_FAILURES || account_failures.len() >= MAX_ACCOUNT_LOGIN_FAILURES {
+            return false;
+        }
+        peer_failures.push_back(now);
+        account_failures.push_back(now);
+        true
+    }
+
+    // True if `peer` is over the per-peer limit, for attempts that have no username window
+    fn peer_limited(&mut self, peer: PeerId, now: Instant) -> bool {
+        let window = Duration::from_secs(LOGIN_FAILURE_WINDOW_SECS);
+        let Some(failures) = self.by_peer.get_mut(&peer) else { return false };
+        while failures.front().is_some_and(|at| now.duration_since(*at) >= window) {
+            failures.pop_front();
+        }
+        failures.len() >= MAX_LOGIN_FAILURES
+    }
+
+    // Counts a failure that `begin` didn't record, e.g. a wrong operator token
+    fn failed(&mut self, peer: PeerId, now: Instant) {
+        self.by_peer.entry(peer).or_default().push_back(now);
+    }
+
+    // The password matched: both counts start over
+    fn succeeded(&mut self, peer: PeerId, username: &str) {
+        self.by_peer.remove(&peer);
+        self.by_account.remove(&(peer, username.to_string()));
+    }
+
+    // Forgets failures that have left the window
+    fn prune(&mut self, now: Instant) {
+        let window = Duration::from_secs(LOGIN_FAILURE_WINDOW_SECS);
+        let recent = |failures: &mut VecDeque<Instant>| {
+            failures.retain(|at| now.duration_since(*at) < window);
+            !failures.is_empty()
+        };
+        self.by_peer.retain(|_, failures| recent(failures));
+        self.by_account.retain(|_, failures| recent(failures));
+    }
+}
+
+// Argon2 takes tens of milliseconds per hash, which would stall every peer if it ran on the
+// swarm loop. Hashing and password checks run on tokio's blocking pool instead, at most
+// `hashing_slots()` at a time since each one holds the configured memory cost, and the outcome
+// comes back to the loop with the channel of the request it answers.
+struct PasswordDone {
+    peer: PeerId,
+    channel: request_response::ResponseChannel<AuthResponse>,
+    outcome: PasswordOutcome,
+}
+
+enum PasswordOutcome {
+    // Hash for a name reserved in `registering`; `reclaimed` when it replaces credentials an operator cleared
+    Registered { name: String, dob: String, reclaimed: bool, hash: String },
+    // `checked` is the stored hash the password was verified against, and `upgraded` its Argon2id
+    // replacement when it was a legacy hash that matched
+    LoggedIn { name: String, checked: Option<String>, verified: bool, upgraded: Option<String> },
+    // The new hash, when the old password matched `checked`
+    PasswordChanged { name: String, checked: Option<String>, new_hash: Option<String> },
+    // The work panicked; `reserved` when `name` is held in `registering` and must be freed
+    Failed { action: &'static str, name: String, reserved: bool },
+}
+
+fn hashing_slots() -> usize {
+    std::thread::available_parallelism().map_or(2, |n| n.get())
+}
+
+fn spawn_password_work(
+    done: &tokio::sync::mpsc::UnboundedSender<PasswordDone>,
+    slots: &std::sync::Arc<tokio::sync::Semaphore>,
+    peer: PeerId,
+    channel: request_response::ResponseChannel<AuthResponse>,
+    failed: PasswordOutcome,
+    work: impl FnOnce() -> PasswordOutcome + Send + 'static,
+) {
+    let (done, slots) = (done.clone(), slots.clone());
+    tokio::spawn(async move {
+        let Ok(_slot) = slots.acquire_owned().await else { return };
+        let outcome = tokio::task::spawn_blocking(work).await.unwrap_or_else(|e| {
+            tracing::error!("Password hashing for {} failed: {}", peer, e);
+            failed
+        });
+        let _ = done.send(PasswordDone { peer, channel, outcome });
+    });
+}
+
+// Sessions expire after this long without use; every accepted command extends them
+const SESSION_IDLE_SECS: u64 = 15 * 60;
+
+struct Session {
+    username: String,
+    // Tokens only work from the connection identity they were issued to
+    peer: PeerId,
+    expires_at: Instant,
+}
+
+// The display name of the account `name` refers to in any casing, or `name` itself (trimmed) if there's none
+fn resolve_username(usernames: &HashMap<String, String>, name: &str) -> String {
+    usernames.get(&username_key(name)).cloned().unwrap_or_else(|| name.trim().to_string())
+}
+
+// Random 32-byte token, hex-encoded
+fn issue_session(sessions: &mut HashMap<String, Session>, username: &str, peer: PeerId) -> String {
+    let token = hex::encode(rand::random::<[u8; 32]>());
+    let expires_at = Instant::now() + Duration::from_secs(SESSION_IDLE_SECS);
+    sessions.insert(token.clone(), Session { username: username.to_string(), peer, expires_at });
+    token
+}
+
+// Username of a live session presented by `peer`; expired tokens are dropped
+fn resolve_session(sessions: &mut HashMap<String, Session>, token: &str, peer: PeerId) -> Option<String> {
+    let now = Instant::now();
+    let session = sessions.get_mut(token)?;
+    if session.expires_at <= now {
+        sessions.remove(token);
+        return None;
+    }
+    if session.peer != peer {
+        return None;
+    }
+    session.expires_at = now + Duration::from_secs(SESSION_IDLE_SECS);
+    Some(session.username.clone())
+}
+
+/// Tracing target of the account audit trail: registrations, logins, logouts, password
+/// changes and deletions, with the username, peer and outcome but never a password or hash.
+pub const AUDIT_TARGET: &str = "audit";
+
+fn audit(action: &str, username: &str, peer: PeerId, reply: &AuthResponse) {
+    let outcome = match reply {
+        AuthResponse::Error(e) => e.code(),
+        AuthResponse::InvalidSession => "invalid_session".to_string(),
+        _ => "ok".to_string(),
+    };
+    tracing::info!(target: AUDIT_TARGET, action, username, %peer, outcome);
+}
+
+
+// A logged-in peer that hasn't sent an auth request or answered a ping for this long is taken
+// offline. Clients poll LIST every minute and pings run every 15s, so only dead connections go quiet.
+const PRESENCE_TIMEOUT_SECS: u64 = 90;
+
+// Removes peers not seen within PRESENCE_TIMEOUT_SECS from the online directory; returns them
+// with the user they were logged in as
+fn reap_stale_sessions(
+    username_to_peer: &mut HashMap<String, HashSet<PeerId>>,
+    last_seen: &HashMap<PeerId, Instant>,
+    now: Instant,
+) -> Vec<(String, PeerId)> {
+    let timeout = Duration::from_secs(PRESENCE_TIMEOUT_SECS);
+    let mut reaped = Vec::new();
+    username_to_peer.retain(|name, peers| {
+        peers.retain(|pid| {
+            let fresh = last_seen.get(pid).is_some_and(|seen| now.saturating_duration_since(*seen) < timeout);
+            if !fresh {
+                reaped.push((name.clone(), *pid));
+            }
+            fresh
+        });
+        !peers.is_empty()
+    });
+    reaped
+}
+
+// How often rendezvous registrations are written to disk when persistence is enabled
+const REGISTRATION_SNAPSHOT_SECS: u64 = 30;
+
+// Unsupported-protocol attempts tolerated per peer before strict mode disconnects it
+const MAX_PROTOCOL_PROBES: u32 = 3;
+
+// --- Unsupported protocol detection ---
+// libp2p-swarm rejects inbound streams for protocols no behaviour supports without emitting
+// a SwarmEvent; it only logs a debug event inside the connection's span. This layer picks
+// those events up and reports the connection's peer to `run_server`.
+const NO_PROTOCOL_EVENT: &str = "no protocol could be agreed upon for inbound stream";
+const CONNECTION_SPAN: &str = "new_established_connection";
+
+/// Tracing layer reporting peers whose inbound streams failed protocol negotiation.
+/// Install it next to the regular log layer and pass the receiver in `ServerConfig::protocol_probes`.
+pub fn protocol_probe_layer<S>() -> (impl tracing_subscriber::Layer<S>, tokio::sync::mpsc::UnboundedReceiver<PeerId>)
+where
+    S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+{
+    use tracing_subscriber::Layer;
+    let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+    // Own filter so probes are seen even when the log level hides libp2p debug output
+    let filter = tracing_subscriber::filter::Targets::new().with_target("libp2p_swarm", tracing::Level::DEBUG);
+    (ProtocolProbeLayer { tx }.with_filter(filter), rx)
+}
+
+struct ProtocolProbeLayer {
+    tx: tokio::sync::mpsc::UnboundedSender<PeerId>,
+}
+
+// Remote peer of a connection span, stored in the span's extensions
+struct ConnectionPeer(PeerId);
+
+// Collects one named field of a span or event as text
+struct FieldVisitor {
+    name: &'static str,
+    value: Option<String>,
+}
+
+impl tracing::field::Visit for FieldVisitor {
+    fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+        if field.name() == self.name {
+            self.value = Some(value.to_string());
+        }
+    }
+
+    fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+        if field.name() == self.name {
+            self.value = Some(format!("{:?}", value));
+        }
+    }
+}
+
+impl<S> tracing_subscriber::Layer<S> for ProtocolProbeLayer
+where
+    S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &tracing::span::Attributes<'_>, id: &tracing::span::Id, ctx: tracing_subscriber::layer::Context<'_, S>) {
+        if attrs.metadata().name() != CONNECTION_SPAN {
+            return;
+        }
+        let mut visitor = FieldVisitor { name: "peer", value: None };
+        attrs.record(&mut visitor);
+        if let Some(peer) = visitor.value.and_then(|v| PeerId::from_str(&v).ok())
+            && let Some(span) = ctx.span(id)
+        {
+            span.extensions_mut().insert(ConnectionPeer(peer));
+        }
+    }
+
+    fn on_event(&self, event: &tracing::Event<'_>, ctx: tracing_subscriber::layer::Context<'_, S>) {
+        let mut visitor = FieldVisitor { name: "message", value: None };
+        event.record(&mut visitor);
+        if visitor.value.as_deref() != Some(NO_PROTOCOL_EVENT) {
+            return;
+        }
+        let Some(scope) = ctx.event_scope(event) else { return };
+        for span in scope {
+            if let Some(ConnectionPeer(peer)) = span.extensions().get::<ConnectionPeer>() {
+                let _ = self.tx.send(*peer);
+                return;
+            }
+        }
+    }
+}
+
+// --- Network Behaviour Definition ---
+#[derive(NetworkBehaviour)]
+struct MyBehaviour {
+    // First, so connections over the caps are refused before the others set up handlers
+    limits: connection_limits::Behaviour,
+    identify: identify::Behaviour,
+    rendezvous: rendezvous::server::Behaviour,
+    ping: ping::Behaviour,
+    request_response: request_response::Behaviour<HelloCodec>,
+    auth: request_response::Behaviour<AuthCodec>,
+    relay: request_response::Behaviour<RelayCodec>,
+    // Enabled by ServerConfig::circuit_relay
+    circuit_relay: Toggle<libp2p::relay::Behaviour>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename = "users")]
+struct UsersXml {
+    #[serde(rename = "user", default)]
+    users: Vec<UserXml>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct UserXml {
+    #[serde(rename = "username")]
+    username: String,
+    #[serde(rename = "password_hash")]
+    password_hash: String,
+    #[serde(rename = "birthdate")]
+    birthdate: String, // YYYY-MM-DD
+    // Unix milliseconds when the user last went offline; missing until then, and in older files
+    #[serde(rename = "last_seen", default, skip_serializing_if = "Option::is_none")]
+    last_seen: Option<u64>,
+}
+
+// Drops every online session of `name` and disconnects the peers that held them
+fn end_session(
+    swarm: &mut libp2p::Swarm<MyBehaviour>,
+    username_to_peer: &mut HashMap<String, HashSet<PeerId>>,
+    sessions: &mut HashMap<String, Session>,
+    name: &str,
+) {
+    sessions.retain(|_, s| s.username != name);
+    for pid in username_to_peer.remove(name).unwrap_or_default() {
+        let _ = swarm.disconnect_peer_id(pid);
+        tracing::info!("Ended session of '{}' on {}", name, pid);
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename = "registrations")]
+struct RegistrationsXml {
+    #[serde(rename = "registration", default)]
+    registrations: Vec<RegistrationXml>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct RegistrationXml {
+    namespace: String,
+    peer_id: String,
+    // Hex-encoded signed envelope of the peer record, so clients can verify it
+    envelope: String,
+    // Unix seconds
+    expires_at: u64,
+}
+
+fn unix_now_ms() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn load_registrations(path: &Path) -> RegistrationsXml {
+    match fs::read_to_string(path) {
+        Ok(text) => quick_xml::de::from_str::<RegistrationsXml>(&text).unwrap_or_default(),
+        Err(_) => RegistrationsXml::default(),
+    }
+}
+
+fn save_registrations<'a>(path: &Path, registrations: impl Iterator<Item = &'a RegistrationXml>) {
+    let snapshot = RegistrationsXml { registrations: registrations.cloned().collect() };
+    if let Ok(xml_body) = quick_xml::se::to_string(&snapshot) {
+        let xml_all = format!("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n{}", xml_body);
+        if let Err(e) = write_atomic(path, &xml_all) {
+            tracing::error!("Failed to save registrations to {}: {}", path.display(), e);
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename = "offline_messages")]
+struct OfflineQueueXml {
+    #[serde(rename = "message", default)]
+    messages: Vec<QueuedMessageXml>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct QueuedMessageXml {
+    to: String,
+    from: String,
+    // An attribute, because element text loses leading and trailing whitespace on reload
+    #[serde(rename = "@text")]
+    text: String,
+    forwarded: bool,
+    sent_at_ms: u64,
+    #[serde(default)]
+    encrypted: bool,
+    #[serde(default)]
+    from_peer: String,
+}
+
+// Queued messages by recipient, in the order they were stored
+fn load_offline_queue(path: &Path) -> HashMap<String, Vec<QueuedMessage>> {
+    let xml = match fs::read_to_string(path) {
+        Ok(text) => quick_xml::de::from_str::<OfflineQueueXml>(&text).unwrap_or_default(),
+        Err(_) => OfflineQueueXml::default(),
+    };
+    let mut queue: HashMap<String, Vec<QueuedMessage>> = HashMap::new();
+    for m in xml.messages {
+        queue.entry(m.to).or_default().push(QueuedMessage {
+            from: m.from,
+            text: m.text,
+            forwarded: m.forwarded,
+            sent_at_ms: m.sent_at_ms,
+            encrypted: m.encrypted,
+            from_peer: m.from_peer,
+        });
+    }
+    queue
+}
+
+fn save_offline_queue(path: &Path, queue: &HashMap<String, Vec<QueuedMessage>>) -> io::Result<()> {
+    let mut recipients: Vec<&String> = queue.keys().collect();
+    recipients.sort();
+    let messages = recipients
+        .into_iter()
+        .flat_map(|to| {
+            queue[to].iter().map(move |m| QueuedMessageXml {
+                to: to.clone(),
+                from: m.from.clone(),
+                text: m.text.clone(),
+                forwarded: m.forwarded,
+                sent_at_ms: m.sent_at_ms,
+                encrypted: m.encrypted,
+                from_peer: m.from_peer.clone(),
+            })
+        })
+        .collect();
+    let xml_body = quick_xml::se::to_string(&OfflineQueueXml { messages }).map_err(io::Error::other)?;
+    let xml_all = format!("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n{}", xml_body);
+    write_atomic(path, &xml_all)
+}
+
+// Like persist_users: failures are logged and retried from the snapshot tick via `dirty`
+fn persist_offline_queue(path: &Path, queue: &HashMap<String, Vec<QueuedMessage>>, dirty: &mut bool) -> bool {
+    match save_offline_queue(path, queue) {
+        Ok(()) => {
+            *dirty = false;
+            true
+        }
+        Err(e) => {
+            tracing::error!("Failed to save offline messages to {}: {} (kept in memory, will retry)", path.display(), e);
+            *dirty = true;
+            false
+        }
+    }
+}
+
+// Also forgets a delivery in flight, so its late acknowledgement can't drain messages queued afterwards
+fn clear_offline_queue(
+    path: &Path,
+    queue: &mut HashMap<String, Vec<QueuedMessage>>,
+    dirty: &mut bool,
+    pending_deliveries: &mut HashMap<request_response::OutboundRequestId, (String, usize)>,
+    name: &str,
+) {
+    pending_deliveries.retain(|_, (pending, _)| pending != name);
+    if queue.remove(name).is_some() {
+        persist_offline_queue(path, queue, dirty);
+    }
+}
+
+// Argon2id PHC string (`$argon2id$v=19$m=...`) with a fresh 16-byte salt per call
+fn hash_password(hasher: &Argon2, pw: &str) -> String {
+    let salt: [u8; 16] = rand::random();
+    let salt = SaltString::encode_b64(&salt).expect("16-byte salt encodes");
+    hasher
+        .hash_password(pw.as_bytes(), &salt)
+        .expect("argon2 parameters are validated at startup")
+        .to_string()
+}
+
+// Records written before Argon2: `<salt hex>$<sha256(salt ++ password) hex>`, or a bare
+// sha256 hex as older users.xml files and ADMIN TRANSFER hashes have
+fn is_legacy_hash(stored: &str) -> bool {
+    !stored.starts_with("$argon2")
+}
+
+fn legacy_digest(salt: &[u8], pw: &str) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(salt);
+    hasher.update(pw.as_bytes());
+    hasher.finalize().to_vec()
+}
+
+// Constant-time comparison of decoded digest bytes, so timing doesn't reveal how much of
+// the hash matched; argon2 compares its own hashes the same way
+fn legacy_matches(salt: &[u8], candidate: &str, expected_hex: &str) -> bool {
+    match hex::decode(expected_hex) {
+        Ok(expected) => legacy_digest(salt, candidate).ct_eq(&expected).into(),
+        Err(_) => false,
+    }
+}
+
+// Constant-time, so response timing doesn't reveal how much of the operator token matched
+fn admin_token_matches(expected: Option<&str>, candidate: &str) -> bool {
+    expected.is_some_and(|expected| expected.as_bytes().ct_eq(candidate.as_bytes()).into())
+}
+
+fn verify_password(stored: &str, candidate: &str) -> bool {
+    if !is_legacy_hash(stored) {
+        // Cost parameters come from the PHC string, not the current config
+        return PasswordHash::new(stored)
+            .map(|hash| Argon2::default().verify_password(candidate.as_bytes(), &hash).is_ok())
+            .unwrap_or(false);
+    }
+    match stored.split_once('$') {
+        Some((salt_hex, hash)) => match hex::decode(salt_hex) {
+            Ok(salt) => legacy_matches(&salt, candidate, hash),
+            Err(_) => false,
+        },
+        None => !stored.is_empty() && legacy_matches(&[], candidate, stored),
+    }
+}
+
+// Replaces a legacy record with `new_hash`, the Argon2id hash of its password made once the
+// password was verified. Returns whether anything changed and needs saving.
+fn upgrade_legacy_hash(
+    users_by_name: &mut HashMap<String, (String, String)>,
+    users_xml: &mut UsersXml,
+    name: &str,
+    new_hash: String,
+) -> bool {
+    let Some(entry) = users_by_name.get_mut(name) else { return false };
+    if !is_legacy_hash(&entry.0) {
+        return false;
+    }
+    entry.0 = new_hash.clone();
+    if let Some(u) = users_xml.users.iter_mut().find(|u| u.username == name) {
+        u.password_hash = new_hash;
+    }
+    tracing::info!("Upgraded password hash for {} to Argon2id", name);
+    true
+}
+
+// users.xml.bak next to `path`
+fn users_backup_path(path: &Path) -> PathBuf {
+    let mut bak = path.as_os_str().to_owned();
+    bak.push(".bak");
+    PathBuf::from(bak)
+}
+
+fn read_users(path: &Path) -> io::Result<UsersXml> {
+    let text = fs::read_to_string(path)?;
+    quick_xml::de::from_str::<UsersXml>(&text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+// A missing file is a fresh server; an unreadable one falls back to the backup of the previous save
+fn load_users(path: &Path) -> UsersXml {
+    match read_users(path) {
+        Ok(users) => users,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => UsersXml::default(),
+        Err(e) => {
+            let bak = users_backup_path(path);
+            tracing::error!("Could not read {}: {}; trying {}", path.display(), e, bak.display());
+            read_users(&bak).unwrap_or_else(|e| {
+                tracing::error!("Could not read {}: {}", bak.display(), e);
+                UsersXml::default()
+            })
+        }
+    }
+}
+
+fn save_users(path: &Path, users: &UsersXml) -> io::Result<()> {
+    // Serialize with correct root; include XML header
+    let xml_body = quick_xml::se::to_string(users).map_err(io::Error::other)?;
+    let xml_all = format!("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n{}", xml_body);
+    // Keep the file being replaced as the backup, but only if it still parses
+    if let Ok(previous) = fs::read_to_string(path)
+        && quick_xml::de::from_str::<UsersXml>(&previous).is_ok()
+    {
+        let bak = users_backup_path(path);
+        if let Err(e) = write_atomic(&bak, &previous) {
+            tracing::warn!("Could not back up {} to {}: {}", path.display(), bak.display(), e);
+        }
+    }
+    write_atomic(path, &xml_all)
+}
+
+// Saves users.xml, logging failures. A failed write leaves the in-memory state authoritative
+// and sets `dirty` so the snapshot tick retries it. Returns whether the data is on disk.
+fn persist_users(path: &Path, users: &UsersXml, dirty: &mut bool) -> bool {
+    match save_users(path, users) {
+        Ok(()) => {
+            *dirty = false;
+            true
+        }
+        Err(e) => {
+            tracing::error!("Failed to save users to {}: {} (kept in memory, will retry)", path.display(), e);
+            *dirty = true;
+            false
+        }
+    }
+}
+
+// Writes to a sibling temp file, fsyncs it and renames it over `path`, so a full disk or crash
+// mid-write never leaves a truncated file behind. A failure is returned at once rather than
+// retried here, which would stall the event loop; callers mark the data dirty and the snapshot
+// tick tries again.
+fn write_atomic(path: &Path, contents: &str) -> io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    let result = write_synced(&tmp, contents).and_then(|_| fs::rename(&tmp, path)).map(|_| sync_parent(path));
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    result
+}
+
+fn write_synced(path: &Path, contents: &str) -> io::Result<()> {
+    use std::io::Write;
+    let mut file = fs::File::create(path)?;
+    file.write_all(contents.as_bytes())?;
+    file.sync_all()
+}
+
+// Makes the rename itself durable; best effort, as not every platform can open a directory
+fn sync_parent(path: &Path) {
+    if let Some(dir) = path.parent().and_then(|dir| fs::File::open(dir).ok()) {
+        let _ = dir.sync_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Cheap costs so the tests stay fast; production uses PasswordHashing::default()
+    fn test_hasher() -> Argon2<'static> {
+        PasswordHashing { memory_kib: 64, iterations: 1 }.hasher().unwrap()
+    }
+
+    fn users_index(users: &UsersXml) -> HashMap<String, (String, String)> {
+        users
+            .users
+            .iter()
+            .map(|u| (u.username.clone(), (u.password_hash.clone(), u.birthdate.clone())))
+            .collect()
+    }
+
+    #[test]
+    fn registered_password_survives_reload() {
+        let dir = std::env::temp_dir().join(format!("neutral-users-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("users.xml");
+
+        let hasher = test_hasher();
+        let users = UsersXml {
+            users: vec![UserXml {
+                username: "alice".into(),
+                password_hash: hash_password(&hasher, "correct horse"),
+                birthdate: "1990-01-01".into(),
+                last_seen: Some(1_700_000_000_000),
+            }],
+        };
+        save_users(&path, &users).unwrap();
+
+        let reloaded = load_users(&path);
+        let _ = fs::remove_dir_all(&dir);
+        let stored = &reloaded.users[0].password_hash;
+        assert!(stored.starts_with("$argon2id$"));
+        assert!(verify_password(stored, "correct horse"));
+        assert!(!verify_password(stored, "battery staple"));
+        assert_eq!(reloaded.users[0].last_seen, Some(1_700_000_000_000));
+    }
+
+    #[test]
+    fn interrupted_user_save_keeps_the_previous_file() {
+        let dir = std::env::temp_dir().join(format!("neutral-users-crash-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("users.xml");
+        let user = |name: &str| UserXml { username: name.into(), password_hash: "hash".into(), birthdate: "1990-01-01".into(), last_seen: None };
+        save_users(&path, &UsersXml { users: vec![user("alice")] }).unwrap();
+        save_users(&path, &UsersXml { users: vec![user("alice"), user("bob")] }).unwrap();
+
+        // A crash after writing half of the temp file, before the rename
+        fs::write(dir.join("users.xml.tmp"), "<?xml version=\"1.0\"?>\n<users><user><username>al").unwrap();
+        let names = |users: UsersXml| users.users.into_iter().map(|u| u.username).collect::<Vec<_>>();
+        assert_eq!(names(load_users(&path)), ["alice", "bob"]);
+
+        // A damaged users.xml falls back to the copy from before the last save
+        fs::write(&path, "<users><user>").unwrap();
+        let reloaded = load_users(&path);
+        let _ = fs::remove_dir_all(&dir);
+        assert_eq!(names(reloaded), ["alice"]);
+    }
+
+    #[test]
+    fn failed_user_save_is_reported_and_left_dirty() {
+        let dir = std::env::temp_dir().join(format!("neutral-users-unwritable-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        // A directory where users.xml should be can't be renamed over, even by root
+        let path = dir.join("users.xml");
+        fs::create_dir_all(&path).unwrap();
+        let users = UsersXml { users: vec![UserXml { username: "alice".into(), password_hash: "hash".into(), birthdate: "1990-01-01".into(), last_seen: None }] };
+        assert!(save_users(&path, &users).is_err());
+        let mut dirty = false;
+        assert!(!persist_users(&path, &users, &mut dirty));
+        assert!(dirty);
+        // The temp file doesn't linger
+        assert!(!dir.join("users.xml.tmp").exists());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn offline_queue_survives_reload_in_order() {
+        let dir = std::env::temp_dir().join(format!("neutral-queue-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("offline_messages.xml");
+
+        let message = |text: &str| QueuedMessage {
+            from: "alice".into(),
+            text: text.into(),
+            forwarded: false,
+            sent_at_ms: 1_700_000_000_000,
+            encrypted: false,
+            from_peer: String::new(),
+        };
+        let sealed = QueuedMessage { encrypted: true, from_peer: PeerId::random().to_string(), ..message("00ff") };
+        let queue = HashMap::from([
+            ("bob".to_string(), vec![message("first"), message("  <second> & \"more\"\n\tend  "), sealed]),
+            ("carol".to_string(), vec![QueuedMessage { forwarded: true, ..message("hi") }]),
+        ]);
+        save_offline_queue(&path, &queue).unwrap();
+
+        let reloaded = load_offline_queue(&path);
+        let _ = fs::remove_dir_all(&dir);
+        assert_eq!(reloaded, queue);
+    }
+
+    #[test]
+    fn legacy_sha256_record_authenticates_and_upgrades() {
+        let legacy = hex::encode(legacy_digest(&[], "hunter2"));
+        assert_eq!(legacy.len(), 64);
+        let mut users = UsersXml {
+            users: vec![UserXml { username: "bob".into(), password_hash: legacy.clone(), birthdate: "1985-05-05".into(), last_seen: None }],
+        };
+        let mut by_name = users_index(&users);
+        assert!(verify_password(&legacy, "hunter2"));
+        assert!(!verify_password(&legacy, "hunter3"));
+
+        let hasher = test_hasher();
+        assert!(upgrade_legacy_hash(&mut by_name, &mut users, "bob", hash_password(&hasher, "hunter2")));
+        let upgraded = &users.users[0].password_hash;
+        assert!(!is_legacy_hash(upgraded));
+        assert_eq!(&by_name["bob"].0, upgraded);
+        assert!(verify_password(upgraded, "hunter2"));
+
+        // Already on Argon2id: nothing left to upgrade
+        assert!(!upgrade_legacy_hash(&mut by_name, &mut users, "bob", hash_password(&hasher, "hunter2")));
+    }
+
+    #[test]
+    fn verify_password_accepts_only_the_right_password() {
+        let salt = [7u8; 16];
+        let records = [
+            hash_password(&test_hasher(), "open sesame"),
+            format!("{}${}", hex::encode(salt), hex::encode(legacy_digest(&salt, "open sesame"))),
+            hex::encode(legacy_digest(&[], "open sesame")),
+        ];
+        for stored in &records {
+            assert!(verify_password(stored, "open sesame"), "{}", stored);
+            assert!(!verify_password(stored, "open sesame!"), "{}", stored);
+            assert!(!verify_password(stored, ""), "{}", stored);
+        }
+        // Truncated or garbled records never match
+        assert!(!verify_password(&records[2][..40], "open sesame"));
+        assert!(!verify_password("not-hex", "open sesame"));
+    }
+
+    #[test]
+    fn password_policy() {
+        assert!(validate_password("a").is_err());
+        assert!(validate_password("abcdefgh").is_err());
+        assert!(validate_password("abc|defg").is_ok());
+        assert!(validate_password("abcdefg1").is_ok());
+        assert!(validate_password("correct horse").is_ok());
+    }
+
+    #[test]
+    fn username_policy() {
+        assert!(validate_username("Bob").is_ok());
+        assert!(validate_username("zoë_1.x-y").is_ok());
+        assert!(validate_username("ab").is_err());
+        assert!(validate_username(&"a".repeat(33)).is_err());
+        assert!(validate_username("bob smith").is_err());
+        assert!(validate_username(".bob").is_err());
+        for delimiter in ["a|b", "a:b", "a,b", "a=b"] {
+            assert!(validate_username(delimiter).is_err(), "{}", delimiter);
+        }
+    }
+
+    #[test]
+    fn addresses_parse_as_ip_host_or_multiaddr() {
+        let parsed = |arg: &str| parse_tcp_address(arg).map(|addr| addr.to_string());
+        assert_eq!(parsed("203.0.113.9:62649").unwrap(), "/ip4/203.0.113.9/tcp/62649");
+        assert_eq!(parsed("[::1]:62649").unwrap(), "/ip6/::1/tcp/62649");
+        assert_eq!(parsed("chat.example.org:4001").unwrap(), "/dns/chat.example.org/tcp/4001");
+        assert_eq!(parsed("/dns4/chat.example.org/tcp/62649").unwrap(), "/dns4/chat.example.org/tcp/62649");
+        for bad in ["::1:62649", "chat.example.org", "host:port", ":62649", "/ip4/nope"] {
+            assert!(parsed(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn repeated_bad_logins_are_throttled_before_hashing() {
+        let mut limiter = LoginLimiter::default();
+        let peer = PeerId::random();
+        let now = Instant::now();
+        // Attempts that never succeed, some still being checked when the next one starts
+        for _ in 0..MAX_ACCOUNT_LOGIN_FAILURES {
+            assert!(limiter.begin(peer, "carol", now));
+        }
+        assert!(!limiter.begin(peer, "carol", now));
+        // Other usernames from the same peer, up to the per-peer limit
+        for _ in MAX_ACCOUNT_LOGIN_FAILURES..MAX_LOGIN_FAILURES {
+            assert!(limiter.begin(peer, "dave", now));
+        }
+        assert!(!limiter.begin(peer, "erin", now));
+
+        // The owner can still log in from a fresh peer, however often others got it wrong
+        let owner = PeerId::random();
+        assert!(limiter.begin(owner, "carol", now));
+        limiter.succeeded(owner, "carol");
+
+        // The peer is let back in once the window passes
+        let later = now + Duration::from_secs(LOGIN_FAILURE_WINDOW_SECS);
+        assert!(limiter.begin(peer, "carol", later));
+        limiter.succeeded(peer, "carol");
+        for _ in 0..MAX_ACCOUNT_LOGIN_FAILURES {
+            assert!(limiter.begin(peer, "carol", later));
+        }
+    }
+
+    #[test]
+    fn silent_peers_are_taken_offline() {
+        let (quiet, chatty) = (PeerId::random(), PeerId::random());
+        let mut online: HashMap<String, HashSet<PeerId>> =
+            [("alice".to_string(), [quiet, chatty].into()), ("bob".to_string(), [quiet].into())].into();
+        let start = Instant::now();
+        let later = start + Duration::from_secs(PRESENCE_TIMEOUT_SECS);
+        let last_seen: HashMap<PeerId, Instant> = [(quiet, start), (chatty, later)].into();
+
+        assert!(reap_stale_sessions(&mut online, &last_seen, start).is_empty());
+        let mut reaped = reap_stale_sessions(&mut online, &last_seen, later);
+        reaped.sort();
+        assert_eq!(reaped, [("alice".to_string(), quiet), ("bob".to_string(), quiet)]);
+        // Alice is still online on her other device; bob is gone
+        assert_eq!(online, [("alice".to_string(), [chatty].into())].into());
+    }
+
+    #[test]
+    fn only_the_sessions_own_peer_gets_the_directory() {
+        let (alice, mallory) = (PeerId::random(), PeerId::random());
+        let mut sessions = HashMap::new();
+        let token = issue_session(&mut sessions, "alice", alice);
+
+        assert_eq!(resolve_session(&mut sessions, "", mallory), None);
+        assert_eq!(resolve_session(&mut sessions, "not-a-token", mallory), None);
+        // A token seen on the wire is no use from another connection
+        assert_eq!(resolve_session(&mut sessions, &token, mallory), None);
+        assert_eq!(resolve_session(&mut sessions, &token, alice).as_deref(), Some("alice"));
+
+        sessions.get_mut(&token).unwrap().expires_at = Instant::now();
+        assert_eq!(resolve_session(&mut sessions, &token, alice), None);
+        assert!(sessions.is_empty());
+    }
+
+    #[test]
+    fn named_spaces_only_see_their_own_sessions() {
+        let (laptop, phone, bob, carol) = (PeerId::random(), PeerId::random(), PeerId::random(), PeerId::random());
+        let account = (String::from("hash"), String::from("2000-01-01"));
+        let users_by_name: HashMap<String, (String, String)> =
+            ["alice", "bob", "carol", "dave"].into_iter().map(|name| (name.to_string(), account.clone())).collect();
+        let online: HashMap<String, HashSet<PeerId>> =
+            [("alice".to_string(), [laptop, phone].into()), ("bob".to_string(), [bob].into()), ("carol".to_string(), [carol].into())].into();
+        let work = "work".to_string();
+        let spaces: HashMap<PeerId, String> = [(phone, work.clone()), (bob, work.clone()), (carol, "home".to_string())].into();
+        let key = [7; 32];
+        let handle = |pid: PeerId| session_handle(&key, &pid);
+
+        // The default space sees every session but its own, and offline accounts
+        let expected: HashMap<String, Vec<String>> = [
+            ("alice".to_string(), vec![handle(phone)]),
+            ("bob".to_string(), vec![handle(bob)]),
+            ("carol".to_string(), vec![handle(carol)]),
+            ("dave".to_string(), Vec::new()),
+        ]
+        .into();
+        assert_eq!(directory(&online, &users_by_name, &spaces, &key, laptop), expected);
+        let expected: HashMap<String, Vec<String>> = [("alice".to_string(), vec![handle(phone)]), ("bob".to_string(), Vec::new())].into();
+        assert_eq!(directory(&online, &users_by_name, &spaces, &key, bob), expected);
+    }
+
+    #[test]
+    fn accounts_are_listed_a_page_at_a_time() {
+        let account = |hash: &str| (hash.to_string(), "2000-01-01".to_string());
+        let mut users_by_name: HashMap<String, (String, String)> =
+            (0..ACCOUNTS_PAGE_SIZE + 2).map(|i| (format!("user{:04}", i), account("hash"))).collect();
+        users_by_name.insert("released".to_string(), account(""));
+        let online: HashMap<String, HashSet<PeerId>> = [("user0001".to_string(), [PeerId::random()].into())].into();
+
+        let seen = |name: &str| UserXml { username: name.into(), password_hash: "hash".into(), birthdate: "2000-01-01".into(), last_seen: Some(42) };
+        let users_xml = UsersXml { users: vec![seen("user0000"), seen("user0001")] };
+
+        let AuthResponse::Accounts { users, next, last_seen } = accounts_page(&users_by_name, &online, &users_xml, 0) else { panic!("not a page") };
+        assert_eq!(users.len(), ACCOUNTS_PAGE_SIZE);
+        assert_eq!(users[..2], [("user0000".to_string(), false), ("user0001".to_string(), true)]);
+        assert_eq!(next, Some(ACCOUNTS_PAGE_SIZE as u32));
+        // Online accounts show as online, not with a stale time
+        assert_eq!(last_seen, HashMap::from([("user0000".to_string(), 42)]));
+
+        let AuthResponse::Accounts { users, next, .. } = accounts_page(&users_by_name, &online, &users_xml, ACCOUNTS_PAGE_SIZE) else { panic!("not a page") };
+        assert_eq!(users.len(), 2);
+        assert_eq!(next, None);
+        assert!(users.iter().all(|(name, _)| name != "released"));
+    }
+
+    #[test]
+    fn directory_lists_offline_accounts_but_not_released_ones() {
+        let account = |hash: &str| (hash.to_string(), "2000-01-01".to_string());
+        let users_by_name: HashMap<String, (String, String)> = [
+            ("alice".to_string(), account("hash-a")),
+            ("bob".to_string(), account("hash-b")),
+            ("released".to_string(), account("")),
+        ]
+        .into();
+        let (laptop, phone) = (PeerId::random(), PeerId::random());
+        let online: HashMap<String, HashSet<PeerId>> = [("alice".to_string(), [laptop, phone].into())].into();
+        let key = [7; 32];
+        let mut sessions = vec![session_handle(&key, &laptop), session_handle(&key, &phone)];
+        sessions.sort();
+        let expected: HashMap<String, Vec<String>> = [("alice".to_string(), sessions), ("bob".to_string(), Vec::new())].into();
+        assert_eq!(directory(&online, &users_by_name, &HashMap::new(), &key, PeerId::random()), expected);
+        // Handles say nothing about the PeerId behind them, and only match the server's own key
+        assert!(expected["alice"].iter().all(|handle| handle.parse::<PeerId>().is_err()));
+        assert_ne!(session_handle(&[8; 32], &laptop), session_handle(&key, &laptop));
+    }
+
+    // Runs a server with cheap hashing in a fresh data directory, after `configure` adjusts the
+    // defaults; returns its PeerId and address
+    const CHEAP_HASHING: PasswordHashing = PasswordHashing { memory_kib: 64, iterations: 1 };
+
+    async fn start_server(name: &str, configure: impl FnOnce(&mut ServerConfig)) -> (PeerId, Multiaddr, PathBuf) {
+        let data_dir = std::env::temp_dir().join(format!("neutral-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&data_dir);
+        fs::create_dir_all(&data_dir).unwrap();
+        let keypair = Keypair::generate_ed25519();
+        let server = keypair.public().to_peer_id();
+        let (ready_tx, ready_rx) = tokio::sync::oneshot::channel();
+        let mut config = ServerConfig {
+            keypair,
+            listen_addr: "/ip4/127.0.0.1/tcp/0".parse().unwrap(),
+            data_dir: data_dir.clone(),
+            persist_registrations: false,
+            admin_token: None,
+            protocol_probes: None,
+            strict_protocols: false,
+            shutdown: None,
+            password_hashing: CHEAP_HASHING,
+            max_frame_bytes: protocol::DEFAULT_MAX_FRAME_BYTES,
+            circuit_relay: false,
+            metrics_addr: None,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            max_connections_per_peer: DEFAULT_MAX_CONNECTIONS_PER_PEER,
+        };
+        configure(&mut config);
+        tokio::spawn(run_server(config, Some(ready_tx)));
+        (server, ready_rx.await.unwrap(), data_dir)
+    }
+
+    fn test_swarm<B: NetworkBehaviour>(behaviour: impl FnOnce(&Keypair) -> B) -> libp2p::Swarm<B> {
+        libp2p::SwarmBuilder::with_new_identity()
+            .with_tokio()
+            .with_tcp(tcp::Config::default(), noise::Config::new, yamux::Config::default)
+            .unwrap()
+            .with_behaviour(|key| behaviour(key))
+            .unwrap()
+            .with_swarm_config(|c| c.with_idle_connection_timeout(Duration::from_secs(30)))
+            .build()
+    }
+
+    #[tokio::test]
+    async fn concurrent_registrations_of_one_name_let_only_one_through() {
+        let (server, addr, data_dir) = start_server("register-race", |_| {}).await;
+        let mut client = test_swarm(|_| {
+            request_response::Behaviour::with_codec(
+                AuthCodec::default(),
+                std::iter::once((AuthProtocol(), request_response::ProtocolSupport::Full)),
+                request_response::Config::default(),
+            )
+        });
+        client.dial(addr).unwrap();
+        while !matches!(client.select_next_some().await, SwarmEvent::ConnectionEstablished { .. }) {}
+        // Both go out on the one connection before the server answers either
+        for casing in ["racer", "RACER"] {
+            let request = AuthRequest::Register { username: casing.into(), password: "secret-42".into(), birthdate: "2000-01-01".into() };
+            client.behaviour_mut().send_request(&server, request);
+        }
+        let mut replies = Vec::new();
+        while replies.len() < 2 {
+            if let SwarmEvent::Behaviour(request_response::Event::Message { message: request_response::Message::Response { response, .. }, .. }) =
+                tokio::time::timeout(Duration::from_secs(10), client.select_next_some()).await.expect("no auth reply")
+            {
+                replies.push(response);
+            }
+        }
+        assert_eq!(replies.iter().filter(|r| matches!(r, AuthResponse::Session { .. })).count(), 1, "{:?}", replies);
+        assert!(replies.contains(&AuthResponse::Error(AuthError::UsernameTaken)), "{:?}", replies);
+        let _ = fs::remove_dir_all(&data_dir);
+    }
+
+    #[tokio::test]
+    async fn registrations_that_cannot_be_saved_get_a_storage_error() {
+        let (server, addr, data_dir) = start_server("unwritable", |config| fs::create_dir(config.data_dir.join("users.xml")).unwrap()).await;
+        let mut client = test_swarm(|_| {
+            request_response::Behaviour::with_codec(
+                AuthCodec::default(),
+                std::iter::once((AuthProtocol(), request_response::ProtocolSupport::Full)),
+                request_response::Config::default(),
+            )
+        });
+        client.dial(addr).unwrap();
+        while !matches!(client.select_next_some().await, SwarmEvent::ConnectionEstablished { .. }) {}
+        let request = AuthRequest::Register { username: "unsaved".into(), password: "secret-42".into(), birthdate: "2000-01-01".into() };
+        client.behaviour_mut().send_request(&server, request);
+        let response = loop {
+            if let SwarmEvent::Behaviour(request_response::Event::Message { message: request_response::Message::Response { response, .. }, .. }) =
+                tokio::time::timeout(Duration::from_secs(10), client.select_next_some()).await.expect("no auth reply")
+            {
+                break response;
+            }
+        };
+        assert_eq!(response, AuthResponse::Error(AuthError::Storage));
+        let _ = fs::remove_dir_all(&data_dir);
+    }
+
+    #[tokio::test]
+    async fn logged_out_users_are_listed_with_when_they_were_last_seen() {
+        let (server, addr, data_dir) = start_server("last-seen", |_| {}).await;
+        let mut client = test_swarm(|_| {
+            request_response::Behaviour::with_codec(
+                AuthCodec::default(),
+                std::iter::once((AuthProtocol(), request_response::ProtocolSupport::Full)),
+                request_response::Config::default(),
+            )
+        });
+        client.dial(addr).unwrap();
+        while !matches!(client.select_next_some().await, SwarmEvent::ConnectionEstablished { .. }) {}
+        let ask = async |client: &mut libp2p::Swarm<request_response::Behaviour<AuthCodec>>, request| {
+            client.behaviour_mut().send_request(&server, request);
+            loop {
+                if let SwarmEvent::Behaviour(request_response::Event::Message { message: request_response::Message::Response { response, .. }, .. }) =
+                    tokio::time::timeout(Duration::from_secs(10), client.select_next_some()).await.expect("no auth reply")
+                {
+                    return response;
+                }
+            }
+        };
+        let register = |name: &str| AuthRequest::Register { username: name.into(), password: "secret-42".into(), birthdate: "2000-01-01".into() };
+        let AuthResponse::Session { token } = ask(&mut client, register("wanderer")).await else { panic!("not registered") };
+        let before = unix_now_ms();
+        assert_eq!(ask(&mut client, AuthRequest::Logout { token }).await, AuthResponse::Ok);
+        let AuthResponse::Session { token } = ask(&mut client, register("watcher")).await else { panic!("not registered") };
+
+        let AuthResponse::Directory { users, last_seen } = ask(&mut client, AuthRequest::List { token, space: None, last_seen: true }).await else {
+            panic!("no directory")
+        };
+        // The watcher's only session is its own, which isn't listed to it
+        assert!(users["wanderer"].is_empty() && users["watcher"].is_empty());
+        // Online users have no last-seen time
+        assert_eq!(last_seen.keys().collect::<Vec<_>>(), ["wanderer"]);
+        assert!((before..=unix_now_ms()).contains(&last_seen["wanderer"]));
+        let _ = fs::remove_dir_all(&data_dir);
+    }
+
+    #[tokio::test]
+    async fn admin_commands_hand_over_or_free_an_account() {
+        let (server, addr, data_dir) = start_server("admin", |config| config.admin_token = Some("operator".into())).await;
+        let connect = async || {
+            let mut client = test_swarm(|_| {
+                request_response::Behaviour::with_codec(
+                    AuthCodec::default(),
+                    std::iter::once((AuthProtocol(), request_response::ProtocolSupport::Full)),
+                    request_response::Config::default(),
+                )
+            });
+            client.dial(addr.clone()).unwrap();
+            while !matches!(client.select_next_some().await, SwarmEvent::ConnectionEstablished { .. }) {}
+            client
+        };
+        let ask = async |client: &mut libp2p::Swarm<request_response::Behaviour<AuthCodec>>, request| {
+            client.behaviour_mut().send_request(&server, request);
+            loop {
+                if let SwarmEvent::Behaviour(request_response::Event::Message { message: request_response::Message::Response { response, .. }, .. }) =
+                    tokio::time::timeout(Duration::from_secs(10), client.select_next_some()).await.expect("no auth reply")
+                {
+                    return response;
+                }
+            }
+        };
+        // Ending a session disconnects the devices that held it
+        let ended = async |client: &mut libp2p::Swarm<request_response::Behaviour<AuthCodec>>| loop {
+            let event = tokio::time::timeout(Duration::from_secs(10), client.select_next_some()).await.expect("session not ended");
+            if matches!(event, SwarmEvent::ConnectionClosed { .. }) {
+                return;
+            }
+        };
+        let admin = |command| AuthRequest::Admin { token: "operator".into(), command };
+        let login = |password: &str| AuthRequest::Login { username: "heirloom".into(), password: password.into() };
+        let register = |password: &str| AuthRequest::Register { username: "heirloom".into(), password: password.into(), birthdate: "2000-01-01".into() };
+        let mut operator = connect().await;
+
+        // TRANSFER with a hash: the old password stops working and the new one logs in
+        let mut owner = connect().await;
+        assert!(matches!(ask(&mut owner, register("first-owner-1")).await, AuthResponse::Session { .. }));
+        let handed_over = hex::encode(Sha256::digest(b"second-owner-2"));
+        let transfer = AdminCommand::Transfer { username: "HEIRLOOM".into(), password_hash: Some(handed_over) };
+        assert_eq!(ask(&mut operator, admin(transfer)).await, AuthResponse::Ok);
+        ended(&mut owner).await;
+        let mut heir = connect().await;
+        assert_eq!(ask(&mut heir, login("first-owner-1")).await, AuthResponse::Error(AuthError::InvalidPassword));
+        assert!(matches!(ask(&mut heir, login("second-owner-2")).await, AuthResponse::Session { .. }));
+
+        // TRANSFER without a hash: no one can log in, and the next Register claims the name
+        let transfer = AdminCommand::Transfer { username: "heirloom".into(), password_hash: None };
+        assert_eq!(ask(&mut operator, admin(transfer)).await, AuthResponse::Ok);
+        ended(&mut heir).await;
+        let mut claimant = connect().await;
+        assert_eq!(ask(&mut claimant, login("second-owner-2")).await, AuthResponse::Error(AuthError::AwaitingOwner));
+        assert!(matches!(ask(&mut claimant, register("third-owner-3")).await, AuthResponse::Session { .. }));
+
+        // RELEASE: the account is gone and so are its sessions
+        let release = AdminCommand::Release { username: "heirloom".into() };
+        assert_eq!(ask(&mut operator, admin(release)).await, AuthResponse::Ok);
+        ended(&mut claimant).await;
+        let mut stranger = connect().await;
+        assert_eq!(ask(&mut stranger, login("third-owner-3")).await, AuthResponse::Error(AuthError::UnknownUser));
+        assert!(!fs::read_to_string(data_dir.join("users.xml")).unwrap().contains("heirloom"));
+
+        // Guessing the operator token is limited like guessing a password, even once it's right
+        let guess = |token: &str| AuthRequest::Admin { token: token.into(), command: AdminCommand::Release { username: "anyone".into() } };
+        let mut guesser = connect().await;
+        for _ in 0..MAX_LOGIN_FAILURES {
+            assert_eq!(ask(&mut guesser, guess("operat0r")).await, AuthResponse::Error(AuthError::NotAuthorized));
+        }
+        assert_eq!(ask(&mut guesser, guess("operator")).await, AuthResponse::Error(AuthError::RateLimited));
+        let _ = fs::remove_dir_all(&data_dir);
+    }
+
+    #[tokio::test]
+    async fn slow_logins_do_not_stall_rendezvous() {
+        #[derive(NetworkBehaviour)]
+        struct Prober {
+            auth: request_response::Behaviour<AuthCodec>,
+            rendezvous: rendezvous::client::Behaviour,
+        }
+        let (server, addr, data_dir) = start_server("slow-logins", |config| config.password_hashing = PasswordHashing::default()).await;
+        let mut client = test_swarm(|key| Prober {
+            auth: request_response::Behaviour::with_codec(
+                AuthCodec::default(),
+                std::iter::once((AuthProtocol(), request_response::ProtocolSupport::Full)),
+                request_response::Config::default().with_request_timeout(Duration::from_secs(60)),
+            ),
+            rendezvous: rendezvous::client::Behaviour::new(key.clone()),
+        });
+        client.dial(addr).unwrap();
+        while !matches!(client.select_next_some().await, SwarmEvent::ConnectionEstablished { .. }) {}
+        // Two accounts, since one peer may only have a few logins in flight for each
+        let accounts = ["patient", "patience"];
+        for name in accounts {
+            let register = AuthRequest::Register { username: name.into(), password: "secret-42".into(), birthdate: "2000-01-01".into() };
+            client.behaviour_mut().auth.send_request(&server, register);
+            loop {
+                if let SwarmEvent::Behaviour(ProberEvent::Auth(request_response::Event::Message { message: request_response::Message::Response { response, .. }, .. })) =
+                    client.select_next_some().await
+                {
+                    assert!(matches!(response, AuthResponse::Session { .. }), "{:?}", response);
+                    break;
+                }
+            }
+        }
+        // Logins in flight count against the limiter until they succeed, so stay under it
+        let sent = Instant::now();
+        for name in accounts.iter().cycle().take(MAX_LOGIN_FAILURES) {
+            client.behaviour_mut().auth.send_request(&server, AuthRequest::Login { username: name.to_string(), password: "secret-42".into() });
+        }
+        client.behaviour_mut().rendezvous.discover(None, None, None, server);
+
+        let (mut discovered, mut logged_in) = (None, Vec::new());
+        while discovered.is_none() || logged_in.len() < MAX_LOGIN_FAILURES {
+            match tokio::time::timeout(Duration::from_secs(60), client.select_next_some()).await.expect("no reply") {
+                SwarmEvent::Behaviour(ProberEvent::Auth(request_response::Event::Message { message: request_response::Message::Response { response, .. }, .. })) => {
+                    assert!(matches!(response, AuthResponse::Session { .. }), "{:?}", response);
+                    logged_in.push(sent.elapsed());
+                }
+                SwarmEvent::Behaviour(ProberEvent::Rendezvous(rendezvous::client::Event::Discovered { .. })) => discovered = Some(sent.elapsed()),
+                SwarmEvent::Behaviour(ProberEvent::Rendezvous(event)) => panic!("{:?}", event),
+                _ => {}
+            }
+        }
+        // Hashing inline would hold the discovery back until every login had been checked
+        let (discovered, last_login) = (discovered.unwrap(), logged_in.into_iter().max().unwrap());
+        assert!(discovered * 2 < last_login, "discovery took {:?}, the logins {:?}", discovered, last_login);
+        let _ = fs::remove_dir_all(&data_dir);
+    }
+
+    #[tokio::test]
+    async fn connections_over_the_per_peer_cap_are_refused() {
+        use libp2p::swarm::dial_opts::{DialOpts, PeerCondition};
+        let (server, addr, data_dir) = start_server("limits", |config| config.max_connections_per_peer = 1).await;
+        let mut client = test_swarm(|_| ping::Behaviour::default());
+        for _ in 0..2 {
+            let dial = DialOpts::peer_id(server).addresses(vec![addr.clone()]).condition(PeerCondition::Always).build();
+            client.dial(dial).unwrap();
+        }
+        // Both dials succeed on our side; the server then closes the second connection
+        let settle = tokio::time::sleep(Duration::from_secs(3));
+        tokio::pin!(settle);
+        loop {
+            tokio::select! {
+                _ = &mut settle => break,
+                _ = client.select_next_some() => {}
+            }
+        }
+        assert_eq!(client.network_info().connection_counters().num_established(), 1);
+        let _ = fs::remove_dir_all(&data_dir);
+    }
+
+    #[tokio::test]
+    async fn one_account_is_online_and_reachable_on_two_devices() {
+        #[derive(NetworkBehaviour)]
+        struct Device {
+            auth: request_response::Behaviour<AuthCodec>,
+            relay: request_response::Behaviour<RelayCodec>,
+        }
+        let (server, addr, data_dir) = start_server("two-devices", |_| {}).await;
+        let connect = async || {
+            let mut device = test_swarm(|_| Device {
+                auth: request_response::Behaviour::with_codec(
+                    AuthCodec::default(),
+                    std::iter::once((AuthProtocol(), request_response::ProtocolSupport::Full)),
+                    request_response::Config::default(),
+                ),
+                relay: request_response::Behaviour::with_codec(
+                    RelayCodec::default(),
+                    std::iter::once((RelayProtocol(), request_response::ProtocolSupport::Full)),
+                    request_response::Config::default(),
+                ),
+            });
+            device.dial(addr.clone()).unwrap();
+            while !matches!(device.select_next_some().await, SwarmEvent::ConnectionEstablished { .. }) {}
+            device
+        };
+        let ask = async |device: &mut libp2p::Swarm<Device>, request| {
+            device.behaviour_mut().auth.send_request(&server, request);
+            loop {
+                if let SwarmEvent::Behaviour(DeviceEvent::Auth(request_response::Event::Message { message: request_response::Message::Response { response, .. }, .. })) =
+                    tokio::time::timeout(Duration::from_secs(10), device.select_next_some()).await.expect("no auth reply")
+                {
+                    return response;
+                }
+            }
+        };
+        let register = |name: &str| AuthRequest::Register { username: name.into(), password: "secret-42".into(), birthdate: "2000-01-01".into() };
+        let (mut laptop, mut phone, mut sender) = (connect().await, connect().await, connect().await);
+        assert!(matches!(ask(&mut laptop, register("nomad")).await, AuthResponse::Session { .. }));
+        let login = AuthRequest::Login { username: "nomad".into(), password: "secret-42".into() };
+        assert!(matches!(ask(&mut phone, login).await, AuthResponse::Session { .. }));
+
+        // Both devices are listed as online, each under a handle that resolves to it
+        let AuthResponse::Session { token } = ask(&mut sender, register("courier")).await else { panic!("not registered") };
+        let AuthResponse::Users(users) = ask(&mut sender, AuthRequest::List { token: token.clone(), space: None, last_seen: false }).await else {
+            panic!("no directory")
+        };
+        let mut resolved = Vec::new();
+        for session in &users["nomad"] {
+            let AuthResponse::Route { peer, .. } = ask(&mut sender, AuthRequest::Resolve { token: token.clone(), session: session.clone() }).await else {
+                panic!("handle not resolved")
+            };
+            resolved.push(peer);
+        }
+        resolved.sort();
+        let mut devices = vec![laptop.local_peer_id().to_string(), phone.local_peer_id().to_string()];
+        devices.sort();
+        assert_eq!(resolved, devices);
+
+        // A message routed through the server reaches both of them
+        let store = RelayRequest::Store { token, to: "nomad".into(), text: "hello".into(), forwarded: false, encrypted: false };
+        sender.behaviour_mut().relay.send_request(&server, store);
+        loop {
+            if let SwarmEvent::Behaviour(DeviceEvent::Relay(request_response::Event::Message { message: request_response::Message::Response { response, .. }, .. })) =
+                tokio::time::timeout(Duration::from_secs(10), sender.select_next_some()).await.expect("no relay reply")
+            {
+                assert_eq!(response, RelayResponse::Routed);
+                break;
+            }
+        }
+        for device in [&mut laptop, &mut phone] {
+            loop {
+                if let SwarmEvent::Behaviour(DeviceEvent::Relay(request_response::Event::Message {
+                    message: request_response::Message::Request { request: RelayRequest::Deliver { messages }, channel, .. },
+                    ..
+                })) = tokio::time::timeout(Duration::from_secs(10), device.select_next_some()).await.expect("message not delivered")
+                {
+                    assert_eq!(messages.iter().map(|m| m.text.as_str()).collect::<Vec<_>>(), ["hello"]);
+                    let _ = device.behaviour_mut().relay.send_response(channel, RelayResponse::Ok);
+                    break;
+                }
+            }
+        }
+        let _ = fs::remove_dir_all(&data_dir);
+    }
+
+    #[tokio::test]
+    async fn directory_handles_resolve_for_sessions_only() {
+        let (server, addr, data_dir) = start_server("handles", |_| {}).await;
+        let connect = async || {
+            let mut client = test_swarm(|_| {
+                request_response::Behaviour::with_codec(
+                    AuthCodec::default(),
+                    std::iter::once((AuthProtocol(), request_response::ProtocolSupport::Full)),
+                    request_response::Config::default(),
+                )
+            });
+            client.dial(addr.clone()).unwrap();
+            while !matches!(client.select_next_some().await, SwarmEvent::ConnectionEstablished { .. }) {}
+            client
+        };
+        let ask = async |client: &mut libp2p::Swarm<request_response::Behaviour<AuthCodec>>, request| {
+            client.behaviour_mut().send_request(&server, request);
+            loop {
+                if let SwarmEvent::Behaviour(request_response::Event::Message { message: request_response::Message::Response { response, .. }, .. }) =
+                    tokio::time::timeout(Duration::from_secs(10), client.select_next_some()).await.expect("no auth reply")
+                {
+                    return response;
+                }
+            }
+        };
+        let register = |name: &str| AuthRequest::Register { username: name.into(), password: "secret-42".into(), birthdate: "2000-01-01".into() };
+        let (mut alice, mut bob) = (connect().await, connect().await);
+        let AuthResponse::Session { token } = ask(&mut alice, register("alice")).await else { panic!("not registered") };
+        let AuthResponse::Session { token: bob_token } = ask(&mut bob, register("bob")).await else { panic!("not registered") };
+
+        // The directory carries a handle, not bob's PeerId
+        let list = |token: &String| AuthRequest::List { token: token.clone(), space: None, last_seen: false };
+        let AuthResponse::Users(users) = ask(&mut alice, list(&token)).await else { panic!("no directory") };
+        let [handle] = users["bob"].as_slice() else { panic!("bob not online: {:?}", users) };
+        assert_ne!(*handle, bob.local_peer_id().to_string());
+        let resolve = |session: &str| AuthRequest::Resolve { token: token.clone(), session: session.to_string() };
+        assert_eq!(
+            ask(&mut alice, resolve(handle)).await,
+            AuthResponse::Route { session: handle.clone(), peer: bob.local_peer_id().to_string() }
+        );
+
+        // Bob is told who resolved him, under the handle his own directory lists her with
+        let introduced = loop {
+            if let SwarmEvent::Behaviour(request_response::Event::Message {
+                message: request_response::Message::Request { request: AuthRequest::Introduce { session, peer }, channel, .. },
+                ..
+            }) = tokio::time::timeout(Duration::from_secs(10), bob.select_next_some()).await.expect("no introduction")
+            {
+                let _ = bob.behaviour_mut().send_response(channel, AuthResponse::Ok);
+                break (session, peer);
+            }
+        };
+        assert_eq!(introduced.1, alice.local_peer_id().to_string());
+        let AuthResponse::Users(users) = ask(&mut bob, list(&bob_token)).await else { panic!("no directory") };
+        assert_eq!(users["alice"], [introduced.0]);
+
+        // Only live handles resolve, only for a session, and only so many times a minute
+        assert_eq!(ask(&mut alice, resolve("00112233445566778899aabbccddeeff")).await, AuthResponse::Error(AuthError::UnknownUser));
+        let stranger = AuthRequest::Resolve { token: "not-a-token".into(), session: handle.clone() };
+        assert_eq!(ask(&mut alice, stranger).await, AuthResponse::InvalidSession);
+        for _ in 2..MAX_RESOLVES {
+            assert!(matches!(ask(&mut alice, resolve(handle)).await, AuthResponse::Route { .. }));
+        }
+        assert_eq!(ask(&mut alice, resolve(handle)).await, AuthResponse::Error(AuthError::RateLimited));
+        let _ = fs::remove_dir_all(&data_dir);
+    }
+
+    #[tokio::test]
+    async fn strict_mode_disconnects_repeated_protocol_probes() {
+        use tracing_subscriber::layer::SubscriberExt;
+        // Single-threaded runtime, so the connection tasks log through this thread's subscriber
+        let (layer, mut probes) = protocol_probe_layer();
+        let _subscriber = tracing::subscriber::set_default(tracing_subscriber::registry().with(layer));
+        let mut client = test_swarm(|_| {
+            request_response::Behaviour::with_codec(
+                protocol::CborCodec::<&'static str, String, String>::default(),
+                std::iter::once(("/neutral-probe/1.0.0", request_response::ProtocolSupport::Full)),
+                request_response::Config::default(),
+            )
+        });
+        let prober = *client.local_peer_id();
+        // The client's connection logs through the same subscriber, so only the server's view of
+        // the prober is passed on to it, and counted on the way
+        let (probe_tx, probe_rx) = tokio::sync::mpsc::unbounded_channel();
+        let (seen_tx, mut seen) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Some(peer) = probes.recv().await {
+                if peer == prober {
+                    let _ = seen_tx.send(peer);
+                    let _ = probe_tx.send(peer);
+                }
+            }
+        });
+        let (server, addr, data_dir) = start_server("probes", |config| {
+            config.protocol_probes = Some(probe_rx);
+            config.strict_protocols = true;
+        })
+        .await;
+        client.dial(addr).unwrap();
+        while !matches!(client.select_next_some().await, SwarmEvent::ConnectionEstablished { .. }) {}
+
+        for count in 1..=MAX_PROTOCOL_PROBES {
+            assert!(client.is_connected(&server), "disconnected after {} probe(s)", count - 1);
+            client.behaviour_mut().send_request(&server, "hello?".to_string());
+            let counted = tokio::time::timeout(Duration::from_secs(10), async {
+                loop {
+                    tokio::select! {
+                        peer = seen.recv() => break peer,
+                        _ = client.select_next_some() => {}
+                    }
+                }
+            });
+            assert_eq!(counted.await.expect("probe not counted"), Some(prober));
+        }
+        // The last probe reached the limit, so the server closes the connection
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while !matches!(client.select_next_some().await, SwarmEvent::ConnectionClosed { num_established: 0, .. }) {}
+        })
+        .await
+        .expect("prober still connected");
+        let _ = fs::remove_dir_all(&data_dir);
+    }
+}
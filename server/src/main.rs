@@ -1,455 +1,207 @@
-use async_trait::async_trait;
-use futures::{prelude::*, StreamExt};
-use libp2p::{
-    identify, noise, ping, rendezvous, request_response,
-    swarm::{NetworkBehaviour, SwarmEvent},
-    tcp, yamux,
-    PeerId,
-};
-use std::{error::Error, io, collections::HashMap, fs, path::{Path, PathBuf}};
-use tracing_subscriber::EnvFilter;
-use serde::{Serialize, Deserialize};
-use sha2::{Sha256, Digest};
+use std::{error::Error, io, path::{Path, PathBuf}};
+use server::{protocol::DEFAULT_MAX_FRAME_BYTES, protocol_probe_layer, run_server, PasswordHashing, ServerConfig, AUDIT_TARGET};
+use libp2p::multiaddr::Protocol;
+use tracing_subscriber::{filter::Targets, prelude::*, EnvFilter};
 
-// --- Protocol Definition ---
-#[derive(Debug, Clone)]
-struct HelloProtocol();
-
-#[derive(Default, Clone)]
-struct HelloCodec();
-
-impl AsRef<str> for HelloProtocol {
-    fn as_ref(&self) -> &str {
-        "/hello/1.0"
-    }
-}
-
-#[async_trait]
-impl request_response::Codec for HelloCodec {
-    type Protocol = HelloProtocol;
-    type Request = String;
-    type Response = String;
-
-    async fn read_request<T>(&mut self, _: &Self::Protocol, io: &mut T) -> io::Result<Self::Request>
-    where
-        T: AsyncRead + Unpin + Send,
-    {
-        // THE FIX: Pass a new mutable reference to the function.
-        let vec = unsigned_varint::aio::read_u16(&mut *io)
-            .await
-            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
-        let mut buffer = vec![0; vec as usize];
-        io.read_exact(&mut buffer).await?;
-        Ok(String::from_utf8(buffer).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?)
-    }
-
-    async fn read_response<T>(
-        &mut self,
-        _: &Self::Protocol,
-        io: &mut T,
-    ) -> io::Result<Self::Response>
-    where
-        T: AsyncRead + Unpin + Send,
-    {
-        // THE FIX: Pass a new mutable reference to the function.
-        let vec = unsigned_varint::aio::read_u16(&mut *io)
-            .await
-            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
-        let mut buffer = vec![0; vec as usize];
-        io.read_exact(&mut buffer).await?;
-        Ok(String::from_utf8(buffer).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?)
-    }
-
-    async fn write_request<T>(
-        &mut self,
-        _: &Self::Protocol,
-        io: &mut T,
-        req: Self::Request,
-    ) -> io::Result<()>
-    where
-        T: AsyncWrite + Unpin + Send,
-    {
-        let mut uvi_buf = unsigned_varint::encode::u16_buffer();
-        let encoded_len = unsigned_varint::encode::u16(req.len() as u16, &mut uvi_buf);
-
-        io.write_all(encoded_len).await?;
-        io.write_all(req.as_bytes()).await?;
-        io.flush().await
-    }
-
-    async fn write_response<T>(
-        &mut self,
-        _: &Self::Protocol,
-        io: &mut T,
-        res: Self::Response,
-    ) -> io::Result<()>
-    where
-        T: AsyncWrite + Unpin + Send,
-    {
-        let mut uvi_buf = unsigned_varint::encode::u16_buffer();
-        let encoded_len = unsigned_varint::encode::u16(res.len() as u16, &mut uvi_buf);
-
-        io.write_all(encoded_len).await?;
-        io.write_all(res.as_bytes()).await?;
-        io.flush().await
-    }
-}
-
-// --- Auth Protocol Definition ---
-#[derive(Debug, Clone)]
-struct AuthProtocol();
-
-#[derive(Default, Clone)]
-struct AuthCodec();
-
-impl AsRef<str> for AuthProtocol {
-    fn as_ref(&self) -> &str {
-        "/auth/1.0"
-    }
-}
-
-#[async_trait]
-impl request_response::Codec for AuthCodec {
-    type Protocol = AuthProtocol;
-    type Request = String;
-    type Response = String;
-
-    async fn read_request<T>(&mut self, _: &Self::Protocol, io: &mut T) -> io::Result<Self::Request>
-    where
-        T: AsyncRead + Unpin + Send,
-    {
-        let len = unsigned_varint::aio::read_u16(&mut *io)
-            .await
-            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
-        let mut buffer = vec![0; len as usize];
-        io.read_exact(&mut buffer).await?;
-        Ok(String::from_utf8(buffer).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?)
-    }
-
-    async fn read_response<T>(
-        &mut self,
-        _: &Self::Protocol,
-        io: &mut T,
-    ) -> io::Result<Self::Response>
-    where
-        T: AsyncRead + Unpin + Send,
-    {
-        let len = unsigned_varint::aio::read_u16(&mut *io)
-            .await
-            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
-        let mut buffer = vec![0; len as usize];
-        io.read_exact(&mut buffer).await?;
-        Ok(String::from_utf8(buffer).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?)
-    }
-
-    async fn write_request<T>(
-        &mut self,
-        _: &Self::Protocol,
-        io: &mut T,
-        req: Self::Request,
-    ) -> io::Result<()>
-    where
-        T: AsyncWrite + Unpin + Send,
-    {
-        let mut uvi_buf = unsigned_varint::encode::u16_buffer();
-        let encoded_len = unsigned_varint::encode::u16(req.len() as u16, &mut uvi_buf);
-        io.write_all(encoded_len).await?;
-        io.write_all(req.as_bytes()).await?;
-        io.flush().await
-    }
-
-    async fn write_response<T>(
-        &mut self,
-        _: &Self::Protocol,
-        io: &mut T,
-        res: Self::Response,
-    ) -> io::Result<()>
-    where
-        T: AsyncWrite + Unpin + Send,
-    {
-        let mut uvi_buf = unsigned_varint::encode::u16_buffer();
-        let encoded_len = unsigned_varint::encode::u16(res.len() as u16, &mut uvi_buf);
-        io.write_all(encoded_len).await?;
-        io.write_all(res.as_bytes()).await?;
-        io.flush().await
-    }
-}
-
-// --- Main Application Logic ---       
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
-    let _ = tracing_subscriber::fmt()
-        .with_env_filter(
-            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    // --audit-log=<dir> also writes account events to <dir>/auth.log.YYYY-MM-DD, a new file
+    // each day; the guard flushes the background writer when main returns
+    let (audit_layer, _audit_guard) = match args.iter().find_map(|a| a.strip_prefix("--audit-log=")) {
+        Some(dir) => {
+            let (writer, guard) = tracing_appender::non_blocking(tracing_appender::rolling::daily(dir, "auth.log"));
+            let layer = tracing_subscriber::fmt::layer()
+                .with_writer(writer)
+                .with_ansi(false)
+                .with_filter(Targets::new().with_target(AUDIT_TARGET, tracing::Level::INFO));
+            (Some(layer), Some(guard))
+        }
+        None => (None, None),
+    };
+    let (probe_layer, protocol_probes) = protocol_probe_layer();
+    let _ = tracing_subscriber::registry()
+        .with(
+            tracing_subscriber::fmt::layer().with_filter(
+                EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+            ),
         )
+        .with(audit_layer)
+        .with(probe_layer)
         .try_init();
 
-    // Optional CLI: ip:port to listen on (defaults to 0.0.0.0:62649)
-    let listen_arg = std::env::args().nth(1).unwrap_or_else(|| "0.0.0.0:62649".to_string());
-    let (listen_ip, listen_port) = match listen_arg.split_once(':') {
-        Some((ip, port)) if !ip.is_empty() && !port.is_empty() => (ip.to_string(), port.to_string()),
-        _ => ("0.0.0.0".to_string(), "62649".to_string()),
+    // Optional CLI: ip:port, [ipv6]:port or a multiaddr to listen on (defaults to 0.0.0.0:62649)
+    // Flags: --persist-registrations keeps rendezvous registrations across restarts
+    //        --strict-protocols disconnects peers that keep probing unsupported protocols
+    //        --data-dir=<dir> keeps users.xml and the other state files there, default <data dir>/neutral/server
+    //        --key-file=<path> loads (or creates) the server identity there, default <data dir>/server_key
+    //        --insecure-fixed-key uses the publicly known all-zeros key instead, for local testing only
+    //        --relay also acts as a circuit relay so clients behind NAT can hole punch
+    //        --audit-log=<dir> keeps a daily-rotated audit trail of account events (see above)
+    //        --metrics=<ip:port> serves Prometheus metrics over HTTP there
+    //        --idle-timeout=<secs> closes connections without open streams after that long (default 60)
+    let persist_registrations = args.iter().any(|a| a == "--persist-registrations");
+    let strict_protocols = args.iter().any(|a| a == "--strict-protocols");
+    let insecure_fixed_key = args.iter().any(|a| a == "--insecure-fixed-key");
+    let circuit_relay = args.iter().any(|a| a == "--relay");
+    let metrics_addr = match args.iter().find_map(|a| a.strip_prefix("--metrics=")) {
+        Some(addr) => match addr.parse() {
+            Ok(addr) => Some(addr),
+            Err(e) => {
+                eprintln!("Invalid --metrics address '{}': {} (expected ip:port)", addr, e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+    let idle_timeout = match args.iter().find_map(|a| a.strip_prefix("--idle-timeout=")) {
+        Some(secs) => match secs.parse::<u64>() {
+            Ok(secs) if secs > 0 => std::time::Duration::from_secs(secs),
+            _ => {
+                eprintln!("Invalid --idle-timeout '{}' (expected a number of seconds above 0)", secs);
+                std::process::exit(1);
+            }
+        },
+        None => server::DEFAULT_IDLE_TIMEOUT,
+    };
+    let data_dir = args
+        .iter()
+        .find_map(|a| a.strip_prefix("--data-dir="))
+        .map(PathBuf::from)
+        .unwrap_or_else(default_data_dir);
+    // Created up front so a mistyped path fails here rather than at the first registration
+    let data_dir = match std::fs::create_dir_all(&data_dir).and_then(|_| data_dir.canonicalize()) {
+        Ok(dir) => dir,
+        Err(e) => {
+            eprintln!("Cannot use data directory {}: {}", data_dir.display(), e);
+            std::process::exit(1);
+        }
+    };
+    println!("Data directory: {}", data_dir.display());
+    let key_file = args
+        .iter()
+        .find_map(|a| a.strip_prefix("--key-file="))
+        .map(PathBuf::from)
+        .unwrap_or_else(|| data_dir.join("server_key"));
+    let default_listen: libp2p::Multiaddr = "/ip4/0.0.0.0/tcp/62649".parse()?;
+    // Host names can't be listened on, only IP addresses
+    let listen_addr = match args.iter().find(|a| !a.starts_with("--")).map(|arg| (arg, server::parse_tcp_address(arg))) {
+        None => default_listen,
+        Some((_, Ok(addr))) if matches!(addr.iter().next(), Some(Protocol::Ip4(_) | Protocol::Ip6(_))) => addr,
+        Some((arg, Ok(_))) => {
+            eprintln!("Cannot listen on '{}': give an IP address; using {}", arg, default_listen);
+            default_listen
+        }
+        Some((arg, Err(e))) => {
+            eprintln!("Invalid listen address '{}' ({}); using {}", arg, e, default_listen);
+            default_listen
+        }
+    };
+
+    // Argon2id cost for new password hashes; NEUTRAL_ARGON2_MEMORY_KIB / NEUTRAL_ARGON2_ITERATIONS override the defaults
+    let defaults = PasswordHashing::default();
+    let password_hashing = PasswordHashing {
+        memory_kib: env_u32("NEUTRAL_ARGON2_MEMORY_KIB").unwrap_or(defaults.memory_kib),
+        iterations: env_u32("NEUTRAL_ARGON2_ITERATIONS").unwrap_or(defaults.iterations),
     };
 
-    let keypair = libp2p::identity::Keypair::ed25519_from_bytes([0; 32]).unwrap();
+    let keypair = if insecure_fixed_key {
+        println!("WARNING: using the publicly known fixed key; anyone can impersonate this server");
+        libp2p::identity::Keypair::ed25519_from_bytes([0; 32]).unwrap()
+    } else {
+        match load_or_create_key(&key_file) {
+            Ok(keypair) => keypair,
+            Err(e) => {
+                eprintln!("Cannot load server key from {}: {}", key_file.display(), e);
+                eprintln!("Fix or remove the file (a new key changes the server's PeerId), or pass --key-file=<path>.");
+                std::process::exit(1);
+            }
+        }
+    };
     let server_peer_id = libp2p::PeerId::from(keypair.public());
     println!("Rendezvous server peer id: {}", server_peer_id);
 
-    let mut swarm = libp2p::SwarmBuilder::with_existing_identity(keypair)
-        .with_tokio()
-        .with_tcp(
-            tcp::Config::default(),
-            noise::Config::new,
-            yamux::Config::default,
-        )?
-        .with_behaviour(|key| MyBehaviour {
-            identify: identify::Behaviour::new(identify::Config::new(
-                "rendezvous-example/1.0.0".to_string(),
-                key.public(),
-            )),
-            rendezvous: rendezvous::server::Behaviour::new(rendezvous::server::Config::default()),
-            ping: ping::Behaviour::new(ping::Config::default()),
-            request_response: request_response::Behaviour::new(
-                std::iter::once((HelloProtocol(), request_response::ProtocolSupport::Full)),
-                request_response::Config::default(),
-            ),
-            auth: request_response::Behaviour::new(
-                std::iter::once((AuthProtocol(), request_response::ProtocolSupport::Full)),
-                request_response::Config::default(),
-            ),
-        })?
-        .with_swarm_config(|c: libp2p::swarm::Config| c.with_idle_connection_timeout(std::time::Duration::from_secs(60)))
-        .build();
-
-    let listen_multiaddr_str = format!("/ip4/{}/tcp/{}", listen_ip, listen_port);
-    let _ = swarm.listen_on(listen_multiaddr_str.parse().unwrap());
-    println!("Listening on {}", listen_multiaddr_str);
-
-    // Persistent user store
-    // Use a path relative to the server crate directory to be stable across working directories
-    let users_path: PathBuf = Path::new(env!("CARGO_MANIFEST_DIR")).join("users.xml");
-    let mut users_xml = load_users(&users_path);
-    let mut users_by_name: HashMap<String, (String, String)> = HashMap::new();
-    for u in &users_xml.users {
-        users_by_name.insert(u.username.clone(), (u.password_hash.clone(), u.birthdate.clone()));
-    }
-    let mut username_to_peer: HashMap<String, PeerId> = HashMap::new();
-
-    while let Some(event) = swarm.next().await {
-        match event {
-            SwarmEvent::ConnectionEstablished { peer_id, .. } => {
-                tracing::info!("Connected to {}", peer_id);
-            }
-            SwarmEvent::ConnectionClosed { peer_id, .. } => {
-                tracing::info!("Disconnected from {}", peer_id);
-                // Remove any usernames associated with this peer so LIST stays accurate
-                let mut removed: Vec<String> = Vec::new();
-                username_to_peer.retain(|name, pid| {
-                    let keep = *pid != peer_id;
-                    if !keep { removed.push(name.clone()); }
-                    keep
-                });
-                if !removed.is_empty() {
-                    tracing::info!("Removed usernames on disconnect: {:?}", removed);
-                }
-            }
-            SwarmEvent::Behaviour(MyBehaviourEvent::Rendezvous(
-                rendezvous::server::Event::PeerRegistered { peer, registration },
-            )) => {
-                tracing::info!(
-                    "Peer {} registered for namespace '{}'",
-                    peer,
-                    registration.namespace
-                );
+    // Ctrl-C stops the server after it has saved its state. Dropping the sender would also stop
+    // it, so it's kept alive if the handler can't be installed.
+    let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel();
+    tokio::spawn(async move {
+        match tokio::signal::ctrl_c().await {
+            Ok(()) => {
+                println!("Ctrl-C received, shutting down");
+                let _ = shutdown_tx.send(());
             }
-            SwarmEvent::Behaviour(MyBehaviourEvent::Rendezvous(
-                rendezvous::server::Event::DiscoverServed {
-                    enquirer,
-                    registrations,
-                },
-            )) => {
-                tracing::info!(
-                    "Served peer {} with {} registrations",
-                    enquirer,
-                    registrations.len()
-                );
-            }
-            // Chat protocol
-            SwarmEvent::Behaviour(MyBehaviourEvent::RequestResponse(event)) => match event {
-                request_response::Event::Message { peer, message } => match message {
-                    request_response::Message::Request {
-                        request, channel, ..
-                    } => {
-                        tracing::info!("Received request: '{}' from peer {}", request, peer);
-                        if let Err(e) = swarm.behaviour_mut().request_response.send_response(
-                            channel,
-                            "Hello Back from Server".to_string(),
-                        ) {
-                            tracing::error!("Failed to send response: {}", e);
-                        }
-                    }
-                    request_response::Message::Response { response, .. } => {
-                        tracing::warn!(
-                            "Received unexpected response: '{}' from peer {}",
-                            response,
-                            peer
-                        );
-                    }
-                },
-                _ => {}
-            },
-            // Auth protocol
-            SwarmEvent::Behaviour(MyBehaviourEvent::Auth(event)) => match event {
-                request_response::Event::Message { peer, message } => match message {
-                    request_response::Message::Request { request, 
-                        channel, .. } => {
-                        let text = request.to_string();
-                        // Expect formats:
-                        // REGISTER:username|password|YYYY-MM-DD
-                        // LOGIN:username|password
-                        let resp = if let Some(rest) = text.strip_prefix("REGISTER:") {
-                            let parts: Vec<&str> = rest.split('|').collect();
-                            if parts.len() != 3 { "AUTH:ERR:Invalid register payload".to_string() }
-                            else {
-                                let name = parts[0].trim().to_string();
-                                let pw = parts[1];
-                                let dob = parts[2].trim().to_string();
-                                match users_by_name.get(&name) {
-                                    None => {
-                                        let pw_hash = hash_password(pw);
-                                        users_by_name.insert(name.clone(), (pw_hash.clone(), dob.clone()));
-                                        users_xml.users.push(UserXml { username: name.clone(), password_hash: pw_hash, birthdate: dob });
-                                        save_users(&users_path, &users_xml);
-                                        username_to_peer.insert(name, peer);
-                                        "AUTH:OK".to_string()
-                                    }
-                                    Some(_) => "AUTH:ERR:Username taken".to_string(),
-                                }
-                            }
-                        } else if let Some(rest) = text.strip_prefix("LOGIN:") {
-                            let parts: Vec<&str> = rest.split('|').collect();
-                            if parts.len() != 2 { "AUTH:ERR:Invalid login payload".to_string() }
-                            else {
-                                let name = parts[0].trim();
-                                let pw = parts[1];
-                                match users_by_name.get(name) {
-                                    Some((hash, _dob)) => {
-                                        if *hash == hash_password(pw) {
-                                            match username_to_peer.get(name) {
-                                                Some(pid) if *pid == peer => "AUTH:OK".to_string(),
-                                                Some(_) => "AUTH:ERR:Username belongs to another peer".to_string(),
-                                                None => { username_to_peer.insert(name.to_string(), peer); "AUTH:OK".to_string() }
-                                            }
-                                        } else {
-                                            "AUTH:ERR:Invalid password".to_string()
-                                        }
-                                    }
-                                    None => "AUTH:ERR:Unknown user".to_string(),
-                                }
-                            }
-                        } else if let Some(rest) = text.strip_prefix("LOGOUT:") {
-                            let name = rest.trim();
-                            match username_to_peer.get(name) {
-                                Some(pid) if *pid == peer => {
-                                    username_to_peer.remove(name);
-                                    "AUTH:OK".to_string()
-                                }
-                                Some(_) => "AUTH:ERR:Username belongs to another peer".to_string(),
-                                None => "AUTH:ERR:Unknown user".to_string(),
-                            }
-                        } else if let Some(rest) = text.strip_prefix("DELETE:") {
-                            // DELETE:username|password
-                            let parts: Vec<&str> = rest.split('|').collect();
-                            if parts.len() != 2 { "DELETE:ERR:Invalid delete payload".to_string() }
-                            else {
-                                let name = parts[0].trim();
-                                let pw = parts[1];
-                                match users_by_name.get(name) {
-                                    Some((hash, _dob)) if *hash == hash_password(pw) => {
-                                        // Remove from in-memory maps
-                                        users_by_name.remove(name);
-                                        username_to_peer.remove(name);
-                                        // Remove from XML list and persist
-                                        users_xml.users.retain(|u| u.username != name);
-                                        save_users(&users_path, &users_xml);
-                                        "DELETE:OK".to_string()
-                                    }
-                                    Some(_) => "DELETE:ERR:Invalid password".to_string(),
-                                    None => "DELETE:ERR:Unknown user".to_string(),
-                                }
-                            }
-                        } else if text.trim() == "LIST" {
-                            // Return a mapping of username=peerid for all logged-in users
-                            let mut pairs: Vec<String> = Vec::new();
-                            for (name, pid) in &username_to_peer {
-                                pairs.push(format!("{}={}", name, pid));
-                            }
-                            format!("LIST:{}", pairs.join(","))
-                        } else {
-                            "AUTH:ERR:Unknown command".to_string()
-                        };
-                        if let Err(e) = swarm.behaviour_mut().auth.send_response(channel, resp) {
-                            tracing::error!("Failed to send auth response: {}", e);
-                        }
-                    }
-                    _ => {}
-                },
-                _ => {}
-            },
-            other => {
-                tracing::debug!("Unhandled {:?}", other);
+            Err(e) => {
+                tracing::warn!("Cannot listen for Ctrl-C: {}", e);
+                std::future::pending::<()>().await;
             }
         }
-    }
-
-    Ok(())
-}
-
-// --- Network Behaviour Definition ---
-#[derive(NetworkBehaviour)]
-struct MyBehaviour {
-    identify: identify::Behaviour,
-    rendezvous: rendezvous::server::Behaviour,
-    ping: ping::Behaviour,
-    request_response: request_response::Behaviour<HelloCodec>,
-    auth: request_response::Behaviour<AuthCodec>,
+    });
+
+    run_server(
+        ServerConfig {
+            keypair,
+            listen_addr,
+            data_dir,
+            persist_registrations,
+            // Operator token for ADMIN: commands; admin commands are disabled when unset
+            admin_token: std::env::var("NEUTRAL_ADMIN_TOKEN").ok().filter(|t| !t.is_empty()),
+            protocol_probes: Some(protocol_probes),
+            strict_protocols,
+            shutdown: Some(shutdown_rx),
+            password_hashing,
+            // Largest chat/auth frame in bytes; NEUTRAL_MAX_FRAME_BYTES overrides the 1 MiB default
+            max_frame_bytes: env_u32("NEUTRAL_MAX_FRAME_BYTES").map_or(DEFAULT_MAX_FRAME_BYTES, |n| n as usize),
+            circuit_relay,
+            metrics_addr,
+            idle_timeout,
+            // Connection caps; NEUTRAL_MAX_CONNECTIONS / NEUTRAL_MAX_CONNECTIONS_PER_PEER override the defaults
+            max_connections: env_u32("NEUTRAL_MAX_CONNECTIONS").filter(|n| *n > 0).unwrap_or(server::DEFAULT_MAX_CONNECTIONS),
+            max_connections_per_peer: env_u32("NEUTRAL_MAX_CONNECTIONS_PER_PEER").filter(|n| *n > 0).unwrap_or(server::DEFAULT_MAX_CONNECTIONS_PER_PEER),
+        },
+        None,
+    )
+    .await
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, Default)]
-#[serde(rename = "users")]
-struct UsersXml {
-    #[serde(rename = "user", default)]
-    users: Vec<UserXml>,
-}
-
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct UserXml {
-    #[serde(rename = "username")]
-    username: String,
-    #[serde(rename = "password_hash")]
-    password_hash: String,
-    #[serde(rename = "birthdate")]
-    birthdate: String, // YYYY-MM-DD
-}
-
-fn hash_password(pw: &str) -> String {
-    let mut hasher = Sha256::new();
-    hasher.update(pw.as_bytes());
-    let out = hasher.finalize();
-    hex::encode(out)
+// <data dir>/neutral/server, e.g. ~/.local/share/neutral/server on Linux. Older versions kept
+// their files in the server crate directory; those are still used until they're moved.
+fn default_data_dir() -> PathBuf {
+    let legacy = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let Some(dir) = dirs::data_dir().map(|dir| dir.join("neutral").join("server")) else { return legacy.to_path_buf() };
+    if !dir.join("users.xml").exists() && legacy.join("users.xml").exists() {
+        println!("Using the accounts in {}; move its users.xml, server_key and other .xml files to {} to switch", legacy.display(), dir.display());
+        return legacy.to_path_buf();
+    }
+    dir
 }
 
-fn load_users(path: &Path) -> UsersXml {
-    match fs::read_to_string(path) {
-        Ok(text) => quick_xml::de::from_str::<UsersXml>(&text).unwrap_or_default(),
-        Err(_) => UsersXml::default(),
+// The key file holds the raw 32-byte ed25519 secret; a missing file gets a fresh random key
+fn load_or_create_key(path: &Path) -> io::Result<libp2p::identity::Keypair> {
+    match std::fs::read(path) {
+        Ok(bytes) => {
+            let len = bytes.len();
+            let secret: [u8; 32] = bytes.try_into().map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("expected 32 bytes, found {}", len))
+            })?;
+            libp2p::identity::Keypair::ed25519_from_bytes(secret)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let keypair = libp2p::identity::Keypair::generate_ed25519();
+            let secret = keypair.clone().try_into_ed25519().expect("generated an ed25519 key").secret();
+            let mut options = std::fs::OpenOptions::new();
+            options.write(true).create_new(true);
+            #[cfg(unix)]
+            std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+            io::Write::write_all(&mut options.open(path)?, secret.as_ref())?;
+            println!("Generated new server key at {}", path.display());
+            Ok(keypair)
+        }
+        Err(e) => Err(e),
     }
 }
 
-fn save_users(path: &Path, users: &UsersXml) {
-    // Serialize with correct root; include XML header
-    if let Ok(xml_body) = quick_xml::se::to_string(users) {
-        let xml_all = format!("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n{}", xml_body);
-        let _ = fs::write(path, xml_all);
-    }
-}
\ No newline at end of file
+fn env_u32(name: &str) -> Option<u32> {
+    std::env::var(name).ok().and_then(|v| v.trim().parse().ok())
+}
@@ -0,0 +1,130 @@
+// Counters and gauges for operators, served as Prometheus text over plain HTTP when the
+// server is started with a metrics address. The swarm loop updates them; a separate task
+// answers scrapes, so a slow scraper never holds up the swarm.
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+
+use crate::protocol::AuthRequest;
+
+#[derive(Default)]
+pub struct Metrics {
+    registered_users: AtomicU64,
+    online_users: AtomicU64,
+    // Auth requests received, by request type
+    auth_requests: Mutex<BTreeMap<&'static str, u64>>,
+    // Rendezvous DISCOVER requests answered, and auth-protocol Discover requests
+    discovers_served: AtomicU64,
+}
+
+impl Metrics {
+    pub fn set_users(&self, registered: usize, online: usize) {
+        self.registered_users.store(registered as u64, Ordering::Relaxed);
+        self.online_users.store(online as u64, Ordering::Relaxed);
+    }
+
+    pub fn auth_request(&self, request: &AuthRequest) {
+        let kind = match request {
+            AuthRequest::Register { .. } => "register",
+            AuthRequest::Login { .. } => "login",
+            AuthRequest::ChangePassword { .. } => "change_password",
+            AuthRequest::Logout { .. } => "logout",
+            AuthRequest::Delete { .. } => "delete",
+            AuthRequest::Admin { .. } => "admin",
+            AuthRequest::Discover { .. } => "discover",
+            AuthRequest::List { .. } => "list",
+            AuthRequest::ListAll { .. } => "list_all",
+            AuthRequest::Resolve { .. } => "resolve",
+            AuthRequest::Presence { .. } => "presence",
+            AuthRequest::Introduce { .. } => "introduce",
+        };
+        *self.auth_requests.lock().unwrap().entry(kind).or_insert(0) += 1;
+    }
+
+    pub fn discover_served(&self) {
+        self.discovers_served.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "# HELP neutral_registered_users Accounts in users.xml.");
+        let _ = writeln!(out, "# TYPE neutral_registered_users gauge");
+        let _ = writeln!(out, "neutral_registered_users {}", self.registered_users.load(Ordering::Relaxed));
+        let _ = writeln!(out, "# HELP neutral_online_users Users currently logged in.");
+        let _ = writeln!(out, "# TYPE neutral_online_users gauge");
+        let _ = writeln!(out, "neutral_online_users {}", self.online_users.load(Ordering::Relaxed));
+        let _ = writeln!(out, "# HELP neutral_auth_requests_total Auth requests received, by type.");
+        let _ = writeln!(out, "# TYPE neutral_auth_requests_total counter");
+        for (kind, count) in self.auth_requests.lock().unwrap().iter() {
+            let _ = writeln!(out, "neutral_auth_requests_total{{type=\"{}\"}} {}", kind, count);
+        }
+        let _ = writeln!(out, "# HELP neutral_discovers_served_total Peer discovery requests answered.");
+        let _ = writeln!(out, "# TYPE neutral_discovers_served_total counter");
+        let _ = writeln!(out, "neutral_discovers_served_total {}", self.discovers_served.load(Ordering::Relaxed));
+        out
+    }
+}
+
+// Answers every HTTP request on `listener` with the current metrics, whatever the path
+pub async fn serve(listener: TcpListener, metrics: Arc<Metrics>) {
+    loop {
+        let Ok((mut stream, peer)) = listener.accept().await else { continue };
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            // Only the request line matters; the rest of the request is ignored
+            let mut request = [0u8; 1024];
+            if stream.read(&mut request).await.is_err() {
+                return;
+            }
+            let body = metrics.render();
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            if let Err(e) = stream.write_all(response.as_bytes()).await {
+                tracing::debug!("Metrics response to {} failed: {}", peer, e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn scrape_reports_gauges_and_counters() {
+        let metrics = Arc::new(Metrics::default());
+        metrics.set_users(3, 1);
+        metrics.auth_request(&AuthRequest::Login { username: "alice".into(), password: "secret".into() });
+        metrics.auth_request(&AuthRequest::Login { username: "bob".into(), password: "secret".into() });
+        metrics.auth_request(&AuthRequest::Logout { token: "t".into() });
+        metrics.discover_served();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, metrics));
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("\nneutral_registered_users 3\n"));
+        assert!(response.contains("\nneutral_online_users 1\n"));
+        assert!(response.contains("\nneutral_auth_requests_total{type=\"login\"} 2\n"));
+        assert!(response.contains("\nneutral_auth_requests_total{type=\"logout\"} 1\n"));
+        assert!(response.contains("\nneutral_discovers_served_total 1\n"));
+    }
+}
@@ -0,0 +1,667 @@
+// Chat, auth, relay and file protocol codecs, shared by the server and the client.
+// Every frame is an unsigned-varint u64 length prefix followed by the payload: UTF-8 text
+// for chat, a CBOR-encoded enum for the others. Chat text is decoded leniently, so a corrupt
+// byte shows as U+FFFD instead of losing the message; the CBOR protocols stay strict and
+// reject a string that isn't valid UTF-8, since a mangled token or username is never useful.
+use async_trait::async_trait;
+use futures::prelude::*;
+use libp2p::request_response;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{collections::HashMap, io, marker::PhantomData};
+
+// Default frame size limit; longer declared lengths are rejected before allocating
+pub const DEFAULT_MAX_FRAME_BYTES: usize = 1024 * 1024;
+
+// Server version advertised through identify. Clients refuse a server with another major
+// version; the minor version says which optional requests it answers (1.1: spaces).
+pub const SERVER_PROTOCOL_PREFIX: &str = "/neutral-server/";
+pub const SERVER_PROTOCOL_VERSION: &str = "/neutral-server/1.1.0";
+
+// Chat request a client sends the server periodically so the connection never counts as idle;
+// the server echoes it back
+pub const KEEPALIVE: &str = "KEEPALIVE";
+
+#[derive(Debug, Clone)]
+pub struct HelloProtocol();
+
+impl AsRef<str> for HelloProtocol {
+    fn as_ref(&self) -> &str {
+        "/hello/1.0"
+    }
+}
+
+#[derive(Clone)]
+pub struct HelloCodec {
+    max_frame_bytes: usize,
+}
+
+impl HelloCodec {
+    pub fn new(max_frame_bytes: usize) -> Self {
+        Self { max_frame_bytes }
+    }
+}
+
+impl Default for HelloCodec {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_FRAME_BYTES)
+    }
+}
+
+#[async_trait]
+impl request_response::Codec for HelloCodec {
+    type Protocol = HelloProtocol;
+    type Request = String;
+    type Response = String;
+
+    async fn read_request<T>(&mut self, _: &Self::Protocol, io: &mut T) -> io::Result<Self::Request>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        Ok(decode_text(read_frame(io, self.max_frame_bytes).await?))
+    }
+
+    async fn read_response<T>(
+        &mut self,
+        _: &Self::Protocol,
+        io: &mut T,
+    ) -> io::Result<Self::Response>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        Ok(decode_text(read_frame(io, self.max_frame_bytes).await?))
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &Self::Protocol,
+        io: &mut T,
+        req: Self::Request,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_frame(io, req.as_bytes(), self.max_frame_bytes).await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &Self::Protocol,
+        io: &mut T,
+        res: Self::Response,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_frame(io, res.as_bytes(), self.max_frame_bytes).await
+    }
+}
+
+// Usernames are unique ignoring case; this is the form they're compared in. The server keeps
+// the casing an account was registered with as its display name.
+pub fn username_key(name: &str) -> String {
+    name.trim().to_lowercase()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AuthRequest {
+    // birthdate is yyyy-mm-dd
+    Register { username: String, password: String, birthdate: String },
+    Login { username: String, password: String },
+    // The old password counts against the login limit
+    ChangePassword { username: String, old_password: String, new_password: String },
+    // Online users of the session's directory, each session under an opaque handle; see Resolve.
+    // With a `space`, the session joins it and only sees (and is seen by) sessions in the same
+    // space; None is the shared space of everyone.
+    // Left out by older clients, hence the default.
+    // `last_seen` asks for Directory instead of Users; older servers ignore it and answer Users.
+    List {
+        token: String,
+        #[serde(default)]
+        space: Option<String>,
+        #[serde(default)]
+        last_seen: bool,
+    },
+    // A page of every registered account in name order, starting at `offset`, without sessions
+    ListAll { token: String, offset: u32 },
+    // The PeerId behind `session`, a handle from the caller's directory, for dialing it; answered
+    // with Route. The server tells that session who asked, with Introduce.
+    Resolve { token: String, session: String },
+    Logout { token: String },
+    // Deletes the session's account
+    Delete { token: String },
+    // Signed peer records the server knows for the namespace
+    Discover { namespace: String },
+    // Operator-only; `token` must match NEUTRAL_ADMIN_TOKEN
+    Admin { token: String, command: AdminCommand },
+    // Server to client: the directory, as in Directory, after someone came online or went offline; answered with Ok
+    Presence {
+        users: HashMap<String, Vec<String>>,
+        #[serde(default)]
+        last_seen: HashMap<String, u64>,
+    },
+    // Server to client: `peer` resolved one of this client's sessions and is about to dial it;
+    // `session` is the handle the directory lists `peer` under. Answered with Ok.
+    Introduce { session: String, peer: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AdminCommand {
+    // Replaces the credentials with a sha256-hex hash; None clears them so the next Register claims the name
+    Transfer { username: String, password_hash: Option<String> },
+    // Deletes the account and frees the name
+    Release { username: String },
+}
+
+impl AdminCommand {
+    // The account the command acts on, as the operator typed it
+    pub fn username(&self) -> &str {
+        match self {
+            AdminCommand::Transfer { username, .. } | AdminCommand::Release { username } => username,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AuthResponse {
+    Ok,
+    // Successful Register/Login
+    Session { token: String },
+    Error(AuthError),
+    // Unknown or expired session token
+    InvalidSession,
+    // Every registered username -> the handles of its sessions, empty while offline. A handle
+    // stands for one PeerId and only the server can map it back; the asker's own session is left out.
+    Users(HashMap<String, Vec<String>>),
+    // Users, plus when each offline user was last online in Unix milliseconds; accounts that
+    // haven't been online since the server started recording it have no entry
+    Directory { users: HashMap<String, Vec<String>>, last_seen: HashMap<String, u64> },
+    // Protobuf-encoded signed envelopes of peer records
+    Discovered(Vec<Vec<u8>>),
+    // Resolve's answer: the PeerId the handle stands for
+    Route { session: String, peer: String },
+    // A ListAll page: (username, online) pairs, and the offset of the next page if there is one.
+    // `last_seen` is as in Directory, for the offline accounts on the page.
+    Accounts {
+        users: Vec<(String, bool)>,
+        next: Option<u32>,
+        #[serde(default)]
+        last_seen: HashMap<String, u64>,
+    },
+}
+
+// Why the server refused an auth request. It travels as a stable code plus an optional
+// detail, so clients never parse display text; the client words it for the user.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "AuthErrorCode", into = "AuthErrorCode")]
+pub enum AuthError {
+    UsernameTaken,
+    InvalidPassword,
+    UnknownUser,
+    // The username is logged in from another peer; not sent since accounts allow several sessions
+    WrongPeer,
+    // Too many failed logins; try again later
+    RateLimited,
+    // The password policy's reason, from `validate_password`
+    WeakPassword(String),
+    // The username policy's reason, from `validate_username`
+    InvalidUsername(String),
+    InvalidBirthdate,
+    // Younger than the minimum registration age
+    TooYoung { min_age: u32 },
+    // An operator reset the credentials; the next Register claims the name
+    AwaitingOwner,
+    // The server couldn't save the change
+    Storage,
+    // Admin Transfer with a malformed password hash
+    InvalidCredential,
+    NotAuthorized,
+    // A request the receiver doesn't answer, e.g. Presence sent to the server
+    UnexpectedRequest,
+    // A code this build doesn't know, from a newer peer
+    Unknown(String),
+}
+
+impl AuthError {
+    // The stable code the error travels as
+    pub fn code(&self) -> String {
+        AuthErrorCode::from(self.clone()).code
+    }
+}
+
+// Servers before typed errors sent `Error { reason }` with display text; it decodes as the code,
+// so their refusals still reach the user as AuthError::Unknown
+#[derive(Clone, Serialize, Deserialize)]
+struct AuthErrorCode {
+    #[serde(alias = "reason")]
+    code: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+}
+
+impl From<AuthError> for AuthErrorCode {
+    fn from(error: AuthError) -> Self {
+        let (code, detail) = match error {
+            AuthError::UsernameTaken => ("username_taken", None),
+            AuthError::InvalidPassword => ("invalid_password", None),
+            AuthError::UnknownUser => ("unknown_user", None),
+            AuthError::WrongPeer => ("wrong_peer", None),
+            AuthError::RateLimited => ("rate_limited", None),
+            AuthError::WeakPassword(reason) => ("weak_password", Some(reason)),
+            AuthError::InvalidUsername(reason) => ("invalid_username", Some(reason)),
+            AuthError::InvalidBirthdate => ("invalid_birthdate", None),
+            AuthError::TooYoung { min_age } => ("too_young", Some(min_age.to_string())),
+            AuthError::AwaitingOwner => ("awaiting_owner", None),
+            AuthError::Storage => ("storage", None),
+            AuthError::InvalidCredential => ("invalid_credential", None),
+            AuthError::NotAuthorized => ("not_authorized", None),
+            AuthError::UnexpectedRequest => ("unexpected_request", None),
+            AuthError::Unknown(code) => return AuthErrorCode { code, detail: None },
+        };
+        AuthErrorCode { code: code.to_string(), detail }
+    }
+}
+
+impl From<AuthErrorCode> for AuthError {
+    fn from(AuthErrorCode { code, detail }: AuthErrorCode) -> Self {
+        match code.as_str() {
+            "username_taken" => AuthError::UsernameTaken,
+            "invalid_password" => AuthError::InvalidPassword,
+            "unknown_user" => AuthError::UnknownUser,
+            "wrong_peer" => AuthError::WrongPeer,
+            "rate_limited" => AuthError::RateLimited,
+            "weak_password" => AuthError::WeakPassword(detail.unwrap_or_default()),
+            "invalid_username" => AuthError::InvalidUsername(detail.unwrap_or_default()),
+            "invalid_birthdate" => AuthError::InvalidBirthdate,
+            "too_young" => match detail.and_then(|age| age.parse().ok()) {
+                Some(min_age) => AuthError::TooYoung { min_age },
+                None => AuthError::Unknown(code),
+            },
+            "awaiting_owner" => AuthError::AwaitingOwner,
+            "storage" => AuthError::Storage,
+            "invalid_credential" => AuthError::InvalidCredential,
+            "not_authorized" => AuthError::NotAuthorized,
+            "unexpected_request" => AuthError::UnexpectedRequest,
+            _ => AuthError::Unknown(code),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AuthProtocol();
+
+impl AsRef<str> for AuthProtocol {
+    fn as_ref(&self) -> &str {
+        "/auth/4.0"
+    }
+}
+
+pub type AuthCodec = CborCodec<AuthProtocol, AuthRequest, AuthResponse>;
+
+// Store-and-forward for chat messages to users the sender can't reach directly
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RelayRequest {
+    // Client to server: queue a message for `to`, sent as the session's user.
+    // `encrypted` texts are sealed end to end for the recipient; the server can't read them.
+    Store {
+        token: String,
+        to: String,
+        text: String,
+        forwarded: bool,
+        #[serde(default)]
+        encrypted: bool,
+    },
+    // Server to client after login: messages queued while the user was away, oldest first
+    Deliver { messages: Vec<QueuedMessage> },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QueuedMessage {
+    pub from: String,
+    pub text: String,
+    pub forwarded: bool,
+    // Milliseconds since the Unix epoch, when the server queued it
+    pub sent_at_ms: u64,
+    #[serde(default)]
+    pub encrypted: bool,
+    // PeerId that stored the message, whose key the recipient needs to open an encrypted text
+    #[serde(default)]
+    pub from_peer: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RelayResponse {
+    // Queued until the recipient next logs in
+    Ok,
+    // The recipient is online: the server is delivering it to one of their sessions now
+    Routed,
+    Error { reason: String },
+    InvalidSession,
+}
+
+#[derive(Debug, Clone)]
+pub struct RelayProtocol();
+
+impl AsRef<str> for RelayProtocol {
+    fn as_ref(&self) -> &str {
+        "/relay/2.0"
+    }
+}
+
+pub type RelayCodec = CborCodec<RelayProtocol, RelayRequest, RelayResponse>;
+
+// Largest chunk a file sender puts in one Chunk request; well under the default frame limit
+pub const FILE_CHUNK_BYTES: usize = 64 * 1024;
+
+// Peer-to-peer file transfer: an offer, then the chunks in order, then a checksum check.
+// `transfer_id` is picked by the sender and only unique between one pair of peers.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FileRequest {
+    // `sha256` is lowercase hex of the whole file
+    Offer { transfer_id: u64, filename: String, size: u64, sha256: String },
+    Chunk {
+        transfer_id: u64,
+        offset: u64,
+        #[serde(with = "serde_bytes")]
+        data: Vec<u8>,
+    },
+    // All chunks sent; the receiver checks the size and hash before keeping the file
+    Finish { transfer_id: u64 },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FileResponse {
+    // Offer accepted, chunk stored, or file verified and saved
+    Ok,
+    // The receiver dropped the transfer, e.g. "File too large" or "Checksum mismatch"
+    Rejected { reason: String },
+}
+
+#[derive(Debug, Clone)]
+pub struct FileProtocol();
+
+impl AsRef<str> for FileProtocol {
+    fn as_ref(&self) -> &str {
+        "/file/1.0"
+    }
+}
+
+pub type FileCodec = CborCodec<FileProtocol, FileRequest, FileResponse>;
+
+// Codec for protocols whose messages are CBOR-encoded `Req`/`Res` values
+pub struct CborCodec<P, Req, Res> {
+    max_frame_bytes: usize,
+    _types: PhantomData<(P, Req, Res)>,
+}
+
+impl<P, Req, Res> CborCodec<P, Req, Res> {
+    pub fn new(max_frame_bytes: usize) -> Self {
+        Self { max_frame_bytes, _types: PhantomData }
+    }
+}
+
+impl<P, Req, Res> Clone for CborCodec<P, Req, Res> {
+    fn clone(&self) -> Self {
+        Self::new(self.max_frame_bytes)
+    }
+}
+
+impl<P, Req, Res> Default for CborCodec<P, Req, Res> {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_FRAME_BYTES)
+    }
+}
+
+#[async_trait]
+impl<P, Req, Res> request_response::Codec for CborCodec<P, Req, Res>
+where
+    P: AsRef<str> + Clone + Send + Sync + 'static,
+    Req: Serialize + DeserializeOwned + Send + Sync + 'static,
+    Res: Serialize + DeserializeOwned + Send + Sync + 'static,
+{
+    type Protocol = P;
+    type Request = Req;
+    type Response = Res;
+
+    async fn read_request<T>(&mut self, _: &Self::Protocol, io: &mut T) -> io::Result<Self::Request>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_message(io, self.max_frame_bytes).await
+    }
+
+    async fn read_response<T>(
+        &mut self,
+        _: &Self::Protocol,
+        io: &mut T,
+    ) -> io::Result<Self::Response>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_message(io, self.max_frame_bytes).await
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &Self::Protocol,
+        io: &mut T,
+        req: Self::Request,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_message(io, &req, self.max_frame_bytes).await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &Self::Protocol,
+        io: &mut T,
+        res: Self::Response,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_message(io, &res, self.max_frame_bytes).await
+    }
+}
+
+async fn read_message<T, M>(io: &mut T, max_frame_bytes: usize) -> io::Result<M>
+where
+    T: AsyncRead + Unpin + Send,
+    M: DeserializeOwned,
+{
+    let buffer = read_frame(io, max_frame_bytes).await?;
+    ciborium::from_reader(buffer.as_slice()).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+}
+
+async fn write_message<T, M>(io: &mut T, message: &M, max_frame_bytes: usize) -> io::Result<()>
+where
+    T: AsyncWrite + Unpin + Send,
+    M: Serialize,
+{
+    let mut buffer = Vec::new();
+    ciborium::into_writer(message, &mut buffer).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+    write_frame(io, &buffer, max_frame_bytes).await
+}
+
+async fn read_frame<T>(io: &mut T, max_frame_bytes: usize) -> io::Result<Vec<u8>>
+where
+    T: AsyncRead + Unpin + Send,
+{
+    let len = unsigned_varint::aio::read_u64(&mut *io)
+        .await
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    if len > max_frame_bytes as u64 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("frame of {} bytes exceeds the {} byte limit", len, max_frame_bytes),
+        ));
+    }
+    // Grow with the bytes that actually arrive, so a peer that declares a large frame
+    // and stalls holds no more memory than it has sent
+    let mut buffer = Vec::new();
+    (&mut *io).take(len).read_to_end(&mut buffer).await?;
+    if buffer.len() as u64 != len {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "frame ended before its declared length"));
+    }
+    Ok(buffer)
+}
+
+fn decode_text(buffer: Vec<u8>) -> String {
+    String::from_utf8(buffer).unwrap_or_else(|e| {
+        tracing::warn!("Chat frame is not valid UTF-8 ({}); replacing the bad bytes", e.utf8_error());
+        String::from_utf8_lossy(e.as_bytes()).into_owned()
+    })
+}
+
+async fn write_frame<T>(io: &mut T, payload: &[u8], max_frame_bytes: usize) -> io::Result<()>
+where
+    T: AsyncWrite + Unpin + Send,
+{
+    // Checked here too, so an oversized message fails locally instead of at the peer
+    if payload.len() > max_frame_bytes {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("frame of {} bytes exceeds the {} byte limit", payload.len(), max_frame_bytes),
+        ));
+    }
+    let mut uvi_buf = unsigned_varint::encode::u64_buffer();
+    let encoded_len = unsigned_varint::encode::u64(payload.len() as u64, &mut uvi_buf);
+    io.write_all(encoded_len).await?;
+    io.write_all(payload).await?;
+    io.flush().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use request_response::Codec;
+
+    #[tokio::test]
+    async fn messages_round_trip_through_the_codec() {
+        let request = AuthRequest::Register {
+            username: "alice|:,=%".to_string(),
+            password: "pass:word|1".to_string(),
+            birthdate: "2000-01-31".to_string(),
+        };
+        let mut wire = Vec::new();
+        AuthCodec::default().write_request(&AuthProtocol(), &mut wire, request.clone()).await.unwrap();
+        let mut reader = futures::io::Cursor::new(wire);
+        assert_eq!(AuthCodec::default().read_request(&AuthProtocol(), &mut reader).await.unwrap(), request);
+
+        let response = AuthResponse::Users(HashMap::from([("bob=%7C".to_string(), vec!["12D3KooW".to_string(), "12D3KooX".to_string()]), ("carol".to_string(), Vec::new())]));
+        let mut wire = Vec::new();
+        AuthCodec::default().write_response(&AuthProtocol(), &mut wire, response.clone()).await.unwrap();
+        let mut reader = futures::io::Cursor::new(wire);
+        assert_eq!(AuthCodec::default().read_response(&AuthProtocol(), &mut reader).await.unwrap(), response);
+    }
+
+    // AuthProtocol's id stands for this exact encoding of the directory. If the expected bytes
+    // have to change, the id has to change with them.
+    #[test]
+    fn directory_encoding_matches_the_protocol_id() {
+        assert_eq!(AuthProtocol().as_ref(), "/auth/4.0");
+        let mut payload = Vec::new();
+        ciborium::into_writer(&AuthResponse::Users(HashMap::from([("bob".to_string(), vec!["h1".to_string()])])), &mut payload).unwrap();
+        // {"Users": {"bob": ["h1"]}}: a list of session handles per name, empty while offline.
+        // 3.0 had the same shape with PeerIds in it.
+        assert_eq!(hex::encode(&payload), "a1655573657273a163626f6281626831");
+    }
+
+    #[tokio::test]
+    async fn auth_errors_decode_by_code() {
+        for error in [AuthError::WeakPassword("too short".to_string()), AuthError::InvalidUsername("too long".to_string()), AuthError::TooYoung { min_age: 13 }, AuthError::RateLimited] {
+            let mut wire = Vec::new();
+            AuthCodec::default().write_response(&AuthProtocol(), &mut wire, AuthResponse::Error(error.clone())).await.unwrap();
+            let mut reader = futures::io::Cursor::new(wire);
+            assert_eq!(AuthCodec::default().read_response(&AuthProtocol(), &mut reader).await.unwrap(), AuthResponse::Error(error));
+        }
+
+        // A newer server's code still decodes, keeping the code for the UI
+        let mut payload = Vec::new();
+        let newer = AuthErrorCode { code: "account_suspended".to_string(), detail: Some("until May".to_string()) };
+        ciborium::into_writer(&newer, &mut payload).unwrap();
+        let decoded: AuthError = ciborium::from_reader(payload.as_slice()).unwrap();
+        assert_eq!(decoded, AuthError::Unknown("account_suspended".to_string()));
+
+        // The free-text form from before error codes
+        #[derive(Serialize)]
+        enum LegacyResponse {
+            Error { reason: String },
+        }
+        let mut payload = Vec::new();
+        ciborium::into_writer(&LegacyResponse::Error { reason: "Username taken".to_string() }, &mut payload).unwrap();
+        let decoded: AuthResponse = ciborium::from_reader(payload.as_slice()).unwrap();
+        assert_eq!(decoded, AuthResponse::Error(AuthError::Unknown("Username taken".to_string())));
+    }
+
+    #[tokio::test]
+    async fn large_chat_messages_round_trip() {
+        let message = "x".repeat(200 * 1024);
+        let mut wire = Vec::new();
+        HelloCodec::default().write_request(&HelloProtocol(), &mut wire, message.clone()).await.unwrap();
+        let mut reader = futures::io::Cursor::new(wire);
+        assert_eq!(HelloCodec::default().read_request(&HelloProtocol(), &mut reader).await.unwrap(), message);
+    }
+
+    #[tokio::test]
+    async fn file_chunks_are_encoded_as_bytes() {
+        let request = FileRequest::Chunk { transfer_id: 7, offset: 0, data: vec![0xff; FILE_CHUNK_BYTES] };
+        let mut wire = Vec::new();
+        FileCodec::default().write_request(&FileProtocol(), &mut wire, request.clone()).await.unwrap();
+        // A CBOR byte string, not an array of integers that could double the size
+        assert!(wire.len() < FILE_CHUNK_BYTES + 64);
+        let mut reader = futures::io::Cursor::new(wire);
+        assert_eq!(FileCodec::default().read_request(&FileProtocol(), &mut reader).await.unwrap(), request);
+    }
+
+    #[tokio::test]
+    async fn oversized_declared_lengths_are_rejected() {
+        // Only the length prefix is sent; the reader must fail before allocating or reading a body
+        let mut uvi_buf = unsigned_varint::encode::u64_buffer();
+        let wire = unsigned_varint::encode::u64(u64::MAX, &mut uvi_buf).to_vec();
+        let err = HelloCodec::default()
+            .read_request(&HelloProtocol(), &mut futures::io::Cursor::new(wire.clone()))
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let err = AuthCodec::new(1024)
+            .read_request(&AuthProtocol(), &mut futures::io::Cursor::new(wire))
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn invalid_utf8_is_replaced_in_chat_but_rejected_in_auth() {
+        let mut wire = Vec::new();
+        write_frame(&mut wire, b"MSG:caf\xe9 ok", DEFAULT_MAX_FRAME_BYTES).await.unwrap();
+        let mut reader = futures::io::Cursor::new(wire);
+        assert_eq!(HelloCodec::default().read_request(&HelloProtocol(), &mut reader).await.unwrap(), "MSG:caf\u{fffd} ok");
+
+        // Same length as "ab", so only the string's bytes change, not its CBOR header
+        let mut payload = Vec::new();
+        ciborium::into_writer(&AuthRequest::List { token: "ab".to_string(), space: None, last_seen: false }, &mut payload).unwrap();
+        let at = payload.windows(2).position(|w| w == b"ab").unwrap();
+        payload[at..at + 2].copy_from_slice(&[0xff, 0xfe]);
+        let mut wire = Vec::new();
+        write_frame(&mut wire, &payload, DEFAULT_MAX_FRAME_BYTES).await.unwrap();
+        let err = AuthCodec::default()
+            .read_request(&AuthProtocol(), &mut futures::io::Cursor::new(wire))
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn truncated_frames_are_rejected() {
+        let mut uvi_buf = unsigned_varint::encode::u64_buffer();
+        let mut wire = unsigned_varint::encode::u64(DEFAULT_MAX_FRAME_BYTES as u64, &mut uvi_buf).to_vec();
+        wire.extend_from_slice(b"short");
+        let err = HelloCodec::default()
+            .read_request(&HelloProtocol(), &mut futures::io::Cursor::new(wire))
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,63 @@
+//! Field escaping for the text payloads (chat `MSG:`/`FWD:` and gossip room messages), whose
+//! fields are joined with '|' and whose prefixes use ':'. Shared by client and server so both frame payloads alike.
+
+// '%' first, so escaping stays reversible
+const ESCAPED: [(char, &str); 5] = [('%', "%25"), ('|', "%7C"), (':', "%3A"), (',', "%2C"), ('=', "%3D")];
+
+/// Percent-escapes the delimiter characters (and '%') in one field value.
+pub fn escape(field: &str) -> String {
+    let mut out = String::with_capacity(field.len());
+    for c in field.chars() {
+        match ESCAPED.iter().find(|(raw, _)| *raw == c) {
+            Some((_, code)) => out.push_str(code),
+            None => out.push(c),
+        }
+    }
+    out
+}
+
+/// Reverses `escape`; `None` if the field has a '%' that isn't one of our escapes.
+pub fn unescape(field: &str) -> Option<String> {
+    let mut out = String::with_capacity(field.len());
+    let mut rest = field;
+    while let Some(pos) = rest.find('%') {
+        out.push_str(&rest[..pos]);
+        let code = rest.get(pos..pos + 3)?;
+        let (raw, _) = ESCAPED.iter().find(|(_, c)| c.eq_ignore_ascii_case(code))?;
+        out.push(*raw);
+        rest = &rest[pos + 3..];
+    }
+    out.push_str(rest);
+    Some(out)
+}
+
+/// Escapes each field and joins them with '|'.
+pub fn join(fields: &[&str]) -> String {
+    fields.iter().map(|f| escape(f)).collect::<Vec<_>>().join("|")
+}
+
+/// Splits a '|'-joined payload and unescapes each field.
+pub fn split(payload: &str) -> Option<Vec<String>> {
+    payload.split('|').map(unescape).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NASTY: &str = "a|b:c,d=e%f %7C";
+
+    #[test]
+    fn fields_round_trip() {
+        assert_eq!(unescape(&escape(NASTY)).as_deref(), Some(NASTY));
+        assert!(!escape(NASTY).contains(['|', ':', ',', '=']));
+        assert_eq!(split(&join(&[NASTY, "", "plain"])), Some(vec![NASTY.to_string(), String::new(), "plain".to_string()]));
+    }
+
+    #[test]
+    fn malformed_escapes_are_rejected() {
+        assert_eq!(unescape("50%"), None);
+        assert_eq!(unescape("%41"), None);
+        assert_eq!(unescape("%7c"), Some("|".to_string()));
+    }
+}